@@ -201,14 +201,6 @@ pub(crate) unsafe fn dpx_open_dfont_file(filename: &str) -> Option<InFile> {
     }
 }
 
-pub(crate) unsafe fn dpx_delete_old_cache(life: i32) {
-    /* This used to delete files in tmpdir, but that code was ripped out since
-     * it would have been annoying to port to Windows. */
-    if life == -2i32 {
-        keep_cache = -1i32
-    };
-}
-
 pub(crate) unsafe fn dpx_delete_temp_file(tmp: &str, force: i32) {
     use std::fs;
     if tmp.is_empty() {
@@ -498,3 +498,180 @@ impl ParseFloatDecimal for &[u8] {
         q
     }
 }
+
+/// Decodes LZW data as found in GIF (codes packed LSB first) and TIFF (codes
+/// packed MSB first, widening one code early) images. Decoding stops at the
+/// end-of-information code, at the end of the data or after `limit` bytes;
+/// None is returned for malformed data.
+pub(crate) fn lzw_decode(
+    data: &[u8],
+    min_code_size: u32,
+    msb_first: bool,
+    early_change: bool,
+    limit: usize,
+) -> Option<Vec<u8>> {
+    const MAX_CODES: usize = 4096;
+    if min_code_size < 2 || min_code_size > 8 {
+        return None;
+    }
+    let clear = 1usize << min_code_size;
+    let eoi = clear + 1;
+    let mut prefix = vec![0u16; MAX_CODES];
+    let mut suffix = vec![0u8; MAX_CODES];
+    let mut first = vec![0u8; MAX_CODES];
+    let mut length = vec![0usize; MAX_CODES];
+    for code in 0..clear {
+        suffix[code] = code as u8;
+        first[code] = code as u8;
+        length[code] = 1;
+    }
+    let early = early_change as usize;
+    let mut width = min_code_size + 1;
+    let mut next = eoi + 1;
+    let mut prev: Option<usize> = None;
+    let mut out = Vec::new();
+    let mut bits: u32 = 0;
+    let mut nbits = 0;
+    let mut pos = 0;
+    while out.len() < limit {
+        while nbits < width {
+            if pos == data.len() {
+                return Some(out);
+            }
+            if msb_first {
+                bits = bits << 8 | data[pos] as u32;
+            } else {
+                bits |= (data[pos] as u32) << nbits;
+            }
+            pos += 1;
+            nbits += 8;
+        }
+        let code = if msb_first {
+            let code = bits >> (nbits - width);
+            bits &= (1 << (nbits - width)) - 1;
+            code
+        } else {
+            let code = bits & ((1 << width) - 1);
+            bits >>= width;
+            code
+        } as usize;
+        nbits -= width;
+
+        if code == clear {
+            width = min_code_size + 1;
+            next = eoi + 1;
+            prev = None;
+            continue;
+        } else if code == eoi {
+            break;
+        }
+        match prev {
+            Some(p) if code == next && next < MAX_CODES => {
+                /* The code being defined right now: prev + first of prev */
+                prefix[next] = p as u16;
+                suffix[next] = first[p];
+                first[next] = first[p];
+                length[next] = length[p] + 1;
+                next += 1;
+            }
+            _ if code < next && code != eoi => {
+                if let Some(p) = prev {
+                    if next < MAX_CODES {
+                        prefix[next] = p as u16;
+                        suffix[next] = first[code];
+                        first[next] = first[p];
+                        length[next] = length[p] + 1;
+                        next += 1;
+                    }
+                }
+            }
+            _ => return None,
+        }
+        let start = out.len();
+        out.resize(start + length[code], 0);
+        let mut c = code;
+        for b in out[start..].iter_mut().rev() {
+            *b = suffix[c];
+            c = prefix[c] as usize;
+        }
+        prev = Some(code);
+        if next + early >= 1 << width && width < 12 {
+            width += 1;
+        }
+    }
+    out.truncate(limit);
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The symbols both encoded streams below were made from.
+    fn symbols() -> Vec<u8> {
+        let mut x = 1u32;
+        (0..300)
+            .map(|_| {
+                x = x.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((x >> 16) % 4) as u8
+            })
+            .collect()
+    }
+
+    /// GIF flavour: least significant bit first, no early change.
+    const GIF_LZW: [u8; 100] = [
+        0x94, 0x32, 0x29, 0x03, 0xc7, 0x80, 0xa2, 0x59, 0x6a, 0x04, 0x01, 0xa0, 0x51, 0x21, 0x64,
+        0xc8, 0x0d, 0x86, 0x07, 0x88, 0x5b, 0x97, 0x98, 0x4c, 0x37, 0x61, 0x06, 0x74, 0x89, 0x64,
+        0xe2, 0xd5, 0x9e, 0xb5, 0x68, 0xf2, 0x72, 0x6c, 0xda, 0xd5, 0x80, 0x34, 0x0e, 0x31, 0xd9,
+        0x05, 0x23, 0x2c, 0x81, 0x58, 0x2b, 0x4b, 0xaa, 0x03, 0x43, 0xe1, 0x78, 0xc8, 0x11, 0x2d,
+        0xa3, 0xc8, 0x20, 0x2c, 0xa5, 0x85, 0x0d, 0x77, 0xb3, 0x2a, 0x59, 0xbc, 0x0c, 0xa5, 0x01,
+        0x54, 0x5e, 0xcb, 0xba, 0xed, 0x27, 0x31, 0x64, 0x58, 0x8e, 0x39, 0xc9, 0xa1, 0x84, 0x1a,
+        0xe5, 0x7e, 0x4e, 0xb4, 0x02, 0xd3, 0x51, 0x74, 0x50, 0x00,
+    ];
+
+    /// TIFF flavour: most significant bit first, early change.
+    const TIFF_LZW: [u8; 101] = [
+        0x89, 0x09, 0xc9, 0x18, 0x3e, 0x01, 0x11, 0x32, 0xd4, 0x30, 0x88, 0x10, 0x32, 0x82, 0x1c,
+        0xa0, 0xe0, 0x63, 0x3c, 0x04, 0x4d, 0xba, 0x4c, 0xc6, 0x3a, 0x9b, 0x03, 0x20, 0xbc, 0x52,
+        0x12, 0xf6, 0xaf, 0x2c, 0x5b, 0x59, 0x16, 0x3b, 0x6d, 0x17, 0x1a, 0x40, 0x68, 0x76, 0x2c,
+        0x8b, 0x98, 0x84, 0x95, 0x02, 0xc5, 0x65, 0x94, 0x9d, 0x60, 0xa1, 0xc3, 0xc9, 0x08, 0xda,
+        0x19, 0x14, 0x64, 0x41, 0x64, 0xa2, 0xdb, 0x38, 0x6f, 0x5a, 0x52, 0xc7, 0x86, 0x4a, 0x0d,
+        0x81, 0x2a, 0xbe, 0x57, 0x56, 0xcf, 0x89, 0x86, 0x30, 0xb4, 0x77, 0x24, 0x83, 0xa5, 0x50,
+        0x8d, 0xcb, 0xf9, 0xda, 0x05, 0x0c, 0x0e, 0xa2, 0x83, 0x82, 0x80,
+    ];
+
+    #[test]
+    fn lzw_gif() {
+        let decoded = lzw_decode(&GIF_LZW, 2, false, false, usize::MAX).unwrap();
+        assert_eq!(decoded, symbols());
+    }
+
+    #[test]
+    fn lzw_tiff() {
+        let decoded = lzw_decode(&TIFF_LZW, 2, true, true, usize::MAX).unwrap();
+        assert_eq!(decoded, symbols());
+    }
+
+    #[test]
+    fn lzw_limit() {
+        let decoded = lzw_decode(&GIF_LZW, 2, false, false, 123).unwrap();
+        assert_eq!(decoded, &symbols()[..123]);
+        /* A truncated stream gives what could be decoded */
+        let decoded = lzw_decode(&TIFF_LZW[..50], 2, true, true, usize::MAX).unwrap();
+        assert!(!decoded.is_empty() && decoded.len() < 300);
+        assert_eq!(decoded, &symbols()[..decoded.len()]);
+    }
+
+    #[test]
+    fn lzw_bad_input() {
+        /* A code that has not been defined yet */
+        assert_eq!(lzw_decode(&[0x07], 2, false, false, usize::MAX), None);
+        /* An end of information code right away */
+        assert_eq!(
+            lzw_decode(&[0x05], 2, false, false, usize::MAX),
+            Some(vec![])
+        );
+        assert_eq!(lzw_decode(&GIF_LZW, 1, false, false, usize::MAX), None);
+        assert_eq!(lzw_decode(&GIF_LZW, 9, false, false, usize::MAX), None);
+    }
+}
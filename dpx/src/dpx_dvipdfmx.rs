@@ -45,22 +45,24 @@ use super::dpx_pdffont::{
 use super::dpx_tt_aux::tt_aux_set_verbose;
 use crate::bridge::DisplayExt;
 use crate::dpx_pdfparse::parse_unsigned;
-use crate::info;
+use crate::{info, warn};
 use std::ffi::CStr;
 use std::ptr;
 
 use super::dpx_cid::CIDFont_set_flags;
 use super::dpx_dpxconf::paperinfo;
-use super::dpx_dpxfile::dpx_delete_old_cache;
 use super::dpx_error::shut_up;
 use super::dpx_fontmap::{
     pdf_close_fontmaps, pdf_fontmap_set_verbose, pdf_init_fontmaps, pdf_load_fontmap_file,
 };
+use super::dpx_imagecache::image_cache_init;
+use super::dpx_pdfconform::{pdf_conform_init, pdf_conform_violation, PdfConformance};
 use super::dpx_pdfencrypt::{pdf_enc_compute_id_string, pdf_enc_set_passwd, pdf_enc_set_verbose};
 use super::dpx_pdfobj::{
     pdf_files_close, pdf_files_init, pdf_get_version, pdf_obj_reset_global_state,
     pdf_obj_set_verbose, pdf_set_compression, pdf_set_use_predictor, pdf_set_version,
 };
+use super::dpx_pdfsign::{pdf_sign_enabled, pdf_sign_init, PdfSignatureConfig};
 use super::dpx_tfm::tfm_reset_global_state;
 use super::dpx_vf::vf_reset_global_state;
 use crate::specials::{
@@ -69,9 +71,17 @@ use crate::specials::{
 use libc::{atoi, free, strlen};
 
 use std::borrow::Cow;
+use std::path::PathBuf;
 
 pub struct XdvipdfmxConfig {
     pub paperspec: Cow<'static, str>,
+    pub conformance: PdfConformance,
+    pub output_intent_profile: Option<String>,
+    pub linearize: bool,
+    pub optimize: bool,
+    pub signature: Option<PdfSignatureConfig>,
+    /// Directory in which converted images are kept across runs, if any.
+    pub image_cache_path: Option<PathBuf>,
 }
 
 pub(crate) type PageRange = page_range;
@@ -95,11 +105,6 @@ static mut font_dpi: i32 = 600i32;
  * See, dev_set_string() in pdfdev.c.
  */
 static mut pdfdecimaldigits: i32 = 3i32;
-/* Image cache life in hours */
-/*  0 means erase all old images and leave new images */
-/* -1 means erase all old images and also erase new images */
-/* -2 means ignore image cache (default) */
-static mut image_cache_life: i32 = -2i32;
 /* Encryption */
 static mut do_encryption: i32 = 0i32;
 static mut key_bits: i32 = 40i32;
@@ -335,7 +340,13 @@ pub unsafe fn dvipdfmx_main(
     permission = 0x3ci32;
     font_dpi = 600i32;
     pdfdecimaldigits = 5i32;
-    image_cache_life = -2i32;
+    /* The archival and print standards require all fonts to be embedded. */
+    pdf_conform_init(
+        dpx_config.conformance,
+        dpx_config.output_intent_profile.as_deref(),
+    );
+    always_embed = (dpx_config.conformance != PdfConformance::None) as i32;
+    pdf_sign_init(dpx_config.signature.as_ref());
     pdf_load_fontmap_file("pdftex.map", '+' as i32);
     pdf_load_fontmap_file("kanjix.map", '+' as i32);
     pdf_load_fontmap_file("ckx.map", '+' as i32);
@@ -348,7 +359,7 @@ pub unsafe fn dvipdfmx_main(
     /*kpse_init_prog("", font_dpi, NULL, NULL);
     kpse_set_program_enabled(kpse_pk_format, true, kpse_src_texmf_cnf);*/
     pdf_font_set_dpi(font_dpi);
-    dpx_delete_old_cache(image_cache_life);
+    image_cache_init(dpx_config.image_cache_path.as_deref());
     pdf_enc_compute_id_string(
         if dvi_filename.is_empty() {
             None
@@ -389,6 +400,16 @@ pub unsafe fn dvipdfmx_main(
     if ver_minor >= 3i32 && ver_minor <= 7i32 {
         pdf_set_version(ver_minor as u32);
     }
+    if dpx_config.conformance == PdfConformance::PdfX4 {
+        pdf_set_version(6_u32);
+    }
+    if do_encryption != 0 && dpx_config.conformance != PdfConformance::None {
+        pdf_conform_violation("encryption is not permitted".to_owned());
+        do_encryption = 0i32;
+    }
+    if do_encryption != 0 && pdf_sign_enabled() {
+        panic!("Signed PDF output cannot be encrypted.");
+    }
     if do_encryption != 0 {
         if !(key_bits >= 40i32 && key_bits <= 128i32 && key_bits % 8i32 == 0i32)
             && key_bits != 256i32
@@ -416,6 +437,15 @@ pub unsafe fn dvipdfmx_main(
     if opt_flags & 1i32 << 6i32 != 0 {
         enable_object_stream = false
     }
+    let mut linearize = dpx_config.linearize;
+    if linearize && do_encryption != 0 {
+        warn!("Linearization of encrypted PDF output is not supported.");
+        linearize = false;
+    }
+    if linearize && pdf_sign_enabled() {
+        warn!("Linearization of signed PDF output is not supported.");
+        linearize = false;
+    }
     /* Set default paper size here so that all page's can inherite it.
      * annot_grow:    Margin of annotation.
      * bookmark_open: Miximal depth of open bookmarks.
@@ -424,6 +454,8 @@ pub unsafe fn dvipdfmx_main(
         pdf_filename,
         do_encryption != 0,
         enable_object_stream,
+        linearize,
+        dpx_config.optimize,
         paper_width,
         paper_height,
         annot_grow,
@@ -0,0 +1,248 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! GIF images. The first frame is placed on the logical screen; pixels it
+//! leaves uncovered and those of the transparent color become transparent.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use super::dpx_dpxutil::lzw_decode;
+use super::dpx_pdfximage::{pdf_ximage_set_image, pdf_ximage_soft_mask};
+use crate::dpx_pdfobj::{
+    pdf_ref_obj, pdf_release_obj, pdf_stream, pdf_string, IntoObj, PushObj, STREAM_COMPRESS,
+};
+use crate::dpx_pdfximage::{pdf_ximage, ximage_info};
+use crate::warn;
+
+pub fn check_for_gif<R: Read + Seek>(handle: &mut R) -> bool {
+    let mut sigbytes = [0u8; 6];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    handle.read_exact(&mut sigbytes).is_ok() && (&sigbytes == b"GIF87a" || &sigbytes == b"GIF89a")
+}
+
+pub fn gif_get_bbox<R: Read + Seek>(handle: &mut R) -> Result<(u32, u32, f64, f64), ()> {
+    let mut header = [0u8; 10];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    handle.read_exact(&mut header).map_err(|_| {
+        warn!("GIF: Reading file failed.");
+    })?;
+    let width = u16::from_le_bytes([header[6], header[7]]) as u32;
+    let height = u16::from_le_bytes([header[8], header[9]]) as u32;
+    Ok((width, height, 1., 1.))
+}
+
+struct Frame<'a> {
+    left: usize,
+    top: usize,
+    width: usize,
+    height: usize,
+    interlaced: bool,
+    palette: &'a [u8],
+    transparent: Option<u8>,
+    min_code_size: u32,
+    data: Vec<u8>,
+}
+
+fn short_data<T>() -> Result<T, ()> {
+    warn!("GIF: Unexpected end of file.");
+    Err(())
+}
+
+/* Concatenates data sub-blocks starting at `pos`; returns the end position. */
+fn read_sub_blocks(data: &[u8], mut pos: usize, out: &mut Vec<u8>) -> Result<usize, ()> {
+    loop {
+        let len = match data.get(pos) {
+            Some(&len) => len as usize,
+            None => return short_data(),
+        };
+        pos += 1;
+        if len == 0 {
+            return Ok(pos);
+        }
+        match data.get(pos..pos + len) {
+            Some(block) => out.extend_from_slice(block),
+            None => return short_data(),
+        }
+        pos += len;
+    }
+}
+
+fn color_table(data: &[u8], pos: usize, flags: u8) -> Result<&[u8], ()> {
+    let len = 3 << ((flags & 7) + 1);
+    match data.get(pos..pos + len) {
+        Some(table) => Ok(table),
+        None => short_data(),
+    }
+}
+
+fn first_frame(data: &[u8]) -> Result<Frame<'_>, ()> {
+    if data.len() < 13 {
+        return short_data();
+    }
+    let flags = data[10];
+    let mut pos = 13;
+    let mut global = &data[0..0];
+    if flags & 0x80 != 0 {
+        global = color_table(data, pos, flags)?;
+        pos += global.len();
+    }
+    let mut transparent = None;
+    loop {
+        match data.get(pos) {
+            Some(0x21) => {
+                let label = data.get(pos + 1).cloned();
+                let mut ext = vec![];
+                pos = read_sub_blocks(data, pos + 2, &mut ext)?;
+                /* Graphic Control Extension */
+                if label == Some(0xf9) && ext.len() >= 4 && ext[0] & 1 != 0 {
+                    transparent = Some(ext[3]);
+                }
+            }
+            Some(0x2c) => {
+                let desc = match data.get(pos + 1..pos + 10) {
+                    Some(desc) => desc,
+                    None => return short_data(),
+                };
+                let field = |i: usize| u16::from_le_bytes([desc[i], desc[i + 1]]) as usize;
+                let flags = desc[8];
+                pos += 10;
+                let mut palette = global;
+                if flags & 0x80 != 0 {
+                    palette = color_table(data, pos, flags)?;
+                    pos += palette.len();
+                }
+                let min_code_size = match data.get(pos) {
+                    Some(&size) => size as u32,
+                    None => return short_data(),
+                };
+                let mut lzw = vec![];
+                read_sub_blocks(data, pos + 1, &mut lzw)?;
+                return Ok(Frame {
+                    left: field(0),
+                    top: field(2),
+                    width: field(4),
+                    height: field(6),
+                    interlaced: flags & 0x40 != 0,
+                    palette,
+                    transparent,
+                    min_code_size,
+                    data: lzw,
+                });
+            }
+            Some(0x3b) => {
+                warn!("GIF: No image found.");
+                return Err(());
+            }
+            Some(b) => {
+                warn!("GIF: Unknown block type: 0x{:02x}", b);
+                return Err(());
+            }
+            None => return short_data(),
+        }
+    }
+}
+
+/* Row order of interlaced frames: every 8th row from 0, every 8th from 4,
+ * every 4th from 2 and finally every 2nd from 1. */
+fn interlaced_rows(height: usize) -> Vec<usize> {
+    let mut rows = Vec::with_capacity(height);
+    for &(start, step) in &[(0, 8), (4, 8), (2, 4), (1, 2)] {
+        rows.extend((start..height).step_by(step));
+    }
+    rows
+}
+
+pub(crate) unsafe fn gif_include_image<R: Read + Seek>(
+    ximage: &mut pdf_ximage,
+    handle: &mut R,
+) -> Result<(), ()> {
+    let mut data = vec![];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    if handle.read_to_end(&mut data).is_err() {
+        warn!("GIF: Reading file failed.");
+        return Err(());
+    }
+    let frame = first_frame(&data)?;
+    let width = u16::from_le_bytes([data[6], data[7]]) as usize;
+    let height = u16::from_le_bytes([data[8], data[9]]) as usize;
+    if width == 0 || height == 0 {
+        warn!("GIF: Invalid image size: {}x{}", width, height);
+        return Err(());
+    }
+    if frame.palette.is_empty() {
+        warn!("GIF: No color table found.");
+        return Err(());
+    }
+    let pixels = lzw_decode(
+        &frame.data,
+        frame.min_code_size.max(2),
+        false,
+        false,
+        frame.width * frame.height,
+    )
+    .ok_or_else(|| {
+        warn!("GIF: Broken LZW data.");
+    })?;
+
+    let mut indices = vec![0u8; width * height];
+    let mut alpha = vec![0u8; width * height];
+    let rows = if frame.interlaced {
+        interlaced_rows(frame.height)
+    } else {
+        (0..frame.height).collect()
+    };
+    for (src, &row) in pixels.chunks(frame.width.max(1)).zip(rows.iter()) {
+        let y = frame.top + row;
+        if y >= height {
+            continue;
+        }
+        for (i, &index) in src.iter().enumerate() {
+            let x = frame.left + i;
+            if x >= width {
+                break;
+            }
+            indices[y * width + x] = index;
+            if frame.transparent != Some(index) {
+                alpha[y * width + x] = 255;
+            }
+        }
+    }
+
+    let mut info = ximage_info::init();
+    info.width = width as i32;
+    info.height = height as i32;
+    info.bits_per_component = 8;
+    info.num_components = 1;
+
+    let mut stream = pdf_stream::new(STREAM_COMPRESS);
+    let mut colorspace = vec![];
+    colorspace.push_obj("Indexed");
+    colorspace.push_obj("DeviceRGB");
+    colorspace.push_obj((frame.palette.len() / 3 - 1) as f64);
+    colorspace.push_obj(pdf_string::new(frame.palette));
+    let stream_dict = stream.get_dict_mut();
+    stream_dict.set("ColorSpace", colorspace);
+    if let Some(mask) = pdf_ximage_soft_mask("GIF", info.width, info.height, &alpha) {
+        stream_dict.set("SMask", pdf_ref_obj(mask));
+        pdf_release_obj(mask);
+    }
+    stream.add_slice(&indices);
+    pdf_ximage_set_image(ximage, &info, stream.into_obj());
+    Ok(())
+}
@@ -0,0 +1,602 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! Cache of converted raster images that persists across runs.
+//!
+//! The streams making up an image XObject (the image itself, its soft mask,
+//! ...) are saved with their filters already applied, in a file named after
+//! a digest of the source file and of the options that affect the
+//! conversion: the page, the PDF version, the compression settings, the
+//! conformance level and the encryption algorithm. Images that refer to
+//! objects shared with other images, such as ICC based color spaces, are not
+//! cached.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use super::dpx_pdfconform::pdf_conform_get_level;
+use super::dpx_pdfencrypt::pdf_enc_get_algorithm;
+use super::dpx_pdfximage::pdf_ximage_set_image;
+use crate::dpx_pdfobj::{
+    pdf_begin_capture, pdf_dict, pdf_end_capture, pdf_get_compression, pdf_get_encryption,
+    pdf_get_use_predictor, pdf_get_version, pdf_name, pdf_new_null, pdf_obj, pdf_ref_obj,
+    pdf_release_captured, pdf_release_obj, pdf_stream, pdf_stream_apply_filters, pdf_string,
+    IntoObj, PdfObjVariant,
+};
+use crate::dpx_pdfximage::{pdf_ximage, ximage_info};
+use crate::warn;
+
+/* Bump this whenever the way images are converted changes. */
+const CACHE_SERIAL: u32 = 1;
+const MAGIC: &[u8] = b"%TectonicImageCache\n";
+
+static mut cache_dir: Option<PathBuf> = None;
+
+pub(crate) unsafe fn image_cache_init(dir: Option<&Path>) {
+    cache_dir = dir.map(|dir| dir.to_owned());
+}
+
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(Vec<u8>),
+    Name(Vec<u8>),
+    Array(Vec<Value>),
+    Dict(Entries),
+    /* Index of another stream of the same image */
+    Ref(usize),
+}
+
+struct CachedStream {
+    dict: Entries,
+    content: Vec<u8>,
+}
+
+struct CachedImage {
+    width: i32,
+    height: i32,
+    xdensity: f64,
+    ydensity: f64,
+    page_count: i32,
+    /* The image XObject comes first */
+    streams: Vec<CachedStream>,
+}
+
+/// Includes an image with `include`, unless a converted copy of it is found
+/// in the image cache. Newly converted images are added to the cache.
+pub(crate) unsafe fn image_cache_include<R, F>(
+    ximage: &mut pdf_ximage,
+    handle: &mut R,
+    include: F,
+) -> Result<(), ()>
+where
+    R: Read + Seek,
+    F: FnOnce(&mut pdf_ximage, &mut R) -> Result<(), ()>,
+{
+    /* Extra entries for the image dictionary are not part of the key. */
+    let path = if ximage.attr.dict.is_null() {
+        cache_path(handle, ximage.attr.page_no)
+    } else {
+        None
+    };
+    let path = match path {
+        Some(path) => path,
+        None => return include(ximage, handle),
+    };
+    if let Some(image) = fs::read(&path).ok().and_then(|data| read_image(&data)) {
+        load_image(ximage, image);
+        return Ok(());
+    }
+
+    pdf_begin_capture();
+    let result = include(ximage, handle);
+    let objects = pdf_end_capture();
+    if result.is_ok() {
+        if let Some(image) = save_image(ximage, &objects) {
+            if let Err(e) = write_file(&path, &image) {
+                warn!(
+                    "Could not write image cache file \"{}\": {}",
+                    path.display(),
+                    e
+                );
+            }
+        }
+    }
+    for object in objects {
+        pdf_release_captured(object);
+    }
+    result
+}
+
+unsafe fn cache_path<R: Read + Seek>(handle: &mut R, page_no: i32) -> Option<PathBuf> {
+    let dir = cache_dir.as_ref()?;
+    let mut data = vec![];
+    handle.seek(SeekFrom::Start(0)).ok()?;
+    handle.read_to_end(&mut data).ok()?;
+    handle.seek(SeekFrom::Start(0)).ok()?;
+    let mut sha = Sha256::new();
+    sha.input(&data);
+    sha.input(conversion_options(page_no).as_bytes());
+    let digest: String = sha.result().iter().map(|b| format!("{:02x}", b)).collect();
+    Some(dir.join(format!("{}.ximg", digest)))
+}
+
+/* Everything but the file itself that goes into the cache key. Anything
+ * else that changes how an image is converted calls for a new CACHE_SERIAL.
+ */
+unsafe fn conversion_options(page_no: i32) -> String {
+    let encryption = if pdf_get_encryption() {
+        let (version, revision, key_size) = pdf_enc_get_algorithm();
+        format!("V{}R{}L{}", version, revision, key_size)
+    } else {
+        "none".to_owned()
+    };
+    format!(
+        "{}:{}:{}:{}:{}:{}:{}",
+        CACHE_SERIAL,
+        page_no,
+        pdf_get_version(),
+        pdf_get_compression(),
+        pdf_get_use_predictor(),
+        pdf_conform_get_level().name(),
+        encryption
+    )
+}
+
+fn write_file(path: &Path, data: &[u8]) -> std::io::Result<()> {
+    /* Write to a temporary file first, so that concurrent runs never see a
+     * partially written entry. */
+    let temp = path.with_extension(format!("tmp{}", std::process::id()));
+    fs::write(&temp, data)?;
+    fs::rename(&temp, path).map_err(|e| {
+        let _ = fs::remove_file(&temp);
+        e
+    })
+}
+
+/* Converts the captured objects making up the image, or returns None if the
+ * image refers to anything else. */
+unsafe fn save_image(ximage: &pdf_ximage, objects: &[*mut pdf_obj]) -> Option<Vec<u8>> {
+    if ximage.reference.is_null() {
+        return None;
+    }
+    let main = (*ximage.reference).as_indirect().id.0;
+    let mut objects = objects.to_vec();
+    objects.sort_by_key(|&object| ((*object).label() != main, (*object).label()));
+    if objects.first().map(|&object| (*object).label()) != Some(main) {
+        return None;
+    }
+    let index: HashMap<u32, usize> = objects
+        .iter()
+        .enumerate()
+        .map(|(i, &object)| ((*object).label(), i))
+        .collect();
+    let mut streams = vec![];
+    for &object in &objects {
+        if !(*object).is_stream() {
+            return None;
+        }
+        let stream = (*object).as_stream_mut();
+        pdf_stream_apply_filters(stream);
+        streams.push(CachedStream {
+            dict: dict_value(stream.get_dict_mut(), &index)?,
+            content: stream.content.clone(),
+        });
+    }
+    let image = CachedImage {
+        width: ximage.attr.width,
+        height: ximage.attr.height,
+        xdensity: ximage.attr.xdensity,
+        ydensity: ximage.attr.ydensity,
+        page_count: ximage.attr.page_count,
+        streams,
+    };
+    Some(write_image(&image))
+}
+
+type Entries = Vec<(Vec<u8>, Value)>;
+
+unsafe fn dict_value(dict: &mut pdf_dict, index: &HashMap<u32, usize>) -> Option<Entries> {
+    unsafe fn add_entry(
+        key: &pdf_name,
+        value: &mut pdf_obj,
+        pdata: &mut (&HashMap<u32, usize>, Entries),
+    ) -> i32 {
+        match value_of(value, pdata.0) {
+            Some(value) => {
+                pdata.1.push((key.to_bytes().to_vec(), value));
+                0
+            }
+            None => -1,
+        }
+    }
+    let mut pdata = (index, vec![]);
+    if dict.foreach(add_entry, &mut pdata) < 0 {
+        None
+    } else {
+        Some(pdata.1)
+    }
+}
+
+unsafe fn value_of(object: &mut pdf_obj, index: &HashMap<u32, usize>) -> Option<Value> {
+    Some(match object.data {
+        PdfObjVariant::NULL => Value::Null,
+        PdfObjVariant::BOOLEAN(v) => Value::Bool(v),
+        PdfObjVariant::NUMBER(_) => Value::Number(object.as_f64()),
+        PdfObjVariant::STRING(_) => Value::String(object.as_string().to_bytes().to_vec()),
+        PdfObjVariant::NAME(_) => Value::Name(object.as_name().to_bytes().to_vec()),
+        PdfObjVariant::ARRAY(_) => Value::Array(
+            object
+                .as_array()
+                .iter()
+                .map(|&item| value_of(&mut *item, index))
+                .collect::<Option<_>>()?,
+        ),
+        PdfObjVariant::DICT(_) => Value::Dict(dict_value(object.as_dict_mut(), index)?),
+        PdfObjVariant::INDIRECT(_) => {
+            let indirect = object.as_indirect();
+            if !indirect.pf.is_null() {
+                return None;
+            }
+            Value::Ref(*index.get(&indirect.id.0)?)
+        }
+        _ => return None,
+    })
+}
+
+/* Creates the streams of a cached image and makes them the image's
+ * resources. */
+unsafe fn load_image(ximage: &mut pdf_ximage, image: CachedImage) {
+    let objects: Vec<*mut pdf_obj> = image
+        .streams
+        .iter()
+        .map(|_| pdf_stream::new(0).into_obj())
+        .collect();
+    for (&object, cached) in objects.iter().zip(image.streams.into_iter()) {
+        let stream = (*object).as_stream_mut();
+        fill_dict(stream.get_dict_mut(), cached.dict, &objects);
+        stream.content = cached.content;
+    }
+    ximage.attr.page_count = image.page_count;
+    let mut info = ximage_info::init();
+    info.width = image.width;
+    info.height = image.height;
+    info.xdensity = image.xdensity;
+    info.ydensity = image.ydensity;
+    pdf_ximage_set_image(ximage, &info, objects[0]);
+    for &object in &objects[1..] {
+        pdf_release_obj(object);
+    }
+}
+
+unsafe fn fill_dict(dict: &mut pdf_dict, entries: Entries, objects: &[*mut pdf_obj]) {
+    for (key, value) in entries {
+        dict.set(key, make_obj(value, objects));
+    }
+}
+
+unsafe fn make_obj(value: Value, objects: &[*mut pdf_obj]) -> *mut pdf_obj {
+    match value {
+        Value::Null => pdf_new_null(),
+        Value::Bool(v) => v.into_obj(),
+        Value::Number(v) => v.into_obj(),
+        Value::String(v) => pdf_string::new(v).into_obj(),
+        Value::Name(v) => pdf_name::new(v).into_obj(),
+        Value::Array(items) => items
+            .into_iter()
+            .map(|item| make_obj(item, objects))
+            .collect::<Vec<_>>()
+            .into_obj(),
+        Value::Dict(entries) => {
+            let mut dict = pdf_dict::new();
+            fill_dict(&mut dict, entries, objects);
+            dict.into_obj()
+        }
+        Value::Ref(i) => pdf_ref_obj(objects[i]),
+    }
+}
+
+/* A simple binary encoding of the cached image. Numbers are little endian,
+ * byte strings are preceded by their length. */
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+fn put_value(buf: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => buf.push(b'n'),
+        Value::Bool(v) => {
+            buf.push(b'b');
+            buf.push(*v as u8);
+        }
+        Value::Number(v) => {
+            buf.push(b'f');
+            buf.extend_from_slice(&v.to_le_bytes());
+        }
+        Value::String(v) => {
+            buf.push(b's');
+            put_bytes(buf, v);
+        }
+        Value::Name(v) => {
+            buf.push(b'/');
+            put_bytes(buf, v);
+        }
+        Value::Array(items) => {
+            buf.push(b'[');
+            buf.extend_from_slice(&(items.len() as u32).to_le_bytes());
+            for item in items {
+                put_value(buf, item);
+            }
+        }
+        Value::Dict(entries) => {
+            buf.push(b'<');
+            put_entries(buf, entries);
+        }
+        Value::Ref(i) => {
+            buf.push(b'R');
+            buf.extend_from_slice(&(*i as u32).to_le_bytes());
+        }
+    }
+}
+
+fn put_entries(buf: &mut Vec<u8>, entries: &[(Vec<u8>, Value)]) {
+    buf.extend_from_slice(&(entries.len() as u32).to_le_bytes());
+    for (key, value) in entries {
+        put_bytes(buf, key);
+        put_value(buf, value);
+    }
+}
+
+fn write_image(image: &CachedImage) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&image.width.to_le_bytes());
+    buf.extend_from_slice(&image.height.to_le_bytes());
+    buf.extend_from_slice(&image.xdensity.to_le_bytes());
+    buf.extend_from_slice(&image.ydensity.to_le_bytes());
+    buf.extend_from_slice(&image.page_count.to_le_bytes());
+    buf.extend_from_slice(&(image.streams.len() as u32).to_le_bytes());
+    for stream in &image.streams {
+        put_entries(&mut buf, &stream.dict);
+        put_bytes(&mut buf, &stream.content);
+    }
+    buf
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    num_streams: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.data.len() {
+            return None;
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Some(head)
+    }
+    fn byte(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+    fn u32(&mut self) -> Option<u32> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        Some(u32::from_le_bytes(b))
+    }
+    fn i32(&mut self) -> Option<i32> {
+        self.u32().map(|v| v as i32)
+    }
+    fn f64(&mut self) -> Option<f64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Some(f64::from_le_bytes(b))
+    }
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let len = self.u32()? as usize;
+        self.take(len).map(|b| b.to_vec())
+    }
+    fn value(&mut self) -> Option<Value> {
+        Some(match self.byte()? {
+            b'n' => Value::Null,
+            b'b' => Value::Bool(self.byte()? != 0),
+            b'f' => Value::Number(self.f64()?),
+            b's' => Value::String(self.bytes()?),
+            b'/' => Value::Name(self.bytes()?),
+            b'[' => {
+                let len = self.u32()?;
+                Value::Array((0..len).map(|_| self.value()).collect::<Option<_>>()?)
+            }
+            b'<' => Value::Dict(self.entries()?),
+            b'R' => {
+                let i = self.u32()? as usize;
+                if i >= self.num_streams {
+                    return None;
+                }
+                Value::Ref(i)
+            }
+            _ => return None,
+        })
+    }
+    fn entries(&mut self) -> Option<Entries> {
+        let len = self.u32()?;
+        (0..len)
+            .map(|_| Some((self.bytes()?, self.value()?)))
+            .collect()
+    }
+}
+
+/* Returns None for anything but a complete, well-formed cache file. */
+fn read_image(data: &[u8]) -> Option<CachedImage> {
+    if !data.starts_with(MAGIC) {
+        return None;
+    }
+    let mut r = Reader {
+        data: &data[MAGIC.len()..],
+        num_streams: 0,
+    };
+    let width = r.i32()?;
+    let height = r.i32()?;
+    let xdensity = r.f64()?;
+    let ydensity = r.f64()?;
+    let page_count = r.i32()?;
+    r.num_streams = r.u32()? as usize;
+    if r.num_streams == 0 {
+        return None;
+    }
+    let mut streams = vec![];
+    for _ in 0..r.num_streams {
+        let dict = r.entries()?;
+        let content = r.bytes()?;
+        streams.push(CachedStream { dict, content });
+    }
+    if !r.data.is_empty() {
+        return None;
+    }
+    Some(CachedImage {
+        width,
+        height,
+        xdensity,
+        ydensity,
+        page_count,
+        streams,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dpx_pdfconform::{pdf_conform_init, PdfConformance};
+    use crate::dpx_pdfencrypt::pdf_enc_set_passwd;
+    use crate::dpx_pdfobj::{pdf_obj_reset_global_state, pdf_out_flush, pdf_out_init};
+    use crate::test_util::with_memory_io;
+
+    /// An image with a soft mask, using every kind of value.
+    fn image() -> CachedImage {
+        let mask = CachedStream {
+            dict: vec![
+                (b"Type".to_vec(), Value::Name(b"XObject".to_vec())),
+                (b"Width".to_vec(), Value::Number(3.)),
+                (
+                    b"Decode".to_vec(),
+                    Value::Array(vec![Value::Number(1.), Value::Number(0.)]),
+                ),
+            ],
+            content: b"\x00\x80\xff".to_vec(),
+        };
+        let main = CachedStream {
+            dict: vec![
+                (b"Width".to_vec(), Value::Number(3.)),
+                (b"Interpolate".to_vec(), Value::Bool(true)),
+                (b"SMask".to_vec(), Value::Ref(1)),
+                (b"Name".to_vec(), Value::String(b"(\x00)".to_vec())),
+                (
+                    b"DecodeParms".to_vec(),
+                    Value::Dict(vec![
+                        (b"Predictor".to_vec(), Value::Number(15.)),
+                        (b"Unused".to_vec(), Value::Null),
+                    ]),
+                ),
+            ],
+            content: vec![7; 9],
+        };
+        CachedImage {
+            width: 3,
+            height: 1,
+            xdensity: 0.5,
+            ydensity: 0.25,
+            page_count: 1,
+            streams: vec![main, mask],
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let data = write_image(&image());
+        let read = read_image(&data).unwrap();
+        assert_eq!((read.width, read.height, read.page_count), (3, 1, 1));
+        assert_eq!((read.xdensity, read.ydensity), (0.5, 0.25));
+        assert_eq!(read.streams.len(), 2);
+        assert_eq!(read.streams[1].content, b"\x00\x80\xff");
+        match read.streams[0].dict[2] {
+            (ref key, Value::Ref(1)) => assert_eq!(key, b"SMask"),
+            _ => panic!("the soft mask reference was not read back"),
+        }
+        assert_eq!(write_image(&read), data);
+    }
+
+    #[test]
+    fn bad_files() {
+        let data = write_image(&image());
+        for len in 0..data.len() {
+            assert!(read_image(&data[..len]).is_none(), "{} bytes", len);
+        }
+        let mut longer = data.clone();
+        longer.push(0);
+        assert!(read_image(&longer).is_none());
+        let mut bad_magic = data.clone();
+        bad_magic[1] = b't';
+        assert!(read_image(&bad_magic).is_none());
+
+        /* A reference to a stream the file does not have */
+        let mut image = image();
+        image.streams.pop();
+        assert!(read_image(&write_image(&image)).is_none());
+        image.streams.clear();
+        assert!(read_image(&write_image(&image)).is_none());
+    }
+
+    #[test]
+    fn options() {
+        with_memory_io(&[], || unsafe {
+            let options = |encrypt| {
+                pdf_obj_reset_global_state();
+                pdf_out_init("options.pdf", encrypt, false, false, false);
+                let options = conversion_options(1);
+                pdf_out_flush();
+                options
+            };
+            let plain = options(false);
+            assert_ne!(conversion_options(2), plain);
+
+            pdf_conform_init(PdfConformance::PdfA2b, None);
+            let pdfa = options(false);
+            pdf_conform_init(PdfConformance::None, None);
+            assert_ne!(pdfa, plain);
+            assert_eq!(options(false), plain);
+
+            let empty = b"\0".as_ptr() as *const i8;
+            pdf_enc_set_passwd(128, 0, empty, empty);
+            let rc4 = options(true);
+            pdf_enc_set_passwd(40, 0, empty, empty);
+            let rc4_40 = options(true);
+            assert_ne!(rc4, plain);
+            assert_ne!(rc4, rc4_40);
+            assert_eq!(options(false), plain);
+        })
+        .unwrap();
+    }
+}
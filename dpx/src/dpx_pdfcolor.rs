@@ -1150,6 +1150,41 @@ pub(crate) unsafe fn iccp_load_profile(ident: *const i8, profile: &[u8]) -> i32
     cspc_id = pdf_colorspace_defineresource(ident, 4i32, cdata, resource.into_obj());
     cspc_id
 }
+/* Validates a profile meant for an OutputIntent and wraps it in a stream
+ * suitable for its DestOutputProfile entry. Returns NULL on failure. */
+pub(crate) unsafe fn iccp_load_output_profile(ident: &str, profile: &[u8]) -> *mut pdf_obj {
+    let mut icch = iccHeader::default();
+    iccp_init_iccHeader(&mut icch);
+    if iccp_unpack_header(&mut icch, profile, 1i32) < 0i32 {
+        warn!("Invalid ICC profile header in \"{}\"", ident);
+        return ptr::null_mut();
+    }
+    if iccp_version_supported(
+        icch.version >> 24i32 & 0xffi32,
+        icch.version >> 16i32 & 0xffi32,
+    ) == 0
+    {
+        warn!(
+            "ICC profile \"{}\" not supported in current PDF version setting.",
+            ident
+        );
+        return ptr::null_mut();
+    }
+    let num_components = if icch.colorSpace == str2iccSig(b"RGB ") {
+        3
+    } else if icch.colorSpace == str2iccSig(b"GRAY") {
+        1
+    } else if icch.colorSpace == str2iccSig(b"CMYK") {
+        4
+    } else {
+        warn!("Unsupported color space in output profile \"{}\".", ident);
+        return ptr::null_mut();
+    };
+    let mut stream = pdf_stream::new(STREAM_COMPRESS);
+    stream.get_dict_mut().set("N", num_components as f64);
+    stream.add_slice(profile);
+    stream.into_obj()
+}
 static mut CSPC_CACHE: CspcCache = CspcCache {
     count: 0_u32,
     capacity: 0_u32,
@@ -0,0 +1,450 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+#![allow(non_upper_case_globals)]
+
+//! Support for the PDF/A-2b and PDF/X-4 output conformance levels.
+//!
+//! When a conformance level is active, the document gets an XMP metadata
+//! stream mirroring the Info dictionary and an OutputIntent carrying an
+//! embedded ICC profile. Anything in the document that the standard forbids
+//! is recorded as a violation; violations are reported together as a fatal
+//! error when the document is closed.
+
+use std::io::Read;
+
+use super::dpx_pdfcolor::iccp_load_output_profile;
+use super::dpx_pdfencrypt::pdf_enc_id_array;
+use crate::bridge::{InFile, TTInputFormat};
+use crate::dpx_pdfobj::{
+    pdf_dict, pdf_obj, pdf_ref_obj, pdf_release_obj, pdf_stream, pdf_string, IntoObj,
+};
+
+/// An output conformance level for the generated PDF.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PdfConformance {
+    /// No particular standard is targeted.
+    None,
+    /// ISO 19005-2 (PDF/A-2), level B.
+    PdfA2b,
+    /// ISO 15930-7 (PDF/X-4).
+    PdfX4,
+}
+
+impl PdfConformance {
+    /// The conventional name of the standard, as used in messages.
+    pub fn name(self) -> &'static str {
+        match self {
+            PdfConformance::None => "plain PDF",
+            PdfConformance::PdfA2b => "PDF/A-2b",
+            PdfConformance::PdfX4 => "PDF/X-4",
+        }
+    }
+}
+
+impl Default for PdfConformance {
+    fn default() -> Self {
+        PdfConformance::None
+    }
+}
+
+/* PDF/A-2 falls back to this when no profile is given; it is part of the
+ * TeX Live colorprofiles package. PDF/X-4 has no sensible default. */
+const DEFAULT_PDFA_PROFILE: &str = "sRGB.icc";
+
+/* The blend modes defined in ISO 32000-1; both standards forbid others. */
+const STANDARD_BLEND_MODES: [&[u8]; 16] = [
+    b"Normal",
+    b"Multiply",
+    b"Screen",
+    b"Overlay",
+    b"Darken",
+    b"Lighten",
+    b"ColorDodge",
+    b"ColorBurn",
+    b"HardLight",
+    b"SoftLight",
+    b"Difference",
+    b"Exclusion",
+    b"Hue",
+    b"Saturation",
+    b"Color",
+    b"Luminosity",
+];
+
+static mut conformance: PdfConformance = PdfConformance::None;
+static mut output_profile: Option<String> = None;
+static mut violations: Vec<String> = Vec::new();
+
+pub(crate) unsafe fn pdf_conform_init(level: PdfConformance, profile: Option<&str>) {
+    conformance = level;
+    output_profile = profile.map(|s| s.to_owned());
+    violations.clear();
+}
+
+pub(crate) unsafe fn pdf_conform_get_level() -> PdfConformance {
+    conformance
+}
+
+/// Records something in the document that the active standard forbids. Does
+/// nothing if no conformance level is active.
+pub(crate) unsafe fn pdf_conform_violation(msg: String) {
+    if conformance != PdfConformance::None {
+        violations.push(msg);
+    }
+}
+
+/// Checks an ExtGState resource for transparency features that the active
+/// standard does not allow.
+pub(crate) unsafe fn pdf_conform_check_extgstate(name: &[u8], gstate: &pdf_obj) {
+    if conformance == PdfConformance::None || !gstate.is_dict() {
+        return;
+    }
+    let bm = match gstate.as_dict().get("BM") {
+        Some(bm) => bm,
+        None => return,
+    };
+    let allowed = |mode: &pdf_obj| {
+        mode.is_name()
+            && STANDARD_BLEND_MODES
+                .iter()
+                .any(|&m| m == mode.as_name().to_bytes())
+    };
+    let ok = if bm.is_array() {
+        bm.as_array().iter().all(|&mode| allowed(&*mode))
+    } else {
+        allowed(bm)
+    };
+    if !ok {
+        pdf_conform_violation(format!(
+            "ExtGState \"{}\" uses a non-standard blend mode",
+            String::from_utf8_lossy(name)
+        ));
+    }
+}
+
+/// Adds the Info entries, XMP metadata, and OutputIntent required by the
+/// active standard. Must be called after the Info dictionary is otherwise
+/// complete, since the XMP packet mirrors it.
+pub(crate) unsafe fn pdf_conform_close_docinfo(info: &mut pdf_obj, catalog: &mut pdf_obj) {
+    if conformance == PdfConformance::None {
+        return;
+    }
+    let info = info.as_dict_mut();
+    if !info.has("ModDate") {
+        if let Some(date) = info.get("CreationDate") {
+            let date = date.as_string().to_bytes().to_vec();
+            info.set("ModDate", pdf_string::new(date));
+        }
+    }
+    if conformance == PdfConformance::PdfX4 {
+        info.set("GTS_PDFXVersion", pdf_string::new("PDF/X-4"));
+        if !info.has("Trapped") {
+            info.set("Trapped", "False");
+        }
+    }
+    let xmp = make_xmp_packet(info);
+    let mut metadata = pdf_stream::new(0);
+    metadata.get_dict_mut().set("Type", "Metadata");
+    metadata.get_dict_mut().set("Subtype", "XML");
+    metadata.add_slice(xmp.as_bytes());
+    let metadata = metadata.into_obj();
+    catalog.as_dict_mut().set("Metadata", pdf_ref_obj(metadata));
+    pdf_release_obj(metadata);
+
+    if let Some(intent) = make_output_intent() {
+        catalog.as_dict_mut().set("OutputIntents", vec![intent]);
+    }
+}
+
+/// Reports all recorded violations as a fatal error.
+pub(crate) unsafe fn pdf_conform_finish() {
+    if violations.is_empty() {
+        return;
+    }
+    let mut msg = format!("the document does not conform to {}:", conformance.name());
+    for v in violations.drain(..) {
+        msg.push_str("\n  ");
+        msg.push_str(&v);
+    }
+    panic!("{}", msg);
+}
+
+unsafe fn make_output_intent() -> Option<*mut pdf_obj> {
+    let name = match (output_profile.as_ref(), conformance) {
+        (Some(name), _) => name.clone(),
+        (None, PdfConformance::PdfA2b) => DEFAULT_PDFA_PROFILE.to_owned(),
+        _ => {
+            pdf_conform_violation("no output intent ICC profile was specified".to_owned());
+            return None;
+        }
+    };
+    let mut profile = Vec::new();
+    match InFile::open(&name, TTInputFormat::PICT, 0) {
+        Some(mut handle) => {
+            if handle.read_to_end(&mut profile).is_err() {
+                pdf_conform_violation(format!("could not read ICC profile \"{}\"", name));
+                return None;
+            }
+        }
+        None => {
+            pdf_conform_violation(format!("could not open ICC profile \"{}\"", name));
+            return None;
+        }
+    }
+    if conformance == PdfConformance::PdfX4 && profile.len() >= 16 && &profile[12..16] != b"prtr" {
+        pdf_conform_violation(format!(
+            "ICC profile \"{}\" is not an output device profile",
+            name
+        ));
+        return None;
+    }
+    let dest = iccp_load_output_profile(&name, &profile);
+    if dest.is_null() {
+        pdf_conform_violation(format!("ICC profile \"{}\" cannot be embedded", name));
+        return None;
+    }
+    let condition = name.trim_end_matches(".icc").trim_end_matches(".icm");
+    let mut intent = pdf_dict::new();
+    intent.set("Type", "OutputIntent");
+    intent.set(
+        "S",
+        if conformance == PdfConformance::PdfX4 {
+            "GTS_PDFX"
+        } else {
+            "GTS_PDFA1"
+        },
+    );
+    intent.set("OutputConditionIdentifier", pdf_string::new(condition));
+    intent.set("Info", pdf_string::new(condition));
+    intent.set("DestOutputProfile", pdf_ref_obj(dest));
+    pdf_release_obj(dest);
+    Some(intent.into_obj())
+}
+
+unsafe fn make_xmp_packet(info: &pdf_dict) -> String {
+    let text = |key: &str| {
+        info.get(key)
+            .filter(|v| v.is_string())
+            .map(|v| xml_escape(&decode_text_string(v.as_string().to_bytes())))
+    };
+    let date = |key: &str| {
+        info.get(key)
+            .filter(|v| v.is_string())
+            .and_then(|v| xmp_date(v.as_string().to_bytes()))
+    };
+
+    let mut doc_id = String::new();
+    for id in pdf_enc_id_array() {
+        if doc_id.is_empty() && (*id).is_string() {
+            for b in (*id).as_string().to_bytes() {
+                doc_id.push_str(&format!("{:02x}", b));
+            }
+        }
+        pdf_release_obj(id);
+    }
+
+    let mut x = String::new();
+    x.push_str("<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n");
+    x.push_str("<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n");
+    x.push_str(" <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n");
+    x.push_str("  <rdf:Description rdf:about=\"\"\n");
+    x.push_str("    xmlns:dc=\"http://purl.org/dc/elements/1.1/\"\n");
+    x.push_str("    xmlns:pdf=\"http://ns.adobe.com/pdf/1.3/\"\n");
+    x.push_str("    xmlns:xmp=\"http://ns.adobe.com/xap/1.0/\"\n");
+    x.push_str("    xmlns:xmpMM=\"http://ns.adobe.com/xap/1.0/mm/\"\n");
+    if conformance == PdfConformance::PdfX4 {
+        x.push_str("    xmlns:pdfxid=\"http://www.npes.org/pdfx/ns/id/\">\n");
+    } else {
+        x.push_str("    xmlns:pdfaid=\"http://www.aiim.org/pdfa/ns/id/\">\n");
+    }
+    x.push_str("   <dc:format>application/pdf</dc:format>\n");
+    if let Some(title) = text("Title") {
+        x.push_str(&format!(
+            "   <dc:title><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:title>\n",
+            title
+        ));
+    }
+    if let Some(author) = text("Author") {
+        x.push_str(&format!(
+            "   <dc:creator><rdf:Seq><rdf:li>{}</rdf:li></rdf:Seq></dc:creator>\n",
+            author
+        ));
+    }
+    if let Some(subject) = text("Subject") {
+        x.push_str(&format!(
+            "   <dc:description><rdf:Alt><rdf:li xml:lang=\"x-default\">{}</rdf:li></rdf:Alt></dc:description>\n",
+            subject
+        ));
+    }
+    if let Some(keywords) = text("Keywords") {
+        x.push_str(&format!("   <pdf:Keywords>{}</pdf:Keywords>\n", keywords));
+    }
+    if let Some(producer) = text("Producer") {
+        x.push_str(&format!("   <pdf:Producer>{}</pdf:Producer>\n", producer));
+    }
+    if let Some(trapped) = info.get("Trapped").filter(|v| v.is_name()) {
+        x.push_str(&format!(
+            "   <pdf:Trapped>{}</pdf:Trapped>\n",
+            trapped.as_name().to_string_lossy()
+        ));
+    }
+    if let Some(creator) = text("Creator") {
+        x.push_str(&format!(
+            "   <xmp:CreatorTool>{}</xmp:CreatorTool>\n",
+            creator
+        ));
+    }
+    if let Some(created) = date("CreationDate") {
+        x.push_str(&format!(
+            "   <xmp:CreateDate>{}</xmp:CreateDate>\n",
+            created
+        ));
+    }
+    if let Some(modified) = date("ModDate") {
+        x.push_str(&format!(
+            "   <xmp:ModifyDate>{}</xmp:ModifyDate>\n",
+            modified
+        ));
+        x.push_str(&format!(
+            "   <xmp:MetadataDate>{}</xmp:MetadataDate>\n",
+            modified
+        ));
+    }
+    if !doc_id.is_empty() {
+        x.push_str(&format!(
+            "   <xmpMM:DocumentID>uuid:{}</xmpMM:DocumentID>\n",
+            doc_id
+        ));
+        x.push_str("   <xmpMM:VersionID>1</xmpMM:VersionID>\n");
+        x.push_str("   <xmpMM:RenditionClass>default</xmpMM:RenditionClass>\n");
+    }
+    if conformance == PdfConformance::PdfX4 {
+        x.push_str("   <pdfxid:GTS_PDFXVersion>PDF/X-4</pdfxid:GTS_PDFXVersion>\n");
+    } else {
+        x.push_str("   <pdfaid:part>2</pdfaid:part>\n");
+        x.push_str("   <pdfaid:conformance>B</pdfaid:conformance>\n");
+    }
+    x.push_str("  </rdf:Description>\n");
+    x.push_str(" </rdf:RDF>\n");
+    x.push_str("</x:xmpmeta>\n");
+    x.push_str("<?xpacket end=\"w\"?>");
+    x
+}
+
+/* Decodes a PDF text string, which is either UTF-16BE with a byte order mark
+ * or PDFDocEncoding. The latter agrees with Latin-1 outside of a few
+ * punctuation characters, which is close enough for metadata. */
+fn decode_text_string(s: &[u8]) -> String {
+    if s.len() >= 2 && s[0] == 0xfe && s[1] == 0xff {
+        let units: Vec<u16> = s[2..]
+            .chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect();
+        String::from_utf16_lossy(&units)
+    } else {
+        s.iter().map(|&b| char::from(b)).collect()
+    }
+}
+
+fn xml_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            c if (c as u32) < 0x20 && c != '\t' && c != '\n' && c != '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/* Converts a PDF date string ("D:YYYYMMDDHHmmSSOHH'mm'") to the ISO 8601
+ * form used by XMP. Missing trailing fields take their default values. */
+fn xmp_date(s: &[u8]) -> Option<String> {
+    let s = std::str::from_utf8(s).ok()?;
+    let s = if s.starts_with("D:") { &s[2..] } else { s };
+    let digits = s.bytes().take_while(|b| b.is_ascii_digit()).count();
+    if digits < 4 {
+        return None;
+    }
+    let field = |start: usize, default: &'static str| {
+        if digits >= start + 2 {
+            &s[start..start + 2]
+        } else {
+            default
+        }
+    };
+    let mut out = format!(
+        "{}-{}-{}T{}:{}:{}",
+        &s[..4],
+        field(4, "01"),
+        field(6, "01"),
+        field(8, "00"),
+        field(10, "00"),
+        field(12, "00")
+    );
+    let tz = &s[digits..];
+    match tz.chars().next() {
+        Some('+') | Some('-') => {
+            let tz_digits: String = tz[1..].chars().filter(|c| c.is_ascii_digit()).collect();
+            if tz_digits.len() >= 2 {
+                let minutes = if tz_digits.len() >= 4 {
+                    &tz_digits[2..4]
+                } else {
+                    "00"
+                };
+                out.push_str(&format!("{}{}:{}", &tz[..1], &tz_digits[..2], minutes));
+            }
+        }
+        Some('Z') => out.push('Z'),
+        _ => {}
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            xmp_date(b"D:20200704123456+02'00'").unwrap(),
+            "2020-07-04T12:34:56+02:00"
+        );
+        assert_eq!(
+            xmp_date(b"D:20200704123456-00'00'").unwrap(),
+            "2020-07-04T12:34:56-00:00"
+        );
+        assert_eq!(xmp_date(b"D:2020").unwrap(), "2020-01-01T00:00:00");
+        assert_eq!(xmp_date(b"D:20200704Z").unwrap(), "2020-07-04T00:00:00Z");
+        assert!(xmp_date(b"yesterday").is_none());
+    }
+
+    #[test]
+    fn text_strings() {
+        assert_eq!(decode_text_string(b"\xfe\xff\x00A\x00&"), "A&");
+        assert_eq!(decode_text_string(b"caf\xe9"), "caf\u{e9}");
+        assert_eq!(xml_escape("a<b & \"c\""), "a&lt;b &amp; &quot;c&quot;");
+    }
+}
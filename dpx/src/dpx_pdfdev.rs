@@ -1767,6 +1767,34 @@ pub(crate) unsafe fn pdf_dev_end_actualtext() {
     graphics_mode();
     pdf_doc_add_page_content(b" EMC");
 }
+
+/* Begin a marked-content sequence with the given tag and property list.
+ * The property list is PDF source (normally a dictionary) and may be empty.
+ */
+pub(crate) unsafe fn pdf_dev_begin_marked_content(tag: &[u8], props: &[u8]) {
+    graphics_mode();
+    let mut content = Vec::from(b"\n/".as_ref());
+    for &c in tag {
+        if c < b'!' || c > b'~' || b"#()/<>[]{}%".contains(&c) {
+            content.extend(format!("#{:02X}", c).as_bytes());
+        } else {
+            content.push(c);
+        }
+    }
+    if props.is_empty() {
+        content.extend(b" BMC");
+    } else {
+        content.push(b' ');
+        content.extend(props);
+        content.extend(b" BDC");
+    }
+    pdf_doc_add_page_content(&content);
+}
+
+pub(crate) unsafe fn pdf_dev_end_marked_content() {
+    graphics_mode();
+    pdf_doc_add_page_content(b" EMC");
+}
 /* The name transform_info is misleading.
  * I'll put this here for a moment...
  */
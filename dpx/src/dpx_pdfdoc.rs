@@ -32,6 +32,7 @@ use crate::bridge::DisplayExt;
 use crate::mfree;
 use crate::streq_ptr;
 use crate::{info, warn};
+use std::collections::{HashMap, HashSet};
 use std::ffi::CStr;
 use std::ptr;
 
@@ -43,9 +44,14 @@ use super::dpx_dvipdfmx::is_xdv;
 use super::dpx_jpegimage::check_for_jpeg;
 use super::dpx_mem::new;
 use super::dpx_pdfcolor::{pdf_close_colors, pdf_color_set_verbose, pdf_init_colors, WHITE};
+use super::dpx_pdfconform::{
+    pdf_conform_check_extgstate, pdf_conform_close_docinfo, pdf_conform_finish,
+    pdf_conform_get_level, pdf_conform_violation, PdfConformance,
+};
 use super::dpx_pdfdev::{
-    pdf_dev_bop, pdf_dev_eop, pdf_dev_get_coord, pdf_dev_get_param, pdf_dev_reset_color,
-    pdf_dev_reset_fonts,
+    pdf_dev_begin_actualtext, pdf_dev_begin_marked_content, pdf_dev_bop, pdf_dev_end_actualtext,
+    pdf_dev_end_marked_content, pdf_dev_eop, pdf_dev_get_coord, pdf_dev_get_param,
+    pdf_dev_reset_color, pdf_dev_reset_fonts,
 };
 use super::dpx_pdfdraw::{
     pdf_dev_current_depth, pdf_dev_grestore, pdf_dev_grestore_to, pdf_dev_gsave,
@@ -59,6 +65,7 @@ use super::dpx_pdfnames::{
     pdf_delete_name_tree, pdf_names_add_object, pdf_names_create_tree, pdf_new_name_tree,
 };
 use super::dpx_pdfresource::{pdf_close_resources, pdf_init_resources};
+use super::dpx_pdfsign::{pdf_sign_enabled, pdf_sign_new_value};
 use super::dpx_pdfximage::{
     pdf_close_images, pdf_init_images, pdf_ximage_defineresource, pdf_ximage_findresource,
     pdf_ximage_get_reference, pdf_ximage_init_form_info, pdf_ximage_set_verbose, XInfo,
@@ -66,9 +73,9 @@ use super::dpx_pdfximage::{
 use super::dpx_pngimage::check_for_png;
 use crate::bridge::{InFile, TTInputFormat};
 use crate::dpx_pdfobj::{
-    pdf_deref_obj, pdf_dict, pdf_file, pdf_file_get_catalog, pdf_link_obj, pdf_obj, pdf_out_flush,
-    pdf_out_init, pdf_ref_obj, pdf_release_obj, pdf_remove_dict, pdf_set_encrypt, pdf_set_id,
-    pdf_set_info, pdf_set_root, pdf_stream, pdf_string, IntoObj, PdfObjType, PushObj,
+    pdf_deref_obj, pdf_dict, pdf_file, pdf_file_get_catalog, pdf_link_obj, pdf_name, pdf_obj,
+    pdf_out_flush, pdf_out_init, pdf_ref_obj, pdf_release_obj, pdf_remove_dict, pdf_set_encrypt,
+    pdf_set_id, pdf_set_info, pdf_set_root, pdf_stream, pdf_string, IntoObj, PdfObjType, PushObj,
     STREAM_COMPRESS,
 };
 use libc::{free, strcmp, strcpy, strlen};
@@ -106,6 +113,41 @@ pub(crate) struct pdf_bead {
     pub(crate) page_no: i32,
     pub(crate) rect: Rect,
 }
+#[derive(Clone)]
+pub(crate) struct pdf_struct_elem {
+    pub(crate) role: Vec<u8>,
+    pub(crate) dict: *mut pdf_obj,
+    pub(crate) kids: Vec<*mut pdf_obj>,
+}
+#[derive(Clone)]
+pub(crate) struct pdf_ocg {
+    pub(crate) ident: String,
+    pub(crate) dict: *mut pdf_obj,
+    pub(crate) visible: bool,
+}
+/* A marked-content sequence that is open in the page content. */
+#[derive(Clone)]
+pub(crate) enum pdf_marked_content {
+    /* The sequence of the innermost open structure element. */
+    Struct,
+    /* An artifact, with its property list as PDF source. */
+    Artifact(Vec<u8>),
+    /* A layer, by index into ocgs. */
+    Ocg(usize),
+    /* An /ActualText span; false once it has been ended early. */
+    ActualText(bool),
+}
+#[derive(Clone)]
+pub(crate) struct pdf_struct_tree {
+    pub(crate) root: *mut pdf_obj,
+    pub(crate) kids: Vec<*mut pdf_obj>,
+    pub(crate) open: Vec<pdf_struct_elem>,
+    /* Parent elements of the marked-content sequences on the current page,
+     * indexed by MCID. */
+    pub(crate) page_parents: Vec<*mut pdf_obj>,
+    pub(crate) parent_tree: Vec<*mut pdf_obj>,
+    pub(crate) next_key: i32,
+}
 #[derive(Copy, Clone)]
 #[repr(C)]
 pub(crate) struct pdf_olitem {
@@ -138,6 +180,13 @@ pub(crate) struct pdf_doc {
     pub(crate) pages: C2RustUnnamed_2,
     pub(crate) outlines: C2RustUnnamed_1,
     pub(crate) articles: Vec<pdf_article>,
+    pub(crate) structure: pdf_struct_tree,
+    pub(crate) ocgs: Vec<pdf_ocg>,
+    /* The open marked-content sequences of all kinds, innermost last. */
+    pub(crate) marked_content: Vec<pdf_marked_content>,
+    /* The interactive form fields, and the AcroForm SigFlags */
+    pub(crate) fields: Vec<*mut pdf_obj>,
+    pub(crate) sig_flags: i32,
     pub(crate) names: *mut name_dict,
     pub(crate) check_gotos: i32,
     pub(crate) gotos: ht_table,
@@ -264,6 +313,18 @@ static mut pdoc: pdf_doc = pdf_doc {
         current_depth: 0,
     },
     articles: Vec::new(),
+    structure: pdf_struct_tree {
+        root: ptr::null_mut(),
+        kids: Vec::new(),
+        open: Vec::new(),
+        page_parents: Vec::new(),
+        parent_tree: Vec::new(),
+        next_key: 0,
+    },
+    ocgs: Vec::new(),
+    marked_content: Vec::new(),
+    fields: Vec::new(),
+    sig_flags: 0,
     names: ptr::null_mut(),
     check_gotos: 0,
     gotos: ht_table {
@@ -455,6 +516,7 @@ unsafe fn pdf_doc_close_docinfo(mut p: *mut pdf_doc) {
             .as_dict_mut()
             .set("CreationDate", pdf_string::new(now));
     }
+    pdf_conform_close_docinfo(&mut *docinfo, &mut *(*p).root.dict);
     pdf_release_obj(docinfo);
     (*p).info = ptr::null_mut();
 }
@@ -499,6 +561,9 @@ pub(crate) unsafe fn pdf_doc_add_page_resource(
         resource_ref = pdf_ref_obj(resource_ref)
         /* leak */
     }
+    if category == "ExtGState" && !(*resource_ref).as_indirect().obj.is_null() {
+        pdf_conform_check_extgstate(resource_name, &*(*resource_ref).as_indirect().obj);
+    }
     let resources = pdf_doc_get_page_resources(p, category);
     if let Some(duplicate) = (*resources).as_dict_mut().get_mut(resource_name) {
         if (*duplicate)
@@ -537,6 +602,23 @@ unsafe fn doc_flush_page(p: *mut pdf_doc, page: &mut pdf_page, parent_ref: *mut
         mediabox.push_obj((page.cropbox.max.y / 0.01 + 0.5).floor() * 0.01);
         (*page.page_obj).as_dict_mut().set("MediaBox", mediabox);
     }
+    /* PDF/X requires every page to state its finished size. */
+    if pdf_conform_get_level() == PdfConformance::PdfX4
+        && !(*page.page_obj).as_dict().has("TrimBox")
+        && !(*page.page_obj).as_dict().has("ArtBox")
+    {
+        let bbox = if page.flags & 1i32 << 0i32 != 0 {
+            page.cropbox
+        } else {
+            (*p).pages.mediabox
+        };
+        let mut trimbox = vec![];
+        trimbox.push_obj((bbox.min.x / 0.01 + 0.5).floor() * 0.01);
+        trimbox.push_obj((bbox.min.y / 0.01 + 0.5).floor() * 0.01);
+        trimbox.push_obj((bbox.max.x / 0.01 + 0.5).floor() * 0.01);
+        trimbox.push_obj((bbox.max.y / 0.01 + 0.5).floor() * 0.01);
+        (*page.page_obj).as_dict_mut().set("TrimBox", trimbox);
+    }
     let mut count = 0_u32;
     let mut contents_array = vec![];
     if !page.content_refs[0].is_null() {
@@ -1743,6 +1825,9 @@ pub(crate) unsafe fn pdf_doc_add_annot(
     rect_array.push_obj(((annbox.max.y + annot_grow) / 0.001 + 0.5).floor() * 0.001);
     (*annot_dict).as_dict_mut().set("Rect", rect_array);
     (*page.annots).as_array_mut().push(pdf_ref_obj(annot_dict));
+    if is_form_field(&*annot_dict) {
+        doc_add_field(p, annot_dict);
+    }
     if new_annot != 0 {
         pdf_doc_add_goto(annot_dict);
     };
@@ -1919,6 +2004,689 @@ unsafe fn pdf_doc_close_articles(p: *mut pdf_doc) {
         (*p).root.threads = ptr::null_mut()
     };
 }
+/*
+ * Marked content.
+ *
+ * Structure elements, artifacts, layers and ActualText spans all become
+ * marked-content sequences, which must nest properly in the content stream.
+ * They are kept on a single stack. Ending a sequence that is not the
+ * innermost one also ends the sequences nested in it, which are then
+ * resumed in the same order. All sequences are ended at the end of each
+ * page and resumed on the next one, except that ActualText spans are not
+ * resumed once ended early.
+ */
+unsafe fn pdf_doc_init_marked_content(p: &mut pdf_doc) {
+    p.marked_content = Vec::new();
+}
+unsafe fn mc_emit_end(mc: &pdf_marked_content) {
+    match mc {
+        pdf_marked_content::ActualText(false) => {}
+        pdf_marked_content::ActualText(true) => pdf_dev_end_actualtext(),
+        _ => pdf_dev_end_marked_content(),
+    }
+}
+/* Begins a sequence again after it was ended early. */
+unsafe fn mc_resume(p: &mut pdf_doc, mc: pdf_marked_content) {
+    match mc {
+        pdf_marked_content::Struct => {
+            struct_begin_mc(p);
+            return;
+        }
+        pdf_marked_content::Artifact(ref props) => {
+            pdf_dev_begin_marked_content(b"Artifact", props);
+        }
+        pdf_marked_content::Ocg(idx) => ocg_begin_mc(p, idx),
+        pdf_marked_content::ActualText(_) => {
+            p.marked_content.push(pdf_marked_content::ActualText(false));
+            return;
+        }
+    }
+    p.marked_content.push(mc);
+}
+/* Ends the innermost open sequence for which pred holds. Returns false if
+ * there is none.
+ */
+unsafe fn mc_end<F: Fn(&pdf_marked_content) -> bool>(p: &mut pdf_doc, pred: F) -> bool {
+    let i = match p.marked_content.iter().rposition(pred) {
+        Some(i) => i,
+        None => return false,
+    };
+    let inner = p.marked_content.split_off(i + 1);
+    for mc in inner.iter().rev() {
+        mc_emit_end(mc);
+    }
+    mc_emit_end(&p.marked_content.pop().unwrap());
+    for mc in inner {
+        mc_resume(p, mc);
+    }
+    true
+}
+fn mc_count<F: Fn(&pdf_marked_content) -> bool>(p: &pdf_doc, pred: F) -> usize {
+    p.marked_content.iter().filter(|mc| pred(mc)).count()
+}
+/* Called at the end of each page, before the page is finished. */
+unsafe fn pdf_doc_mc_end_page(p: &mut pdf_doc) {
+    for mc in p.marked_content.iter_mut().rev() {
+        mc_emit_end(mc);
+        if let pdf_marked_content::ActualText(open) = mc {
+            *open = false;
+        }
+    }
+    p.marked_content
+        .retain(|mc| !matches!(mc, pdf_marked_content::Struct));
+}
+/* Called at the beginning of each page, to resume what was open. */
+unsafe fn pdf_doc_mc_begin_page(p: &mut pdf_doc) {
+    let open = std::mem::replace(&mut p.marked_content, Vec::new());
+    for mc in open {
+        mc_resume(p, mc);
+    }
+    struct_begin_mc(p);
+}
+unsafe fn pdf_doc_close_marked_content(p: &mut pdf_doc) {
+    if mc_count(p, |mc| matches!(mc, pdf_marked_content::Artifact(_))) > 0 {
+        warn!("Unbalanced artifact marking found.");
+    }
+    let n = mc_count(p, |mc| matches!(mc, pdf_marked_content::Ocg(_)));
+    if n > 0 {
+        warn!("{} layer(s) not closed.", n);
+    }
+    let n = mc_count(p, |mc| matches!(mc, pdf_marked_content::ActualText(_)));
+    if n > 0 {
+        warn!("{} ActualText span(s) not closed.", n);
+    }
+    p.marked_content = Vec::new();
+}
+/*
+ * Logical structure (Tagged PDF).
+ *
+ * Structure elements are opened and closed by specials. While an element is
+ * open, the page content is wrapped in marked-content sequences tagged with
+ * its role and an automatically assigned MCID. These sequences are never
+ * nested in each other or in artifacts: opening a child element or an
+ * artifact ends the sequence of the parent, which gets a fresh MCID once
+ * the child is closed, as it does whenever its sequence is resumed.
+ */
+unsafe fn pdf_doc_init_structure(p: &mut pdf_doc) {
+    let s = &mut p.structure;
+    s.root = ptr::null_mut();
+    s.kids = Vec::new();
+    s.open = Vec::new();
+    s.page_parents = Vec::new();
+    s.parent_tree = Vec::new();
+    s.next_key = 0;
+}
+unsafe fn struct_begin_mc(p: &mut pdf_doc) {
+    let suspended = p.marked_content.iter().any(|mc| {
+        matches!(
+            mc,
+            pdf_marked_content::Struct | pdf_marked_content::Artifact(_)
+        )
+    });
+    if suspended || !p.pending_forms.is_null() {
+        return;
+    }
+    if p.structure.open.is_empty() {
+        return;
+    }
+    let page_ref = pdf_doc_ref_page(pdf_doc_current_page_number());
+    let s = &mut p.structure;
+    let elem = s.open.last_mut().unwrap();
+    let mcid = s.page_parents.len();
+    s.page_parents.push(pdf_ref_obj(elem.dict));
+    let mut mcr = pdf_dict::new();
+    mcr.set("Type", "MCR");
+    mcr.set("Pg", page_ref);
+    mcr.set("MCID", mcid as f64);
+    elem.kids.push(mcr.into_obj());
+    pdf_dev_begin_marked_content(&elem.role, format!("<</MCID {}>>", mcid).as_bytes());
+    p.marked_content.push(pdf_marked_content::Struct);
+}
+unsafe fn struct_end_mc(p: &mut pdf_doc) {
+    mc_end(p, |mc| matches!(mc, pdf_marked_content::Struct));
+}
+/* Called at the end of each page, once its marked content is ended. */
+unsafe fn pdf_doc_struct_end_page(p: &mut pdf_doc) {
+    if !p.structure.page_parents.is_empty() {
+        let s = &mut p.structure;
+        let key = s.next_key;
+        s.next_key += 1;
+        let parents = std::mem::replace(&mut s.page_parents, Vec::new());
+        s.parent_tree.push_obj(key as f64);
+        s.parent_tree.push_obj(parents);
+        let currentpage = &mut p.pages.entries[p.pages.num_entries];
+        (*currentpage.page_obj)
+            .as_dict_mut()
+            .set("StructParents", key as f64);
+    }
+}
+
+/* Returns the dictionary of the new element. It is kept until the element
+ * is closed; use pdf_link_obj() to keep it longer.
+ */
+pub(crate) unsafe fn pdf_doc_begin_struct(role: &[u8], attrib: *mut pdf_obj) -> *mut pdf_obj {
+    let p = &mut pdoc;
+    struct_end_mc(p);
+    let s = &mut p.structure;
+    if s.root.is_null() {
+        s.root = pdf_dict::new().into_obj();
+    }
+    let parent = match s.open.last() {
+        Some(elem) => elem.dict,
+        None => s.root,
+    };
+    let mut dict = pdf_dict::new();
+    dict.set("Type", "StructElem");
+    dict.set("S", pdf_name::new(role));
+    dict.set("P", pdf_ref_obj(parent));
+    if !attrib.is_null() {
+        dict.merge((*attrib).as_dict());
+    }
+    let dict = dict.into_obj();
+    match s.open.last_mut() {
+        Some(elem) => elem.kids.push(pdf_ref_obj(dict)),
+        None => s.kids.push(pdf_ref_obj(dict)),
+    }
+    s.open.push(pdf_struct_elem {
+        role: role.to_vec(),
+        dict,
+        kids: Vec::new(),
+    });
+    struct_begin_mc(p);
+    dict
+}
+
+pub(crate) unsafe fn pdf_doc_end_struct() -> i32 {
+    let p = &mut pdoc;
+    if p.structure.open.is_empty() {
+        return -1;
+    }
+    struct_end_mc(p);
+    let elem = p.structure.open.pop().unwrap();
+    flush_struct_elem(elem);
+    struct_begin_mc(p);
+    0
+}
+unsafe fn flush_struct_elem(elem: pdf_struct_elem) {
+    if !elem.kids.is_empty() {
+        (*elem.dict).as_dict_mut().set("K", elem.kids);
+    }
+    pdf_release_obj(elem.dict);
+}
+
+/* props is the property list of the artifact as PDF source, which may be
+ * empty.
+ */
+pub(crate) unsafe fn pdf_doc_begin_artifact(props: &[u8]) {
+    let p = &mut pdoc;
+    struct_end_mc(p);
+    pdf_dev_begin_marked_content(b"Artifact", props);
+    p.marked_content
+        .push(pdf_marked_content::Artifact(props.to_vec()));
+}
+
+pub(crate) unsafe fn pdf_doc_end_artifact() -> i32 {
+    let p = &mut pdoc;
+    if !mc_end(p, |mc| matches!(mc, pdf_marked_content::Artifact(_))) {
+        return -1;
+    }
+    struct_begin_mc(p);
+    0
+}
+unsafe fn pdf_doc_close_structure(p: &mut pdf_doc) {
+    let s = &mut p.structure;
+    if !s.open.is_empty() {
+        warn!("{} structure element(s) not closed.", s.open.len());
+        while let Some(elem) = s.open.pop() {
+            flush_struct_elem(elem);
+        }
+    }
+    for parent in s.page_parents.drain(..) {
+        pdf_release_obj(parent);
+    }
+    if s.root.is_null() {
+        return;
+    }
+    let mut parent_tree = pdf_dict::new();
+    parent_tree.set("Nums", std::mem::replace(&mut s.parent_tree, Vec::new()));
+    let parent_tree = parent_tree.into_obj();
+    let root = &mut *s.root;
+    root.as_dict_mut().set("Type", "StructTreeRoot");
+    root.as_dict_mut()
+        .set("K", std::mem::replace(&mut s.kids, Vec::new()));
+    root.as_dict_mut()
+        .set("ParentTree", pdf_ref_obj(parent_tree));
+    root.as_dict_mut()
+        .set("ParentTreeNextKey", s.next_key as f64);
+    pdf_release_obj(parent_tree);
+    let mut mark_info = pdf_dict::new();
+    mark_info.set("Marked", true);
+    let catalog = (*p.root.dict).as_dict_mut();
+    catalog.set("StructTreeRoot", pdf_ref_obj(s.root));
+    catalog.set("MarkInfo", mark_info);
+    pdf_release_obj(s.root);
+    s.root = ptr::null_mut();
+}
+/*
+ * Optional content. Layers are declared by identifier and become optional
+ * content groups; content belonging to a layer is enclosed in an /OC
+ * marked-content sequence that refers to the group through the Properties
+ * resources.
+ */
+unsafe fn pdf_doc_init_ocgs(p: &mut pdf_doc) {
+    p.ocgs = Vec::new();
+}
+unsafe fn ocg_begin_mc(p: &mut pdf_doc, idx: usize) {
+    let res_name = format!("OC{}", idx + 1);
+    pdf_doc_add_page_resource(
+        "Properties",
+        res_name.as_bytes(),
+        pdf_ref_obj(p.ocgs[idx].dict),
+    );
+    pdf_dev_begin_marked_content(b"OC", format!("/{}", res_name).as_bytes());
+}
+
+/* Returns the OCG dictionary of the new layer, or NULL if a layer with the
+ * same identifier already exists. The dictionary is owned by the document.
+ */
+pub(crate) unsafe fn pdf_doc_define_ocg(
+    ident: &str,
+    name: *mut pdf_obj,
+    visible: bool,
+) -> *mut pdf_obj {
+    let p = &mut pdoc;
+    if p.ocgs.iter().any(|ocg| ocg.ident == ident) {
+        return ptr::null_mut();
+    }
+    let mut dict = pdf_dict::new();
+    dict.set("Type", "OCG");
+    dict.set("Name", name);
+    let dict = dict.into_obj();
+    p.ocgs.push(pdf_ocg {
+        ident: ident.to_owned(),
+        dict,
+        visible,
+    });
+    dict
+}
+
+pub(crate) unsafe fn pdf_doc_set_ocg_visibility(ident: &str, visible: bool) -> i32 {
+    let p = &mut pdoc;
+    if let Some(ocg) = p.ocgs.iter_mut().find(|ocg| ocg.ident == ident) {
+        ocg.visible = visible;
+        0
+    } else {
+        -1
+    }
+}
+
+pub(crate) unsafe fn pdf_doc_begin_ocg(ident: &str) -> i32 {
+    let p = &mut pdoc;
+    if let Some(idx) = p.ocgs.iter().position(|ocg| ocg.ident == ident) {
+        ocg_begin_mc(p, idx);
+        p.marked_content.push(pdf_marked_content::Ocg(idx));
+        0
+    } else {
+        -1
+    }
+}
+
+pub(crate) unsafe fn pdf_doc_end_ocg() -> i32 {
+    let p = &mut pdoc;
+    if mc_end(p, |mc| matches!(mc, pdf_marked_content::Ocg(_))) {
+        0
+    } else {
+        -1
+    }
+}
+unsafe fn pdf_doc_close_ocgs(p: &mut pdf_doc) {
+    if p.ocgs.is_empty() {
+        return;
+    }
+    let mut all = vec![];
+    let mut order = vec![];
+    let mut off = vec![];
+    for ocg in p.ocgs.drain(..) {
+        all.push(pdf_ref_obj(ocg.dict));
+        order.push(pdf_ref_obj(ocg.dict));
+        if !ocg.visible {
+            off.push(pdf_ref_obj(ocg.dict));
+        }
+        pdf_release_obj(ocg.dict);
+    }
+    let mut config = pdf_dict::new();
+    config.set("BaseState", "ON");
+    config.set("Order", order);
+    if !off.is_empty() {
+        config.set("OFF", off);
+    }
+    let mut properties = pdf_dict::new();
+    properties.set("OCGs", all);
+    properties.set("D", config);
+    (*p.root.dict).as_dict_mut().set("OCProperties", properties);
+}
+/*
+ * Replacement text.
+ *
+ * Content between "bactualtext (text)" and "eactualtext" is marked with the
+ * given /ActualText, which text extraction and screen readers use instead of
+ * the glyphs. A span cannot be resumed without repeating its text, so one
+ * that is ended early, at the end of a page or to end a sequence it is
+ * nested in, is not resumed and the rest of its content is left unmarked.
+ */
+pub(crate) unsafe fn pdf_doc_begin_actualtext(text: &[u16]) {
+    let p = &mut pdoc;
+    let mut unicodes = text.to_vec();
+    pdf_dev_begin_actualtext(unicodes.as_mut_ptr(), unicodes.len() as i32);
+    p.marked_content.push(pdf_marked_content::ActualText(true));
+}
+
+pub(crate) unsafe fn pdf_doc_end_actualtext() -> i32 {
+    let p = &mut pdoc;
+    if mc_end(p, |mc| matches!(mc, pdf_marked_content::ActualText(_))) {
+        0
+    } else {
+        -1
+    }
+}
+/*
+ * Interactive forms. Every widget annotation that is a form field, whether
+ * it comes from pdf:ann, pdf:field or a signature field, is collected here
+ * and the AcroForm dictionary is assembled from them at the end of the
+ * document, before the named objects of specials are flushed, since the
+ * dictionary or its /Fields may be one of them. Dotted field names such as
+ * "address.city" are turned into a field hierarchy. Whatever the document
+ * put into the AcroForm dictionary itself is kept; only missing entries are
+ * filled in.
+ *
+ * When signing is enabled, the first signature field carries the document's
+ * signature. If the document does not reserve one itself, an invisible field
+ * is added to the first page.
+ */
+unsafe fn pdf_doc_init_acroform(p: &mut pdf_doc) {
+    p.fields = Vec::new();
+    p.sig_flags = 0;
+}
+unsafe fn is_form_field(annot: &pdf_obj) -> bool {
+    let dict = annot.as_dict();
+    let is_widget = dict
+        .get("Subtype")
+        .filter(|subtype| subtype.is_name())
+        .map_or(false, |subtype| subtype.as_name().to_bytes() == b"Widget");
+    is_widget && (dict.has("FT") || dict.has("T") || dict.has("Parent"))
+}
+unsafe fn doc_add_field(p: &mut pdf_doc, field: *mut pdf_obj) {
+    if !p.fields.iter().any(|&f| f == field) {
+        p.fields.push(pdf_link_obj(field));
+    }
+}
+unsafe fn sig_field_init(p: &mut pdf_doc, field: &mut pdf_obj) {
+    let dict = field.as_dict_mut();
+    dict.set("Type", "Annot");
+    dict.set("Subtype", "Widget");
+    dict.set("FT", "Sig");
+    if !dict.has("F") {
+        /* Print, Locked */
+        dict.set("F", 132f64);
+    }
+    if !dict.has("T") {
+        dict.set(
+            "T",
+            pdf_string::new(format!("Signature{}", p.fields.len() + 1)),
+        );
+    }
+    /* SignaturesExist */
+    p.sig_flags |= 1;
+    if let Some(value) = pdf_sign_new_value(&asn_date()) {
+        dict.set("V", value);
+        /* AppendOnly */
+        p.sig_flags |= 2;
+    }
+}
+
+pub(crate) unsafe fn pdf_doc_add_signature_field(rect: &Rect, field: *mut pdf_obj) {
+    let p = &mut pdoc;
+    sig_field_init(p, &mut *field);
+    let page_no = pdf_doc_current_page_number();
+    (*field).as_dict_mut().set("P", pdf_doc_ref_page(page_no));
+    /* An empty appearance; viewers draw their own for signed fields. */
+    let mut appearance = pdf_stream::new(0);
+    let dict = appearance.get_dict_mut();
+    dict.set("Type", "XObject");
+    dict.set("Subtype", "Form");
+    let mut bbox = vec![];
+    bbox.push_obj(0f64);
+    bbox.push_obj(0f64);
+    bbox.push_obj(rect.max.x - rect.min.x);
+    bbox.push_obj(rect.max.y - rect.min.y);
+    dict.set("BBox", bbox);
+    let appearance = appearance.into_obj();
+    let mut ap = pdf_dict::new();
+    ap.set("N", pdf_ref_obj(appearance));
+    pdf_release_obj(appearance);
+    (*field).as_dict_mut().set("AP", ap);
+    pdf_doc_add_annot(page_no, rect, field, 0);
+}
+
+/* The partial names of a field, if its name asks for a place in the hierarchy */
+unsafe fn field_name_parts(field: &pdf_obj) -> Option<Vec<Vec<u8>>> {
+    let name = field.as_dict().get("T").filter(|t| t.is_string())?;
+    let name = name.as_string().to_bytes();
+    /* Only plain names; a dot means something else in UTF-16BE. */
+    if name.starts_with(b"\xfe\xff") {
+        return None;
+    }
+    let parts: Vec<Vec<u8>> = name.split(|&c| c == b'.').map(|s| s.to_vec()).collect();
+    if parts.len() < 2 || parts.iter().any(|s| s.is_empty()) {
+        None
+    } else {
+        Some(parts)
+    }
+}
+
+/* Builds the field hierarchy and returns references to the top-level fields
+ * along with the intermediate fields created for it. */
+unsafe fn build_field_tree(fields: &[*mut pdf_obj]) -> (Vec<*mut pdf_obj>, Vec<*mut pdf_obj>) {
+    let terminal_names: HashSet<Vec<u8>> = fields
+        .iter()
+        .filter(|&&field| !(*field).as_dict().has("Parent"))
+        .filter_map(|&field| (*field).as_dict().get("T").filter(|t| t.is_string()))
+        .map(|t| t.as_string().to_bytes().to_vec())
+        .collect();
+    let mut roots = vec![];
+    let mut nodes: HashMap<Vec<u8>, *mut pdf_obj> = HashMap::new();
+    let mut created = vec![];
+    for &field in fields {
+        if (*field).as_dict().has("Parent") {
+            continue;
+        }
+        let parts = match field_name_parts(&*field) {
+            Some(parts) => parts,
+            None => {
+                roots.push(pdf_ref_obj(field));
+                continue;
+            }
+        };
+        let prefixes: Vec<Vec<u8>> = (1..parts.len()).map(|i| parts[..i].join(&b'.')).collect();
+        if let Some(clash) = prefixes.iter().find(|&name| terminal_names.contains(name)) {
+            warn!(
+                "Form field \"{}\" is also used as a parent field name.",
+                clash.display()
+            );
+            roots.push(pdf_ref_obj(field));
+            continue;
+        }
+        let mut parent: *mut pdf_obj = ptr::null_mut();
+        for (i, prefix) in prefixes.into_iter().enumerate() {
+            let node = if let Some(&node) = nodes.get(&prefix) {
+                node
+            } else {
+                let mut dict = pdf_dict::new();
+                dict.set("T", pdf_string::new(&parts[i]));
+                dict.set("Kids", Vec::new());
+                let node = dict.into_obj();
+                if parent.is_null() {
+                    roots.push(pdf_ref_obj(node));
+                } else {
+                    (*node).as_dict_mut().set("Parent", pdf_ref_obj(parent));
+                    field_kids(parent).push(pdf_ref_obj(node));
+                }
+                nodes.insert(prefix, node);
+                created.push(node);
+                node
+            };
+            parent = node;
+        }
+        let dict = (*field).as_dict_mut();
+        dict.set("T", pdf_string::new(&parts[parts.len() - 1]));
+        dict.set("Parent", pdf_ref_obj(parent));
+        field_kids(parent).push(pdf_ref_obj(field));
+    }
+    (roots, created)
+}
+unsafe fn field_kids<'a>(node: *mut pdf_obj) -> &'a mut Vec<*mut pdf_obj> {
+    (*node)
+        .as_dict_mut()
+        .get_mut("Kids")
+        .unwrap()
+        .as_array_mut()
+}
+
+unsafe fn form_font(base_font: &str) -> *mut pdf_obj {
+    let mut font = pdf_dict::new();
+    font.set("Type", "Font");
+    font.set("Subtype", "Type1");
+    font.set("BaseFont", base_font);
+    if base_font != "ZapfDingbats" {
+        font.set("Encoding", "WinAnsiEncoding");
+    }
+    let font = font.into_obj();
+    let reference = pdf_ref_obj(font);
+    pdf_release_obj(font);
+    reference
+}
+
+pub(crate) unsafe fn pdf_doc_close_acroform() {
+    let p = &mut pdoc;
+    if pdf_sign_enabled() && p.sig_flags & 2 == 0 && p.pages.num_entries > 0 {
+        let field = pdf_dict::new().into_obj();
+        sig_field_init(p, &mut *field);
+        let mut rect = vec![];
+        for _ in 0..4 {
+            rect.push_obj(0f64);
+        }
+        (*field).as_dict_mut().set("Rect", rect);
+        (*field).as_dict_mut().set("P", pdf_doc_ref_page(1));
+        let page = doc_get_page_entry(p, 1);
+        if page.annots.is_null() {
+            page.annots = Vec::new().into_obj();
+        }
+        (*page.annots).as_array_mut().push(pdf_ref_obj(field));
+        doc_add_field(p, field);
+        pdf_release_obj(field);
+    }
+    let fields = std::mem::replace(&mut p.fields, Vec::new());
+    if fields.is_empty() {
+        return;
+    }
+    let catalog = (*p.root.dict).as_dict_mut();
+    if !catalog.has("AcroForm") {
+        catalog.set("AcroForm", pdf_dict::new());
+    }
+    /* The dictionary and its /Fields may be named objects, as in hyperref's
+     * "/AcroForm << /Fields @afields >>".
+     */
+    let acroform_obj = pdf_deref_obj(catalog.get_mut("AcroForm"));
+    if acroform_obj.is_null() || !(*acroform_obj).is_dict() {
+        warn!("AcroForm is not a dictionary; leaving it untouched.");
+        pdf_release_obj(acroform_obj);
+        for field in fields {
+            pdf_release_obj(field);
+        }
+        return;
+    }
+    let acroform = (*acroform_obj).as_dict_mut();
+    let given = pdf_deref_obj(acroform.get_mut("Fields"));
+    if !given.is_null() && !(*given).is_array() {
+        warn!("/Fields of the AcroForm dictionary is not an array; leaving it untouched.");
+        pdf_release_obj(given);
+        pdf_release_obj(acroform_obj);
+        for field in fields {
+            pdf_release_obj(field);
+        }
+        return;
+    }
+
+    let (roots, created) = build_field_tree(&fields);
+    let labels: HashSet<u32> = fields.iter().map(|&field| (*field).label()).collect();
+    let mut all_fields = vec![];
+    if !given.is_null() {
+        /* Fields the document listed itself and that we don't know of stay. */
+        for obj in std::mem::replace((*given).as_array_mut(), Vec::new()) {
+            if (*obj).is_indirect()
+                && (*obj).as_indirect().pf.is_null()
+                && labels.contains(&(*obj).as_indirect().id.0)
+            {
+                pdf_release_obj(obj);
+            } else {
+                all_fields.push(obj);
+            }
+        }
+    }
+    all_fields.extend(roots);
+    if given.is_null() {
+        acroform.set("Fields", all_fields);
+    } else {
+        /* Fill in the array in place so that references to it stay valid. */
+        *(*given).as_array_mut() = all_fields;
+        pdf_release_obj(given);
+    }
+    if p.sig_flags != 0 && !acroform.has("SigFlags") {
+        acroform.set("SigFlags", p.sig_flags as f64);
+    }
+
+    let is_sig = |field: *mut pdf_obj| {
+        (*field)
+            .as_dict()
+            .get("FT")
+            .filter(|ft| ft.is_name())
+            .map_or(false, |ft| ft.as_name().to_bytes() == b"Sig")
+    };
+    if fields.iter().any(|&field| !is_sig(field)) {
+        if !acroform.has("DA") {
+            acroform.set("DA", pdf_string::new("/Helv 0 Tf 0 g"));
+        }
+        if !acroform.has("DR") {
+            let mut fonts = pdf_dict::new();
+            fonts.set("Helv", form_font("Helvetica"));
+            fonts.set("ZaDb", form_font("ZapfDingbats"));
+            let mut resources = pdf_dict::new();
+            resources.set("Font", fonts);
+            acroform.set("DR", resources);
+        }
+        /* Let viewers draw the fields that come without an appearance. */
+        let needs_appearances = fields
+            .iter()
+            .any(|&field| !is_sig(field) && !(*field).as_dict().has("AP"));
+        if needs_appearances && !acroform.has("NeedAppearances") {
+            if pdf_conform_get_level() != PdfConformance::None {
+                pdf_conform_violation(
+                    "form fields without an appearance are not permitted".to_owned(),
+                );
+            } else {
+                acroform.set("NeedAppearances", true);
+            }
+        }
+    }
+
+    pdf_release_obj(acroform_obj);
+    for node in created {
+        pdf_release_obj(node);
+    }
+    for field in fields {
+        pdf_release_obj(field);
+    }
+}
 /* page_no = 0 for root page tree node. */
 
 pub(crate) unsafe fn pdf_doc_set_mediabox(page_no: usize, mediabox: &Rect) {
@@ -2184,10 +2952,13 @@ pub(crate) unsafe fn pdf_doc_begin_page(scale: f64, x_origin: f64, y_origin: f64
     /* pdf_doc_new_page() allocates page content stream. */
     pdf_doc_new_page(p);
     pdf_dev_bop(&mut M);
+    pdf_doc_mc_begin_page(p);
 }
 
 pub(crate) unsafe fn pdf_doc_end_page() {
     let p = &mut pdoc;
+    pdf_doc_mc_end_page(p);
+    pdf_doc_struct_end_page(p);
     pdf_dev_eop();
     doc_fill_page_background(p);
     pdf_doc_finish_page(p);
@@ -2212,6 +2983,8 @@ pub(crate) unsafe fn pdf_open_document(
     filename: &str,
     enable_encrypt: bool,
     enable_object_stream: bool,
+    enable_linearization: bool,
+    enable_optimization: bool,
     media_width: f64,
     media_height: f64,
     annot_grow_amount: f64,
@@ -2219,7 +2992,13 @@ pub(crate) unsafe fn pdf_open_document(
     check_gotos: i32,
 ) {
     let p = &mut pdoc;
-    pdf_out_init(filename, enable_encrypt, enable_object_stream);
+    pdf_out_init(
+        filename,
+        enable_encrypt,
+        enable_object_stream,
+        enable_linearization,
+        enable_optimization,
+    );
     pdf_doc_init_catalog(p);
     p.opt.annot_grow = annot_grow_amount;
     p.opt.outline_open_depth = bookmark_open_depth;
@@ -2238,6 +3017,10 @@ pub(crate) unsafe fn pdf_open_document(
     }
     pdf_doc_init_bookmarks(p, bookmark_open_depth);
     pdf_doc_init_articles(p);
+    pdf_doc_init_marked_content(p);
+    pdf_doc_init_structure(p);
+    pdf_doc_init_ocgs(p);
+    pdf_doc_init_acroform(p);
     pdf_doc_init_names(p, check_gotos);
     pdf_doc_init_page_tree(p, media_width, media_height);
     pdf_doc_set_bgcolor(None);
@@ -2277,6 +3060,9 @@ pub(crate) unsafe fn pdf_close_document() {
     pdf_doc_close_articles(p); /* Should be at last. */
     pdf_doc_close_names(p);
     pdf_doc_close_bookmarks(p);
+    pdf_doc_close_marked_content(p);
+    pdf_doc_close_structure(p);
+    pdf_doc_close_ocgs(p);
     pdf_doc_close_page_tree(p);
     pdf_doc_close_docinfo(p);
     pdf_doc_close_catalog(p);
@@ -2284,6 +3070,7 @@ pub(crate) unsafe fn pdf_close_document() {
     pdf_close_fonts();
     pdf_close_colors();
     pdf_close_resources();
+    pdf_conform_finish();
     pdf_out_flush();
     thumb_basename = String::new();
 }
@@ -2469,3 +3256,119 @@ pub(crate) unsafe fn pdf_doc_expand_box(rect: &Rect) {
     breaking_state.rect.max.y = breaking_state.rect.max.y.max(rect.max.y);
     breaking_state.dirty = 1;
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dpx_pdfobj::{pdf_obj_reset_global_state, pdf_out_flush, pdf_out_init};
+    use crate::test_util::with_memory_io;
+
+    unsafe fn field(name: &[u8]) -> *mut pdf_obj {
+        let mut dict = pdf_dict::new();
+        dict.set("Type", "Annot");
+        dict.set("Subtype", "Widget");
+        dict.set("FT", "Tx");
+        dict.set("T", pdf_string::new(name));
+        dict.into_obj()
+    }
+
+    unsafe fn name_of(field: *mut pdf_obj) -> Vec<u8> {
+        (*field)
+            .as_dict()
+            .get("T")
+            .unwrap()
+            .as_string()
+            .to_bytes()
+            .to_vec()
+    }
+
+    unsafe fn refers_to(reference: &pdf_obj, object: *mut pdf_obj) -> bool {
+        reference.as_indirect().id.0 == (*object).label()
+    }
+
+    #[test]
+    fn field_names() {
+        unsafe {
+            let parts = |name: &[u8]| {
+                let f = field(name);
+                let parts = field_name_parts(&*f);
+                pdf_release_obj(f);
+                parts
+            };
+            assert_eq!(
+                parts(b"address.city"),
+                Some(vec![b"address".to_vec(), b"city".to_vec()])
+            );
+            assert_eq!(parts(b"a.b.c").map(|p| p.len()), Some(3));
+            assert_eq!(parts(b"name"), None);
+            assert_eq!(parts(b"a..b"), None);
+            assert_eq!(parts(b".a"), None);
+            assert_eq!(parts(b"a."), None);
+            /* In UTF-16BE, 0x2E is half of some other character. */
+            assert_eq!(parts(b"\xfe\xff\x2e\x2e\x00a"), None);
+
+            let unnamed = pdf_dict::new().into_obj();
+            assert_eq!(field_name_parts(&*unnamed), None);
+            pdf_release_obj(unnamed);
+        }
+    }
+
+    #[test]
+    fn field_tree() {
+        with_memory_io(&[], || unsafe {
+            pdf_obj_reset_global_state();
+            pdf_out_init("fields.pdf", false, false, false, false);
+
+            let fields: Vec<_> = [
+                &b"address.city"[..],
+                b"name",
+                b"address.zip",
+                b"a.b.c",
+                b"x",
+                b"x.y",
+            ]
+            .iter()
+            .map(|name| field(name))
+            .collect();
+            let (roots, created) = build_field_tree(&fields);
+
+            /* address, name, a, x and the clashing x.y, in order */
+            assert_eq!(roots.len(), 5);
+            assert_eq!(created.len(), 3);
+            let address = created[0];
+            assert_eq!(name_of(address), b"address");
+            assert!(refers_to(&*roots[0], address));
+            assert!(refers_to(&*roots[1], fields[1]));
+            assert!(refers_to(&*roots[2], created[1]));
+            assert!(refers_to(&*roots[3], fields[4]));
+            assert!(refers_to(&*roots[4], fields[5]));
+
+            /* The terminal fields keep their last partial name only. */
+            assert_eq!(name_of(fields[0]), b"city");
+            assert_eq!(name_of(fields[2]), b"zip");
+            let kids = field_kids(address);
+            assert_eq!(kids.len(), 2);
+            assert!(refers_to(&*kids[0], fields[0]));
+            assert!(refers_to(&*kids[1], fields[2]));
+            let parent = (*fields[2]).as_dict().get("Parent").unwrap();
+            assert!(refers_to(parent, address));
+
+            /* a.b.c hangs below two new fields */
+            let (a, b) = (created[1], created[2]);
+            assert!(refers_to(&*field_kids(a)[0], b));
+            assert!(refers_to(&*field_kids(b)[0], fields[3]));
+            assert_eq!(name_of(b), b"b");
+            assert_eq!(name_of(fields[3]), b"c");
+
+            /* x.y cannot go below the field x, so it is left alone. */
+            assert_eq!(name_of(fields[5]), b"x.y");
+            assert!(!(*fields[5]).as_dict().has("Parent"));
+
+            for object in roots.into_iter().chain(created).chain(fields) {
+                pdf_release_obj(object);
+            }
+            pdf_out_flush();
+        })
+        .unwrap();
+    }
+}
@@ -41,6 +41,7 @@ use super::dpx_cmap::{
 use super::dpx_cmap_read::{CMap_parse, CMap_parse_check_sig};
 use super::dpx_cmap_write::CMap_create_stream;
 use super::dpx_dpxfile::dpx_tt_open;
+use super::dpx_pdfconform::{pdf_conform_get_level, PdfConformance};
 use crate::dpx_pdfobj::{
     pdf_dict, pdf_get_version, pdf_link_obj, pdf_name, pdf_obj, pdf_release_obj, pdf_stream,
     IntoObj, PushObj,
@@ -390,11 +391,20 @@ pub(crate) unsafe fn pdf_encoding_complete() {
                     ptr::null_mut()
                 },
             );
+        }
+        /* Text in the predefined encodings can be extracted without a
+         * ToUnicode CMap, but PDF/A and PDF/X validators want one anyway.
+         * The used characters are not tracked for those, so all go in. */
+        if !encoding_is_predefined || pdf_conform_get_level() != PdfConformance::None {
             assert!(encoding.tounicode.is_null());
             encoding.tounicode = pdf_create_ToUnicode_CMap(
                 &encoding.enc_name,
                 encoding.glyphs.as_mut(),
-                encoding.is_used.as_mut_ptr(),
+                if encoding_is_predefined {
+                    ptr::null()
+                } else {
+                    encoding.is_used.as_ptr()
+                },
             )
             .map(IntoObj::into_obj)
             .unwrap_or(ptr::null_mut());
@@ -501,6 +511,9 @@ pub(crate) unsafe fn pdf_encoding_get_tounicode(encoding_id: i32) -> *mut pdf_ob
  * Note: The PDF 1.4 reference is not consistent: Section 5.9 describes
  * the Unicode mapping of PDF 1.3 and Section 9.7.2 (in the context of
  * Tagged PDF) the one of PDF 1.5.
+ *
+ * PDF/A and PDF/X output always gets a complete CMap, since validators
+ * do not know about the predefined character codes.
  */
 
 pub(crate) unsafe fn pdf_create_ToUnicode_CMap(
@@ -516,6 +529,7 @@ pub(crate) unsafe fn pdf_create_ToUnicode_CMap(
     CMap_set_wmode(&mut cmap, 0i32);
     CMap_set_CIDSysInfo(&mut cmap, &mut CSI_UNICODE);
     CMap_add_codespacerange(&mut cmap, range_min.as_ptr(), range_max.as_ptr(), 1);
+    let complete = pdf_conform_get_level() != PdfConformance::None;
     let mut all_predef = 1;
     for code in 0..=0xff {
         if !(!is_used.is_null() && *is_used.offset(code as isize) == 0) {
@@ -525,7 +539,8 @@ pub(crate) unsafe fn pdf_create_ToUnicode_CMap(
                 /* Adobe glyph naming conventions are not used by viewers,
                  * hence even ligatures (e.g, "f_i") must be explicitly defined
                  */
-                if pdf_get_version() < 5_u32 || agln.is_null() || (*agln).is_predef == 0 {
+                if complete || pdf_get_version() < 5_u32 || agln.is_null() || (*agln).is_predef == 0
+                {
                     wbuf[0] = (code & 0xff) as u8;
                     let mut p = wbuf.as_mut_ptr().offset(1);
                     let endptr = wbuf.as_mut_ptr().offset(1024);
@@ -545,7 +560,7 @@ pub(crate) unsafe fn pdf_create_ToUnicode_CMap(
             }
         }
     }
-    let stream = if all_predef != 0 {
+    let stream = if all_predef != 0 && !complete {
         None
     } else {
         CMap_create_stream(&mut cmap)
@@ -665,6 +665,11 @@ pub(crate) unsafe fn pdf_enc_set_passwd(
     };
 }
 
+/* The algorithm version and revision and the key length in bytes */
+pub(crate) unsafe fn pdf_enc_get_algorithm() -> (i32, i32, i32) {
+    (sec_data.V, sec_data.R, sec_data.key_size)
+}
+
 unsafe fn calculate_key(p: &mut pdf_sec) -> [u8; 16] {
     let mut len = p.key_size as usize + 5;
     let mut tmp = [0u8; 25];
@@ -37,6 +37,7 @@ use super::dpx_cmap::{
 };
 use super::dpx_fontmap::fontmap;
 use super::dpx_mem::new;
+use super::dpx_pdfconform::{pdf_conform_get_level, pdf_conform_violation, PdfConformance};
 use super::dpx_pdfencoding::{
     pdf_close_encodings, pdf_encoding_add_usedchars, pdf_encoding_complete,
     pdf_encoding_findresource, pdf_encoding_get_name, pdf_encoding_get_tounicode,
@@ -481,6 +482,19 @@ pub(crate) unsafe fn pdf_close_fonts() {
                 .as_dict_mut()
                 .set("Encoding", "MacRomanEncoding"); /* After encoding */
         }
+        if (*font_0).subtype == 0i32 && pdf_font_get_flag(font_0, 1i32 << 2i32) != 0 {
+            pdf_conform_violation(format!("font \"{}\" is not embedded", (*font_0).fontname));
+        }
+        if (*font_0).subtype != 4i32
+            && pdf_conform_get_level() != PdfConformance::None
+            && !(*font_0).resource.is_null()
+            && !(*(*font_0).resource).as_dict().has("ToUnicode")
+        {
+            pdf_conform_violation(format!(
+                "font \"{}\" has no ToUnicode mapping",
+                (*font_0).fontname
+            ));
+        }
         pdf_flush_font(&mut *font_0);
         pdf_clean_font_struct(font_0);
         font_id += 1
@@ -27,7 +27,7 @@
 )]
 
 use crate::bridge::DisplayExt;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::CString;
 use std::io::{Read, Seek, SeekFrom, Write};
 
@@ -36,11 +36,13 @@ use crate::{info, warn};
 use std::ffi::CStr;
 use std::ptr;
 
+use super::dpx_dpxutil::{is_delim, is_space, xtoi};
 use super::dpx_mem::{new, renew};
 use super::dpx_mfileio::{tt_mfgets, work_buffer};
 use super::dpx_pdfdev::pdf_sprint_number;
 use super::dpx_pdfencrypt::{pdf_enc_set_generation, pdf_enc_set_label, pdf_encrypt_data};
 use super::dpx_pdfparse::skip_white;
+use super::dpx_pdfsign::pdf_sign_output;
 use crate::bridge::{
     ttstub_input_get_size, ttstub_input_getc, ttstub_output_close, ttstub_output_open_stdout,
     ttstub_output_putc,
@@ -56,10 +58,10 @@ pub(crate) const STREAM_USE_PREDICTOR: i32 = 1 << 1;
 
 /// Objects with this flag will not be put into an object stream.
 /// For instance, all stream objects have this flag set.
-const OBJ_NO_OBJSTM: i32 = 1 << 0;
+pub(crate) const OBJ_NO_OBJSTM: i32 = 1 << 0;
 /// Objects with this flag will not be encrypted.
 /// This implies OBJ_NO_OBJSTM if encryption is turned on.
-const OBJ_NO_ENCRYPT: i32 = 1 << 1;
+pub(crate) const OBJ_NO_ENCRYPT: i32 = 1 << 1;
 
 /// (label, generation)
 pub(crate) type ObjectId = (u32, u16);
@@ -480,6 +482,18 @@ pub(crate) unsafe fn pdf_set_compression(level: i32) {
 pub(crate) unsafe fn pdf_set_use_predictor(bval: i32) {
     compression_use_predictor = (if bval != 0 { 1i32 } else { 0i32 }) as i8;
 }
+
+pub(crate) unsafe fn pdf_get_compression() -> i32 {
+    compression_level as i32
+}
+
+pub(crate) unsafe fn pdf_get_use_predictor() -> bool {
+    compression_use_predictor != 0
+}
+
+pub(crate) unsafe fn pdf_get_encryption() -> bool {
+    doc_enc_mode
+}
 static mut pdf_version: u32 = 5_u32;
 
 pub(crate) unsafe fn pdf_set_version(version: u32) {
@@ -502,6 +516,51 @@ pub(crate) unsafe fn pdf_obj_set_verbose(level: i32) {
 }
 static mut current_objstm: *mut pdf_obj = ptr::null_mut(); // TODO: replace with Option<pdf_stream>
 static mut do_objstm: i32 = 0;
+/*
+ * Linearized ("fast web view") and size optimized output: labeled objects
+ * are held back when released and only written by pdf_out_flush(), once the
+ * structure of the whole document is known.
+ */
+static mut linearize: bool = false;
+static mut optimize: bool = false;
+static mut deferred_objects: Vec<*mut pdf_obj> = Vec::new();
+/* Maps original labels to the object numbers used in the linearized file */
+static mut lin_renumber: Vec<u32> = Vec::new();
+/* The whole file is kept in memory when it has to be patched (signing) */
+static mut hold_output: bool = false;
+static mut held_output: Option<Vec<u8>> = None;
+/* Labeled objects released while an image is loaded for the image cache */
+static mut captured_objects: Option<Vec<*mut pdf_obj>> = None;
+
+pub(crate) unsafe fn pdf_set_held_output(hold: bool) {
+    hold_output = hold;
+}
+
+/// Holds back labeled objects when they are released, instead of writing
+/// them, until `pdf_end_capture()` is called.
+pub(crate) unsafe fn pdf_begin_capture() {
+    captured_objects = Some(Vec::new());
+}
+
+/// Returns the objects held back since `pdf_begin_capture()`. Each of them
+/// must be passed to `pdf_release_captured()` once the caller is done.
+pub(crate) unsafe fn pdf_end_capture() -> Vec<*mut pdf_obj> {
+    captured_objects.take().unwrap_or_default()
+}
+
+pub(crate) unsafe fn pdf_release_captured(object: *mut pdf_obj) {
+    assert!((*object).refcount == 0);
+    (*object).refcount = 1;
+    pdf_release_obj(object);
+}
+
+/// File offset at which the object with the given label was written.
+pub(crate) unsafe fn pdf_output_obj_offset(label: u32) -> Option<usize> {
+    output_xref
+        .get(label as usize)
+        .filter(|entry| entry.typ == 1)
+        .map(|entry| entry.id.0 as usize)
+}
 unsafe fn add_xref_entry(label: usize, typ: u8, id: ObjectId) {
     if label >= pdf_max_ind_objects {
         pdf_max_ind_objects = (label / 512 + 1) * 512;
@@ -515,12 +574,23 @@ unsafe fn add_xref_entry(label: usize, typ: u8, id: ObjectId) {
     }
 }
 
-pub(crate) unsafe fn pdf_out_init(filename: &str, do_encryption: bool, enable_object_stream: bool) {
+pub(crate) unsafe fn pdf_out_init(
+    filename: &str,
+    do_encryption: bool,
+    enable_object_stream: bool,
+    enable_linearization: bool,
+    enable_optimization: bool,
+) {
     output_xref = vec![];
     pdf_max_ind_objects = 0;
     add_xref_entry(0, 0_u8, (0_u32, 0xffff_u16));
     next_label = 1;
-    if pdf_version >= 5_u32 {
+    linearize = enable_linearization && !do_encryption;
+    optimize = enable_optimization;
+    deferred_objects = Vec::new();
+    lin_renumber = Vec::new();
+    /* Linearized files are written with classic xref tables only. */
+    if pdf_version >= 5_u32 && !linearize {
         if enable_object_stream {
             xref_stream = pdf_stream::new(STREAM_COMPRESS).into_obj();
             (*xref_stream).flags |= OBJ_NO_ENCRYPT;
@@ -537,6 +607,7 @@ pub(crate) unsafe fn pdf_out_init(filename: &str, do_encryption: bool, enable_ob
         do_objstm = 0i32
     }
     output_stream = ptr::null_mut();
+    held_output = if hold_output { Some(Vec::new()) } else { None };
     if filename.is_empty() {
         panic!("stdout PDF output not supported");
     }
@@ -630,6 +701,26 @@ unsafe fn dump_xref_stream() {
 }
 
 pub(crate) unsafe fn pdf_out_flush() {
+    if (linearize || optimize) && pdf_output_handle.is_some() {
+        if optimize {
+            optimize_deferred();
+        }
+        if linearize {
+            if flush_linearized() {
+                ttstub_output_close(pdf_output_handle.take().unwrap());
+                release_deferred();
+                return;
+            }
+            /* No usable page tree: write the held back objects the usual way. */
+            warn!("Unable to linearize PDF output; writing a regular file.");
+        }
+        linearize = false;
+        optimize = false;
+        for &object in deferred_objects.iter() {
+            write_released_obj(object);
+        }
+        release_deferred();
+    }
     if let Some(handle) = pdf_output_handle.as_mut() {
         /* Flush current object stream */
         if !current_objstm.is_null() {
@@ -653,12 +744,16 @@ pub(crate) unsafe fn pdf_out_flush() {
             dump_xref_table();
             dump_trailer_dict();
         }
-        /* Done with xref table */
-        output_xref = vec![];
         pdf_out(handle, b"startxref\n");
         let out = format!("{}\n", startxref);
         pdf_out(handle, out.as_bytes());
         pdf_out(handle, b"%%EOF\n");
+        if let Some(mut data) = held_output.take() {
+            pdf_sign_output(&mut data);
+            handle.write(&data).unwrap();
+        }
+        /* Done with xref table */
+        output_xref = vec![];
         if verbose != 0 {
             if compression_level as i32 > 0i32 {
                 info!(
@@ -676,6 +771,860 @@ pub(crate) unsafe fn pdf_out_flush() {
     };
 }
 
+/* Bit packing for the hint tables, most significant bit first. */
+struct HintBits {
+    data: Vec<u8>,
+    byte: u8,
+    nbits: u32,
+}
+
+impl HintBits {
+    fn new() -> Self {
+        Self {
+            data: Vec::new(),
+            byte: 0,
+            nbits: 0,
+        }
+    }
+    fn put(&mut self, value: u64, bits: u32) {
+        for i in (0..bits).rev() {
+            self.byte = self.byte << 1 | ((value >> i) & 1) as u8;
+            self.nbits += 1;
+            if self.nbits == 8 {
+                self.data.push(self.byte);
+                self.byte = 0;
+                self.nbits = 0;
+            }
+        }
+    }
+    /* Every item of a hint table starts on a byte boundary. */
+    fn pad(&mut self) {
+        if self.nbits > 0 {
+            self.put(0, 8 - self.nbits);
+        }
+    }
+}
+
+fn hint_bits_needed(value: u64) -> u32 {
+    64 - value.leading_zeros()
+}
+
+unsafe fn ref_label(object: Option<&pdf_obj>) -> Option<u32> {
+    object
+        .filter(|o| o.is_indirect() && o.as_indirect().pf.is_null())
+        .map(|o| o.as_indirect().id.0)
+}
+
+/* Labels of all objects referenced from object, optionally following /Parent links. */
+unsafe fn collect_refs(object: *mut pdf_obj, refs: &mut Vec<u32>, parent: bool) {
+    match (*object).data {
+        PdfObjVariant::ARRAY(v) => {
+            for &item in (*v).values.iter() {
+                if !item.is_null() {
+                    collect_refs(item, refs, parent);
+                }
+            }
+        }
+        PdfObjVariant::DICT(v) => {
+            for (k, &item) in (*v).inner.iter() {
+                if parent || k.to_bytes() != b"Parent" {
+                    collect_refs(item, refs, parent);
+                }
+            }
+        }
+        PdfObjVariant::STREAM(v) => collect_refs((*v).dict, refs, parent),
+        PdfObjVariant::INDIRECT(v) => {
+            if (*v).pf.is_null() {
+                refs.push((*v).id.0);
+            }
+        }
+        _ => {}
+    }
+}
+
+unsafe fn lin_walk_pages(
+    label: u32,
+    index: &HashMap<u32, usize>,
+    nodes: &mut HashSet<u32>,
+    pages: &mut Vec<u32>,
+) {
+    let object = match index.get(&label) {
+        Some(&i) => deferred_objects[i],
+        None => return,
+    };
+    if !(*object).is_dict() || !nodes.insert(label) {
+        return;
+    }
+    let dict = (*object).as_dict();
+    let typ = dict
+        .get("Type")
+        .filter(|typ| typ.is_name())
+        .map(|typ| typ.as_name().to_bytes());
+    match typ {
+        Some(b"Pages") => {
+            if let Some(kids) = dict.get("Kids").filter(|kids| kids.is_array()) {
+                for &kid in kids.as_array() {
+                    if let Some(kid) = ref_label(Some(&*kid)) {
+                        lin_walk_pages(kid, index, nodes, pages);
+                    }
+                }
+            }
+        }
+        Some(b"Page") => pages.push(label),
+        _ => {}
+    }
+}
+
+/* The page object followed by everything reachable from it. */
+unsafe fn lin_page_objects(
+    page: u32,
+    index: &HashMap<u32, usize>,
+    stop: &HashSet<u32>,
+) -> Vec<u32> {
+    let mut seen = HashSet::new();
+    seen.insert(page);
+    let mut objects = vec![page];
+    let mut i = 0;
+    while i < objects.len() {
+        let mut refs = vec![];
+        collect_refs(deferred_objects[index[&objects[i]]], &mut refs, false);
+        for label in refs {
+            if index.contains_key(&label) && !stop.contains(&label) && seen.insert(label) {
+                objects.push(label);
+            }
+        }
+        i += 1;
+    }
+    objects
+}
+
+unsafe fn serialize_obj(object: *mut pdf_obj, label: Option<u32>) -> Vec<u8> {
+    let scratch = pdf_stream::new(0).into_obj();
+    output_stream = scratch;
+    enc_mode = false;
+    let handle = pdf_output_handle.as_mut().unwrap();
+    if let Some(label) = label {
+        pdf_out(handle, format!("{} 0 obj\n", label).as_bytes());
+    }
+    pdf_write_obj(object, handle);
+    if label.is_some() {
+        pdf_out(handle, b"\nendobj\n");
+    }
+    output_stream = ptr::null_mut();
+    let data = std::mem::replace(&mut (*scratch).as_stream_mut().content, Vec::new());
+    pdf_release_obj(scratch);
+    data
+}
+
+/*
+ * Write the held back objects as a linearized file: the first page and
+ * everything it needs come right after the linearization dictionary and
+ * the primary hint stream, followed by the remaining pages, the objects
+ * they share, and all other objects.  Returns false if the document has
+ * no page tree to order the objects by.
+ */
+unsafe fn flush_linearized() -> bool {
+    let index: HashMap<u32, usize> = deferred_objects
+        .iter()
+        .enumerate()
+        .map(|(i, &object)| ((*object).label(), i))
+        .collect();
+    let catalog = match ref_label((*trailer_dict).as_dict().get("Root")) {
+        Some(label) if index.contains_key(&label) => label,
+        _ => return false,
+    };
+    if !(*deferred_objects[index[&catalog]]).is_dict() {
+        return false;
+    }
+    let mut stop = HashSet::new();
+    stop.insert(catalog);
+    let mut pages = vec![];
+    if let Some(root) = ref_label((*deferred_objects[index[&catalog]]).as_dict().get("Pages")) {
+        lin_walk_pages(root, &index, &mut stop, &mut pages);
+    }
+    if pages.is_empty() {
+        return false;
+    }
+    let page_objects: Vec<Vec<u32>> = pages
+        .iter()
+        .map(|&page| lin_page_objects(page, &index, &stop))
+        .collect();
+    let first = &page_objects[0];
+    let first_pos: HashMap<u32, usize> = first.iter().enumerate().map(|(i, &l)| (l, i)).collect();
+    let mut use_count = HashMap::new();
+    for objects in &page_objects[1..] {
+        for &label in objects {
+            if !first_pos.contains_key(&label) {
+                *use_count.entry(label).or_insert(0) += 1;
+            }
+        }
+    }
+    /* Main part: pages 2..N with their private objects, shared objects, the rest */
+    let mut placed: HashSet<u32> = first.iter().cloned().collect();
+    placed.insert(catalog);
+    let mut main = vec![];
+    let mut page_parts = vec![];
+    for objects in &page_objects[1..] {
+        let start = main.len();
+        for &label in objects {
+            if use_count.get(&label) == Some(&1) && placed.insert(label) {
+                main.push(label);
+            }
+        }
+        page_parts.push(start..main.len());
+    }
+    let shared_start = main.len();
+    for objects in &page_objects[1..] {
+        for &label in objects {
+            if placed.insert(label) {
+                main.push(label);
+            }
+        }
+    }
+    let n_shared = main.len() - shared_start;
+    for &object in deferred_objects.iter() {
+        if placed.insert((*object).label()) {
+            main.push((*object).label());
+        }
+    }
+
+    let max_label = index.keys().cloned().max().unwrap_or(0);
+    lin_renumber = vec![0; max_label as usize + 1];
+    for (i, &label) in main.iter().enumerate() {
+        lin_renumber[label as usize] = i as u32 + 1;
+    }
+    let lin_label = main.len() as u32 + 1;
+    lin_renumber[catalog as usize] = lin_label + 1;
+    let hint_label = lin_label + 2;
+    for (i, &label) in first.iter().enumerate() {
+        lin_renumber[label as usize] = hint_label + 1 + i as u32;
+    }
+    let size = hint_label + 1 + first.len() as u32;
+
+    let serialize = |label: u32| {
+        serialize_obj(
+            deferred_objects[index[&label]],
+            Some(lin_renumber[label as usize]),
+        )
+    };
+    let catalog_data = serialize(catalog);
+    let first_data: Vec<Vec<u8>> = first.iter().map(|&l| serialize(l)).collect();
+    let main_data: Vec<Vec<u8>> = main.iter().map(|&l| serialize(l)).collect();
+    let trailer_data = serialize_obj(trailer_dict, None);
+    let first_len: usize = first_data.iter().map(|d| d.len()).sum();
+    let shared_pos: HashMap<u32, usize> = main[shared_start..shared_start + n_shared]
+        .iter()
+        .enumerate()
+        .map(|(i, &l)| (l, i))
+        .collect();
+
+    /* Everything up to the hint stream is of fixed size. */
+    let lin_dict = |l: usize, h: (usize, usize), e: usize, t: usize| {
+        format!(
+            "{} 0 obj\n<</Linearized 1/L {:10}/H [{:10} {:10}]/O {:10}/E {:10}/N {:10}/T {:10}>>\nendobj\n",
+            lin_label,
+            l,
+            h.0,
+            h.1,
+            hint_label + 1,
+            e,
+            pages.len(),
+            t,
+        )
+    };
+    let first_trailer = |prev: usize| {
+        let mut data = format!("trailer\n<</Size {}/Prev {:10}", size, prev).into_bytes();
+        data.extend_from_slice(&trailer_data[2..]);
+        data.extend_from_slice(b"\nstartxref\n0\n%%EOF\n");
+        data
+    };
+    let first_xref_head = format!("xref\n{} {}\n", lin_label, size - lin_label);
+    let lin_offset = pdf_output_file_position;
+    let first_xref_offset = lin_offset + lin_dict(0, (0, 0), 0, 0).len();
+    let catalog_offset = first_xref_offset
+        + first_xref_head.len()
+        + 20 * (size - lin_label) as usize
+        + first_trailer(0).len();
+    let hint_offset = catalog_offset + catalog_data.len();
+
+    /*
+     * Hint tables (PDF Reference, Annex F).  Offsets in them are given as if
+     * the hint stream were not there.  Content streams are not located
+     * separately; each page's content is taken to span the whole page.
+     */
+    let mut page_nobjs = vec![first.len() as u64];
+    let mut page_lengths = vec![first_len as u64];
+    let mut page_shared = vec![vec![]];
+    for (objects, part) in page_objects[1..].iter().zip(page_parts.iter()) {
+        page_nobjs.push(part.len() as u64);
+        page_lengths.push(main_data[part.clone()].iter().map(|d| d.len() as u64).sum());
+        let mut shared = vec![];
+        for label in objects {
+            if let Some(&i) = first_pos.get(label) {
+                shared.push(i as u64);
+            } else if let Some(&i) = shared_pos.get(label) {
+                shared.push((first.len() + i) as u64);
+            }
+        }
+        page_shared.push(shared);
+    }
+    let least_nobjs = *page_nobjs.iter().min().unwrap();
+    let nobjs_bits = hint_bits_needed(page_nobjs.iter().max().unwrap() - least_nobjs);
+    let least_length = *page_lengths.iter().min().unwrap();
+    let length_bits = hint_bits_needed(page_lengths.iter().max().unwrap() - least_length);
+    let nshared_bits = hint_bits_needed(page_shared.iter().map(|s| s.len() as u64).max().unwrap());
+    let shared_id_bits = hint_bits_needed(page_shared.iter().flatten().cloned().max().unwrap_or(0));
+    let mut hints = HintBits::new();
+    hints.put(least_nobjs, 32);
+    hints.put(hint_offset as u64, 32);
+    hints.put(nobjs_bits as u64, 16);
+    hints.put(least_length, 32);
+    hints.put(length_bits as u64, 16);
+    hints.put(0, 32);
+    hints.put(0, 16);
+    hints.put(least_length, 32);
+    hints.put(length_bits as u64, 16);
+    hints.put(nshared_bits as u64, 16);
+    hints.put(shared_id_bits as u64, 16);
+    hints.put(0, 16);
+    hints.put(1, 16);
+    for &n in &page_nobjs {
+        hints.put(n - least_nobjs, nobjs_bits);
+    }
+    hints.pad();
+    for &len in &page_lengths {
+        hints.put(len - least_length, length_bits);
+    }
+    hints.pad();
+    for shared in &page_shared {
+        hints.put(shared.len() as u64, nshared_bits);
+    }
+    hints.pad();
+    for &id in page_shared.iter().flatten() {
+        hints.put(id, shared_id_bits);
+    }
+    hints.pad();
+    /* Fractional positions of shared references take no bits at all. */
+    for &len in &page_lengths {
+        hints.put(len - least_length, length_bits);
+    }
+    hints.pad();
+    let shared_table = hints.data.len();
+    /* One shared object group per object: the first page's, then the shared ones */
+    let group_lengths: Vec<u64> = first_data
+        .iter()
+        .chain(main_data[shared_start..shared_start + n_shared].iter())
+        .map(|d| d.len() as u64)
+        .collect();
+    let least_group = *group_lengths.iter().min().unwrap();
+    let group_bits = hint_bits_needed(group_lengths.iter().max().unwrap() - least_group);
+    let main_prefix: usize = main_data[..shared_start].iter().map(|d| d.len()).sum();
+    if n_shared > 0 {
+        hints.put(shared_start as u64 + 1, 32);
+        hints.put((hint_offset + first_len + main_prefix) as u64, 32);
+    } else {
+        hints.put(0, 32);
+        hints.put(0, 32);
+    }
+    hints.put(first.len() as u64, 32);
+    hints.put(group_lengths.len() as u64, 32);
+    hints.put(0, 16);
+    hints.put(least_group, 32);
+    hints.put(group_bits as u64, 16);
+    for &len in &group_lengths {
+        hints.put(len - least_group, group_bits);
+    }
+    hints.pad();
+    for _ in &group_lengths {
+        hints.put(0, 1);
+    }
+    hints.pad();
+
+    let mut hint_stream = pdf_stream::new(0);
+    hint_stream.add_slice(&hints.data);
+    hint_stream.get_dict_mut().set("S", shared_table as f64);
+    let hint_stream = hint_stream.into_obj();
+    let hint_data = serialize_obj(hint_stream, Some(hint_label));
+    pdf_release_obj(hint_stream);
+
+    let first_offset = hint_offset + hint_data.len();
+    let main_offset = first_offset + first_len;
+    let main_xref_offset = main_offset + main_data.iter().map(|d| d.len()).sum::<usize>();
+    let main_xref_head = format!("xref\n0 {}\n", lin_label);
+    let main_xref_tail = format!(
+        "trailer\n<</Size {}>>\nstartxref\n{}\n%%EOF\n",
+        lin_label, first_xref_offset
+    );
+    let file_length =
+        main_xref_offset + main_xref_head.len() + 20 * lin_label as usize + main_xref_tail.len();
+
+    let mut out = Vec::with_capacity(file_length - lin_offset);
+    let entry = |offset: usize| format!("{:010} {:05} n \n", offset, 0);
+    out.extend_from_slice(
+        lin_dict(
+            file_length,
+            (hint_offset, hint_data.len()),
+            main_offset,
+            main_xref_offset + main_xref_head.len() - 1,
+        )
+        .as_bytes(),
+    );
+    out.extend_from_slice(first_xref_head.as_bytes());
+    out.extend_from_slice(entry(lin_offset).as_bytes());
+    out.extend_from_slice(entry(catalog_offset).as_bytes());
+    out.extend_from_slice(entry(hint_offset).as_bytes());
+    let mut offset = first_offset;
+    for data in &first_data {
+        out.extend_from_slice(entry(offset).as_bytes());
+        offset += data.len();
+    }
+    out.extend_from_slice(&first_trailer(main_xref_offset));
+    out.extend_from_slice(&catalog_data);
+    out.extend_from_slice(&hint_data);
+    for data in first_data.iter().chain(main_data.iter()) {
+        out.extend_from_slice(data);
+    }
+    out.extend_from_slice(main_xref_head.as_bytes());
+    out.extend_from_slice(b"0000000000 65535 f \n");
+    let mut offset = main_offset;
+    for data in &main_data {
+        out.extend_from_slice(entry(offset).as_bytes());
+        offset += data.len();
+    }
+    out.extend_from_slice(main_xref_tail.as_bytes());
+    if lin_offset + out.len() != file_length {
+        /* The offsets above are off; nothing has been written yet, so the
+         * caller can still fall back to a regular file. */
+        lin_renumber = Vec::new();
+        return false;
+    }
+    pdf_out(pdf_output_handle.as_mut().unwrap(), &out);
+
+    pdf_release_obj(trailer_dict);
+    trailer_dict = ptr::null_mut();
+    output_xref = vec![];
+    true
+}
+
+/*
+ * Size optimization of the held back objects: resources that no content
+ * stream uses are removed, objects that can no longer be reached from the
+ * trailer are dropped, and byte-identical objects are merged until nothing
+ * changes any more.  Figures imported from PDF files tend to bring along
+ * their own copies of fonts and resources, which is where this pays off.
+ */
+const OPT_RESOURCE_CATEGORIES: [&str; 7] = [
+    "Font",
+    "XObject",
+    "ExtGState",
+    "ColorSpace",
+    "Pattern",
+    "Shading",
+    "Properties",
+];
+/* Objects whose identity matters and which must never be merged */
+const OPT_UNIQUE_TYPES: [&str; 11] = [
+    "Catalog",
+    "Pages",
+    "Page",
+    "Annot",
+    "StructTreeRoot",
+    "StructElem",
+    "OCG",
+    "Outlines",
+    "Sig",
+    "ObjStm",
+    "XRef",
+];
+const OPT_UNIQUE_KEYS: [&str; 6] = [
+    "Parent",
+    "P",
+    "Rect",
+    "Kids",
+    "StructParent",
+    "StructParents",
+];
+
+unsafe fn opt_resolve(object: *mut pdf_obj, index: &HashMap<u32, *mut pdf_obj>) -> *mut pdf_obj {
+    match ref_label(Some(&*object)) {
+        Some(label) => index.get(&label).cloned().unwrap_or(ptr::null_mut()),
+        None => object,
+    }
+}
+
+/* Stream data with FlateDecode undone; None for anything else we can't read. */
+unsafe fn opt_decoded_content(stream: &pdf_stream) -> Option<Vec<u8>> {
+    let dict = stream.get_dict();
+    let filter = match dict.get("Filter") {
+        None => return Some(stream.content.clone()),
+        Some(filter) if filter.is_array() && filter.as_array().len() == 1 => &*filter.as_array()[0],
+        Some(filter) => filter,
+    };
+    if dict.has("DecodeParms") || !filter.is_name() || filter.as_name().to_bytes() != b"FlateDecode"
+    {
+        return None;
+    }
+    #[cfg(feature = "libz-sys")]
+    {
+        let mut decoded = pdf_stream::new(0);
+        if pdf_add_stream_flate(&mut decoded, &stream.content) == 0 {
+            return Some(std::mem::replace(&mut decoded.content, Vec::new()));
+        }
+    }
+    None
+}
+
+/* Collect every name token; anything that looks like a name counts as used. */
+fn opt_scan_names(content: &[u8], names: &mut HashSet<Vec<u8>>) {
+    let mut i = 0;
+    while i < content.len() {
+        if content[i] != b'/' {
+            i += 1;
+            continue;
+        }
+        i += 1;
+        let mut name = vec![];
+        while i < content.len() && !is_space(&content[i]) && !is_delim(&content[i]) {
+            if content[i] == b'#' && i + 2 < content.len() {
+                let (hi, lo) = (xtoi(content[i + 1]), xtoi(content[i + 2]));
+                if hi >= 0 && lo >= 0 {
+                    name.push((hi << 4 | lo) as u8);
+                    i += 3;
+                    continue;
+                }
+            }
+            name.push(content[i]);
+            i += 1;
+        }
+        names.insert(name);
+    }
+}
+
+fn opt_add_names(
+    map: &mut HashMap<*mut pdf_obj, Option<HashSet<Vec<u8>>>>,
+    order: &mut Vec<*mut pdf_obj>,
+    key: *mut pdf_obj,
+    names: Option<&HashSet<Vec<u8>>>,
+) {
+    match map.get_mut(&key) {
+        None => {
+            order.push(key);
+            map.insert(key, names.cloned());
+        }
+        Some(entry) => match (entry.as_mut(), names) {
+            (Some(entry), Some(names)) => entry.extend(names.iter().cloned()),
+            _ => *entry = None,
+        },
+    }
+}
+
+unsafe fn opt_mergeable(object: *mut pdf_obj) -> bool {
+    let dict = match (*object).data {
+        PdfObjVariant::DICT(v) => &*v,
+        PdfObjVariant::STREAM(v) => (*v).get_dict(),
+        _ => return true,
+    };
+    if OPT_UNIQUE_KEYS.iter().any(|key| dict.has(key)) {
+        return false;
+    }
+    match dict.get("Type").filter(|typ| typ.is_name()) {
+        Some(typ) => {
+            let typ = typ.as_name().to_bytes();
+            !OPT_UNIQUE_TYPES.iter().any(|t| t.as_bytes() == typ)
+        }
+        None => true,
+    }
+}
+
+unsafe fn opt_is_font_program(object: *mut pdf_obj) -> bool {
+    if !(*object).is_stream() {
+        return false;
+    }
+    let dict = (*object).as_stream().get_dict();
+    dict.has("Length1")
+        || dict
+            .get("Subtype")
+            .filter(|typ| typ.is_name())
+            .map(|typ| typ.as_name().to_bytes())
+            .map_or(false, |typ| {
+                typ == b"Type1C" || typ == b"CIDFontType0C" || typ == b"OpenType"
+            })
+}
+
+/* Everything that goes into the written object except its label */
+unsafe fn opt_key(object: *mut pdf_obj) -> Vec<u8> {
+    match (*object).data {
+        PdfObjVariant::STREAM(v) => {
+            let stream = &*v;
+            let parms = &stream.decodeparms;
+            let mut key = serialize_obj(stream.dict, None);
+            key.extend_from_slice(
+                format!(
+                    "\nstream {} {} {} {} {}\n",
+                    stream._flags,
+                    parms.predictor,
+                    parms.colors,
+                    parms.bits_per_component,
+                    parms.columns,
+                )
+                .as_bytes(),
+            );
+            key.extend_from_slice(&stream.content);
+            key
+        }
+        _ => serialize_obj(object, None),
+    }
+}
+
+/* Point references to merged objects at the object that is kept. */
+unsafe fn opt_redirect(object: *mut pdf_obj, alias: &HashMap<u32, *mut pdf_obj>) {
+    match (*object).data {
+        PdfObjVariant::ARRAY(v) => {
+            for &item in (*v).values.iter() {
+                if !item.is_null() {
+                    opt_redirect(item, alias);
+                }
+            }
+        }
+        PdfObjVariant::DICT(v) => {
+            for (_, &item) in (*v).inner.iter() {
+                opt_redirect(item, alias);
+            }
+        }
+        PdfObjVariant::STREAM(v) => opt_redirect((*v).dict, alias),
+        PdfObjVariant::INDIRECT(v) => {
+            if (*v).pf.is_null() {
+                if let Some(&kept) = alias.get(&(*v).id.0) {
+                    (*v).id = (*kept).id;
+                    (*v).obj = kept;
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+unsafe fn free_deferred(objects: Vec<*mut pdf_obj>) {
+    for object in objects {
+        (*object).id = (0, 0);
+        (*object).refcount = 1;
+        pdf_release_obj(object);
+    }
+}
+
+unsafe fn optimize_deferred() {
+    let index: HashMap<u32, *mut pdf_obj> = deferred_objects
+        .iter()
+        .map(|&object| ((*object).label(), object))
+        .collect();
+    let mut total_refs = HashMap::new();
+    let mut refs = vec![];
+    collect_refs(trailer_dict, &mut refs, true);
+    for &object in deferred_objects.iter() {
+        collect_refs(object, &mut refs, true);
+    }
+    for label in refs {
+        *total_refs.entry(label).or_insert(0) += 1;
+    }
+
+    /*
+     * Find out which names the content streams (pages, forms, patterns)
+     * use from their resource dictionaries.  A dictionary that is also
+     * referenced from anywhere else is left alone.
+     */
+    let mut prune = true;
+    let mut res_names = HashMap::new();
+    let mut res_order = vec![];
+    let mut user_refs = HashMap::new();
+    for &object in deferred_objects.iter() {
+        let (dict, content) = match (*object).data {
+            PdfObjVariant::STREAM(v) => ((*v).get_dict(), opt_decoded_content(&*v)),
+            PdfObjVariant::DICT(v) => {
+                let dict = &*v;
+                let typ = dict
+                    .get("Type")
+                    .filter(|typ| typ.is_name())
+                    .map(|typ| typ.as_name().to_bytes());
+                if typ != Some(&b"Page"[..]) {
+                    /* Type 3 fonts without resources use those of the page */
+                    if typ == Some(&b"Font"[..]) && dict.has("CharProcs") && !dict.has("Resources")
+                    {
+                        prune = false;
+                    }
+                    continue;
+                }
+                let mut contents = vec![];
+                match dict.get("Contents") {
+                    Some(c) if c.is_array() => contents.extend(c.as_array().iter().cloned()),
+                    Some(c) => contents.push(c as *const pdf_obj as *mut pdf_obj),
+                    None => {}
+                }
+                let mut data = Some(vec![]);
+                for c in contents {
+                    let c = opt_resolve(c, &index);
+                    let decoded = if !c.is_null() && (*c).is_stream() {
+                        opt_decoded_content((*c).as_stream())
+                    } else {
+                        None
+                    };
+                    match (data.as_mut(), decoded) {
+                        (Some(data), Some(decoded)) => {
+                            data.extend_from_slice(&decoded);
+                            data.push(b'\n');
+                        }
+                        _ => data = None,
+                    }
+                }
+                (dict, data)
+            }
+            _ => continue,
+        };
+        let resources = match dict.get("Resources") {
+            Some(resources) => resources as *const pdf_obj as *mut pdf_obj,
+            None => {
+                /* Forms without resources inherit those of the page */
+                if (*object).is_stream()
+                    && dict
+                        .get("Subtype")
+                        .filter(|typ| typ.is_name())
+                        .map_or(false, |typ| typ.as_name().to_bytes() == b"Form")
+                {
+                    prune = false;
+                }
+                continue;
+            }
+        };
+        if !(*object).is_stream() || dict.has("Subtype") || dict.has("PatternType") {
+            let names = content.map(|content| {
+                let mut names = HashSet::new();
+                opt_scan_names(&content, &mut names);
+                names
+            });
+            if let Some(label) = ref_label(Some(&*resources)) {
+                *user_refs.entry(label).or_insert(0) += 1;
+            }
+            let resources = opt_resolve(resources, &index);
+            if !resources.is_null() && (*resources).is_dict() {
+                opt_add_names(&mut res_names, &mut res_order, resources, names.as_ref());
+            }
+        }
+    }
+    let shared_elsewhere = |object: *mut pdf_obj, refs: &HashMap<u32, i32>| {
+        let label = (*object).label();
+        label != 0 && total_refs.get(&label) != refs.get(&label)
+    };
+    let mut cat_names = HashMap::new();
+    let mut cat_order = vec![];
+    let mut cat_refs = HashMap::new();
+    for &resources in &res_order {
+        if shared_elsewhere(resources, &user_refs) {
+            res_names.insert(resources, None);
+        }
+        let names = res_names[&resources].clone();
+        for category in OPT_RESOURCE_CATEGORIES.iter() {
+            if let Some(value) = (*resources).as_dict().get(category) {
+                if let Some(label) = ref_label(Some(value)) {
+                    *cat_refs.entry(label).or_insert(0) += 1;
+                }
+                let value = opt_resolve(value as *const pdf_obj as *mut pdf_obj, &index);
+                if !value.is_null() && (*value).is_dict() {
+                    opt_add_names(&mut cat_names, &mut cat_order, value, names.as_ref());
+                }
+            }
+        }
+    }
+    let mut removed_resources = 0;
+    if prune {
+        for &category in &cat_order {
+            if shared_elsewhere(category, &cat_refs) {
+                continue;
+            }
+            if let Some(names) = &cat_names[&category] {
+                let unused: Vec<pdf_name> = (*category)
+                    .as_dict()
+                    .inner
+                    .keys()
+                    .filter(|name| !names.contains(name.to_bytes()))
+                    .cloned()
+                    .collect();
+                for name in unused {
+                    pdf_remove_dict(&mut *category, name.to_bytes());
+                    removed_resources += 1;
+                }
+            }
+        }
+    }
+
+    /* Drop whatever is no longer reachable from the trailer. */
+    let mut saved = 0;
+    let mut reachable = HashSet::new();
+    let mut pending = vec![];
+    collect_refs(trailer_dict, &mut pending, true);
+    let fixed: HashSet<u32> = pending.iter().cloned().collect();
+    while let Some(label) = pending.pop() {
+        if reachable.insert(label) {
+            if let Some(&object) = index.get(&label) {
+                collect_refs(object, &mut pending, true);
+            }
+        }
+    }
+    let (kept, dropped): (Vec<_>, Vec<_>) = std::mem::replace(&mut deferred_objects, Vec::new())
+        .into_iter()
+        .partition(|&object| reachable.contains(&(*object).label()));
+    deferred_objects = kept;
+    let n_dropped = dropped.len();
+    for &object in &dropped {
+        saved += opt_key(object).len();
+    }
+    free_deferred(dropped);
+
+    /* Merge identical objects; merging may make their users identical too. */
+    let mut merged = 0;
+    let mut font_programs = 0;
+    loop {
+        let mut seen: HashMap<Vec<u8>, *mut pdf_obj> = HashMap::new();
+        let mut alias = HashMap::new();
+        for &object in deferred_objects.iter() {
+            if fixed.contains(&(*object).label()) || !opt_mergeable(object) {
+                continue;
+            }
+            let key = opt_key(object);
+            if let Some(&kept) = seen.get(&key) {
+                saved += key.len();
+                if opt_is_font_program(object) {
+                    font_programs += 1;
+                }
+                alias.insert((*object).label(), kept);
+            } else {
+                seen.insert(key, object);
+            }
+        }
+        if alias.is_empty() {
+            break;
+        }
+        merged += alias.len();
+        opt_redirect(trailer_dict, &alias);
+        for &object in deferred_objects.iter() {
+            opt_redirect(object, &alias);
+        }
+        let (duplicates, kept): (Vec<_>, Vec<_>) =
+            std::mem::replace(&mut deferred_objects, Vec::new())
+                .into_iter()
+                .partition(|&object| alias.contains_key(&(*object).label()));
+        deferred_objects = kept;
+        free_deferred(duplicates);
+    }
+    info!(
+        "\nSize optimization: removed {} unused resources and {} unreferenced objects, merged {} duplicate objects ({} font programs); saved {} bytes before compression.\n",
+        removed_resources, n_dropped, merged, font_programs, saved,
+    );
+}
+
+unsafe fn release_deferred() {
+    lin_renumber = Vec::new();
+    free_deferred(std::mem::replace(&mut deferred_objects, Vec::new()));
+}
+
 pub(crate) unsafe fn pdf_set_root(mut object: *mut pdf_obj) {
     if (*trailer_dict)
         .as_dict_mut()
@@ -721,7 +1670,11 @@ unsafe fn pdf_out_char(handle: &mut OutputHandleWrapper, c: u8) {
     if !output_stream.is_null() && handle == pdf_output_handle.as_mut().unwrap() {
         (*output_stream).as_stream_mut().add_slice([c].as_ref());
     } else {
-        ttstub_output_putc(handle, c as i32);
+        if held_output.is_some() && handle == pdf_output_handle.as_mut().unwrap() {
+            held_output.as_mut().unwrap().push(c);
+        } else {
+            ttstub_output_putc(handle, c as i32);
+        }
         /* Keep tallys for xref table *only* if writing a pdf file. */
         if pdf_output_handle.is_some() {
             pdf_output_file_position += 1;
@@ -740,7 +1693,11 @@ unsafe fn pdf_out(handle: &mut OutputHandleWrapper, buffer: &[u8]) {
     if !output_stream.is_null() && handle == pdf_output_handle.as_mut().unwrap() {
         (*output_stream).as_stream_mut().add_slice(buffer);
     } else {
-        handle.write(buffer).unwrap();
+        if held_output.is_some() && handle == pdf_output_handle.as_mut().unwrap() {
+            held_output.as_mut().unwrap().extend_from_slice(buffer);
+        } else {
+            handle.write(buffer).unwrap();
+        }
         /* Keep tallys for xref table *only* if writing a pdf file */
         if pdf_output_handle.is_some() {
             pdf_output_file_position += length;
@@ -833,7 +1790,20 @@ pub(crate) unsafe fn pdf_ref_obj(object: *mut pdf_obj) -> *mut pdf_obj {
 }
 unsafe fn write_indirect(indirect: *mut pdf_indirect, handle: &mut OutputHandleWrapper) {
     assert!((*indirect).pf.is_null());
-    let (label, generation) = (*indirect).id;
+    let (mut label, mut generation) = (*indirect).id;
+    if !lin_renumber.is_empty() {
+        match lin_renumber.get(label as usize) {
+            Some(&n) if n != 0 => {
+                label = n;
+                generation = 0;
+            }
+            _ => {
+                /* Reference to an object that never got written */
+                pdf_out(handle, b"null");
+                return;
+            }
+        }
+    }
     pdf_out(handle, format!("{} {} R", label, generation).as_bytes());
 }
 /* The undefined object is used as a placeholder in pdfnames.c
@@ -1699,7 +2669,11 @@ unsafe fn filter_create_predictor_dict(
     parms.set("Predictor", predictor as f64);
     parms
 }
-unsafe fn write_stream(stream: &mut pdf_stream, handle: &mut OutputHandleWrapper) {
+/*
+ * Applies the predictor and compression filters requested for the stream,
+ * recording them in its dictionary, and returns the filtered data.
+ */
+unsafe fn filter_stream(stream: &mut pdf_stream) -> (*mut u8, u32) {
     /*
      * Always work from a copy of the stream. All filters read from
      * "filtered" and leave their result in "filtered".
@@ -1845,6 +2819,24 @@ unsafe fn write_stream(stream: &mut pdf_stream, handle: &mut OutputHandleWrapper
         }
     }
     /* HAVE_ZLIB */
+    (filtered, filtered_length)
+}
+
+/// Filters the stream data right away rather than when the stream is
+/// written, so that it can be kept in its final form.
+pub(crate) unsafe fn pdf_stream_apply_filters(stream: &mut pdf_stream) {
+    let (filtered, filtered_length) = filter_stream(stream);
+    stream.content = if filtered_length > 0 {
+        std::slice::from_raw_parts(filtered, filtered_length as usize).to_vec()
+    } else {
+        Vec::new()
+    };
+    free(filtered as *mut libc::c_void);
+    stream._flags &= !(STREAM_COMPRESS | STREAM_USE_PREDICTOR);
+}
+
+unsafe fn write_stream(stream: &mut pdf_stream, handle: &mut OutputHandleWrapper) {
+    let (mut filtered, mut filtered_length) = filter_stream(stream);
     /* AES will change the size of data! */
     if enc_mode {
         let mut cipher: *mut u8 = ptr::null_mut();
@@ -2554,6 +3546,33 @@ unsafe fn release_objstm(objstm: *mut pdf_obj) {
     pdf_release_obj(objstm);
 }
 
+/* Write a labeled object, either directly or into an object stream */
+unsafe fn write_released_obj(object: *mut pdf_obj) {
+    if do_objstm == 0
+        || (*object).flags & OBJ_NO_OBJSTM != 0
+        || doc_enc_mode as i32 != 0 && (*object).flags & OBJ_NO_ENCRYPT != 0
+        || (*object).generation() as i32 != 0
+    {
+        let handle = pdf_output_handle.as_mut().unwrap();
+        pdf_flush_obj(object, handle);
+    } else {
+        if current_objstm.is_null() {
+            let data: *mut i32 = new(((2i32 * 200i32 + 2i32) as u32 as u64)
+                .wrapping_mul(::std::mem::size_of::<i32>() as u64)
+                as u32) as *mut i32;
+            *data.offset(1) = 0;
+            *data.offset(0) = 0;
+            current_objstm = pdf_stream::new(STREAM_COMPRESS).into_obj();
+            set_objstm_data(&mut *current_objstm, data);
+            pdf_label_obj(current_objstm);
+        }
+        if pdf_add_objstm(&mut *current_objstm, &mut *object) == 200i32 {
+            release_objstm(current_objstm);
+            current_objstm = ptr::null_mut()
+        }
+    }
+}
+
 pub unsafe fn pdf_release_obj(mut object: *mut pdf_obj) {
     if object.is_null() {
         return;
@@ -2575,29 +3594,16 @@ pub unsafe fn pdf_release_obj(mut object: *mut pdf_obj) {
          * Nonzero "label" means object needs to be written before it's destroyed.
          */
         if (*object).label() != 0 && pdf_output_handle.is_some() {
-            if do_objstm == 0
-                || (*object).flags & OBJ_NO_OBJSTM != 0
-                || doc_enc_mode as i32 != 0 && (*object).flags & OBJ_NO_ENCRYPT != 0
-                || (*object).generation() as i32 != 0
-            {
-                let handle = pdf_output_handle.as_mut().unwrap();
-                pdf_flush_obj(object, handle);
-            } else {
-                if current_objstm.is_null() {
-                    let data: *mut i32 = new(((2i32 * 200i32 + 2i32) as u32 as u64)
-                        .wrapping_mul(::std::mem::size_of::<i32>() as u64)
-                        as u32) as *mut i32;
-                    *data.offset(1) = 0;
-                    *data.offset(0) = 0;
-                    current_objstm = pdf_stream::new(STREAM_COMPRESS).into_obj();
-                    set_objstm_data(&mut *current_objstm, data);
-                    pdf_label_obj(current_objstm);
-                }
-                if pdf_add_objstm(&mut *current_objstm, &mut *object) == 200i32 {
-                    release_objstm(current_objstm);
-                    current_objstm = ptr::null_mut()
-                }
+            if let Some(captured) = captured_objects.as_mut() {
+                captured.push(object);
+                return;
+            }
+            if linearize || optimize {
+                /* Written (and freed) by pdf_out_flush() */
+                deferred_objects.push(object);
+                return;
             }
+            write_released_obj(object);
         }
         match (*object).data {
             PdfObjVariant::BOOLEAN(..) => {}
@@ -3955,3 +4961,118 @@ mod png_crate_filter {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bridge::TTInputFormat;
+    use crate::test_util::with_memory_io;
+
+    fn scan(content: &[u8]) -> Vec<Vec<u8>> {
+        let mut names = HashSet::new();
+        opt_scan_names(content, &mut names);
+        let mut names: Vec<_> = names.into_iter().collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn scan_names() {
+        assert_eq!(
+            scan(b"BT /F1 10 Tf/F2 12 Tf ET /Im#31 Do"),
+            vec![b"F1".to_vec(), b"F2".to_vec(), b"Im1".to_vec()]
+        );
+        /* A # that does not start an escape is part of the name */
+        assert_eq!(scan(b"/A#4 /B#zz"), vec![b"A#4".to_vec(), b"B#zz".to_vec()]);
+        /* Names in strings count too: keeping a resource is always safe */
+        assert_eq!(
+            scan(b"/Span<</ActualText(/GS1)>>BDC"),
+            vec![b"ActualText".to_vec(), b"GS1".to_vec(), b"Span".to_vec()]
+        );
+    }
+
+    unsafe fn type1_font(name: &str) -> *mut pdf_obj {
+        let mut font = pdf_dict::new();
+        font.set("Type", "Font");
+        font.set("Subtype", "Type1");
+        font.set("BaseFont", name);
+        font.into_obj()
+    }
+
+    unsafe fn deref_key(dict: *mut pdf_obj, key: &str) -> *mut pdf_obj {
+        let value = pdf_deref_obj((*dict).as_dict_mut().get_mut(key));
+        assert!(!value.is_null(), "no /{} in the PDF output", key);
+        value
+    }
+
+    /*
+     * One page that uses two identical fonts and has a third one that it
+     * does not use, written with the size optimization and read back.
+     */
+    #[test]
+    fn merge_round_trip() {
+        let (_, files) = with_memory_io(&[], || unsafe {
+            pdf_obj_reset_global_state();
+            pdf_out_init("merged.pdf", false, false, false, true);
+            let fonts = [
+                type1_font("Times-Roman"),
+                type1_font("Times-Roman"),
+                type1_font("Helvetica"),
+            ];
+            let mut font_dict = pdf_dict::new();
+            font_dict.set("F1", pdf_ref_obj(fonts[0]));
+            font_dict.set("F2", pdf_ref_obj(fonts[1]));
+            font_dict.set("F3", pdf_ref_obj(fonts[2]));
+            let mut resources = pdf_dict::new();
+            resources.set("Font", font_dict);
+            let mut content = pdf_stream::new(0);
+            content.add_slice(b"BT /F1 10 Tf (a) Tj /F2 10 Tf (b) Tj ET");
+            let content = content.into_obj();
+
+            let catalog = pdf_dict::new().into_obj();
+            let pages = pdf_dict::new().into_obj();
+            let page = pdf_dict::new().into_obj();
+            (*page).as_dict_mut().set("Type", "Page");
+            (*page).as_dict_mut().set("Parent", pdf_ref_obj(pages));
+            (*page).as_dict_mut().set("Resources", resources);
+            (*page).as_dict_mut().set("Contents", pdf_ref_obj(content));
+            (*pages).as_dict_mut().set("Type", "Pages");
+            (*pages).as_dict_mut().set("Kids", vec![pdf_ref_obj(page)]);
+            (*pages).as_dict_mut().set("Count", 1.);
+            (*catalog).as_dict_mut().set("Type", "Catalog");
+            (*catalog).as_dict_mut().set("Pages", pdf_ref_obj(pages));
+            pdf_set_root(catalog);
+
+            for &object in fonts.iter().chain(&[content, page, pages, catalog]) {
+                pdf_release_obj(object);
+            }
+            pdf_out_flush();
+        })
+        .unwrap();
+
+        let data = &files["merged.pdf"];
+        with_memory_io(&[("merged.pdf", data)], || unsafe {
+            let handle = InFile::open("merged.pdf", TTInputFormat::PICT, 0).unwrap();
+            let pf = pdf_open("merged.pdf", handle).unwrap();
+            let pages = deref_key(pdf_file_get_catalog(pf), "Pages");
+            let kids = deref_key(pages, "Kids");
+            let page = pdf_deref_obj(Some(&mut *(*kids).as_array_mut()[0]));
+            let resources = deref_key(page, "Resources");
+            let fonts = deref_key(resources, "Font");
+
+            /* The unused font is gone and the duplicate is merged */
+            assert!(!(*fonts).as_dict().has("F3"));
+            let id = |key| (*fonts).as_dict().get(key).unwrap().as_indirect().id;
+            assert_eq!(id("F1"), id("F2"));
+            let font = deref_key(fonts, "F1");
+            let base_font = (*font).as_dict().get("BaseFont").unwrap();
+            assert_eq!(base_font.as_name().to_bytes(), b"Times-Roman");
+
+            for object in [pages, kids, page, resources, fonts, font].iter() {
+                pdf_release_obj(*object);
+            }
+            pdf_files_close();
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,361 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+#![allow(non_upper_case_globals)]
+
+//! Digital signatures of the generated PDF.
+//!
+//! The signature dictionary is written with placeholders for its ByteRange
+//! and Contents entries while the document is produced. Once the whole file
+//! has been assembled in memory, the placeholders are filled in with the
+//! byte ranges and a detached PKCS#7 signature over them, and only then is
+//! the file written out.
+
+#[cfg(feature = "signing")]
+use openssl::{
+    pkcs7::{Pkcs7, Pkcs7Flags},
+    pkey::{PKey, Private},
+    stack::Stack,
+    x509::X509,
+};
+
+use crate::dpx_pdfobj::{
+    pdf_dict, pdf_obj, pdf_output_obj_offset, pdf_ref_obj, pdf_release_obj, pdf_set_held_output,
+    pdf_string, IntoObj, PushObj, OBJ_NO_ENCRYPT, OBJ_NO_OBJSTM,
+};
+use crate::warn;
+
+/// How to sign the PDF output.
+#[derive(Clone, Debug, Default)]
+pub struct PdfSignatureConfig {
+    /// The PEM-encoded signing certificate, optionally followed by the
+    /// certificates of its chain.
+    pub certificate_pem: Vec<u8>,
+    /// The PEM-encoded private key belonging to the certificate.
+    pub private_key_pem: Vec<u8>,
+    /// Optional entries of the signature dictionary.
+    pub reason: Option<String>,
+    pub location: Option<String>,
+    pub contact_info: Option<String>,
+}
+
+/* Room reserved for the DER-encoded signature; its hex form is twice as long. */
+const SIGNATURE_SIZE: usize = 8192;
+
+#[cfg(feature = "signing")]
+struct Signer {
+    certificate: X509,
+    chain: Stack<X509>,
+    key: PKey<Private>,
+}
+
+static mut config: Option<PdfSignatureConfig> = None;
+#[cfg(feature = "signing")]
+static mut signer: Option<Signer> = None;
+/* Label of the signature dictionary, once it has been written */
+static mut sig_label: u32 = 0;
+
+/// Sets up signing; the certificate and key are checked right away so that
+/// problems show up before any work is done.
+pub(crate) unsafe fn pdf_sign_init(sign: Option<&PdfSignatureConfig>) {
+    config = sign.cloned();
+    sig_label = 0;
+    if let Some(sign) = sign {
+        load_signer(sign);
+    }
+    pdf_set_held_output(config.is_some());
+}
+
+#[cfg(feature = "signing")]
+unsafe fn load_signer(sign: &PdfSignatureConfig) {
+    let mut certs = match X509::stack_from_pem(&sign.certificate_pem) {
+        Ok(certs) if !certs.is_empty() => certs.into_iter(),
+        Ok(_) => panic!("No certificate found for signing the PDF output."),
+        Err(e) => panic!("Unable to read the signing certificate: {}", e),
+    };
+    let certificate = certs.next().unwrap();
+    let mut chain = Stack::new().unwrap();
+    for cert in certs {
+        chain.push(cert).unwrap();
+    }
+    let key = match PKey::private_key_from_pem(&sign.private_key_pem) {
+        Ok(key) => key,
+        Err(e) => panic!("Unable to read the signing key: {}", e),
+    };
+    signer = Some(Signer {
+        certificate,
+        chain,
+        key,
+    });
+}
+
+#[cfg(not(feature = "signing"))]
+unsafe fn load_signer(_sign: &PdfSignatureConfig) {
+    panic!("PDF signing support was not compiled in.");
+}
+
+pub(crate) unsafe fn pdf_sign_enabled() -> bool {
+    config.is_some()
+}
+
+/// Creates the signature dictionary that becomes the value of a signature
+/// field and returns a reference to it, or None if no signing is configured
+/// or the document already has its signature.
+pub(crate) unsafe fn pdf_sign_new_value(date: &str) -> Option<*mut pdf_obj> {
+    let sign = config.as_ref()?;
+    if sig_label != 0 {
+        return None;
+    }
+    let mut dict = pdf_dict::new();
+    /* These two come first so that they are easy to find in the output; the
+     * byte range is wide enough for the real one to be written over it. */
+    let mut byte_range = vec![];
+    byte_range.push_obj(0f64);
+    for _ in 0..3 {
+        byte_range.push_obj(9_999_999_999f64);
+    }
+    dict.set("ByteRange", byte_range);
+    dict.set("Contents", pdf_string::new(vec![0u8; SIGNATURE_SIZE]));
+    dict.set("Type", "Sig");
+    dict.set("Filter", "Adobe.PPKLite");
+    dict.set("SubFilter", "adbe.pkcs7.detached");
+    dict.set("M", pdf_string::new(date));
+    let entries = [
+        ("Reason", &sign.reason),
+        ("Location", &sign.location),
+        ("ContactInfo", &sign.contact_info),
+    ];
+    for (key, value) in entries.iter() {
+        if let Some(value) = value {
+            dict.set(key, pdf_string::new(value.as_bytes()));
+        }
+    }
+    let value = dict.into_obj();
+    /* The placeholders must be found and patched in the file as written. */
+    (*value).flags |= OBJ_NO_OBJSTM | OBJ_NO_ENCRYPT;
+    let reference = pdf_ref_obj(value);
+    sig_label = (*value).label();
+    pdf_release_obj(value);
+    Some(reference)
+}
+
+/* Find `key` and the following `open` .. `close` span after `from`. */
+fn find_span(data: &[u8], from: usize, key: &[u8], open: u8, close: u8) -> (usize, usize) {
+    let start = data[from..]
+        .windows(key.len())
+        .position(|w| w == key)
+        .map(|i| from + i + key.len())
+        .expect("signature dictionary not found in output");
+    let open = start + data[start..].iter().position(|&c| c == open).unwrap();
+    let close = open + data[open..].iter().position(|&c| c == close).unwrap();
+    (open, close + 1)
+}
+
+/// Fills in the signature of the finished file held in `data`.
+pub(crate) unsafe fn pdf_sign_output(data: &mut [u8]) {
+    if config.is_none() {
+        return;
+    }
+    if sig_label == 0 {
+        warn!("No signature field in PDF output; the file was not signed.");
+        return;
+    }
+    let offset = pdf_output_obj_offset(sig_label).expect("signature dictionary was not written");
+    let (range_start, range_end) = find_span(data, offset, b"/ByteRange", b'[', b']');
+    let (contents_start, contents_end) = find_span(data, offset, b"/Contents", b'<', b'>');
+
+    let byte_range = format!(
+        "[0 {} {} {}]",
+        contents_start,
+        contents_end,
+        data.len() - contents_end
+    );
+    if byte_range.len() > range_end - range_start {
+        panic!("No room for the ByteRange of the PDF signature.");
+    }
+    data[range_start..range_end].copy_from_slice(
+        format!("{:width$}", byte_range, width = range_end - range_start).as_bytes(),
+    );
+
+    let mut signed = data[..contents_start].to_vec();
+    signed.extend_from_slice(&data[contents_end..]);
+    let signature = sign_data(&signed);
+    if 2 * signature.len() > contents_end - contents_start - 2 {
+        panic!(
+            "PDF signature is {} bytes, but only {} bytes were reserved.",
+            signature.len(),
+            SIGNATURE_SIZE
+        );
+    }
+    for (i, b) in signature.iter().enumerate() {
+        let hex = format!("{:02X}", b);
+        data[contents_start + 1 + 2 * i..contents_start + 3 + 2 * i]
+            .copy_from_slice(hex.as_bytes());
+    }
+}
+
+#[cfg(feature = "signing")]
+unsafe fn sign_data(signed: &[u8]) -> Vec<u8> {
+    let s = signer.as_ref().unwrap();
+    Pkcs7::sign(
+        &s.certificate,
+        &s.key,
+        &s.chain,
+        signed,
+        Pkcs7Flags::DETACHED | Pkcs7Flags::BINARY,
+    )
+    .and_then(|p7| p7.to_der())
+    .unwrap_or_else(|e| panic!("Unable to sign PDF output: {}", e))
+}
+
+#[cfg(not(feature = "signing"))]
+unsafe fn sign_data(_signed: &[u8]) -> Vec<u8> {
+    unreachable!()
+}
+
+#[cfg(all(test, feature = "signing"))]
+mod test {
+    use super::*;
+    use crate::dpx_pdfobj::{
+        pdf_obj_reset_global_state, pdf_out_flush, pdf_out_init, pdf_set_root,
+    };
+    use crate::test_util::with_memory_io;
+    use openssl::{
+        asn1::Asn1Time, bn::BigNum, hash::MessageDigest, rsa::Rsa, x509::store::X509StoreBuilder,
+        x509::X509Name,
+    };
+
+    /* A key and a self-signed certificate that exist only for this test */
+    fn throwaway_config() -> PdfSignatureConfig {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_text("CN", "Tectonic test").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        let serial = BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap();
+        cert.set_serial_number(&serial).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        PdfSignatureConfig {
+            certificate_pem: cert.build().to_pem().unwrap(),
+            private_key_pem: key.private_key_to_pem_pkcs8().unwrap(),
+            reason: Some("Testing".to_owned()),
+            ..Default::default()
+        }
+    }
+
+    /* An empty document whose only form field is the signature */
+    unsafe fn write_signed_document(sign: &PdfSignatureConfig) {
+        pdf_obj_reset_global_state();
+        pdf_sign_init(Some(sign));
+        pdf_out_init("signed.pdf", false, false, false, false);
+
+        let mut field = pdf_dict::new();
+        field.set("FT", "Sig");
+        field.set("T", pdf_string::new("Signature1"));
+        field.set("V", pdf_sign_new_value("D:20160224084812Z").unwrap());
+        let field = field.into_obj();
+        let mut fields = vec![];
+        fields.push_obj(pdf_ref_obj(field));
+        let mut acroform = pdf_dict::new();
+        acroform.set("Fields", fields);
+        acroform.set("SigFlags", 3f64);
+
+        let pages = pdf_dict::new().into_obj();
+        (*pages).as_dict_mut().set("Type", "Pages");
+        (*pages)
+            .as_dict_mut()
+            .set("Kids", Vec::<*mut pdf_obj>::new());
+        (*pages).as_dict_mut().set("Count", 0f64);
+        let catalog = pdf_dict::new().into_obj();
+        (*catalog).as_dict_mut().set("Type", "Catalog");
+        (*catalog).as_dict_mut().set("Pages", pdf_ref_obj(pages));
+        (*catalog).as_dict_mut().set("AcroForm", acroform);
+        pdf_set_root(catalog);
+
+        for &object in [field, pages, catalog].iter() {
+            pdf_release_obj(object);
+        }
+        pdf_out_flush();
+        pdf_sign_init(None);
+    }
+
+    fn hex_value(c: u8) -> u8 {
+        (c as char).to_digit(16).expect("not a hex digit") as u8
+    }
+
+    #[test]
+    fn byte_range_and_contents() {
+        let sign = throwaway_config();
+        let (_, files) = with_memory_io(&[], || unsafe { write_signed_document(&sign) }).unwrap();
+        let pdf = &files["signed.pdf"];
+
+        let (start, end) = find_span(pdf, 0, b"/ByteRange", b'[', b']');
+        let range: Vec<usize> = std::str::from_utf8(&pdf[start + 1..end - 1])
+            .unwrap()
+            .split_whitespace()
+            .map(|n| n.parse().unwrap())
+            .collect();
+        assert_eq!(range.len(), 4);
+
+        /* The ranges cover the whole file except the Contents string. */
+        let (contents_start, contents_end) = find_span(pdf, 0, b"/Contents", b'<', b'>');
+        assert_eq!(range[0], 0);
+        assert_eq!(range[1], contents_start);
+        assert_eq!(range[2], contents_end);
+        assert_eq!(range[2] + range[3], pdf.len());
+        assert_eq!(contents_end - contents_start, 2 * SIGNATURE_SIZE + 2);
+
+        /* The signature is over exactly those ranges. */
+        let der: Vec<u8> = pdf[contents_start + 1..contents_end - 1]
+            .chunks(2)
+            .map(|pair| 16 * hex_value(pair[0]) + hex_value(pair[1]))
+            .collect();
+        let p7 = Pkcs7::from_der(&der).unwrap();
+        let mut signed = pdf[..range[1]].to_vec();
+        signed.extend_from_slice(&pdf[range[2]..range[2] + range[3]]);
+        let certs = Stack::new().unwrap();
+        let store = X509StoreBuilder::new().unwrap().build();
+        p7.verify(
+            &certs,
+            &store,
+            Some(&signed),
+            None,
+            Pkcs7Flags::NOVERIFY | Pkcs7Flags::BINARY,
+        )
+        .unwrap();
+
+        signed[range[1] / 2] ^= 1;
+        assert!(p7
+            .verify(
+                &certs,
+                &store,
+                Some(&signed),
+                None,
+                Pkcs7Flags::NOVERIFY | Pkcs7Flags::BINARY
+            )
+            .is_err());
+    }
+}
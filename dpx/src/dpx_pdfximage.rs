@@ -31,16 +31,26 @@ use euclid::point2;
 use crate::mfree;
 use crate::strstartswith;
 use crate::{info, warn};
+use std::ffi::CString;
 use std::ptr;
 
 use super::dpx_bmpimage::{bmp_include_image, check_for_bmp};
 use super::dpx_dpxfile::{dpx_delete_temp_file, keep_cache};
+use super::dpx_gifimage::{check_for_gif, gif_include_image};
+use super::dpx_imagecache::image_cache_include;
 use super::dpx_jpegimage::{check_for_jpeg, jpeg_include_image};
 use super::dpx_mfileio::{tt_mfgets, work_buffer};
+use super::dpx_pdfcolor::{iccp_check_colorspace, iccp_load_profile, pdf_get_colorspace_reference};
 use super::dpx_pdfdraw::pdf_dev_transform;
 use super::dpx_pngimage::{check_for_png, png_include_image};
+use super::dpx_svgimage::{check_for_svg, svg_include_image, svg_load_options};
+use super::dpx_tiffimage::{check_for_tiff, tiff_include_image};
+use super::dpx_webpimage::{check_for_webp, webp_include_image};
 use crate::dpx_epdf::pdf_include_page;
-use crate::dpx_pdfobj::{check_for_pdf, pdf_link_obj, pdf_obj, pdf_ref_obj, pdf_release_obj};
+use crate::dpx_pdfobj::{
+    check_for_pdf, pdf_get_version, pdf_link_obj, pdf_obj, pdf_ref_obj, pdf_release_obj,
+    pdf_stream, pdf_stream_set_predictor, IntoObj, STREAM_COMPRESS,
+};
 use crate::shims::sprintf;
 
 use std::io::{Read, Seek, SeekFrom};
@@ -64,6 +74,10 @@ pub(crate) enum ImageType {
     Png,
     Eps,
     Bmp,
+    Webp,
+    Tiff,
+    Gif,
+    Svg,
     #[allow(unused)]
     Jp2,
 }
@@ -138,6 +152,8 @@ pub(crate) struct opt_ {
     pub(crate) cmdtmpl: *mut i8,
 }
 static mut ximages: Vec<pdf_ximage> = Vec::new();
+/* The options for SVG images, with their fonts, once one is included. */
+static mut svg_options: Option<usvg::Options> = None;
 /* tectonic/core-strutils.h: miscellaneous C string utilities
    Copyright 2016-2018 the Tectonic Project
    Licensed under the MIT License.
@@ -189,6 +205,7 @@ impl Drop for pdf_ximage {
 
 pub(crate) unsafe fn pdf_init_images() {
     ximages = Vec::new();
+    svg_options = None;
 }
 
 pub(crate) unsafe fn pdf_close_images() {
@@ -211,6 +228,7 @@ pub(crate) unsafe fn pdf_close_images() {
         }
     }
     ximages = Vec::new();
+    svg_options = None;
     _opts.cmdtmpl = mfree(_opts.cmdtmpl as *mut libc::c_void) as *mut i8;
 }
 unsafe fn source_image_type<R: Read + Seek>(handle: &mut R) -> ImageType {
@@ -222,6 +240,14 @@ unsafe fn source_image_type<R: Read + Seek>(handle: &mut R) -> ImageType {
         ImageType::Png
     } else if check_for_bmp(handle) {
         ImageType::Bmp
+    } else if check_for_webp(handle) {
+        ImageType::Webp
+    } else if check_for_tiff(handle) {
+        ImageType::Tiff
+    } else if check_for_gif(handle) {
+        ImageType::Gif
+    } else if check_for_svg(handle) {
+        ImageType::Svg
     } else if check_for_pdf(handle) {
         ImageType::Pdf
     } else if check_for_ps(handle) != 0 {
@@ -278,7 +304,14 @@ unsafe fn load_image(
             if _opts.verbose != 0 {
                 info!("[PNG]");
             }
-            if png_include_image(&mut I, &mut handle) < 0 {
+            let include = |I: &mut pdf_ximage, handle: &mut InFile| {
+                if png_include_image(I, handle) < 0 {
+                    Err(())
+                } else {
+                    Ok(())
+                }
+            };
+            if image_cache_include(&mut I, &mut handle, include).is_err() {
                 return -1;
             }
             I.subtype = PdfXObjectType::Image;
@@ -287,11 +320,52 @@ unsafe fn load_image(
             if _opts.verbose != 0 {
                 info!("[BMP]");
             }
-            if bmp_include_image(&mut I, &mut handle).is_err() {
+            let include = |I: &mut pdf_ximage, handle: &mut InFile| bmp_include_image(I, handle);
+            if image_cache_include(&mut I, &mut handle, include).is_err() {
+                return -1;
+            }
+            I.subtype = PdfXObjectType::Image;
+        }
+        ImageType::Webp => {
+            if _opts.verbose != 0 {
+                info!("[WEBP]");
+            }
+            let include = |I: &mut pdf_ximage, handle: &mut InFile| webp_include_image(I, handle);
+            if image_cache_include(&mut I, &mut handle, include).is_err() {
+                return -1;
+            }
+            I.subtype = PdfXObjectType::Image;
+        }
+        ImageType::Tiff => {
+            if _opts.verbose != 0 {
+                info!("[TIFF]");
+            }
+            let include = |I: &mut pdf_ximage, handle: &mut InFile| tiff_include_image(I, handle);
+            if image_cache_include(&mut I, &mut handle, include).is_err() {
+                return -1;
+            }
+            I.subtype = PdfXObjectType::Image;
+        }
+        ImageType::Gif => {
+            if _opts.verbose != 0 {
+                info!("[GIF]");
+            }
+            let include = |I: &mut pdf_ximage, handle: &mut InFile| gif_include_image(I, handle);
+            if image_cache_include(&mut I, &mut handle, include).is_err() {
                 return -1;
             }
             I.subtype = PdfXObjectType::Image;
         }
+        ImageType::Svg => {
+            if _opts.verbose != 0 {
+                info!("[SVG]");
+            }
+            let opt = svg_options.get_or_insert_with(|| svg_load_options());
+            if svg_include_image(&mut I, &mut handle, opt).is_err() {
+                return -1;
+            }
+            I.subtype = PdfXObjectType::Form;
+        }
         ImageType::Pdf => {
             if _opts.verbose != 0 {
                 info!("[PDF]");
@@ -486,6 +560,73 @@ pub(crate) unsafe fn pdf_ximage_set_image(
     I.resource = ptr::null_mut();
 }
 
+/// Splits samples with a trailing alpha channel into the color samples and
+/// the alpha samples.
+pub(crate) fn split_alpha(samples: &[u8], num_components: usize) -> (Vec<u8>, Vec<u8>) {
+    let n = num_components + 1;
+    let mut color = Vec::with_capacity(samples.len() / n * num_components);
+    let mut alpha = Vec::with_capacity(samples.len() / n);
+    for pixel in samples.chunks_exact(n) {
+        color.extend_from_slice(&pixel[..num_components]);
+        alpha.push(pixel[num_components]);
+    }
+    (color, alpha)
+}
+
+/// Creates the soft mask of an image from its 8-bit alpha samples. There is
+/// none for fully opaque images, nor when the PDF version in use lacks soft
+/// masks, in which case the transparency is dropped.
+pub(crate) unsafe fn pdf_ximage_soft_mask(
+    kind: &str,
+    width: i32,
+    height: i32,
+    alpha: &[u8],
+) -> Option<*mut pdf_obj> {
+    if alpha.iter().all(|&a| a == 255) {
+        return None;
+    }
+    if pdf_get_version() < 4 {
+        warn!(
+            "{}: Transparency will be ignored. (no support in PDF ver. < 1.4)",
+            kind
+        );
+        return None;
+    }
+    let mut smask = pdf_stream::new(STREAM_COMPRESS);
+    let dict = smask.get_dict_mut();
+    dict.set("Type", "XObject");
+    dict.set("Subtype", "Image");
+    dict.set("Width", width as f64);
+    dict.set("Height", height as f64);
+    dict.set("ColorSpace", "DeviceGray");
+    dict.set("BitsPerComponent", 8f64);
+    smask.add_slice(alpha);
+    let smask = smask.into_obj();
+    if width > 64 {
+        pdf_stream_set_predictor(smask, 2, width, 8, 1);
+    }
+    Some(smask)
+}
+
+/// Loads an ICC profile embedded in an image as its color space, if it fits
+/// the image's colors (-1 for gray, -3 for RGB, -4 for CMYK).
+pub(crate) unsafe fn pdf_ximage_icc_colorspace(
+    kind: &str,
+    colortype: i32,
+    profile: &[u8],
+) -> Option<*mut pdf_obj> {
+    if iccp_check_colorspace(colortype, profile) < 0 {
+        return None;
+    }
+    let ident = CString::new(kind).unwrap();
+    let cspc_id = iccp_load_profile(ident.as_ptr(), profile);
+    if cspc_id < 0 {
+        None
+    } else {
+        Some(pdf_get_colorspace_reference(cspc_id))
+    }
+}
+
 pub(crate) unsafe fn pdf_ximage_set_form(
     I: &mut pdf_ximage,
     form_info: &mut xform_info,
@@ -0,0 +1,1061 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! SVG images, translated into Form XObjects. usvg resolves styling, `use`
+//! references and shapes into a tree of paths, images and groups, and turns
+//! text into outlines. Text is set in the Latin Modern fonts of the bundle,
+//! which stand in for the generic and any other font families, so that the
+//! output does not depend on the fonts installed on the host. Paths,
+//! gradients, clipping paths, masks, group opacity and embedded raster and
+//! SVG images are drawn; filters and pattern paints are not.
+
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use usvg::fontdb::Database;
+use usvg::{
+    FillRule, ImageKind, LineCap, LineJoin, NodeKind, Paint, PathData, PathSegment, SpreadMethod,
+    Transform, Tree, Units, Visibility,
+};
+
+use super::dpx_jpegimage::jpeg_include_image;
+use super::dpx_pdfdev::{pdf_sprint_number, Point, Rect};
+use super::dpx_pdfximage::{
+    pdf_ximage_init_form_info, pdf_ximage_set_form, pdf_ximage_set_image, pdf_ximage_soft_mask,
+    split_alpha,
+};
+use crate::bridge::{InFile, TTInputFormat};
+use crate::dpx_pdfobj::{
+    pdf_dict, pdf_get_version, pdf_link_obj, pdf_obj, pdf_ref_obj, pdf_release_obj, pdf_stream,
+    pdf_stream_set_predictor, IntoObj, PushObj, STREAM_COMPRESS,
+};
+use crate::dpx_pdfximage::{pdf_ximage, xform_info, ximage_info};
+use crate::warn;
+
+/* SVG user units are CSS pixels, 96 to the inch. */
+const PX_TO_BP: f64 = 72. / 96.;
+
+/* The font files SVG text is set in, for serif, sans-serif and monospace. */
+const SVG_SERIF_FONTS: &[&str] = &[
+    "lmroman10-regular.otf",
+    "lmroman10-bold.otf",
+    "lmroman10-italic.otf",
+    "lmroman10-bolditalic.otf",
+];
+const SVG_SANS_FONTS: &[&str] = &[
+    "lmsans10-regular.otf",
+    "lmsans10-bold.otf",
+    "lmsans10-oblique.otf",
+    "lmsans10-boldoblique.otf",
+];
+const SVG_MONO_FONTS: &[&str] = &["lmmono10-regular.otf", "lmmono10-italic.otf"];
+
+/* Loads fonts from the bundle, returning the family of the first one found. */
+unsafe fn load_svg_fonts(fontdb: &mut Database, files: &[&str]) -> Option<String> {
+    let mut family = None;
+    for &file in files {
+        let mut data = vec![];
+        match InFile::open(file, TTInputFormat::OPENTYPE, 0) {
+            Some(mut handle) => {
+                if handle.read_to_end(&mut data).is_err() {
+                    continue;
+                }
+            }
+            None => continue,
+        }
+        let n = fontdb.len();
+        fontdb.load_font_data(data);
+        if family.is_none() && fontdb.len() > n {
+            family = Some(fontdb.faces()[n].family.clone());
+        }
+    }
+    family
+}
+
+/* Options with the fonts for text loaded. Loading the fonts is costly, so
+ * the caller keeps the options for the rest of the run.
+ */
+pub(crate) unsafe fn svg_load_options() -> usvg::Options {
+    let mut opt = usvg::Options::default();
+    let serif = load_svg_fonts(&mut opt.fontdb, SVG_SERIF_FONTS);
+    let sans = load_svg_fonts(&mut opt.fontdb, SVG_SANS_FONTS);
+    let mono = load_svg_fonts(&mut opt.fontdb, SVG_MONO_FONTS);
+    /* The default family is the last resort for text, whatever the
+     * image asks for.
+     */
+    match serif
+        .clone()
+        .or_else(|| sans.clone())
+        .or_else(|| mono.clone())
+    {
+        Some(family) => {
+            opt.fontdb.set_serif_family(family.as_str());
+            opt.fontdb.set_cursive_family(family.as_str());
+            opt.fontdb.set_fantasy_family(family.as_str());
+            opt.fontdb
+                .set_sans_serif_family(sans.unwrap_or_else(|| family.clone()));
+            opt.fontdb
+                .set_monospace_family(mono.unwrap_or_else(|| family.clone()));
+            opt.font_family = family;
+        }
+        None => warn!("SVG: No fonts for text found; text will be left out."),
+    }
+    opt
+}
+
+pub fn check_for_svg<R: Read + Seek>(handle: &mut R) -> bool {
+    let mut head = vec![];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    if handle.by_ref().take(4096).read_to_end(&mut head).is_err() {
+        return false;
+    }
+    let text = if head.starts_with(b"\xef\xbb\xbf") {
+        &head[3..]
+    } else {
+        &head[..]
+    };
+    match text.iter().position(|c| !c.is_ascii_whitespace()) {
+        Some(start) => text[start] == b'<' && text.windows(4).any(|w| w == b"<svg"),
+        None => false,
+    }
+}
+
+fn read_tree<R: Read + Seek>(handle: &mut R, opt: &usvg::Options) -> Result<Tree, ()> {
+    let mut data = vec![];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    handle.read_to_end(&mut data).map_err(|_| {
+        warn!("SVG: Reading file failed.");
+    })?;
+    Tree::from_data(&data, opt).map_err(|e| {
+        warn!("SVG: {}", e);
+    })
+}
+
+/// Returns the width and height of an SVG image in big points, taken from
+/// its `width` and `height` or else from its `viewBox`.
+pub fn svg_get_bbox<R: Read + Seek>(handle: &mut R) -> Result<(f64, f64), ()> {
+    /* Text does not affect the size, so no fonts are needed. */
+    let tree = read_tree(handle, &usvg::Options::default())?;
+    let size = tree.svg_node().size;
+    Ok((size.width() * PX_TO_BP, size.height() * PX_TO_BP))
+}
+
+pub(crate) unsafe fn svg_include_image<R: Read + Seek>(
+    ximage: &mut pdf_ximage,
+    handle: &mut R,
+    opt: &usvg::Options,
+) -> Result<(), ()> {
+    let tree = read_tree(handle, opt)?;
+    let svg = *tree.svg_node();
+    let width = svg.size.width() * PX_TO_BP;
+    let height = svg.size.height() * PX_TO_BP;
+
+    /* Flip the y axis and map the viewBox onto the image. */
+    let mut base = Transform::new(PX_TO_BP, 0., 0., -PX_TO_BP, 0., height);
+    base.append(&usvg::utils::view_box_to_transform(
+        svg.view_box.rect,
+        svg.view_box.aspect,
+        svg.size,
+    ));
+
+    let mut renderer = SvgRenderer::new(tree.clone(), width, height);
+    let mut content = vec![];
+    renderer.transform(&base, &mut content);
+    renderer.draw_children(&tree.root(), &mut content);
+
+    /* The BBox of the top-level form is the image itself. */
+    renderer.ctm = Transform::default();
+    let form = renderer.form(&content, None);
+    renderer.finish();
+
+    let mut info = xform_info::default();
+    pdf_ximage_init_form_info(&mut info);
+    info.bbox = Rect::new(Point::zero(), Point::new(width, height));
+    pdf_ximage_set_form(ximage, &mut info, form);
+    Ok(())
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum Unsupported {
+    Filter,
+    Pattern,
+    Spread,
+    StopOpacity,
+    Transparency,
+}
+
+enum PdfPaint {
+    Color(usvg::Color),
+    Pattern(String),
+}
+
+struct SvgRenderer {
+    /* The tree paint servers, clipping paths and masks are looked up in */
+    tree: Tree,
+    /* Size of the top-level form, which is all that is ever visible */
+    width: f64,
+    height: f64,
+    /* Maps the current user space to the space of the top-level form */
+    ctm: Transform,
+    /* The CTM at which the form being written is painted */
+    form_ctm: Transform,
+    /* All forms share a single resource dictionary. */
+    resources: *mut pdf_obj,
+    ext_gstates: pdf_dict,
+    patterns: pdf_dict,
+    xobjects: pdf_dict,
+    num_resources: usize,
+    alpha_states: HashMap<(u64, u64), String>,
+    warned: Vec<Unsupported>,
+}
+
+impl SvgRenderer {
+    unsafe fn new(tree: Tree, width: f64, height: f64) -> Self {
+        Self {
+            tree,
+            width,
+            height,
+            ctm: Transform::default(),
+            form_ctm: Transform::default(),
+            resources: pdf_dict::new().into_obj(),
+            ext_gstates: pdf_dict::new(),
+            patterns: pdf_dict::new(),
+            xobjects: pdf_dict::new(),
+            num_resources: 0,
+            alpha_states: HashMap::new(),
+            warned: vec![],
+        }
+    }
+
+    fn warn_once(&mut self, what: Unsupported) {
+        if self.warned.contains(&what) {
+            return;
+        }
+        self.warned.push(what);
+        match what {
+            Unsupported::Filter => warn!("SVG: Filters are not supported; drawing unfiltered."),
+            Unsupported::Pattern => warn!("SVG: Pattern paints are not supported."),
+            Unsupported::Spread => {
+                warn!("SVG: Gradients are padded regardless of their spread method.")
+            }
+            Unsupported::StopOpacity => {
+                warn!("SVG: Gradient stop opacity is applied to the whole gradient.")
+            }
+            Unsupported::Transparency => {
+                warn!("SVG: Transparency will be ignored. (no support in PDF ver. < 1.4)")
+            }
+        }
+    }
+
+    /* Fills the shared resource dictionary in and lets go of it. */
+    unsafe fn finish(self) {
+        let dict = (*self.resources).as_dict_mut();
+        dict.set("ExtGState", self.ext_gstates);
+        dict.set("Pattern", self.patterns);
+        dict.set("XObject", self.xobjects);
+        pdf_release_obj(self.resources);
+    }
+
+    fn add_name(&mut self, prefix: &str) -> String {
+        self.num_resources += 1;
+        format!("{}{}", prefix, self.num_resources)
+    }
+
+    unsafe fn add_ext_gstate(&mut self, gstate: pdf_dict) -> String {
+        let name = self.add_name("Gs");
+        self.ext_gstates.set(name.as_str(), gstate);
+        name
+    }
+
+    unsafe fn add_xobject(&mut self, xobject: *mut pdf_obj) -> String {
+        let name = self.add_name("Fm");
+        self.xobjects.set(name.as_str(), pdf_ref_obj(xobject));
+        pdf_release_obj(xobject);
+        name
+    }
+
+    /* Returns the name of an ExtGState setting the fill and stroke alpha. */
+    unsafe fn alpha_state(&mut self, fill: f64, stroke: f64) -> Option<String> {
+        if pdf_get_version() < 4 {
+            self.warn_once(Unsupported::Transparency);
+            return None;
+        }
+        let key = (fill.to_bits(), stroke.to_bits());
+        if let Some(name) = self.alpha_states.get(&key) {
+            return Some(name.clone());
+        }
+        let mut gstate = pdf_dict::new();
+        gstate.set("Type", "ExtGState");
+        gstate.set("ca", fill);
+        gstate.set("CA", stroke);
+        let name = self.add_ext_gstate(gstate);
+        self.alpha_states.insert(key, name.clone());
+        Some(name)
+    }
+
+    fn transform(&mut self, ts: &Transform, buf: &mut Vec<u8>) {
+        if ts.is_default() {
+            return;
+        }
+        for &v in &[ts.a, ts.b, ts.c, ts.d, ts.e, ts.f] {
+            put_number(buf, v);
+        }
+        put_op(buf, "cm");
+        self.ctm.append(ts);
+    }
+
+    /* The visible area, that of the top-level form, in user space */
+    fn visible_bbox(&self) -> usvg::Rect {
+        let viewport = usvg::Rect::new(0., 0., self.width, self.height).unwrap();
+        invert(&self.ctm)
+            .and_then(|inv| viewport.transform(&inv))
+            .unwrap_or(viewport)
+    }
+
+    /* Creates a form painting `content` in the current user space. */
+    unsafe fn form(&mut self, content: &[u8], group: Option<pdf_dict>) -> *mut pdf_obj {
+        let bbox = self.visible_bbox();
+        let mut form = pdf_stream::new(STREAM_COMPRESS);
+        let dict = form.get_dict_mut();
+        dict.set("Type", "XObject");
+        dict.set("Subtype", "Form");
+        dict.set("FormType", 1f64);
+        let mut bbox_array = vec![];
+        bbox_array.push_obj(bbox.left());
+        bbox_array.push_obj(bbox.top());
+        bbox_array.push_obj(bbox.right());
+        bbox_array.push_obj(bbox.bottom());
+        dict.set("BBox", bbox_array);
+        if let Some(group) = group {
+            dict.set("Group", group);
+        }
+        dict.set("Resources", pdf_ref_obj(self.resources));
+        form.add_slice(content);
+        form.into_obj()
+    }
+
+    unsafe fn draw_children(&mut self, parent: &usvg::Node, buf: &mut Vec<u8>) {
+        for child in parent.children() {
+            match *child.borrow() {
+                NodeKind::Path(ref path) => self.draw_path(path, buf),
+                NodeKind::Image(ref image) => self.draw_image(image, buf),
+                NodeKind::Group(ref group) => self.draw_group(&child, group, buf),
+                _ => {}
+            }
+        }
+    }
+
+    unsafe fn draw_group(&mut self, node: &usvg::Node, group: &usvg::Group, buf: &mut Vec<u8>) {
+        if group.filter.is_some() {
+            self.warn_once(Unsupported::Filter);
+        }
+        let saved_ctm = self.ctm;
+        put_op(buf, "q");
+        self.transform(&group.transform, buf);
+        if let Some(ref id) = group.clip_path {
+            self.clip(id, node, buf);
+        }
+        if let Some(ref id) = group.mask {
+            self.mask(id, node, buf);
+        }
+        let opacity = group.opacity.value();
+        match if opacity < 1. {
+            self.alpha_state(opacity, opacity)
+        } else {
+            None
+        } {
+            Some(alpha) => {
+                /* Group opacity applies to the composited children. */
+                let saved_form_ctm = std::mem::replace(&mut self.form_ctm, self.ctm);
+                let mut content = vec![];
+                self.draw_children(node, &mut content);
+                self.form_ctm = saved_form_ctm;
+                let form = self.form(&content, Some(transparency_group(None)));
+                let name = self.add_xobject(form);
+                put_op(buf, &format!("/{} gs /{} Do", alpha, name));
+            }
+            None => self.draw_children(node, buf),
+        }
+        put_op(buf, "Q");
+        self.ctm = saved_ctm;
+    }
+
+    /* Intersects the clipping path with the `clipPath` element `id`. */
+    unsafe fn clip(&mut self, id: &str, node: &usvg::Node, buf: &mut Vec<u8>) {
+        let clip_node = match self.tree.defs_by_id(id) {
+            Some(clip_node) => clip_node,
+            None => return,
+        };
+        let clip = match *clip_node.borrow() {
+            NodeKind::ClipPath(ref clip) => clip.clone(),
+            _ => return,
+        };
+        if let Some(ref inner) = clip.clip_path {
+            self.clip(inner, node, buf);
+        }
+        let mut ts = clip.transform;
+        if clip.units == Units::ObjectBoundingBox {
+            match children_bbox(node, Transform::default()) {
+                Some(bbox) => ts.append(&bbox_transform(&bbox)),
+                None => {
+                    put_op(buf, "0 0 0 0 re W n");
+                    return;
+                }
+            }
+        }
+        let mut even_odd = true;
+        let start = buf.len();
+        clip_outlines(&clip_node, ts, &mut even_odd, buf);
+        if buf.len() == start {
+            put_op(buf, "0 0 0 0 re");
+        }
+        put_op(buf, if even_odd { "W* n" } else { "W n" });
+    }
+
+    /* Sets up the `mask` element `id` as the soft mask. */
+    unsafe fn mask(&mut self, id: &str, node: &usvg::Node, buf: &mut Vec<u8>) {
+        let mask_node = match self.tree.defs_by_id(id) {
+            Some(mask_node) => mask_node,
+            None => return,
+        };
+        let mask = match *mask_node.borrow() {
+            NodeKind::Mask(ref mask) => mask.clone(),
+            _ => return,
+        };
+        if pdf_get_version() < 4 {
+            self.warn_once(Unsupported::Transparency);
+            return;
+        }
+        let bbox = children_bbox(node, Transform::default());
+        let rect = match (mask.units, bbox) {
+            (Units::UserSpaceOnUse, _) => mask.rect,
+            (Units::ObjectBoundingBox, Some(bbox)) => mask.rect.bbox_transform(bbox),
+            (Units::ObjectBoundingBox, None) => {
+                put_op(buf, "0 0 0 0 re W n");
+                return;
+            }
+        };
+
+        let saved_ctm = self.ctm;
+        let saved_form_ctm = std::mem::replace(&mut self.form_ctm, self.ctm);
+        let mut content = vec![];
+        if let Some(ref inner) = mask.mask {
+            self.mask(inner, node, &mut content);
+        }
+        put_rect(&mut content, &rect);
+        put_op(&mut content, "W n");
+        if let (Units::ObjectBoundingBox, Some(bbox)) = (mask.content_units, bbox) {
+            self.transform(&bbox_transform(&bbox), &mut content);
+        }
+        self.draw_children(&mask_node, &mut content);
+        self.ctm = saved_ctm;
+        self.form_ctm = saved_form_ctm;
+
+        let form = self.form(&content, Some(transparency_group(Some("DeviceRGB"))));
+        let mut smask = pdf_dict::new();
+        smask.set("Type", "Mask");
+        smask.set("S", "Luminosity");
+        smask.set("G", pdf_ref_obj(form));
+        pdf_release_obj(form);
+        let mut gstate = pdf_dict::new();
+        gstate.set("Type", "ExtGState");
+        gstate.set("SMask", smask);
+        let name = self.add_ext_gstate(gstate);
+        put_op(buf, &format!("/{} gs", name));
+    }
+
+    unsafe fn draw_path(&mut self, path: &usvg::Path, buf: &mut Vec<u8>) {
+        if path.visibility != Visibility::Visible {
+            return;
+        }
+        let bbox = path.data.bbox();
+        let fill = match path.fill {
+            Some(ref fill) => self
+                .paint(&fill.paint, fill.opacity.value(), &path.transform, bbox)
+                .map(|paint| (paint, fill.rule)),
+            None => None,
+        };
+        let stroke = match path.stroke {
+            Some(ref stroke) => self
+                .paint(&stroke.paint, stroke.opacity.value(), &path.transform, bbox)
+                .map(|paint| (paint, stroke)),
+            None => None,
+        };
+        let op = match (&fill, &stroke) {
+            (Some((_, FillRule::EvenOdd)), Some(_)) => "B*",
+            (Some(_), Some(_)) => "B",
+            (Some((_, FillRule::EvenOdd)), None) => "f*",
+            (Some(_), None) => "f",
+            (None, Some(_)) => "S",
+            (None, None) => return,
+        };
+
+        let saved_ctm = self.ctm;
+        put_op(buf, "q");
+        self.transform(&path.transform, buf);
+        let fill_alpha = fill.as_ref().map(|f| (f.0).1).unwrap_or(1.);
+        let stroke_alpha = stroke.as_ref().map(|s| (s.0).1).unwrap_or(1.);
+        if fill_alpha < 1. || stroke_alpha < 1. {
+            if let Some(name) = self.alpha_state(fill_alpha, stroke_alpha) {
+                put_op(buf, &format!("/{} gs", name));
+            }
+        }
+        if let Some(((ref paint, _), _)) = fill {
+            put_paint(buf, paint, false);
+        }
+        if let Some(((ref paint, _), stroke)) = stroke {
+            put_paint(buf, paint, true);
+            put_number(buf, stroke.width.value());
+            put_op(buf, "w");
+            put_op(
+                buf,
+                match stroke.linecap {
+                    LineCap::Butt => "0 J",
+                    LineCap::Round => "1 J",
+                    LineCap::Square => "2 J",
+                },
+            );
+            put_op(
+                buf,
+                match stroke.linejoin {
+                    LineJoin::Miter => "0 j",
+                    LineJoin::Round => "1 j",
+                    LineJoin::Bevel => "2 j",
+                },
+            );
+            put_number(buf, stroke.miterlimit.value());
+            put_op(buf, "M");
+            if let Some(ref dashes) = stroke.dasharray {
+                buf.push(b'[');
+                for &dash in dashes {
+                    put_number(buf, dash);
+                }
+                buf.extend_from_slice(b"] ");
+                put_number(buf, stroke.dashoffset as f64);
+                put_op(buf, "d");
+            }
+        }
+        put_path_data(buf, &path.data, None);
+        put_op(buf, op);
+        put_op(buf, "Q");
+        self.ctm = saved_ctm;
+    }
+
+    /* Translates a paint of a path with transform `ts` and bounding box
+     * `bbox`, returning it with its opacity. */
+    unsafe fn paint(
+        &mut self,
+        paint: &Paint,
+        opacity: f64,
+        ts: &Transform,
+        bbox: Option<usvg::Rect>,
+    ) -> Option<(PdfPaint, f64)> {
+        let id = match *paint {
+            Paint::Color(color) => return Some((PdfPaint::Color(color), opacity)),
+            Paint::Link(ref id) => id,
+        };
+        let server = self.tree.defs_by_id(id)?;
+        let server = server.borrow();
+        match *server {
+            NodeKind::LinearGradient(ref lg) => {
+                let coords = [lg.x1, lg.y1, lg.x2, lg.y2];
+                self.gradient(2, &coords, &lg.base, opacity, ts, bbox)
+            }
+            NodeKind::RadialGradient(ref rg) => {
+                let coords = [rg.fx, rg.fy, 0., rg.cx, rg.cy, rg.r.value()];
+                self.gradient(3, &coords, &rg.base, opacity, ts, bbox)
+            }
+            NodeKind::Pattern(_) => {
+                self.warn_once(Unsupported::Pattern);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    unsafe fn gradient(
+        &mut self,
+        shading_type: i32,
+        coords: &[f64],
+        gradient: &usvg::BaseGradient,
+        opacity: f64,
+        ts: &Transform,
+        bbox: Option<usvg::Rect>,
+    ) -> Option<(PdfPaint, f64)> {
+        if gradient.stops.is_empty() {
+            return None;
+        }
+        if gradient.spread_method != SpreadMethod::Pad {
+            self.warn_once(Unsupported::Spread);
+        }
+        /* Patterns live in the space of the form they are used in. */
+        let mut matrix = invert(&self.form_ctm)?;
+        matrix.append(&self.ctm);
+        matrix.append(ts);
+        if gradient.units == Units::ObjectBoundingBox {
+            matrix.append(&bbox_transform(&bbox?));
+        }
+        matrix.append(&gradient.transform);
+
+        let stop_opacity = gradient
+            .stops
+            .iter()
+            .map(|stop| stop.opacity.value())
+            .fold(0., f64::max);
+        if gradient
+            .stops
+            .iter()
+            .any(|stop| stop.opacity.value() != stop_opacity)
+        {
+            self.warn_once(Unsupported::StopOpacity);
+        }
+
+        let mut shading = pdf_dict::new();
+        shading.set("ShadingType", shading_type as f64);
+        shading.set("ColorSpace", "DeviceRGB");
+        let mut coords_array = vec![];
+        for &coord in coords {
+            coords_array.push_obj(coord);
+        }
+        shading.set("Coords", coords_array);
+        shading.set("Function", gradient_function(&gradient.stops));
+        let mut extend = vec![];
+        extend.push_obj(true);
+        extend.push_obj(true);
+        shading.set("Extend", extend);
+
+        let mut pattern = pdf_dict::new();
+        pattern.set("Type", "Pattern");
+        pattern.set("PatternType", 2f64);
+        pattern.set("Shading", shading);
+        let mut matrix_array = vec![];
+        for &v in &[matrix.a, matrix.b, matrix.c, matrix.d, matrix.e, matrix.f] {
+            matrix_array.push_obj(v);
+        }
+        pattern.set("Matrix", matrix_array);
+        let name = self.add_name("Sh");
+        self.patterns.set(name.as_str(), pattern);
+        Some((PdfPaint::Pattern(name), opacity * stop_opacity))
+    }
+
+    unsafe fn draw_image(&mut self, image: &usvg::Image, buf: &mut Vec<u8>) {
+        if image.visibility != Visibility::Visible {
+            return;
+        }
+        let rect = image.view_box.rect;
+        let saved_ctm = self.ctm;
+        put_op(buf, "q");
+        self.transform(&image.transform, buf);
+        put_rect(buf, &rect);
+        put_op(buf, "W n");
+        match image.kind {
+            ImageKind::JPEG(ref data) | ImageKind::PNG(ref data) => {
+                if let Some((name, width, height)) = self.raster_image(&image.kind, data) {
+                    let size = usvg::Rect::new(0., 0., width, height).unwrap();
+                    self.transform(&fit_transform(&size, image), buf);
+                    /* Image space is the unit square with its origin at the bottom. */
+                    self.transform(&Transform::new(width, 0., 0., -height, 0., height), buf);
+                    put_op(buf, &format!("/{} Do", name));
+                }
+            }
+            ImageKind::SVG(ref tree) => {
+                let svg = *tree.svg_node();
+                let size = usvg::Rect::new(0., 0., svg.size.width(), svg.size.height()).unwrap();
+                self.transform(&fit_transform(&size, image), buf);
+                self.transform(
+                    &usvg::utils::view_box_to_transform(
+                        svg.view_box.rect,
+                        svg.view_box.aspect,
+                        svg.size,
+                    ),
+                    buf,
+                );
+                let outer = std::mem::replace(&mut self.tree, tree.clone());
+                self.draw_children(&tree.root(), buf);
+                self.tree = outer;
+            }
+        }
+        put_op(buf, "Q");
+        self.ctm = saved_ctm;
+    }
+
+    /* Makes an image XObject of embedded JPEG or PNG data, returning its
+     * name and size in pixels. */
+    unsafe fn raster_image(&mut self, kind: &ImageKind, data: &[u8]) -> Option<(String, f64, f64)> {
+        let mut ximage = pdf_ximage::new();
+        let loaded = match *kind {
+            ImageKind::JPEG(_) => jpeg_include_image(&mut ximage, &mut Cursor::new(data)) >= 0,
+            _ => png_include_data(&mut ximage, data).is_ok(),
+        };
+        if !loaded || ximage.reference.is_null() {
+            warn!("SVG: Could not load an embedded image.");
+            return None;
+        }
+        let name = self.add_xobject(pdf_link_obj(ximage.reference));
+        Some((name, ximage.attr.width as f64, ximage.attr.height as f64))
+    }
+}
+
+unsafe fn transparency_group(colorspace: Option<&str>) -> pdf_dict {
+    let mut group = pdf_dict::new();
+    group.set("S", "Transparency");
+    if let Some(colorspace) = colorspace {
+        group.set("CS", colorspace);
+    }
+    group
+}
+
+/* Decodes PNG data from memory; libpng, as used for PNG files, reads only
+ * from input handles. */
+unsafe fn png_include_data(ximage: &mut pdf_ximage, data: &[u8]) -> Result<(), ()> {
+    let mut decoder = png::Decoder::new(data);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let (output, mut reader) = decoder.read_info().map_err(|e| {
+        warn!("SVG: PNG: {}", e);
+    })?;
+    let mut pixels = vec![0u8; output.buffer_size()];
+    reader.next_frame(&mut pixels).map_err(|e| {
+        warn!("SVG: PNG: {}", e);
+    })?;
+    let (num_components, has_alpha) = match output.color_type {
+        png::ColorType::Grayscale => (1, false),
+        png::ColorType::GrayscaleAlpha => (1, true),
+        png::ColorType::RGBA => (3, true),
+        _ => (3, false),
+    };
+    let (pixels, alpha) = if has_alpha {
+        let (color, alpha) = split_alpha(&pixels, num_components);
+        (color, Some(alpha))
+    } else {
+        (pixels, None)
+    };
+
+    let mut info = ximage_info::init();
+    info.width = output.width as i32;
+    info.height = output.height as i32;
+    info.bits_per_component = 8;
+    info.num_components = num_components as i32;
+
+    let mut stream = pdf_stream::new(STREAM_COMPRESS);
+    let stream_dict = stream.get_dict_mut();
+    stream_dict.set(
+        "ColorSpace",
+        if num_components == 1 {
+            "DeviceGray"
+        } else {
+            "DeviceRGB"
+        },
+    );
+    if let Some(alpha) = alpha {
+        if let Some(mask) = pdf_ximage_soft_mask("PNG", info.width, info.height, &alpha) {
+            stream_dict.set("SMask", pdf_ref_obj(mask));
+            pdf_release_obj(mask);
+        }
+    }
+    stream.add_slice(&pixels);
+    let stream = stream.into_obj();
+    if info.width > 64 {
+        pdf_stream_set_predictor(stream, 15, info.width, 8, info.num_components);
+    }
+    pdf_ximage_set_image(ximage, &info, stream);
+    Ok(())
+}
+
+/* A stitching function through the gradient stops, padded to [0, 1]. */
+unsafe fn gradient_function(stops: &[usvg::Stop]) -> pdf_dict {
+    let mut stops: Vec<(f64, usvg::Color)> = stops
+        .iter()
+        .map(|stop| (stop.offset.value(), stop.color))
+        .collect();
+    if stops[0].0 > 0. {
+        stops.insert(0, (0., stops[0].1));
+    }
+    if stops[stops.len() - 1].0 < 1. {
+        stops.push((1., stops[stops.len() - 1].1));
+    }
+    let rgb = |color: usvg::Color| {
+        let mut array = vec![];
+        array.push_obj(color.red as f64 / 255.);
+        array.push_obj(color.green as f64 / 255.);
+        array.push_obj(color.blue as f64 / 255.);
+        array
+    };
+    let interpolation = |c0: usvg::Color, c1: usvg::Color| {
+        let mut function = pdf_dict::new();
+        function.set("FunctionType", 2f64);
+        let mut domain = vec![];
+        domain.push_obj(0f64);
+        domain.push_obj(1f64);
+        function.set("Domain", domain);
+        function.set("C0", rgb(c0));
+        function.set("C1", rgb(c1));
+        function.set("N", 1f64);
+        function
+    };
+    if stops.len() == 2 {
+        return interpolation(stops[0].1, stops[1].1);
+    }
+    let mut functions = vec![];
+    let mut bounds = vec![];
+    let mut encode = vec![];
+    for (i, pair) in stops.windows(2).enumerate() {
+        functions.push_obj(interpolation(pair[0].1, pair[1].1));
+        if i > 0 {
+            bounds.push_obj(pair[0].0);
+        }
+        encode.push_obj(0f64);
+        encode.push_obj(1f64);
+    }
+    let mut function = pdf_dict::new();
+    function.set("FunctionType", 3f64);
+    let mut domain = vec![];
+    domain.push_obj(0f64);
+    domain.push_obj(1f64);
+    function.set("Domain", domain);
+    function.set("Functions", functions);
+    function.set("Bounds", bounds);
+    function.set("Encode", encode);
+    function
+}
+
+/* Collects the outlines of a clipping path, noting whether all use the
+ * even-odd rule. */
+fn clip_outlines(node: &usvg::Node, ts: Transform, even_odd: &mut bool, buf: &mut Vec<u8>) {
+    for child in node.children() {
+        match *child.borrow() {
+            NodeKind::Path(ref path) if path.visibility == Visibility::Visible => {
+                let mut path_ts = ts;
+                path_ts.append(&path.transform);
+                if path.fill.as_ref().map(|fill| fill.rule) != Some(FillRule::EvenOdd) {
+                    *even_odd = false;
+                }
+                put_path_data(buf, &path.data, Some(&path_ts));
+            }
+            NodeKind::Group(ref group) => {
+                let mut group_ts = ts;
+                group_ts.append(&group.transform);
+                clip_outlines(&child, group_ts, even_odd, buf);
+            }
+            _ => {}
+        }
+    }
+}
+
+/* Bounding box of a node's children in its user space */
+fn children_bbox(node: &usvg::Node, ts: Transform) -> Option<usvg::Rect> {
+    let mut bbox: Option<usvg::Rect> = None;
+    for child in node.children() {
+        let child_bbox = match *child.borrow() {
+            NodeKind::Path(ref path) => {
+                let mut path_ts = ts;
+                path_ts.append(&path.transform);
+                path.data.bbox_with_transform(path_ts, None)
+            }
+            NodeKind::Group(ref group) => {
+                let mut group_ts = ts;
+                group_ts.append(&group.transform);
+                children_bbox(&child, group_ts)
+            }
+            NodeKind::Image(ref image) => {
+                let mut image_ts = ts;
+                image_ts.append(&image.transform);
+                image.view_box.rect.transform(&image_ts)
+            }
+            _ => None,
+        };
+        if let Some(child_bbox) = child_bbox {
+            bbox = Some(match bbox {
+                Some(bbox) => bbox.expand(child_bbox),
+                None => child_bbox,
+            });
+        }
+    }
+    bbox
+}
+
+/* Maps the unit square onto `bbox`, for objectBoundingBox units. */
+fn bbox_transform(bbox: &usvg::Rect) -> Transform {
+    Transform::new(bbox.width(), 0., 0., bbox.height(), bbox.x(), bbox.y())
+}
+
+/* Fits content of the given size into an image element's viewport. */
+fn fit_transform(size: &usvg::Rect, image: &usvg::Image) -> Transform {
+    let rect = image.view_box.rect;
+    let mut ts = Transform::new_translate(rect.x(), rect.y());
+    ts.append(&usvg::utils::view_box_to_transform(
+        *size,
+        image.view_box.aspect,
+        rect.size(),
+    ));
+    ts
+}
+
+fn invert(ts: &Transform) -> Option<Transform> {
+    let det = ts.a * ts.d - ts.b * ts.c;
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    Some(Transform::new(
+        ts.d / det,
+        -ts.b / det,
+        -ts.c / det,
+        ts.a / det,
+        (ts.c * ts.f - ts.d * ts.e) / det,
+        (ts.b * ts.e - ts.a * ts.f) / det,
+    ))
+}
+
+fn put_number(buf: &mut Vec<u8>, value: f64) {
+    pdf_sprint_number(buf, value);
+    buf.push(b' ');
+}
+
+fn put_op(buf: &mut Vec<u8>, op: &str) {
+    buf.extend_from_slice(op.as_bytes());
+    buf.push(b'\n');
+}
+
+fn put_rect(buf: &mut Vec<u8>, rect: &usvg::Rect) {
+    put_number(buf, rect.x());
+    put_number(buf, rect.y());
+    put_number(buf, rect.width());
+    put_number(buf, rect.height());
+    put_op(buf, "re");
+}
+
+fn put_paint(buf: &mut Vec<u8>, paint: &PdfPaint, stroke: bool) {
+    match *paint {
+        PdfPaint::Color(color) => {
+            put_number(buf, color.red as f64 / 255.);
+            put_number(buf, color.green as f64 / 255.);
+            put_number(buf, color.blue as f64 / 255.);
+            put_op(buf, if stroke { "RG" } else { "rg" });
+        }
+        PdfPaint::Pattern(ref name) => {
+            if stroke {
+                put_op(buf, &format!("/Pattern CS /{} SCN", name));
+            } else {
+                put_op(buf, &format!("/Pattern cs /{} scn", name));
+            }
+        }
+    }
+}
+
+fn put_path_data(buf: &mut Vec<u8>, data: &PathData, ts: Option<&Transform>) {
+    let point = |buf: &mut Vec<u8>, x: f64, y: f64| {
+        let (x, y) = ts.map(|ts| ts.apply(x, y)).unwrap_or((x, y));
+        put_number(buf, x);
+        put_number(buf, y);
+    };
+    for segment in data.iter() {
+        match *segment {
+            PathSegment::MoveTo { x, y } => {
+                point(buf, x, y);
+                put_op(buf, "m");
+            }
+            PathSegment::LineTo { x, y } => {
+                point(buf, x, y);
+                put_op(buf, "l");
+            }
+            PathSegment::CurveTo {
+                x1,
+                y1,
+                x2,
+                y2,
+                x,
+                y,
+            } => {
+                point(buf, x1, y1);
+                point(buf, x2, y2);
+                point(buf, x, y);
+                put_op(buf, "c");
+            }
+            PathSegment::ClosePath => put_op(buf, "h"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SQUARE: &[u8] = b"<svg xmlns='http://www.w3.org/2000/svg' width='96' height='48'>\
+                            <rect width='10' height='10'/></svg>";
+
+    #[test]
+    fn detection() {
+        let detect = |data: &[u8]| check_for_svg(&mut Cursor::new(data));
+        assert!(detect(SQUARE));
+        assert!(detect(
+            b"\xef\xbb\xbf<?xml version='1.0'?>\n<!-- drawing -->\n<svg/>"
+        ));
+        assert!(detect(b"  \n<svg/>"));
+        assert!(!detect(b"<html><body></body></html>"));
+        assert!(!detect(b"\x89PNG\r\n\x1a\n<svg"));
+        assert!(!detect(b""));
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(svg_get_bbox(&mut Cursor::new(SQUARE)), Ok((72., 36.)));
+        /* Without a width and height, the viewBox gives the size. */
+        let view_box = b"<svg xmlns='http://www.w3.org/2000/svg' viewBox='10 10 192 24'/>";
+        assert_eq!(
+            svg_get_bbox(&mut Cursor::new(&view_box[..])),
+            Ok((144., 18.))
+        );
+    }
+
+    #[test]
+    fn inverse() {
+        for ts in [
+            Transform::new(2., 0., 0., 4., 8., -16.),
+            Transform::new(0., 1., -1., 0., 3., 5.),
+            Transform::new(1., 0.5, 0.25, 1., -2., 7.),
+        ]
+        .iter()
+        {
+            let mut product = *ts;
+            product.append(&invert(ts).unwrap());
+            assert_eq!(product, Transform::default());
+        }
+        assert_eq!(invert(&Transform::new(1., 2., 2., 4., 0., 0.)), None);
+    }
+
+    #[test]
+    fn path_data() {
+        let mut data = PathData::new();
+        data.push_move_to(0., 0.);
+        data.push_line_to(10., 0.);
+        data.push_curve_to(10., 5., 5., 10., 0., 10.);
+        data.push_close_path();
+
+        let mut buf = vec![];
+        put_path_data(&mut buf, &data, None);
+        assert_eq!(buf, b"0 0 m\n10 0 l\n10 5 5 10 0 10 c\nh\n".to_vec());
+
+        let mut buf = vec![];
+        put_path_data(
+            &mut buf,
+            &data,
+            Some(&Transform::new(0.5, 0., 0., -0.5, 1., 2.)),
+        );
+        assert_eq!(buf, b"1 2 m\n6 2 l\n6 -.5 3.5 -3 1 -3 c\nh\n".to_vec());
+    }
+}
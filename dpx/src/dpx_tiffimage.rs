@@ -0,0 +1,674 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! Baseline TIFF images: bilevel, grayscale, palette, RGB and CMYK, with an
+//! optional alpha channel, stored uncompressed or compressed with PackBits,
+//! LZW or Deflate. The page option selects an image of multi-page files.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+
+use super::dpx_dpxutil::lzw_decode;
+#[cfg(feature = "libz-sys")]
+use super::dpx_pdfobj::pdf_add_stream_flate;
+use super::dpx_pdfximage::{pdf_ximage_icc_colorspace, pdf_ximage_set_image, pdf_ximage_soft_mask};
+use crate::dpx_pdfobj::{
+    pdf_ref_obj, pdf_release_obj, pdf_stream, pdf_stream_set_predictor, pdf_string, IntoObj,
+    PushObj, STREAM_COMPRESS,
+};
+use crate::dpx_pdfximage::{pdf_ximage, ximage_info};
+use crate::warn;
+
+const IMAGE_WIDTH: u16 = 256;
+const IMAGE_LENGTH: u16 = 257;
+const BITS_PER_SAMPLE: u16 = 258;
+const COMPRESSION: u16 = 259;
+const PHOTOMETRIC: u16 = 262;
+const STRIP_OFFSETS: u16 = 273;
+const SAMPLES_PER_PIXEL: u16 = 277;
+const ROWS_PER_STRIP: u16 = 278;
+const STRIP_BYTE_COUNTS: u16 = 279;
+const X_RESOLUTION: u16 = 282;
+const Y_RESOLUTION: u16 = 283;
+const PLANAR_CONFIG: u16 = 284;
+const RESOLUTION_UNIT: u16 = 296;
+const PREDICTOR: u16 = 317;
+const COLOR_MAP: u16 = 320;
+const TILE_WIDTH: u16 = 322;
+const EXTRA_SAMPLES: u16 = 338;
+const SAMPLE_FORMAT: u16 = 339;
+const ICC_PROFILE: u16 = 34675;
+
+pub fn check_for_tiff<R: Read + Seek>(handle: &mut R) -> bool {
+    let mut sigbytes = [0u8; 4];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    handle.read_exact(&mut sigbytes).is_ok() && (sigbytes == *b"II*\x00" || sigbytes == *b"MM\x00*")
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    typ: u16,
+    count: usize,
+    /* Position of the value, which is inline for small ones */
+    pos: usize,
+}
+
+struct Tiff {
+    data: Vec<u8>,
+    big_endian: bool,
+}
+
+type Ifd = HashMap<u16, Entry>;
+
+impl Tiff {
+    fn read<R: Read + Seek>(handle: &mut R) -> Result<Self, ()> {
+        let mut data = vec![];
+        handle.seek(SeekFrom::Start(0)).unwrap();
+        if handle.read_to_end(&mut data).is_err() || data.len() < 8 {
+            warn!("TIFF: Reading file failed.");
+            return Err(());
+        }
+        let big_endian = data[0] == b'M';
+        Ok(Self { data, big_endian })
+    }
+
+    fn bytes(&self, pos: usize, len: usize) -> Result<&[u8], ()> {
+        self.data.get(pos..pos + len).ok_or_else(|| {
+            warn!("TIFF: Unexpected end of file.");
+        })
+    }
+
+    fn u16(&self, pos: usize) -> Result<u16, ()> {
+        let b = self.bytes(pos, 2)?;
+        Ok(if self.big_endian {
+            u16::from_be_bytes([b[0], b[1]])
+        } else {
+            u16::from_le_bytes([b[0], b[1]])
+        })
+    }
+
+    fn u32(&self, pos: usize) -> Result<u32, ()> {
+        let b = self.bytes(pos, 4)?;
+        Ok(if self.big_endian {
+            u32::from_be_bytes([b[0], b[1], b[2], b[3]])
+        } else {
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+        })
+    }
+
+    /* Offsets of the image file directories, one per page */
+    fn pages(&self) -> Result<Vec<usize>, ()> {
+        let mut pages = vec![];
+        let mut offset = self.u32(4)? as usize;
+        while offset != 0 {
+            if pages.contains(&offset) || pages.len() > 65535 {
+                warn!("TIFF: Loop in image file directories.");
+                return Err(());
+            }
+            pages.push(offset);
+            let count = self.u16(offset)? as usize;
+            offset = self.u32(offset + 2 + 12 * count)? as usize;
+        }
+        if pages.is_empty() {
+            warn!("TIFF: No image found.");
+            return Err(());
+        }
+        Ok(pages)
+    }
+
+    fn ifd(&self, offset: usize) -> Result<Ifd, ()> {
+        let mut ifd = Ifd::new();
+        let count = self.u16(offset)? as usize;
+        for i in 0..count {
+            let p = offset + 2 + 12 * i;
+            let tag = self.u16(p)?;
+            let typ = self.u16(p + 2)?;
+            let count = self.u32(p + 4)? as usize;
+            let size = match typ {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue,
+            };
+            let pos = if count * size <= 4 {
+                p + 8
+            } else {
+                self.u32(p + 8)? as usize
+            };
+            self.bytes(pos, count * size)?;
+            ifd.insert(tag, Entry { typ, count, pos });
+        }
+        Ok(ifd)
+    }
+
+    fn values(&self, ifd: &Ifd, tag: u16) -> Result<Option<Vec<u32>>, ()> {
+        let entry = match ifd.get(&tag) {
+            Some(entry) => *entry,
+            None => return Ok(None),
+        };
+        let mut values = Vec::with_capacity(entry.count);
+        for i in 0..entry.count {
+            values.push(match entry.typ {
+                1 | 7 => self.data[entry.pos + i] as u32,
+                3 => self.u16(entry.pos + 2 * i)? as u32,
+                4 => self.u32(entry.pos + 4 * i)?,
+                _ => {
+                    warn!("TIFF: Unexpected type {} of tag {}.", entry.typ, tag);
+                    return Err(());
+                }
+            });
+        }
+        Ok(Some(values))
+    }
+
+    fn value(&self, ifd: &Ifd, tag: u16) -> Result<Option<u32>, ()> {
+        Ok(self
+            .values(ifd, tag)?
+            .and_then(|values| values.first().cloned()))
+    }
+
+    fn rational(&self, ifd: &Ifd, tag: u16) -> Option<f64> {
+        let entry = ifd.get(&tag).filter(|entry| entry.typ == 5)?;
+        let num = self.u32(entry.pos).ok()?;
+        let den = self.u32(entry.pos + 4).ok()?;
+        if num > 0 && den > 0 {
+            Some(num as f64 / den as f64)
+        } else {
+            None
+        }
+    }
+}
+
+/* Picks the directory of the requested page, counting like PDF pages do. */
+fn select_page(tiff: &Tiff, page_no: i32) -> Result<(Ifd, i32), ()> {
+    let pages = tiff.pages()?;
+    let count = pages.len() as i32;
+    let mut page_no = page_no;
+    if page_no < 0 {
+        page_no += count + 1;
+    }
+    let page_no = page_no.max(1).min(count);
+    Ok((tiff.ifd(pages[page_no as usize - 1])?, count))
+}
+
+fn get_density(tiff: &Tiff, ifd: &Ifd) -> (f64, f64) {
+    let scale = match tiff.value(ifd, RESOLUTION_UNIT) {
+        Ok(Some(1)) => return (1., 1.),
+        Ok(Some(3)) => 2.54,
+        _ => 1.,
+    };
+    match (
+        tiff.rational(ifd, X_RESOLUTION),
+        tiff.rational(ifd, Y_RESOLUTION),
+    ) {
+        (Some(x), Some(y)) => (72. / (x * scale), 72. / (y * scale)),
+        (Some(x), None) => (72. / (x * scale), 72. / (x * scale)),
+        _ => (1., 1.),
+    }
+}
+
+pub fn tiff_get_bbox<R: Read + Seek>(
+    handle: &mut R,
+    page_no: i32,
+) -> Result<(u32, u32, f64, f64), ()> {
+    let tiff = Tiff::read(handle)?;
+    let (ifd, _) = select_page(&tiff, page_no)?;
+    let width = tiff.value(&ifd, IMAGE_WIDTH)?.ok_or(())?;
+    let height = tiff.value(&ifd, IMAGE_LENGTH)?.ok_or(())?;
+    let (xdensity, ydensity) = get_density(&tiff, &ifd);
+    Ok((width, height, xdensity, ydensity))
+}
+
+fn unpack_bits(data: &[u8], limit: usize) -> Vec<u8> {
+    let mut out = Vec::with_capacity(limit);
+    let mut i = 0;
+    while i < data.len() && out.len() < limit {
+        let n = data[i] as i8;
+        i += 1;
+        if n >= 0 {
+            let end = (i + n as usize + 1).min(data.len());
+            out.extend_from_slice(&data[i..end]);
+            i = end;
+        } else if n != -128 && i < data.len() {
+            for _ in 0..(1 - n as i32) {
+                out.push(data[i]);
+            }
+            i += 1;
+        }
+    }
+    out
+}
+
+#[cfg(feature = "libz-sys")]
+unsafe fn inflate(data: &[u8]) -> Result<Vec<u8>, ()> {
+    let mut decoded = pdf_stream::new(0);
+    if pdf_add_stream_flate(&mut decoded, data) < 0 {
+        return Err(());
+    }
+    Ok(std::mem::replace(&mut decoded.content, Vec::new()))
+}
+
+#[cfg(not(feature = "libz-sys"))]
+unsafe fn inflate(_data: &[u8]) -> Result<Vec<u8>, ()> {
+    warn!("TIFF: Deflate compression is not supported in this build.");
+    Err(())
+}
+
+/* Reads and decompresses the strips into rows of `rowbytes` bytes. */
+unsafe fn read_strips(
+    tiff: &Tiff,
+    ifd: &Ifd,
+    height: usize,
+    rowbytes: usize,
+) -> Result<Vec<u8>, ()> {
+    let compression = tiff.value(ifd, COMPRESSION)?.unwrap_or(1);
+    let offsets = tiff.values(ifd, STRIP_OFFSETS)?.ok_or_else(|| {
+        warn!("TIFF: Missing strip offsets; tiled images are not supported.");
+    })?;
+    let byte_counts = tiff.values(ifd, STRIP_BYTE_COUNTS)?.ok_or_else(|| {
+        warn!("TIFF: Missing strip byte counts.");
+    })?;
+    if offsets.len() != byte_counts.len() {
+        warn!("TIFF: Inconsistent strip information.");
+        return Err(());
+    }
+    let rows_per_strip = (tiff.value(ifd, ROWS_PER_STRIP)?.unwrap_or(u32::max_value()) as usize)
+        .min(height)
+        .max(1);
+    let mut raster = Vec::with_capacity(height * rowbytes);
+    for (&offset, &byte_count) in offsets.iter().zip(byte_counts.iter()) {
+        let rows = rows_per_strip.min(height - raster.len() / rowbytes);
+        if rows == 0 {
+            break;
+        }
+        let expected = rows * rowbytes;
+        let data = tiff.bytes(offset as usize, byte_count as usize)?;
+        let mut strip = match compression {
+            1 => data.to_vec(),
+            5 => lzw_decode(data, 8, true, true, expected).ok_or_else(|| {
+                warn!("TIFF: Broken LZW data.");
+            })?,
+            8 | 32946 => inflate(data)?,
+            32773 => unpack_bits(data, expected),
+            _ => {
+                warn!("TIFF: Unsupported compression method: {}", compression);
+                return Err(());
+            }
+        };
+        if strip.len() < expected {
+            warn!("TIFF: Image data is shorter than expected.");
+            return Err(());
+        }
+        strip.truncate(expected);
+        raster.extend_from_slice(&strip);
+    }
+    if raster.len() < height * rowbytes {
+        warn!("TIFF: Image data is shorter than expected.");
+        return Err(());
+    }
+    Ok(raster)
+}
+
+/* Undoes horizontal differencing (Predictor 2) row by row. */
+fn undo_predictor(raster: &mut [u8], rowbytes: usize, samples: usize, bps: u32, big_endian: bool) {
+    for row in raster.chunks_mut(rowbytes) {
+        if bps == 8 {
+            for i in samples..row.len() {
+                row[i] = row[i].wrapping_add(row[i - samples]);
+            }
+        } else {
+            let get = |row: &[u8], i: usize| {
+                if big_endian {
+                    u16::from_be_bytes([row[2 * i], row[2 * i + 1]])
+                } else {
+                    u16::from_le_bytes([row[2 * i], row[2 * i + 1]])
+                }
+            };
+            for i in samples..row.len() / 2 {
+                let v = get(row, i).wrapping_add(get(row, i - samples));
+                let b = if big_endian {
+                    v.to_be_bytes()
+                } else {
+                    v.to_le_bytes()
+                };
+                row[2 * i..2 * i + 2].copy_from_slice(&b);
+            }
+        }
+    }
+}
+
+pub(crate) unsafe fn tiff_include_image<R: Read + Seek>(
+    ximage: &mut pdf_ximage,
+    handle: &mut R,
+) -> Result<(), ()> {
+    let tiff = Tiff::read(handle)?;
+    let (ifd, page_count) = select_page(&tiff, ximage.attr.page_no)?;
+    ximage.attr.page_count = page_count;
+
+    if ifd.contains_key(&TILE_WIDTH) {
+        warn!("TIFF: Tiled images are not supported.");
+        return Err(());
+    }
+    let width = tiff.value(&ifd, IMAGE_WIDTH)?.unwrap_or(0) as usize;
+    let height = tiff.value(&ifd, IMAGE_LENGTH)?.unwrap_or(0) as usize;
+    let samples = tiff.value(&ifd, SAMPLES_PER_PIXEL)?.unwrap_or(1) as usize;
+    let bps = tiff.value(&ifd, BITS_PER_SAMPLE)?.unwrap_or(1);
+    let photometric = tiff.value(&ifd, PHOTOMETRIC)?.ok_or_else(|| {
+        warn!("TIFF: Missing photometric interpretation.");
+    })?;
+    if width == 0 || height == 0 || samples == 0 {
+        warn!(
+            "TIFF: Invalid image: width={}, height={}, samples={}",
+            width, height, samples
+        );
+        return Err(());
+    }
+    if tiff.value(&ifd, PLANAR_CONFIG)?.unwrap_or(1) != 1 && samples > 1 {
+        warn!("TIFF: Separate color planes are not supported.");
+        return Err(());
+    }
+    if tiff.value(&ifd, SAMPLE_FORMAT)?.unwrap_or(1) != 1 {
+        warn!("TIFF: Only unsigned integer samples are supported.");
+        return Err(());
+    }
+    let num_colors = match photometric {
+        0 | 1 | 3 => 1,
+        2 => 3,
+        5 => 4,
+        _ => {
+            warn!(
+                "TIFF: Unsupported photometric interpretation: {}",
+                photometric
+            );
+            return Err(());
+        }
+    };
+    if samples < num_colors {
+        warn!("TIFF: Too few samples per pixel: {}", samples);
+        return Err(());
+    }
+    let valid_depth = match bps {
+        1 | 2 | 4 => samples == 1,
+        8 | 16 => photometric != 3 || bps == 8,
+        _ => false,
+    };
+    if !valid_depth {
+        warn!(
+            "TIFF: Unsupported bit depth {} for {} samples per pixel.",
+            bps, samples
+        );
+        return Err(());
+    }
+
+    let rowbytes = (width * samples * bps as usize + 7) / 8;
+    let mut raster = read_strips(&tiff, &ifd, height, rowbytes)?;
+    match tiff.value(&ifd, PREDICTOR)?.unwrap_or(1) {
+        1 => {}
+        2 if bps >= 8 => undo_predictor(&mut raster, rowbytes, samples, bps, tiff.big_endian),
+        p => {
+            warn!("TIFF: Unsupported predictor: {}", p);
+            return Err(());
+        }
+    }
+    /* Keep the most significant byte of 16-bit samples. */
+    if bps == 16 {
+        let skip = if tiff.big_endian { 0 } else { 1 };
+        raster = raster.iter().skip(skip).step_by(2).cloned().collect();
+    }
+    let bpc = bps.min(8) as i32;
+
+    /* Extra samples past the colors; the first may be alpha. */
+    let extra = tiff.values(&ifd, EXTRA_SAMPLES)?.unwrap_or_default();
+    let alpha_kind = if samples > num_colors {
+        extra
+            .first()
+            .cloned()
+            .filter(|&kind| kind == 1 || kind == 2)
+    } else {
+        None
+    };
+    let mut alpha = None;
+    if samples > num_colors {
+        let mut color = Vec::with_capacity(width * height * num_colors);
+        let mut mask = Vec::with_capacity(width * height);
+        for pixel in raster.chunks_exact(samples) {
+            color.extend_from_slice(&pixel[..num_colors]);
+            mask.push(pixel[num_colors]);
+        }
+        raster = color;
+        if alpha_kind.is_some() {
+            alpha = Some(mask);
+        }
+    }
+
+    let mut info = ximage_info::init();
+    info.width = width as i32;
+    info.height = height as i32;
+    info.bits_per_component = bpc;
+    info.num_components = num_colors as i32;
+    let (xdensity, ydensity) = get_density(&tiff, &ifd);
+    info.xdensity = xdensity;
+    info.ydensity = ydensity;
+
+    let mut stream = pdf_stream::new(STREAM_COMPRESS);
+    let profile = ifd
+        .get(&ICC_PROFILE)
+        .map(|entry| &tiff.data[entry.pos..entry.pos + entry.count]);
+    let colorspace = if photometric == 3 {
+        let colormap = tiff.values(&ifd, COLOR_MAP)?.unwrap_or_default();
+        let num_entries = 1usize << bps;
+        if colormap.len() < 3 * num_entries {
+            warn!("TIFF: Missing or short color map.");
+            return Err(());
+        }
+        let mut lookup = Vec::with_capacity(3 * num_entries);
+        for i in 0..num_entries {
+            for c in 0..3 {
+                lookup.push((colormap[c * num_entries + i] >> 8) as u8);
+            }
+        }
+        let mut colorspace = vec![];
+        colorspace.push_obj("Indexed");
+        colorspace.push_obj("DeviceRGB");
+        colorspace.push_obj((num_entries - 1) as f64);
+        colorspace.push_obj(pdf_string::new(lookup));
+        colorspace.into_obj()
+    } else {
+        let colortype = -(num_colors as i32);
+        profile
+            .and_then(|profile| pdf_ximage_icc_colorspace("TIFF", colortype, profile))
+            .unwrap_or_else(|| match num_colors {
+                1 => "DeviceGray".into_obj(),
+                3 => "DeviceRGB".into_obj(),
+                _ => "DeviceCMYK".into_obj(),
+            })
+    };
+    let stream_dict = stream.get_dict_mut();
+    stream_dict.set("ColorSpace", colorspace);
+    if photometric == 0 {
+        /* WhiteIsZero */
+        let mut decode = vec![];
+        decode.push_obj(1f64);
+        decode.push_obj(0f64);
+        stream_dict.set("Decode", decode);
+    }
+    if let Some(alpha) = alpha {
+        if let Some(mask) = pdf_ximage_soft_mask("TIFF", info.width, info.height, &alpha) {
+            if alpha_kind == Some(1) {
+                /* Associated alpha: the colors are premultiplied with black. */
+                let mut matte = vec![];
+                for _ in 0..num_colors {
+                    matte.push_obj(0f64);
+                }
+                (*mask).as_stream_mut().get_dict_mut().set("Matte", matte);
+            }
+            stream_dict.set("SMask", pdf_ref_obj(mask));
+            pdf_release_obj(mask);
+        }
+    }
+    stream.add_slice(&raster);
+    let stream = stream.into_obj();
+    /* Predictor is usually not so efficient for indexed images. */
+    if photometric != 3 && bpc == 8 && info.width > 64 {
+        pdf_stream_set_predictor(stream, 15, info.width, bpc, info.num_components);
+    }
+    pdf_ximage_set_image(ximage, &info, stream);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_util::with_memory_io;
+    use std::io::Cursor;
+
+    /// A two-page file with directory entries but no image data. With
+    /// `looping`, the second directory points back to the first one.
+    fn two_pages(big_endian: bool, looping: bool) -> Vec<u8> {
+        let mut data = vec![];
+        let u16 = |data: &mut Vec<u8>, v: u16| {
+            data.extend_from_slice(&if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            })
+        };
+        let u32 = |data: &mut Vec<u8>, v: u32| {
+            data.extend_from_slice(&if big_endian {
+                v.to_be_bytes()
+            } else {
+                v.to_le_bytes()
+            })
+        };
+        data.extend_from_slice(if big_endian { b"MM\x00*" } else { b"II*\x00" });
+        u32(&mut data, 30);
+        /* Values that do not fit in an entry: 8, 8, 8 at 8, then 300/1 and 100/1 */
+        for _ in 0..3 {
+            u16(&mut data, 8);
+        }
+        u32(&mut data, 300);
+        u32(&mut data, 1);
+        u32(&mut data, 100);
+        u32(&mut data, 1);
+        assert_eq!(data.len(), 30);
+
+        let short = |data: &mut Vec<u8>, tag: u16, v: u16| {
+            u16(data, tag);
+            u16(data, 3);
+            u32(data, 1);
+            u16(data, v);
+            u16(data, 0);
+        };
+        let entry = |data: &mut Vec<u8>, tag: u16, typ: u16, count: u32, v: u32| {
+            u16(data, tag);
+            u16(data, typ);
+            u32(data, count);
+            u32(data, v);
+        };
+
+        u16(&mut data, 5);
+        short(&mut data, IMAGE_WIDTH, 4);
+        entry(&mut data, IMAGE_LENGTH, 4, 1, 2);
+        entry(&mut data, BITS_PER_SAMPLE, 3, 3, 8);
+        entry(&mut data, X_RESOLUTION, 5, 1, 14);
+        short(&mut data, RESOLUTION_UNIT, 2);
+        u32(&mut data, 96);
+
+        u16(&mut data, 5);
+        short(&mut data, IMAGE_WIDTH, 2);
+        short(&mut data, IMAGE_LENGTH, 3);
+        entry(&mut data, X_RESOLUTION, 5, 1, 22);
+        short(&mut data, RESOLUTION_UNIT, 3);
+        /* A type this reader does not know about */
+        entry(&mut data, 999, 99, 1, 0);
+        u32(&mut data, if looping { 30 } else { 0 });
+        data
+    }
+
+    fn parse(big_endian: bool) {
+        let tiff = Tiff::read(&mut Cursor::new(two_pages(big_endian, false))).unwrap();
+        assert_eq!(tiff.big_endian, big_endian);
+        assert_eq!(tiff.pages(), Ok(vec![30, 96]));
+
+        let first = tiff.ifd(30).unwrap();
+        assert_eq!(first.len(), 5);
+        assert_eq!(tiff.value(&first, IMAGE_WIDTH), Ok(Some(4)));
+        assert_eq!(tiff.value(&first, IMAGE_LENGTH), Ok(Some(2)));
+        assert_eq!(
+            tiff.values(&first, BITS_PER_SAMPLE),
+            Ok(Some(vec![8, 8, 8]))
+        );
+        assert_eq!(tiff.value(&first, COMPRESSION), Ok(None));
+        assert_eq!(tiff.rational(&first, X_RESOLUTION), Some(300.));
+        assert_eq!(tiff.rational(&first, Y_RESOLUTION), None);
+        assert_eq!(get_density(&tiff, &first), (72. / 300., 72. / 300.));
+
+        let second = tiff.ifd(96).unwrap();
+        assert_eq!(second.len(), 4);
+        assert_eq!(tiff.value(&second, IMAGE_WIDTH), Ok(Some(2)));
+        assert_eq!(get_density(&tiff, &second), (72. / 254., 72. / 254.));
+    }
+
+    #[test]
+    fn parse_little_endian() {
+        parse(false);
+    }
+
+    #[test]
+    fn parse_big_endian() {
+        parse(true);
+    }
+
+    #[test]
+    fn page_selection() {
+        let data = two_pages(false, false);
+        for &(page_no, width) in [(1, 4), (2, 2), (-1, 2), (-2, 4), (0, 4), (7, 2), (-7, 4)].iter()
+        {
+            let (width_read, ..) = tiff_get_bbox(&mut Cursor::new(&data), page_no).unwrap();
+            assert_eq!(width_read, width, "page {}", page_no);
+        }
+        let tiff = Tiff::read(&mut Cursor::new(data)).unwrap();
+        assert_eq!(select_page(&tiff, 1).unwrap().1, 2);
+    }
+
+    #[test]
+    fn bad_files() {
+        /* The failures are reported as warnings, which need the I/O set up */
+        with_memory_io(&[], || {
+            let tiff = Tiff::read(&mut Cursor::new(two_pages(true, true))).unwrap();
+            assert!(tiff.pages().is_err());
+            /* A rational where an integer is expected */
+            let first = tiff.ifd(30).unwrap();
+            assert!(tiff.values(&first, X_RESOLUTION).is_err());
+
+            let mut data = two_pages(false, false);
+            data.truncate(100);
+            let tiff = Tiff::read(&mut Cursor::new(data)).unwrap();
+            assert!(tiff.pages().is_err());
+            assert!(tiff.ifd(96).is_err());
+
+            let mut data = two_pages(false, false);
+            data[4..8].copy_from_slice(&[0; 4]);
+            let tiff = Tiff::read(&mut Cursor::new(data)).unwrap();
+            assert!(tiff.pages().is_err());
+        })
+        .unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! WebP images, both lossy and lossless. Animated files contribute their
+//! first frame.
+
+use std::io::{BufReader, Read, Seek, SeekFrom};
+
+use image_webp::WebPDecoder;
+
+use super::dpx_pdfximage::{
+    pdf_ximage_icc_colorspace, pdf_ximage_set_image, pdf_ximage_soft_mask, split_alpha,
+};
+use crate::dpx_pdfobj::{
+    pdf_ref_obj, pdf_release_obj, pdf_stream, pdf_stream_set_predictor, IntoObj, STREAM_COMPRESS,
+};
+use crate::dpx_pdfximage::{pdf_ximage, ximage_info};
+use crate::warn;
+
+pub fn check_for_webp<R: Read + Seek>(handle: &mut R) -> bool {
+    let mut sigbytes = [0u8; 12];
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    handle.read_exact(&mut sigbytes).is_ok()
+        && &sigbytes[..4] == b"RIFF"
+        && &sigbytes[8..] == b"WEBP"
+}
+
+pub fn webp_get_bbox<R: Read + Seek>(handle: &mut R) -> Result<(u32, u32, f64, f64), ()> {
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    let decoder = WebPDecoder::new(BufReader::new(handle)).map_err(|e| {
+        warn!("WebP: {}", e);
+    })?;
+    let (width, height) = decoder.dimensions();
+    /* WebP carries no resolution; like PNG without pHYs, 72dpi. */
+    Ok((width, height, 1., 1.))
+}
+
+pub(crate) unsafe fn webp_include_image<R: Read + Seek>(
+    ximage: &mut pdf_ximage,
+    handle: &mut R,
+) -> Result<(), ()> {
+    handle.seek(SeekFrom::Start(0)).unwrap();
+    let mut decoder = WebPDecoder::new(BufReader::new(handle)).map_err(|e| {
+        warn!("WebP: {}", e);
+    })?;
+    if decoder.is_animated() {
+        warn!("WebP: Animated image; only the first frame is used.");
+    }
+    let (width, height) = decoder.dimensions();
+    let size = decoder.output_buffer_size().ok_or_else(|| {
+        warn!("WebP: Image too large: {}x{}", width, height);
+    })?;
+    let mut pixels = vec![0u8; size];
+    decoder.read_image(&mut pixels).map_err(|e| {
+        warn!("WebP: {}", e);
+    })?;
+    let (pixels, alpha) = if decoder.has_alpha() {
+        let (color, alpha) = split_alpha(&pixels, 3);
+        (color, Some(alpha))
+    } else {
+        (pixels, None)
+    };
+
+    let mut info = ximage_info::init();
+    info.width = width as i32;
+    info.height = height as i32;
+    info.bits_per_component = 8;
+    info.num_components = 3;
+
+    let mut stream = pdf_stream::new(STREAM_COMPRESS);
+    let colorspace = match decoder.icc_profile() {
+        Ok(Some(profile)) => pdf_ximage_icc_colorspace("WebP", -3, &profile),
+        _ => None,
+    };
+    let stream_dict = stream.get_dict_mut();
+    stream_dict.set(
+        "ColorSpace",
+        colorspace.unwrap_or_else(|| "DeviceRGB".into_obj()),
+    );
+    if let Some(alpha) = alpha {
+        if let Some(mask) = pdf_ximage_soft_mask("WebP", info.width, info.height, &alpha) {
+            stream_dict.set("SMask", pdf_ref_obj(mask));
+            pdf_release_obj(mask);
+        }
+    }
+    stream.add_slice(&pixels);
+    let stream = stream.into_obj();
+    if info.width > 64 {
+        pdf_stream_set_predictor(stream, 15, info.width, 8, 3);
+    }
+    pdf_ximage_set_image(ximage, &info, stream);
+    Ok(())
+}
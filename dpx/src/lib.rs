@@ -179,6 +179,8 @@ pub(crate) mod dpx_dvipdfmx;
 pub(crate) mod dpx_epdf;
 pub(crate) mod dpx_error;
 pub(crate) mod dpx_fontmap;
+pub(crate) mod dpx_gifimage;
+pub(crate) mod dpx_imagecache;
 pub(crate) mod dpx_jp2image;
 pub(crate) mod dpx_jpegimage;
 pub(crate) mod dpx_mem;
@@ -187,6 +189,7 @@ pub(crate) mod dpx_mpost;
 pub(crate) mod dpx_numbers;
 pub(crate) mod dpx_otl_opt;
 pub(crate) mod dpx_pdfcolor;
+pub(crate) mod dpx_pdfconform;
 pub(crate) mod dpx_pdfdev;
 pub(crate) mod dpx_pdfdoc;
 pub(crate) mod dpx_pdfdraw;
@@ -197,15 +200,18 @@ pub(crate) mod dpx_pdfnames;
 pub(crate) mod dpx_pdfobj;
 pub(crate) mod dpx_pdfparse;
 pub(crate) mod dpx_pdfresource;
+pub(crate) mod dpx_pdfsign;
 pub(crate) mod dpx_pdfximage;
 pub(crate) mod dpx_pkfont;
 pub(crate) mod dpx_pngimage;
 pub(crate) mod dpx_pst;
 pub(crate) mod dpx_sfnt;
 pub(crate) mod dpx_subfont;
+pub(crate) mod dpx_svgimage;
 pub(crate) mod dpx_t1_char;
 pub(crate) mod dpx_t1_load;
 pub(crate) mod dpx_tfm;
+pub(crate) mod dpx_tiffimage;
 pub(crate) mod dpx_truetype;
 pub(crate) mod dpx_tt_aux;
 pub(crate) mod dpx_tt_cmap;
@@ -218,15 +224,24 @@ pub(crate) mod dpx_type1;
 pub(crate) mod dpx_type1c;
 pub(crate) mod dpx_unicode;
 pub(crate) mod dpx_vf;
+pub(crate) mod dpx_webpimage;
 mod shims;
 pub(crate) mod specials;
+#[cfg(test)]
+mod test_util;
 
 pub use crate::dpx_bmpimage::{bmp_get_bbox, check_for_bmp};
 pub use crate::dpx_dvipdfmx::{dvipdfmx_main, XdvipdfmxConfig};
+pub use crate::dpx_gifimage::{check_for_gif, gif_get_bbox};
 pub use crate::dpx_jpegimage::{check_for_jpeg, jpeg_get_bbox};
+pub use crate::dpx_pdfconform::PdfConformance;
 pub use crate::dpx_pdfdev::Corner;
 pub use crate::dpx_pdfdoc::{pdf_doc_get_page, pdf_doc_get_page_count};
 pub use crate::dpx_pdfdraw::pdf_dev_transform;
 pub use crate::dpx_pdfobj::{pdf_file, pdf_obj, pdf_open, pdf_release_obj};
 pub use crate::dpx_pdfobj::{pdf_files_close, pdf_files_init};
+pub use crate::dpx_pdfsign::PdfSignatureConfig;
 pub use crate::dpx_pngimage::{check_for_png, png_get_bbox};
+pub use crate::dpx_svgimage::{check_for_svg, svg_get_bbox};
+pub use crate::dpx_tiffimage::{check_for_tiff, tiff_get_bbox};
+pub use crate::dpx_webpimage::{check_for_webp, webp_get_bbox};
@@ -55,10 +55,13 @@ use crate::dpx_pdfdev::{
 use crate::dpx_pdfdev::{pdf_dev_put_image, transform_info, transform_info_clear, Rect, TMatrix};
 use crate::dpx_pdfdoc::{
     pdf_doc_add_annot, pdf_doc_add_bead, pdf_doc_add_names, pdf_doc_add_page_content,
-    pdf_doc_begin_article, pdf_doc_begin_grabbing, pdf_doc_bookmarks_add, pdf_doc_bookmarks_depth,
-    pdf_doc_bookmarks_down, pdf_doc_bookmarks_up, pdf_doc_current_page_number,
-    pdf_doc_end_grabbing, pdf_doc_get_dictionary, pdf_doc_set_bgcolor, pdf_doc_set_bop_content,
-    pdf_doc_set_eop_content,
+    pdf_doc_add_signature_field, pdf_doc_begin_actualtext, pdf_doc_begin_article,
+    pdf_doc_begin_artifact, pdf_doc_begin_grabbing, pdf_doc_begin_ocg, pdf_doc_begin_struct,
+    pdf_doc_bookmarks_add, pdf_doc_bookmarks_depth, pdf_doc_bookmarks_down, pdf_doc_bookmarks_up,
+    pdf_doc_close_acroform, pdf_doc_current_page_number, pdf_doc_define_ocg,
+    pdf_doc_end_actualtext, pdf_doc_end_artifact, pdf_doc_end_grabbing, pdf_doc_end_ocg,
+    pdf_doc_end_struct, pdf_doc_get_dictionary, pdf_doc_set_bgcolor, pdf_doc_set_bop_content,
+    pdf_doc_set_eop_content, pdf_doc_set_ocg_visibility,
 };
 use crate::dpx_pdfdraw::{pdf_dev_concat, pdf_dev_grestore, pdf_dev_gsave, pdf_dev_transform};
 use crate::dpx_pdfobj::{
@@ -81,6 +84,8 @@ pub(crate) struct spc_pdf_ {
     pub(crate) lowest_level: i32,
     pub(crate) resourcemap: HashMap<String, resource_map>,
     pub(crate) cd: tounicode,
+    /* Identifiers of the open structure elements, innermost last. */
+    pub(crate) struct_idents: Vec<Option<String>>,
     /* quasi-hack to get the primary input */
     /* For to-UTF16-BE conversion :( */
 }
@@ -95,6 +100,7 @@ impl spc_pdf_ {
                 unescape_backslash: 0,
                 taintkeys: ptr::null_mut(),
             },
+            struct_idents: Vec::new(),
         }
     }
 }
@@ -160,6 +166,7 @@ unsafe fn spc_handler_pdfm__init(sd: &mut spc_pdf_) -> i32 {
     sd.annot_dict = ptr::null_mut();
     sd.lowest_level = 255;
     sd.resourcemap.clear();
+    sd.struct_idents.clear();
     let array: Vec<*mut pdf_obj> = DEFAULT_TAINTKEYS
         .iter()
         .map(|&key| key.into_obj())
@@ -175,6 +182,7 @@ unsafe fn spc_handler_pdfm__clean(sd: &mut spc_pdf_) -> i32 {
     sd.lowest_level = 255;
     sd.annot_dict = ptr::null_mut();
     sd.resourcemap.clear();
+    sd.struct_idents.clear();
     pdf_release_obj(sd.cd.taintkeys);
     sd.cd.taintkeys = ptr::null_mut();
     0i32
@@ -187,6 +195,8 @@ pub(crate) unsafe fn spc_pdfm_at_begin_document() -> i32 {
 
 pub(crate) unsafe fn spc_pdfm_at_end_document() -> i32 {
     let sd = &mut _PDF_STAT;
+    /* The AcroForm may refer to named objects, which are flushed next. */
+    pdf_doc_close_acroform();
     spc_handler_pdfm__clean(sd)
 }
 /* Dvipdfm specials */
@@ -483,6 +493,20 @@ impl ParsePdfDictU for &[u8] {
 }
 
 unsafe fn spc_handler_pdfm_annot(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    pdfm_add_annot(spe, args, false)
+}
+/* Form field:
+ *
+ *   pdf:field @ident width 100pt height 12pt depth 0 << /FT /Tx /T (name) >>
+ *
+ * The same as pdf:annot, but the dictionary is made a widget annotation and
+ * the field ends up in the document's AcroForm. A name like "address.city"
+ * puts the field below a parent field "address".
+ */
+unsafe fn spc_handler_pdfm_field(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    pdfm_add_annot(spe, args, true)
+}
+unsafe fn pdfm_add_annot(spe: &mut SpcEnv, args: &mut SpcArg, is_field: bool) -> i32 {
     let sd = &mut _PDF_STAT;
     let mut rect = Rect::zero();
     let mut ident = None;
@@ -515,6 +539,14 @@ unsafe fn spc_handler_pdfm_annot(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
         pdf_release_obj(annot_dict);
         return -1i32;
     }
+    if is_field {
+        let dict = (*annot_dict).as_dict_mut();
+        if !dict.has("FT") && !dict.has("Parent") {
+            spc_warn!(spe, "Form field without /FT (field type).");
+        }
+        dict.set("Type", "Annot");
+        dict.set("Subtype", "Widget");
+    }
     let mut cp = point2(spe.x_user, spe.y_user);
     pdf_dev_transform(&mut cp, None);
     if ti.flags & 1i32 << 0i32 != 0 {
@@ -576,6 +608,319 @@ unsafe fn spc_handler_pdfm_eann(spe: &mut SpcEnv, _args: &mut SpcArg) -> i32 {
     sd.annot_dict = ptr::null_mut();
     error
 }
+/* Logical structure: "bstruct [@ident] /Role [<<attributes>>]" opens a
+ * structure element, "estruct" closes it. Content of artifacts (running
+ * heads, page numbers...) is enclosed in "bartifact [<<props>>]" and
+ * "eartifact".
+ */
+unsafe fn spc_handler_pdfm_bstruct(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    let sd = &mut _PDF_STAT;
+    let mut ident = None;
+    args.cur.skip_white();
+    if !args.cur.is_empty() && args.cur[0] == b'@' {
+        ident = args.cur.parse_opt_ident();
+        args.cur.skip_white();
+    }
+    let role = if let Some(role) = args.cur.parse_pdf_name() {
+        role
+    } else {
+        spc_warn!(spe, "Structure type expected but not found.");
+        return -1i32;
+    };
+    args.cur.skip_white();
+    let mut attrib = ptr::null_mut();
+    if !args.cur.is_empty() {
+        if let Some(dict) = args.cur.parse_pdf_dict_with_tounicode(&mut sd.cd) {
+            if !(*dict).is_dict() {
+                spc_warn!(spe, "Invalid type: not a dictionary object.");
+                pdf_release_obj(dict);
+                pdf_release_obj(role);
+                return -1i32;
+            }
+            attrib = dict;
+        } else {
+            spc_warn!(spe, "Could not find dictionary object.");
+            pdf_release_obj(role);
+            return -1i32;
+        }
+    }
+    let dict = pdf_doc_begin_struct((*role).as_name().to_bytes(), attrib);
+    if let Some(i) = ident.as_ref() {
+        spc_push_object(i, pdf_link_obj(dict));
+    }
+    sd.struct_idents.push(ident);
+    pdf_release_obj(attrib);
+    pdf_release_obj(role);
+    0i32
+}
+unsafe fn spc_handler_pdfm_estruct(spe: &mut SpcEnv, _args: &mut SpcArg) -> i32 {
+    let sd = &mut _PDF_STAT;
+    if pdf_doc_end_struct() < 0 {
+        spc_warn!(
+            spe,
+            "Tried to end a structure element without starting one!"
+        );
+        return -1i32;
+    }
+    if let Some(Some(ident)) = sd.struct_idents.pop() {
+        spc_flush_object(&ident);
+    }
+    0i32
+}
+unsafe fn spc_handler_pdfm_bartifact(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    args.cur.skip_white();
+    if !args.cur.is_empty() {
+        let mut p = args.cur;
+        if let Some(props) = p.parse_pdf_object(ptr::null_mut()) {
+            let is_dict = (*props).is_dict();
+            pdf_release_obj(props);
+            if !is_dict {
+                spc_warn!(spe, "Invalid type: not a dictionary object.");
+                return -1i32;
+            }
+        } else {
+            spc_warn!(spe, "Could not find dictionary object.");
+            return -1i32;
+        }
+        args.cur = &args.cur[..args.cur.len() - p.len()];
+    }
+    pdf_doc_begin_artifact(args.cur);
+    args.cur = &[];
+    0i32
+}
+unsafe fn spc_handler_pdfm_eartifact(spe: &mut SpcEnv, _args: &mut SpcArg) -> i32 {
+    if pdf_doc_end_artifact() < 0 {
+        spc_warn!(spe, "Tried to end an artifact without starting one!");
+        return -1i32;
+    }
+    0i32
+}
+/* Replacement text: "bactualtext (text)" marks the content up to the
+ * matching "eactualtext" with the given /ActualText, e.g. the TeX source of
+ * an equation.
+ */
+unsafe fn spc_handler_pdfm_bactualtext(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    args.cur.skip_white();
+    let text = match args.cur.parse_pdf_object(ptr::null_mut()) {
+        Some(text) if (*text).is_string() => text,
+        Some(text) => {
+            spc_warn!(spe, "Invalid type: replacement text must be a string.");
+            pdf_release_obj(text);
+            return -1i32;
+        }
+        None => {
+            spc_warn!(spe, "Replacement text expected but not found.");
+            return -1i32;
+        }
+    };
+    if is_xdv != 0 {
+        if let PdfObjVariant::STRING(s) = (*text).data {
+            if maybe_reencode_utf8(s) < 0 {
+                warn!("Failed to convert input string to UTF16...");
+            }
+        }
+    }
+    /* Either UTF-16BE with a byte order mark or PDFDocEncoding, which we
+     * take to be Latin-1. */
+    let bytes = (*text).as_string().to_bytes();
+    let unicodes: Vec<u16> = if bytes.starts_with(b"\xfe\xff") {
+        bytes[2..]
+            .chunks(2)
+            .filter(|c| c.len() == 2)
+            .map(|c| u16::from_be_bytes([c[0], c[1]]))
+            .collect()
+    } else {
+        bytes.iter().map(|&b| b as u16).collect()
+    };
+    pdf_doc_begin_actualtext(&unicodes);
+    pdf_release_obj(text);
+    0i32
+}
+unsafe fn spc_handler_pdfm_eactualtext(spe: &mut SpcEnv, _args: &mut SpcArg) -> i32 {
+    if pdf_doc_end_actualtext() < 0 {
+        spc_warn!(spe, "Tried to end an ActualText span without starting one!");
+        return -1i32;
+    }
+    0i32
+}
+/* Optional content: "ocg @ident (Name) [/ON|/OFF]" declares a layer and
+ * its default visibility, "ocgstate @ident /ON|/OFF" changes the latter.
+ * Content between "bocg @ident" and "eocg" belongs to the layer. The OCG
+ * dictionary can be referred to as @ident, e.g. in the /OC of annotations.
+ */
+unsafe fn parse_ocg_visibility(spe: &mut SpcEnv, args: &mut SpcArg) -> Option<bool> {
+    let state = if let Some(state) = args.cur.parse_pdf_name() {
+        state
+    } else {
+        spc_warn!(spe, "Layer visibility (/ON or /OFF) expected.");
+        return None;
+    };
+    let visible = match (*state).as_name().to_bytes() {
+        b"ON" => Some(true),
+        b"OFF" => Some(false),
+        _ => {
+            spc_warn!(spe, "Layer visibility must be /ON or /OFF.");
+            None
+        }
+    };
+    pdf_release_obj(state);
+    visible
+}
+unsafe fn spc_handler_pdfm_ocg(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    args.cur.skip_white();
+    let ident = if let Some(ident) = args.cur.parse_opt_ident() {
+        ident
+    } else {
+        spc_warn!(spe, "Layer identifier expected but not found.");
+        return -1i32;
+    };
+    args.cur.skip_white();
+    let name = match args.cur.parse_pdf_object(ptr::null_mut()) {
+        Some(name) if (*name).is_string() => name,
+        Some(name) => {
+            spc_warn!(spe, "Invalid type: layer name must be a string.");
+            pdf_release_obj(name);
+            return -1i32;
+        }
+        None => {
+            spc_warn!(spe, "Layer name expected but not found.");
+            return -1i32;
+        }
+    };
+    if is_xdv != 0 {
+        if let PdfObjVariant::STRING(s) = (*name).data {
+            if maybe_reencode_utf8(s) < 0 {
+                warn!("Failed to convert input string to UTF16...");
+            }
+        }
+    }
+    args.cur.skip_white();
+    let mut visible = true;
+    if !args.cur.is_empty() {
+        if let Some(v) = parse_ocg_visibility(spe, args) {
+            visible = v;
+        } else {
+            pdf_release_obj(name);
+            return -1i32;
+        }
+    }
+    let dict = pdf_doc_define_ocg(&ident, name, visible);
+    if dict.is_null() {
+        spc_warn!(spe, "Layer \"{}\" already defined.", ident);
+        pdf_release_obj(name);
+        return -1i32;
+    }
+    spc_push_object(&ident, pdf_link_obj(dict));
+    0i32
+}
+unsafe fn spc_handler_pdfm_ocgstate(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    args.cur.skip_white();
+    let ident = if let Some(ident) = args.cur.parse_opt_ident() {
+        ident
+    } else {
+        spc_warn!(spe, "Layer identifier expected but not found.");
+        return -1i32;
+    };
+    args.cur.skip_white();
+    let visible = if let Some(v) = parse_ocg_visibility(spe, args) {
+        v
+    } else {
+        return -1i32;
+    };
+    if pdf_doc_set_ocg_visibility(&ident, visible) < 0 {
+        spc_warn!(spe, "Layer \"{}\" not defined.", ident);
+        return -1i32;
+    }
+    0i32
+}
+unsafe fn spc_handler_pdfm_bocg(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    args.cur.skip_white();
+    let ident = if let Some(ident) = args.cur.parse_opt_ident() {
+        ident
+    } else {
+        spc_warn!(spe, "Layer identifier expected but not found.");
+        return -1i32;
+    };
+    if pdf_doc_begin_ocg(&ident) < 0 {
+        spc_warn!(spe, "Layer \"{}\" not defined.", ident);
+        return -1i32;
+    }
+    0i32
+}
+unsafe fn spc_handler_pdfm_eocg(spe: &mut SpcEnv, _args: &mut SpcArg) -> i32 {
+    if pdf_doc_end_ocg() < 0 {
+        spc_warn!(spe, "Tried to end a layer without starting one!");
+        return -1i32;
+    }
+    0i32
+}
+/* Signature field:
+ *
+ *   pdf:signature @ident width 0 height 12pt depth 0 << /T (Approval) >>
+ *
+ * Reserves a signature field at the current point. The dictionary is
+ * optional; when signing is enabled the first such field receives the
+ * document signature.
+ */
+unsafe fn spc_handler_pdfm_signature(spe: &mut SpcEnv, args: &mut SpcArg) -> i32 {
+    let sd = &mut _PDF_STAT;
+    let mut rect = Rect::zero();
+    let mut ident = None;
+    args.cur.skip_white();
+    if !args.cur.is_empty() && args.cur[0] == b'@' {
+        ident = args.cur.parse_opt_ident();
+        args.cur.skip_white();
+    }
+
+    let ti = if let Ok(ti) = spc_util_read_dimtrns(spe, args, 0) {
+        ti
+    } else {
+        return -1;
+    };
+
+    if ti.flags & 1i32 << 0i32 != 0
+        && (ti.flags & 1i32 << 1i32 != 0 || ti.flags & 1i32 << 2i32 != 0)
+    {
+        spc_warn!(spe, "You can\'t specify both bbox and width/height.");
+        return -1i32;
+    }
+    args.cur.skip_white();
+    let field = if args.cur.is_empty() {
+        pdf_dict::new().into_obj()
+    } else {
+        match args.cur.parse_pdf_dict_with_tounicode(&mut sd.cd) {
+            Some(field) if (*field).is_dict() => field,
+            Some(field) => {
+                spc_warn!(spe, "Invalid type: not dictionary object.");
+                pdf_release_obj(field);
+                return -1i32;
+            }
+            None => {
+                spc_warn!(spe, "Could not find dictionary object.");
+                return -1i32;
+            }
+        }
+    };
+    let mut cp = point2(spe.x_user, spe.y_user);
+    pdf_dev_transform(&mut cp, None);
+    if ti.flags & 1i32 << 0i32 != 0 {
+        rect = ti.bbox.translate(cp.to_vector());
+    } else {
+        rect.min.x = cp.x;
+        rect.min.y = cp.y - spe.mag * ti.depth;
+        rect.max.x = cp.x + spe.mag * ti.width;
+        rect.max.y = cp.y + spe.mag * ti.height
+    }
+    if let Some(i) = ident.as_ref() {
+        spc_push_object(i, pdf_link_obj(field));
+    }
+    pdf_doc_add_signature_field(&rect, field);
+    if let Some(i) = ident {
+        spc_flush_object(&i);
+    }
+    pdf_release_obj(field);
+    0i32
+}
 /* Color:.... */
 unsafe fn spc_handler_pdfm_bcolor(spe: &mut SpcEnv, ap: &mut SpcArg) -> i32 {
     let (psc, pfc) = pdf_color_get_current();
@@ -1509,7 +1854,7 @@ unsafe fn spc_handler_pdfm_tounicode(spe: &mut SpcEnv, args: &mut SpcArg) -> i32
         -1
     }
 }
-const PDFM_HANDLERS: [SpcHandler; 80] = [
+const PDFM_HANDLERS: [SpcHandler; 92] = [
     SpcHandler {
         key: "annotation",
         exec: Some(spc_handler_pdfm_annot),
@@ -1526,6 +1871,10 @@ const PDFM_HANDLERS: [SpcHandler; 80] = [
         key: "ann",
         exec: Some(spc_handler_pdfm_annot),
     },
+    SpcHandler {
+        key: "field",
+        exec: Some(spc_handler_pdfm_field),
+    },
     SpcHandler {
         key: "outline",
         exec: Some(spc_handler_pdfm_outline),
@@ -1718,6 +2067,50 @@ const PDFM_HANDLERS: [SpcHandler; 80] = [
         key: "eann",
         exec: Some(spc_handler_pdfm_eann),
     },
+    SpcHandler {
+        key: "bstruct",
+        exec: Some(spc_handler_pdfm_bstruct),
+    },
+    SpcHandler {
+        key: "estruct",
+        exec: Some(spc_handler_pdfm_estruct),
+    },
+    SpcHandler {
+        key: "bartifact",
+        exec: Some(spc_handler_pdfm_bartifact),
+    },
+    SpcHandler {
+        key: "eartifact",
+        exec: Some(spc_handler_pdfm_eartifact),
+    },
+    SpcHandler {
+        key: "bactualtext",
+        exec: Some(spc_handler_pdfm_bactualtext),
+    },
+    SpcHandler {
+        key: "eactualtext",
+        exec: Some(spc_handler_pdfm_eactualtext),
+    },
+    SpcHandler {
+        key: "ocg",
+        exec: Some(spc_handler_pdfm_ocg),
+    },
+    SpcHandler {
+        key: "ocgstate",
+        exec: Some(spc_handler_pdfm_ocgstate),
+    },
+    SpcHandler {
+        key: "bocg",
+        exec: Some(spc_handler_pdfm_bocg),
+    },
+    SpcHandler {
+        key: "eocg",
+        exec: Some(spc_handler_pdfm_eocg),
+    },
+    SpcHandler {
+        key: "signature",
+        exec: Some(spc_handler_pdfm_signature),
+    },
     SpcHandler {
         key: "btrans",
         exec: Some(spc_handler_pdfm_btrans),
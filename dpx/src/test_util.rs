@@ -0,0 +1,208 @@
+/* This is dvipdfmx, an eXtended version of dvipdfm by Mark A. Wicks.
+
+    Copyright (C) 2020 by the Tectonic Project
+
+    This program is free software; you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation; either version 2 of the License, or
+    (at your option) any later version.
+
+    This program is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with this program; if not, write to the Free Software
+    Foundation, Inc., 59 Temple Place, Suite 330, Boston, MA 02111-1307 USA.
+*/
+
+//! An in-memory stand-in for the I/O that the Tectonic driver provides, for
+//! the unit tests that read or write files.
+
+use std::collections::HashMap;
+use std::ffi::CStr;
+use std::io::{Cursor, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+
+use crate::bridge::{size_t, ssize_t, tt_bridge_api_t, tt_with_bridge, TTInputFormat};
+
+/* Most of the engine state is global, so only one such test runs at a time. */
+static IO_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+#[derive(Default)]
+struct MemoryFiles {
+    files: HashMap<String, Vec<u8>>,
+}
+
+struct Output {
+    name: String,
+    data: Vec<u8>,
+}
+
+type Input = Cursor<Vec<u8>>;
+
+unsafe fn files<'a>(context: *mut libc::c_void) -> &'a mut MemoryFiles {
+    &mut *(context as *mut MemoryFiles)
+}
+
+unsafe fn name_of(name: *const i8) -> String {
+    CStr::from_ptr(name).to_string_lossy().into_owned()
+}
+
+unsafe fn issue_message(_context: *mut libc::c_void, _text: *const i8) {}
+
+unsafe fn get_file_md5(_context: *mut libc::c_void, _path: *const i8, digest: *mut i8) -> i32 {
+    std::ptr::write_bytes(digest, 0, 16);
+    1
+}
+
+unsafe fn get_data_md5(
+    _context: *mut libc::c_void,
+    _data: *const i8,
+    _len: size_t,
+    digest: *mut i8,
+) -> i32 {
+    std::ptr::write_bytes(digest, 0, 16);
+    1
+}
+
+unsafe fn output_open(
+    _context: *mut libc::c_void,
+    name: *const i8,
+    _is_gz: i32,
+) -> *mut libc::c_void {
+    let output = Output {
+        name: name_of(name),
+        data: Vec::new(),
+    };
+    Box::into_raw(Box::new(output)) as *mut libc::c_void
+}
+
+unsafe fn output_open_stdout(context: *mut libc::c_void) -> *mut libc::c_void {
+    output_open(context, b"\0".as_ptr() as *const i8, 0)
+}
+
+unsafe fn output_putc(_context: *mut libc::c_void, handle: *mut libc::c_void, c: i32) -> i32 {
+    (*(handle as *mut Output)).data.push(c as u8);
+    c
+}
+
+unsafe fn output_write(
+    _context: *mut libc::c_void,
+    handle: *mut libc::c_void,
+    data: *const i8,
+    len: size_t,
+) -> size_t {
+    let data = std::slice::from_raw_parts(data as *const u8, len);
+    (*(handle as *mut Output)).data.extend_from_slice(data);
+    len
+}
+
+unsafe fn output_flush(_context: *mut libc::c_void, _handle: *mut libc::c_void) -> i32 {
+    0
+}
+
+unsafe fn output_close(context: *mut libc::c_void, handle: *mut libc::c_void) -> i32 {
+    let output = Box::from_raw(handle as *mut Output);
+    if !output.name.is_empty() {
+        files(context).files.insert(output.name, output.data);
+    }
+    0
+}
+
+unsafe fn input_open(
+    context: *mut libc::c_void,
+    name: *const i8,
+    _format: TTInputFormat,
+    _is_gz: i32,
+) -> *mut libc::c_void {
+    match files(context).files.get(&name_of(name)) {
+        Some(data) => Box::into_raw(Box::new(Cursor::new(data.clone()))) as *mut libc::c_void,
+        None => std::ptr::null_mut(),
+    }
+}
+
+unsafe fn input_open_primary(_context: *mut libc::c_void) -> *mut libc::c_void {
+    std::ptr::null_mut()
+}
+
+unsafe fn input_get_size(_context: *mut libc::c_void, handle: *mut libc::c_void) -> size_t {
+    (*(handle as *mut Input)).get_ref().len()
+}
+
+unsafe fn input_seek(
+    _context: *mut libc::c_void,
+    handle: *mut libc::c_void,
+    offset: ssize_t,
+    whence: i32,
+    internal_error: *mut i32,
+) -> size_t {
+    let pos = match whence {
+        libc::SEEK_SET => SeekFrom::Start(offset as u64),
+        libc::SEEK_CUR => SeekFrom::Current(offset as i64),
+        libc::SEEK_END => SeekFrom::End(offset as i64),
+        _ => {
+            *internal_error = 1;
+            return 0;
+        }
+    };
+    (*(handle as *mut Input)).seek(pos).unwrap_or(0) as size_t
+}
+
+unsafe fn input_read(
+    _context: *mut libc::c_void,
+    handle: *mut libc::c_void,
+    data: *mut i8,
+    len: size_t,
+) -> Option<usize> {
+    let data = std::slice::from_raw_parts_mut(data as *mut u8, len);
+    (*(handle as *mut Input)).read(data).ok()
+}
+
+unsafe fn input_close(_context: *mut libc::c_void, handle: *mut libc::c_void) -> i32 {
+    drop(Box::from_raw(handle as *mut Input));
+    0
+}
+
+/// Runs `f` with the given files available as inputs, and returns its result
+/// along with every file that is available once it is done, including the
+/// ones it wrote. Returns `None` if `f` panics.
+pub(crate) fn with_memory_io<F, T>(
+    inputs: &[(&str, &[u8])],
+    f: F,
+) -> Option<(T, HashMap<String, Vec<u8>>)>
+where
+    F: std::panic::UnwindSafe + FnOnce() -> T,
+{
+    let _guard = IO_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    let mut files = MemoryFiles::default();
+    for &(name, data) in inputs {
+        files.files.insert(name.to_owned(), data.to_owned());
+    }
+
+    let api = tt_bridge_api_t {
+        context: &mut files as *mut MemoryFiles as *mut libc::c_void,
+        issue_warning: Some(issue_message),
+        issue_error: Some(issue_message),
+        get_file_md5: Some(get_file_md5),
+        get_data_md5: Some(get_data_md5),
+        output_open: Some(output_open),
+        output_open_stdout: Some(output_open_stdout),
+        output_putc: Some(output_putc),
+        output_write: Some(output_write),
+        output_flush: Some(output_flush),
+        output_close: Some(output_close),
+        input_open: Some(input_open),
+        input_open_primary: Some(input_open_primary),
+        input_get_size: Some(input_get_size),
+        input_seek: Some(input_seek),
+        input_read: Some(input_read),
+        input_close: Some(input_close),
+    };
+
+    let result = unsafe { tt_with_bridge(&api, f) }?;
+    Some((result, files.files))
+}
@@ -18,13 +18,17 @@ use bibtex::bibtex_main;
 pub use bibtex::BibtexConfig;
 use bridge::TTHistory;
 use dpx::dvipdfmx_main;
-pub use dpx::XdvipdfmxConfig;
+pub use dpx::{PdfConformance, PdfSignatureConfig, XdvipdfmxConfig};
 use xetex_ini::tt_run_engine;
 
 pub use bridge::tt_bridge_api_t;
 pub use bridge::tt_get_error_message;
 pub use xetex_engine_interface::tt_xetex_set_int_variable;
 
+/// The FreeType bindings that the engine is built with, for the drivers that
+/// draw glyphs themselves.
+pub use freetype;
+
 pub unsafe fn tex_simple_main(
     api: *const tt_bridge_api_t,
     dump_name: &str,
@@ -155,6 +159,7 @@ mod xetex_ini;
 mod xetex_io;
 mod xetex_linebreak;
 mod xetex_math;
+mod xetex_mathml;
 mod xetex_output;
 mod xetex_pagebuilder;
 mod xetex_pic;
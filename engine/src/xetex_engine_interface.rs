@@ -1,8 +1,8 @@
 #![allow(non_camel_case_types, non_snake_case, non_upper_case_globals)]
 
 use crate::xetex_ini::{
-    halt_on_error_p, in_initex_mode, semantic_pagination_enabled, shell_escape_enabled,
-    synctex_enabled,
+    halt_on_error_p, in_initex_mode, math_actual_text_enabled, semantic_pagination_enabled,
+    shell_escape_enabled, synctex_enabled,
 };
 
 /* tectonic/core-strutils.h: miscellaneous C string utilities
@@ -27,6 +27,8 @@ pub unsafe fn tt_xetex_set_int_variable(var_name: &str, value: i32) -> i32 {
         synctex_enabled = (value != 0i32) as i32
     } else if var_name == "semantic_pagination_enabled" {
         semantic_pagination_enabled = value != 0i32
+    } else if var_name == "math_actual_text_enabled" {
+        math_actual_text_enabled = value != 0i32
     } else if var_name == "shell_escape_enabled" {
         shell_escape_enabled = value != 0i32
     } else {
@@ -12,6 +12,7 @@ use crate::trie::*;
 use crate::xetex_consts::*;
 use crate::xetex_errors::{confusion, error, overflow};
 use crate::xetex_layout_interface::{destroy_font_manager, set_cp_code};
+use crate::xetex_math::reset_math_sources;
 use crate::xetex_output::Esc;
 use crate::xetex_pagebuilder::initialize_pagebuilder_variables;
 use crate::xetex_shipout::{deinitialize_shipout_variables, initialize_shipout_variables};
@@ -1011,6 +1012,8 @@ pub(crate) static mut used_tectonic_coda_tokens: bool = false;
 #[no_mangle]
 pub(crate) static mut semantic_pagination_enabled: bool = false;
 #[no_mangle]
+pub(crate) static mut math_actual_text_enabled: bool = false;
+#[no_mangle]
 pub(crate) static mut gave_char_warning_help: bool = false;
 /* These ought to live in xetex-pagebuilder.c but are shared a lot: */
 #[no_mangle]
@@ -3698,6 +3701,7 @@ pub(crate) unsafe fn tt_run_engine(dump_name: &str, input_file_name: &str) -> TT
     /*55:*/
     initialize_pagebuilder_variables();
     initialize_shipout_variables();
+    reset_math_sources();
 
     selector = Selector::TERM_ONLY;
     tally = 0i32;
@@ -10,15 +10,17 @@ use crate::xetex_errors::{confusion, error, Confuse};
 use crate::xetex_ext::{Font, NativeFont::*};
 use crate::xetex_ini::{
     adjust_tail, avail, cur_c, cur_dir, cur_f, cur_group, cur_i, cur_lang, cur_list, input_state_t,
-    insert_src_special_every_math, just_box, memory_word, pre_adjust_tail, total_shrink,
-    xtx_ligature_present, LR_problems, LR_ptr, EQTB, EXTEN_BASE, FONT_BC, FONT_EC, FONT_INFO,
-    FONT_LAYOUT_ENGINE, FONT_PARAMS, KERN_BASE, LIG_KERN_BASE, MEM, NEST_PTR, NULL_CHARACTER,
-    PARAM_BASE, SAVE_PTR, SAVE_STACK, SKEW_CHAR,
+    insert_src_special_every_math, just_box, math_actual_text_enabled, memory_word,
+    pre_adjust_tail, semantic_pagination_enabled, total_shrink, xtx_ligature_present, LR_problems,
+    LR_ptr, EQTB, EXTEN_BASE, FONT_BC, FONT_EC, FONT_INFO, FONT_LAYOUT_ENGINE, FONT_PARAMS,
+    INPUT_PTR, INPUT_STACK, KERN_BASE, LIG_KERN_BASE, MEM, NEST_PTR, NULL_CHARACTER, PARAM_BASE,
+    SAVE_PTR, SAVE_STACK, SKEW_CHAR,
 };
 use crate::xetex_ini::{b16x4, b16x4_le_t};
 use crate::xetex_layout_interface::*;
 use crate::xetex_linebreak::line_break;
-use crate::xetex_output::Esc;
+use crate::xetex_mathml::{bracket_formula, mark_formula, mlist_to_mathml};
+use crate::xetex_output::{Cs, Esc};
 use crate::xetex_pagebuilder::build_page;
 use crate::xetex_scaledmath::{tex_round, x_over_n, xn_over_d, Scaled};
 use crate::xetex_xetex0::{
@@ -90,8 +92,12 @@ static mut cur_style: (MathStyle, u8) = (MathStyle::Display, 0);
 static mut cur_size: usize = 0;
 static mut cur_mu: Scaled = Scaled::ZERO;
 static mut mlist_penalties: bool = false;
+/// The formulas whose source is being recorded when
+/// `math_actual_text_enabled` is set, innermost last.
+static mut MATH_SOURCES: Vec<MathSource> = Vec::new();
 
 pub(crate) unsafe fn init_math(input: &mut input_state_t) {
+    begin_math_source(input);
     let (tok, cmd, ..) = get_token(input);
 
     if cmd == Cmd::MathShift && !cur_list.mode.0 {
@@ -369,6 +375,7 @@ pub(crate) unsafe fn init_math(input: &mut input_state_t) {
             s = Scaled::ZERO
         }
         push_math(GroupCode::MathShift);
+        attach_math_source();
         cur_list.mode = (false, ListMode::MMode);
         eq_word_define(INT_BASE as usize + IntPar::cur_fam as usize, -1i32);
         eq_word_define(
@@ -391,6 +398,7 @@ pub(crate) unsafe fn init_math(input: &mut input_state_t) {
     } else {
         back_input(input, tok);
         push_math(GroupCode::MathShift);
+        attach_math_source();
         eq_word_define(INT_BASE as usize + IntPar::cur_fam as usize, -1);
         if insert_src_special_every_math {
             insert_src_special();
@@ -964,7 +972,7 @@ pub(crate) unsafe fn after_math(input: &mut input_state_t) {
         cur_mlist = p;
         cur_style = (MathStyle::Text, 0);
         mlist_penalties = false;
-        mlist_to_hlist();
+        formula_to_hlist();
         let mut a = hpack(llist_link(TEMP_HEAD), Scaled::ZERO, PackMode::Additional);
         a.set_lr_mode(LRMode::DList);
         unsave(input);
@@ -1025,7 +1033,7 @@ pub(crate) unsafe fn after_math(input: &mut input_state_t) {
         cur_mlist = p;
         cur_style = (MathStyle::Text, 0);
         mlist_penalties = !cur_list.mode.0;
-        mlist_to_hlist();
+        formula_to_hlist();
         *LLIST_link(cur_list.tail) = *LLIST_link(TEMP_HEAD);
         while let Some(next) = LLIST_link(cur_list.tail).opt() {
             cur_list.tail = next;
@@ -1052,7 +1060,7 @@ pub(crate) unsafe fn after_math(input: &mut input_state_t) {
         cur_mlist = p;
         cur_style = (MathStyle::Display, 0);
         mlist_penalties = false;
-        mlist_to_hlist();
+        formula_to_hlist();
         let p = llist_link(TEMP_HEAD);
         adjust_tail = Some(ADJUST_HEAD);
         pre_adjust_tail = Some(PRE_ADJUST_HEAD);
@@ -2551,6 +2559,195 @@ unsafe fn make_left_right(
         _ => unreachable!(),
     }
 }
+/// Where the source of a formula is read from: a file, by its level of
+/// `\input` nesting, or a macro argument, by the head of its token list.
+/// Tokens that come out of macro bodies and other token lists are not
+/// source.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SourceLevel {
+    File(usize),
+    Argument(i32),
+}
+
+impl SourceLevel {
+    fn of(input: &input_state_t) -> Option<SourceLevel> {
+        if input.state != InputState::TokenList {
+            Some(SourceLevel::File(input.index as usize))
+        } else if input.index == Btl::Parameter {
+            Some(SourceLevel::Argument(input.start))
+        } else {
+            None
+        }
+    }
+}
+
+/// A formula whose source is being recorded: the nest level of the list it
+/// will be appended to, where its source is read from, and the source so
+/// far.
+struct MathSource {
+    nest: usize,
+    level: SourceLevel,
+    text: String,
+}
+
+/// Forget about the formulas of a previous run.
+pub(crate) unsafe fn reset_math_sources() {
+    MATH_SOURCES.clear();
+}
+
+/// Start recording the source of a formula, before its first token is read.
+/// The source is read at the innermost level of the input stack that is a
+/// file or a macro argument, so that `$x$` in `\section{$x$}` is recorded
+/// from the argument of `\section`, and `\(x\)` from the file even though
+/// the math shift comes out of the definition of `\(`.
+unsafe fn begin_math_source(input: &input_state_t) {
+    if !math_actual_text_enabled {
+        return;
+    }
+    let level = std::iter::once(input)
+        .chain(INPUT_STACK[..INPUT_PTR].iter().rev())
+        .find_map(SourceLevel::of);
+    if let Some(level) = level {
+        MATH_SOURCES.push(MathSource {
+            nest: usize::MAX,
+            level,
+            text: String::new(),
+        });
+    }
+}
+
+/// Tie the formula whose source is being recorded to the list it belongs
+/// to, once `push_math` has run.
+unsafe fn attach_math_source() {
+    if let Some(source) = MATH_SOURCES.last_mut() {
+        if source.nest == usize::MAX {
+            source.nest = NEST_PTR - 1;
+        }
+    }
+}
+
+/// Append a token to the source of the open formulas that are read from
+/// the level of the input stack it comes from.
+pub(crate) unsafe fn record_math_source(input: &input_state_t, cmd: Cmd, chr: i32, cs: i32) {
+    if MATH_SOURCES.is_empty() {
+        return;
+    }
+    let level = match SourceLevel::of(input) {
+        Some(level) => level,
+        None => return,
+    };
+    let token = if cs != 0 {
+        Cs(cs).to_string()
+    } else if cmd == Cmd::Spacer {
+        " ".to_string()
+    } else if let Some(c) = std::char::from_u32(chr as u32) {
+        c.to_string()
+    } else {
+        return;
+    };
+    for source in MATH_SOURCES.iter_mut().filter(|s| s.level == level) {
+        if token != " " || !source.text.ends_with(' ') {
+            source.text.push_str(&token);
+        }
+    }
+}
+
+/// Strip the `\end{...}` of the environment that ends a formula, as in
+/// `\begin{equation} x \end{equation}`.
+fn strip_end_environment(s: &str) -> &str {
+    if let Some(i) = s.rfind("\\end") {
+        let name = s[i + 4..].trim_start();
+        if name.starts_with('{')
+            && name.ends_with('}')
+            && !name[1..name.len() - 1].contains(|c: char| c == '{' || c == '}')
+        {
+            return &s[..i];
+        }
+    }
+    s
+}
+
+/// Trim what is read as part of a formula but is not its source: the math
+/// shifts around it, and the `\)`, `\]` or `\end{...}` that close it.
+fn trim_math_source(source: &str) -> &str {
+    let mut source = source.trim_start_matches(|c: char| c == '$' || c.is_whitespace());
+    loop {
+        let s = source
+            .trim_end()
+            .trim_end_matches('$')
+            .trim_end_matches("\\)")
+            .trim_end_matches("\\]");
+        let s = strip_end_environment(s);
+        if s.len() == source.len() {
+            break;
+        }
+        source = s;
+    }
+    source
+}
+
+/// The source of the formula being finished, if it was recorded. Entries
+/// of formulas that never reached `after_math` (display alignments, say)
+/// are dropped along the way.
+unsafe fn take_math_source() -> Option<String> {
+    while let Some(source) = MATH_SOURCES.last() {
+        if source.nest <= NEST_PTR {
+            break;
+        }
+        MATH_SOURCES.pop();
+    }
+    match MATH_SOURCES.last() {
+        Some(source) if source.nest == NEST_PTR => {}
+        _ => return None,
+    }
+    let source = MATH_SOURCES.pop()?;
+    let text = trim_math_source(&source.text);
+    if text.is_empty() {
+        None
+    } else {
+        Some(text.to_owned())
+    }
+}
+
+/// Encode `s` as a PDF string in UTF-16BE.
+fn pdf_text_string(s: &str) -> String {
+    let mut r = String::from("<FEFF");
+    for u in s.encode_utf16() {
+        r.push_str(&format!("{:04X}", u));
+    }
+    r.push('>');
+    r
+}
+
+/// Convert the formula `cur_mlist` with `mlist_to_hlist`, recording its
+/// structure as MathML when producing semantic output, and marking it up
+/// with its source when `math_actual_text_enabled` is set.
+unsafe fn formula_to_hlist() {
+    let mathml = if semantic_pagination_enabled {
+        Some(mlist_to_mathml(
+            cur_mlist.opt(),
+            cur_style.0 == MathStyle::Display,
+        ))
+    } else {
+        None
+    };
+    let source = if math_actual_text_enabled {
+        take_math_source()
+    } else {
+        None
+    };
+    mlist_to_hlist();
+    if let Some(mathml) = mathml {
+        mark_formula(&mathml);
+    }
+    if let Some(source) = source {
+        bracket_formula(
+            &format!("pdf:bactualtext {}", pdf_text_string(&source)),
+            "pdf:eactualtext",
+        );
+    }
+}
+
 unsafe fn mlist_to_hlist() {
     let mlist = cur_mlist;
     let penalties = mlist_penalties;
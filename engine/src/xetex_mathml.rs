@@ -0,0 +1,325 @@
+//! MathML transcription of math lists.
+//!
+//! Once `mlist_to_hlist` has run, a formula is nothing but boxes, kerns and
+//! positioned glyphs. When producing semantic (SPX) output we serialize the
+//! math list before that conversion happens and bracket the resulting hlist
+//! with a pair of specials:
+//!
+//! ```text
+//! tectonic:math <math xmlns="..."> ... </math>
+//! ... the typeset formula ...
+//! tectonic:endmath
+//! ```
+//!
+//! so that a backend can emit the markup in place of the individual
+//! characters. The serialized markup is pure ASCII; everything else is
+//! written as character references.
+
+use std::fmt::Write;
+
+use crate::node::*;
+use crate::xetex_consts::{Limit, MATH_FONT, TEMP_HEAD};
+use crate::xetex_ext::Font;
+use crate::xetex_ini::{FONT_LAYOUT_ENGINE, MEM};
+use crate::xetex_scaledmath::Scaled;
+use crate::xetex_xetex0::{get_avail, str_toks_cat_utf8};
+use crate::xetex_xetexd::{llist_link, LLIST_link, TeXInt, TeXOpt};
+
+pub(crate) const MATH_BEGIN_SPECIAL: &str = "tectonic:math ";
+pub(crate) const MATH_END_SPECIAL: &str = "tectonic:endmath";
+
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+
+/// Serialize the math list `mlist` as a `<math>` element.
+pub(crate) unsafe fn mlist_to_mathml(mlist: Option<usize>, display: bool) -> String {
+    let mut m = MathMl {
+        out: String::new(),
+        display,
+    };
+    if display {
+        write!(m.out, "<math xmlns=\"{}\" display=\"block\">", MATHML_NS).unwrap();
+    } else {
+        write!(m.out, "<math xmlns=\"{}\">", MATHML_NS).unwrap();
+    }
+    m.mlist(mlist);
+    m.out.push_str("</math>");
+    m.out
+}
+
+/// Bracket the hlist starting at `TEMP_HEAD`, which `mlist_to_hlist` has
+/// just produced, with the begin and end specials for `mathml`.
+pub(crate) unsafe fn mark_formula(mathml: &str) {
+    bracket_formula(
+        &format!("{}{}", MATH_BEGIN_SPECIAL, mathml),
+        MATH_END_SPECIAL,
+    );
+}
+
+/// Bracket the hlist starting at `TEMP_HEAD` with a pair of specials.
+pub(crate) unsafe fn bracket_formula(begin: &str, end: &str) {
+    let hlist = llist_link(TEMP_HEAD);
+    let begin = new_special(begin);
+    let end = new_special(end);
+
+    *LLIST_link(TEMP_HEAD) = Some(begin).tex_int();
+    let mut tail = begin;
+    *LLIST_link(tail) = hlist.tex_int();
+    while let Some(next) = llist_link(tail) {
+        tail = next;
+    }
+    *LLIST_link(tail) = Some(end).tex_int();
+}
+
+/// Create a detached special node whose contents are `s`. This clobbers
+/// the list at `TEMP_HEAD`.
+unsafe fn new_special(s: &str) -> usize {
+    let mut p = Special::new_node();
+    MEM[p.ptr() + 1].b32.s0 = 0;
+    let r = get_avail();
+    MEM[r].b32.s0 = None.tex_int();
+    str_toks_cat_utf8(s, 0);
+    *LLIST_link(r) = *LLIST_link(TEMP_HEAD);
+    p.set_tokens(r as i32);
+    *LLIST_link(p.ptr()) = None.tex_int();
+    p.ptr()
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    Identifier,
+    Operator,
+}
+
+struct MathMl {
+    out: String,
+    display: bool,
+}
+
+impl MathMl {
+    unsafe fn mlist(&mut self, mut p: Option<usize>) {
+        while let Some(q) = p {
+            match Node::from(q) {
+                Node::Math(MathNode::Fraction) => self.fraction(&Fraction(q)),
+                Node::Math(MathNode::Left) | Node::Math(MathNode::Right) => {
+                    if let Some(c) = delimiter_char(LeftRight(q).delimeter()) {
+                        self.out.push_str("<mo fence=\"true\" stretchy=\"true\">");
+                        self.text(c);
+                        self.out.push_str("</mo>");
+                    }
+                }
+                Node::Math(n) => self.noad(q, n),
+                Node::Text(TxtNode::Choice(c)) => {
+                    if self.display {
+                        self.mlist(c.display());
+                    } else {
+                        self.mlist(c.text());
+                    }
+                }
+                _ => {}
+            }
+            p = llist_link(q);
+        }
+    }
+
+    unsafe fn noad(&mut self, q: usize, typ: MathNode) {
+        let n = BaseMath(q);
+        let sub = n.subscr().typ != MathCell::Empty;
+        let sup = n.supscr().typ != MathCell::Empty;
+        let limits = typ == MathNode::Op
+            && match Operator::from(q).limits() {
+                Limit::Limits => true,
+                Limit::Normal => self.display,
+                Limit::NoLimits => false,
+            };
+
+        let tag = match (sub, sup, limits) {
+            (false, false, _) => None,
+            (true, false, false) => Some("msub"),
+            (false, true, false) => Some("msup"),
+            (true, true, false) => Some("msubsup"),
+            (true, false, true) => Some("munder"),
+            (false, true, true) => Some("mover"),
+            (true, true, true) => Some("munderover"),
+        };
+        if let Some(tag) = tag {
+            write!(self.out, "<{}>", tag).unwrap();
+        }
+
+        match typ {
+            MathNode::Op
+            | MathNode::Bin
+            | MathNode::Rel
+            | MathNode::Open
+            | MathNode::Close
+            | MathNode::Punct => self.cell(n.nucleus(), Token::Operator),
+            MathNode::Radical => {
+                self.out.push_str("<msqrt>");
+                self.cell(n.nucleus(), Token::Identifier);
+                self.out.push_str("</msqrt>");
+            }
+            MathNode::Over => {
+                self.out.push_str("<mover>");
+                self.cell(n.nucleus(), Token::Identifier);
+                self.out.push_str("<mo>&#xAF;</mo></mover>");
+            }
+            MathNode::Under => {
+                self.out.push_str("<munder>");
+                self.cell(n.nucleus(), Token::Identifier);
+                self.out.push_str("<mo>_</mo></munder>");
+            }
+            MathNode::Accent => {
+                let a = Accent::from(q);
+                let tag = match a.accent_type() {
+                    AccentType::Normal | AccentType::Fixed => "mover accent=\"true\"",
+                    AccentType::Bottom | AccentType::BottomFixed => "munder accentunder=\"true\"",
+                };
+                write!(self.out, "<{}>", tag).unwrap();
+                self.cell(n.nucleus(), Token::Identifier);
+                self.cell(a.fourth(), Token::Operator);
+                self.out.push_str(if tag.starts_with("mover") {
+                    "</mover>"
+                } else {
+                    "</munder>"
+                });
+            }
+            _ => self.cell(n.nucleus(), Token::Identifier),
+        }
+
+        if let Some(tag) = tag {
+            if sub {
+                self.cell(n.subscr(), Token::Identifier);
+            }
+            if sup {
+                self.cell(n.supscr(), Token::Identifier);
+            }
+            write!(self.out, "</{}>", tag).unwrap();
+        }
+    }
+
+    unsafe fn fraction(&mut self, f: &Fraction) {
+        let left = delimiter_char(f.left_delimeter());
+        let right = delimiter_char(f.right_delimeter());
+        let fenced = left.is_some() || right.is_some();
+
+        if fenced {
+            self.out.push_str("<mrow>");
+            if let Some(c) = left {
+                self.out.push_str("<mo>");
+                self.text(c);
+                self.out.push_str("</mo>");
+            }
+        }
+        if f.thickness() == Scaled::ZERO {
+            self.out.push_str("<mfrac linethickness=\"0\">");
+        } else {
+            self.out.push_str("<mfrac>");
+        }
+        self.cell(f.numerator(), Token::Identifier);
+        self.cell(f.denumerator(), Token::Identifier);
+        self.out.push_str("</mfrac>");
+        if fenced {
+            if let Some(c) = right {
+                self.out.push_str("<mo>");
+                self.text(c);
+                self.out.push_str("</mo>");
+            }
+            self.out.push_str("</mrow>");
+        }
+    }
+
+    /// Write a field of a noad as exactly one MathML element.
+    unsafe fn cell(&mut self, c: &MCell, kind: Token) {
+        match c.typ {
+            MathCell::Empty => self.out.push_str("<mrow/>"),
+            MathCell::MathChar | MathCell::MathTextChar => {
+                let c = math_char(&c.val.chr);
+                let tag = if kind == Token::Operator {
+                    "mo"
+                } else if c.is_numeric() {
+                    "mn"
+                } else if c.is_alphabetic() {
+                    "mi"
+                } else {
+                    "mo"
+                };
+                write!(self.out, "<{}>", tag).unwrap();
+                self.text(c);
+                write!(self.out, "</{}>", tag).unwrap();
+            }
+            MathCell::SubMList => {
+                self.out.push_str("<mrow>");
+                self.mlist(c.val.ptr.opt());
+                self.out.push_str("</mrow>");
+            }
+            MathCell::SubBox => {
+                let mut s = String::new();
+                hlist_text(&mut s, c.val.ptr.opt());
+                let s = s.trim();
+                if s.is_empty() {
+                    self.out.push_str("<mrow/>");
+                } else {
+                    self.out.push_str("<mtext>");
+                    for c in s.chars() {
+                        self.text(c);
+                    }
+                    self.out.push_str("</mtext>");
+                }
+            }
+        }
+    }
+
+    fn text(&mut self, c: char) {
+        match c {
+            '<' => self.out.push_str("&lt;"),
+            '>' => self.out.push_str("&gt;"),
+            '&' => self.out.push_str("&amp;"),
+            '"' => self.out.push_str("&quot;"),
+            ' '..='~' => self.out.push(c),
+            _ => write!(self.out, "&#x{:X};", c as u32).unwrap(),
+        }
+    }
+}
+
+/// The character of a math character field. Only fonts with a Unicode
+/// encoding can be trusted to map code points to characters, so for
+/// legacy TFM fonts we pass through ASCII letters and digits, plus the
+/// punctuation that the roman family shares with ASCII.
+unsafe fn math_char(m: &MathChar) -> char {
+    let c = std::char::from_u32(m.as_utf32()).unwrap_or('\u{FFFD}');
+    if let Font::Native(_) = &FONT_LAYOUT_ENGINE[MATH_FONT(m.family as usize)] {
+        c
+    } else if c.is_ascii_alphanumeric() || (m.family == 0 && "()[]+=/;:!?,.*'".contains(c)) {
+        c
+    } else {
+        '\u{FFFD}'
+    }
+}
+
+/// The small variant of a delimiter, if it is not a null delimiter.
+unsafe fn delimiter_char(d: &Delimeter) -> Option<char> {
+    if d.is_empty() {
+        None
+    } else if d.chr1.as_utf32() != 0 {
+        Some(math_char(&d.chr1))
+    } else {
+        Some(math_char(&d.chr2))
+    }
+}
+
+/// Collect the text of an hlist, such as the contents of an `\hbox` used
+/// inside a formula.
+unsafe fn hlist_text(s: &mut String, mut p: Option<usize>) {
+    while let Some(q) = p {
+        match Node::from(q) {
+            Node::Char(c) => s.push(std::char::from_u32(c.character() as u32).unwrap_or(' ')),
+            Node::Text(TxtNode::Ligature(l)) => hlist_text(s, l.lig_ptr().opt()),
+            Node::Text(TxtNode::WhatsIt(WhatsIt::NativeWord(nw))) => {
+                s.push_str(&String::from_utf16_lossy(nw.text()))
+            }
+            Node::Text(TxtNode::Glue(_)) => s.push(' '),
+            Node::Text(TxtNode::List(b)) => hlist_text(s, b.list_ptr().opt()),
+            _ => {}
+        }
+        p = llist_link(q);
+    }
+}
@@ -17,8 +17,12 @@ use bridge::{InFile, TTInputFormat};
 use dpx::pdf_dev_transform;
 use dpx::Corner;
 use dpx::{bmp_get_bbox, check_for_bmp};
+use dpx::{check_for_gif, gif_get_bbox};
 use dpx::{check_for_jpeg, jpeg_get_bbox};
 use dpx::{check_for_png, png_get_bbox};
+use dpx::{check_for_svg, svg_get_bbox};
+use dpx::{check_for_tiff, tiff_get_bbox};
+use dpx::{check_for_webp, webp_get_bbox};
 use dpx::{pdf_doc_get_page, pdf_doc_get_page_count};
 use dpx::{pdf_open, pdf_release_obj};
 
@@ -105,13 +109,23 @@ unsafe fn pdf_get_rect(
         Err(())
     }
 }
-unsafe fn get_image_size_in_inches(handle: &mut InFile) -> Result<(f32, f32), i32> {
+unsafe fn get_image_size_in_inches(handle: &mut InFile, page: i32) -> Result<(f32, f32), i32> {
     let (width_pix, height_pix, xdensity, ydensity) = if check_for_jpeg(handle) != 0 {
         jpeg_get_bbox(handle).map_err(|_| -1)?
     } else if check_for_bmp(handle) {
         bmp_get_bbox(handle).map_err(|_| -1)?
     } else if check_for_png(handle) != 0 {
         png_get_bbox(handle).map_err(|_| -1)?
+    } else if check_for_webp(handle) {
+        webp_get_bbox(handle).map_err(|_| -1)?
+    } else if check_for_tiff(handle) {
+        tiff_get_bbox(handle, page).map_err(|_| -1)?
+    } else if check_for_gif(handle) {
+        gif_get_bbox(handle).map_err(|_| -1)?
+    } else if check_for_svg(handle) {
+        /* SVG sizes are already in big points */
+        let (width, height) = svg_get_bbox(handle).map_err(|_| -1)?;
+        return Ok(((width / 72.) as f32, (height / 72.) as f32));
     } else {
         return Err(1);
     };
@@ -139,7 +153,7 @@ unsafe fn find_pic_file(filename: &str, pdfBoxType: i32, page: i32) -> Result<(R
         let name = CString::new(filename).unwrap();
         pdf_get_rect(name.as_ptr(), handle, page, pdfBoxType).map_err(|_| -1)?
     } else {
-        match get_image_size_in_inches(&mut handle) {
+        match get_image_size_in_inches(&mut handle, page) {
             Ok((wd, ht)) => Rect::from_size(size2(
                 (wd as f64 * 72.27) as f32,
                 (ht as f64 * 72.27) as f32,
@@ -165,10 +179,11 @@ pub(crate) unsafe fn load_picture(input: &mut input_state_t, is_pdf: bool) {
     let filename = file.to_string();
     let mut pdf_box_type = 0;
     let mut page = 0;
+    /* Multi-page TIFF images take a page too. */
+    if scan_keyword(input, "page") {
+        page = scan_int(input);
+    }
     if is_pdf {
-        if scan_keyword(input, "page") {
-            page = scan_int(input);
-        }
         pdf_box_type = if scan_keyword(input, "crop") {
             1
         } else if scan_keyword(input, "media") {
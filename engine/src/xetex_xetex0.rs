@@ -39,22 +39,23 @@ use crate::xetex_ini::{
     last_badness, last_glue, last_kern, last_leftmost_char, last_node_type, last_penalty,
     last_rightmost_char, lft_hit, lig_stack, ligature_present, line, lo_mem_max, log_file,
     log_opened, long_help_seen, long_state, mag_set, main_f, main_h, main_i, main_j, main_k,
-    main_s, max_buf_stack, max_print_line, max_reg_help_line, max_reg_num, max_strings, mem_end,
-    name_in_progress, name_of_font, no_new_control_sequence, open_parens, output_active,
-    pack_begin_line, page_contents, page_so_far, page_tail, par_loc, par_token, pdf_last_x_pos,
-    pdf_last_y_pos, pool_ptr, pool_size, pre_adjust_tail, prev_class, prim, prim_eqtb, prim_used,
-    pseudo_files, pstack, read_file, read_open, rover, rt_hit, rust_stdout, sa_chain, sa_level,
-    sa_root, scanner_status, selector, set_box_allowed, shown_mode, skip_line, space_class,
-    stop_at_space, str_pool, str_ptr, str_start, tally, term_offset, texmf_log_name, total_shrink,
-    total_stretch, trick_buf, trick_count, use_err_help, used_tectonic_coda_tokens, warning_index,
-    write_file, write_open, xtx_ligature_present, yhash, LR_problems, LR_ptr, BCHAR_LABEL, BUFFER,
-    BUF_SIZE, EOF_SEEN, EQTB, EQTB_TOP, FONT_AREA, FONT_BC, FONT_BCHAR, FONT_DSIZE, FONT_EC,
-    FONT_FALSE_BCHAR, FONT_GLUE, FONT_INFO, FONT_LAYOUT_ENGINE, FONT_MAPPING, FONT_MAX,
-    FONT_MEM_SIZE, FONT_NAME, FONT_PARAMS, FONT_PTR, FONT_SIZE, FULL_SOURCE_FILENAME_STACK,
-    GRP_STACK, HYPHEN_CHAR, IF_STACK, INPUT_FILE, INPUT_PTR, INPUT_STACK, IN_OPEN, KERN_BASE,
-    LIG_KERN_BASE, LINE_STACK, MAX_IN_OPEN, MAX_IN_STACK, MAX_NEST_STACK, MAX_PARAM_STACK,
-    MAX_SAVE_STACK, MEM, NEST, NEST_PTR, NEST_SIZE, PARAM_BASE, PARAM_PTR, PARAM_SIZE, PARAM_STACK,
-    SAVE_PTR, SAVE_SIZE, SAVE_STACK, SKEW_CHAR, SOURCE_FILENAME_STACK, STACK_SIZE,
+    main_s, math_actual_text_enabled, max_buf_stack, max_print_line, max_reg_help_line,
+    max_reg_num, max_strings, mem_end, name_in_progress, name_of_font, no_new_control_sequence,
+    open_parens, output_active, pack_begin_line, page_contents, page_so_far, page_tail, par_loc,
+    par_token, pdf_last_x_pos, pdf_last_y_pos, pool_ptr, pool_size, pre_adjust_tail, prev_class,
+    prim, prim_eqtb, prim_used, pseudo_files, pstack, read_file, read_open, rover, rt_hit,
+    rust_stdout, sa_chain, sa_level, sa_root, scanner_status, selector, set_box_allowed,
+    shown_mode, skip_line, space_class, stop_at_space, str_pool, str_ptr, str_start, tally,
+    term_offset, texmf_log_name, total_shrink, total_stretch, trick_buf, trick_count, use_err_help,
+    used_tectonic_coda_tokens, warning_index, write_file, write_open, xtx_ligature_present, yhash,
+    LR_problems, LR_ptr, BCHAR_LABEL, BUFFER, BUF_SIZE, EOF_SEEN, EQTB, EQTB_TOP, FONT_AREA,
+    FONT_BC, FONT_BCHAR, FONT_DSIZE, FONT_EC, FONT_FALSE_BCHAR, FONT_GLUE, FONT_INFO,
+    FONT_LAYOUT_ENGINE, FONT_MAPPING, FONT_MAX, FONT_MEM_SIZE, FONT_NAME, FONT_PARAMS, FONT_PTR,
+    FONT_SIZE, FULL_SOURCE_FILENAME_STACK, GRP_STACK, HYPHEN_CHAR, IF_STACK, INPUT_FILE, INPUT_PTR,
+    INPUT_STACK, IN_OPEN, KERN_BASE, LIG_KERN_BASE, LINE_STACK, MAX_IN_OPEN, MAX_IN_STACK,
+    MAX_NEST_STACK, MAX_PARAM_STACK, MAX_SAVE_STACK, MEM, NEST, NEST_PTR, NEST_SIZE, PARAM_BASE,
+    PARAM_PTR, PARAM_SIZE, PARAM_STACK, SAVE_PTR, SAVE_SIZE, SAVE_STACK, SKEW_CHAR,
+    SOURCE_FILENAME_STACK, STACK_SIZE,
 };
 use crate::xetex_ini::{b16x4, memory_word, prefixed_command};
 use crate::xetex_ini::{hash_offset, FONT_LETTER_SPACE};
@@ -63,8 +64,8 @@ use crate::xetex_layout_interface::*;
 use crate::xetex_linebreak::line_break;
 use crate::xetex_math::{
     after_math, append_choices, build_choices, fin_mlist, flush_math, init_math, math_ac,
-    math_fraction, math_left_right, math_limit_switch, math_radical, resume_after_display,
-    start_eq_no, sub_sup,
+    math_fraction, math_left_right, math_limit_switch, math_radical, record_math_source,
+    resume_after_display, start_eq_no, sub_sup,
 };
 use crate::xetex_output::{print_chr, print_esc_cstr, print_ln, SaNum};
 use crate::xetex_pagebuilder::build_page;
@@ -4505,6 +4506,9 @@ pub(crate) unsafe fn get_next(input: &mut input_state_t) -> (Cmd, i32, i32) {
                 fatal_error("(interwoven alignment preambles are not allowed)");
             }
         } else {
+            if math_actual_text_enabled {
+                record_math_source(input, cmd, ochr, cs);
+            }
             return (cmd, ochr, cs);
         }
     }
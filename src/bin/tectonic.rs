@@ -4,25 +4,34 @@
 
 use structopt::StructOpt;
 
+use std::ffi::OsString;
+use std::iter;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
 
 use tectonic::config::PersistentConfig;
-use tectonic::driver::{OutputFormat, PassSetting, ProcessingSessionBuilder};
+use tectonic::driver::{OutputFormat, PassSetting, ProcessingSessionBuilder, StdoutOutputSink};
+use tectonic::engines::PdfConformance;
 use tectonic::errors::{ErrorKind, Result};
 use tectonic::status::plain::PlainStatusBackend;
 use tectonic::status::termcolor::TermcolorStatusBackend;
 use tectonic::status::{ChatterLevel, StatusBackend};
+use tectonic::synctex::SyncTex;
 use tectonic::unstable_opts::{UnstableArg, UnstableOptions};
 use tectonic::{errmsg, tt_error, tt_note};
 
+/// Inputs with these extensions are treated as source archives rather than
+/// TeX files.
+const ARCHIVE_EXTENSIONS: &[&str] = &[".tar.gz", ".tgz", ".tar", ".zip"];
+
 #[derive(Debug, StructOpt)]
 #[structopt(name = "Tectonic", about = "Process a (La)TeX document")]
 struct CliOptions {
-    /// The file to process, or "-" to process the standard input stream
-    #[structopt(name = "input")]
-    input: String,
+    /// The file to process, or "-" to process the standard input stream. Source archives ending
+    /// in .zip, .tar, .tar.gz or .tgz are also accepted
+    #[structopt(name = "input", required_unless = "query")]
+    input: Option<String>,
     /// The name of the "format" file used to initialize the TeX engine
     #[structopt(long, short, name = "path", default_value = "latex")]
     format: String,
@@ -43,11 +52,50 @@ struct CliOptions {
     #[structopt(short = "C", long)]
     only_cached: bool,
     /// The kind of output to generate
-    #[structopt(long, name = "format", default_value = "pdf", possible_values(&["pdf", "html", "xdv", "aux", "fmt"]))]
+    #[structopt(long, name = "format", default_value = "pdf", possible_values(&["pdf", "html", "epub", "svg", "txt", "xdv", "aux", "fmt"]))]
     outfmt: String,
     /// Write Makefile-format rules expressing the dependencies of this run to <dest_path>
     #[structopt(long, name = "dest_path")]
     makefile_rules: Option<PathBuf>,
+    /// After a successful run, write a self-contained source archive of the document to
+    /// <archive_path> (.zip, .tar, .tar.gz or .tgz)
+    #[structopt(long, name = "archive_path", parse(from_os_str))]
+    export_archive: Option<PathBuf>,
+    /// Include the resource files from the bundle in the archive written by --export-archive
+    #[structopt(long)]
+    export_bundle_files: bool,
+    /// After a successful run, write a version of the document with all of its included files
+    /// inlined into a single TeX file at <flat_path>
+    #[structopt(long, name = "flat_path", parse(from_os_str))]
+    flatten: Option<PathBuf>,
+    /// Keep the comments of the source files in the document written by --flatten
+    #[structopt(long)]
+    flatten_keep_comments: bool,
+    /// Make the PDF output conform to this standard, failing if the document cannot
+    #[structopt(long, name = "standard", possible_values(&["a-2b", "x-4"]))]
+    pdf_standard: Option<String>,
+    /// The ICC profile to embed as the output intent of a --pdf-standard document
+    /// [default: sRGB.icc for PDF/A]
+    #[structopt(long, name = "icc_file")]
+    output_intent: Option<String>,
+    /// Write a linearized ("fast web view") PDF
+    #[structopt(long)]
+    linearize: bool,
+    /// Make the PDF smaller by merging duplicate objects and dropping unused resources
+    #[structopt(long)]
+    optimize: bool,
+    /// Sign the PDF output with the PEM certificate in <cert_file>
+    #[structopt(long, name = "cert_file", parse(from_os_str), requires = "key_file")]
+    sign_cert: Option<PathBuf>,
+    /// The PEM private key belonging to the --sign-cert certificate
+    #[structopt(long, name = "key_file", parse(from_os_str), requires = "cert_file")]
+    sign_key: Option<PathBuf>,
+    /// The reason for signing recorded in the PDF signature
+    #[structopt(long, name = "reason", requires = "cert_file")]
+    sign_reason: Option<String>,
+    /// Also render each page to a PNG image at this resolution, in pixels per inch
+    #[structopt(long, name = "dpi")]
+    png_preview: Option<f64>,
     /// Which engines to run
     #[structopt(long, default_value = "default", possible_values(&["default", "tex", "bibtex_first"]))]
     pass: String,
@@ -63,20 +111,76 @@ struct CliOptions {
     /// Generate SyncTeX data
     #[structopt(long)]
     synctex: bool,
+    /// Query the SyncTeX data of a processed document instead of processing one. Pass
+    /// "--synctex-query help" to list the queries
+    #[structopt(
+        long = "synctex-query",
+        name = "query",
+        conflicts_with = "input",
+        min_values = 1,
+        allow_hyphen_values = true,
+        parse(from_os_str)
+    )]
+    synctex_query: Option<Vec<OsString>>,
+    /// Mark up each equation in the PDF output with its TeX source, for screen readers and copy-paste
+    #[structopt(long)]
+    math_actual_text: bool,
     /// Tell the engine that no file at <hide_path> exists, if it tries to read it
     #[structopt(long, name = "hide_path")]
     hide: Option<Vec<PathBuf>>,
     /// Print the engine's chatter during processing
     #[structopt(long = "print", short)]
     print_stdout: bool,
-    /// The directory in which to place output files [default: the directory containing <input>]
+    /// The directory in which to place output files, or "-" to write the main output to the
+    /// standard output stream; multi-page SVG output needs a directory [default: the directory
+    /// containing <input>]
     #[structopt(name = "outdir", short, long, parse(from_os_str))]
     outdir: Option<PathBuf>,
+    /// The main file within a source archive given as <input> [default: detected automatically]
+    #[structopt(long, name = "main_file")]
+    archive_main: Option<String>,
+    /// Write output files atomically, so that an interrupted run never leaves a partial file behind
+    #[structopt(long)]
+    atomic_writes: bool,
     /// Unstable options. Pass -Zhelp to show a list
     // TODO we can't pass -Zhelp without also passing <input>
     #[structopt(name = "option", short = "Z", number_of_values = 1)]
     unstable: Vec<UnstableArg>,
 }
+
+/// The queries of `tectonic --synctex-query`, which looks up the SyncTeX data
+/// of a document that has already been processed.
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "synctex-query",
+    bin_name = "tectonic --synctex-query",
+    about = "Query the SyncTeX data of a processed document"
+)]
+enum SynctexOptions {
+    /// Find the places in the output that were produced by a line of an input file
+    View {
+        /// The output document, such as a PDF, or its .synctex(.gz) file
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// The input file, as it was named to the engine
+        input: String,
+        /// The line of the input file
+        line: i32,
+    },
+    /// Find the line of an input file that produced a point of the output
+    Edit {
+        /// The output document, such as a PDF, or its .synctex(.gz) file
+        #[structopt(parse(from_os_str))]
+        output: PathBuf,
+        /// The number of the page, starting at 1
+        page: u32,
+        /// The horizontal position, in big points from the left edge of the page
+        x: f64,
+        /// The vertical position, in big points from the top edge of the page
+        y: f64,
+    },
+}
+
 fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBackend) -> Result<()> {
     let unstable = UnstableOptions::from_unstable_args(args.unstable.into_iter());
 
@@ -88,9 +192,16 @@ fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBack
         .keep_logs(args.keep_logs)
         .keep_intermediates(args.keep_intermediates)
         .format_cache_path(config.format_cache_path()?)
-        .synctex(args.synctex);
+        .synctex(args.synctex)
+        .math_actual_text(args.math_actual_text)
+        .atomic_writes(args.atomic_writes);
 
-    sess_builder.output_format(OutputFormat::from_str(&args.outfmt).unwrap());
+    if let Some(path) = config.image_cache_path()? {
+        sess_builder.image_cache_path(path);
+    }
+
+    let output_format = OutputFormat::from_str(&args.outfmt).unwrap();
+    sess_builder.output_format(output_format);
 
     let pass = PassSetting::from_str(&args.pass).unwrap();
     sess_builder.pass(pass);
@@ -103,12 +214,55 @@ fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBack
         sess_builder.makefile_output_path(p);
     }
 
+    if let Some(p) = args.export_archive {
+        sess_builder
+            .export_archive_path(p)
+            .export_bundle_files(args.export_bundle_files);
+    }
+
+    if let Some(p) = args.flatten {
+        sess_builder
+            .flattened_output_path(p)
+            .flatten_keep_comments(args.flatten_keep_comments);
+    }
+
+    if let Some(s) = args.pdf_standard {
+        sess_builder.pdf_conformance(match s.as_ref() {
+            "a-2b" => PdfConformance::PdfA2b,
+            "x-4" => PdfConformance::PdfX4,
+            _ => unreachable!(),
+        });
+    }
+
+    if let Some(p) = args.output_intent {
+        sess_builder.output_intent_profile(p);
+    }
+
+    sess_builder.linearize_pdf(args.linearize);
+    sess_builder.optimize_pdf(args.optimize);
+
+    if let Some(dpi) = args.png_preview {
+        sess_builder.png_preview(dpi);
+    }
+
+    if let (Some(cert), Some(key)) = (args.sign_cert, args.sign_key) {
+        sess_builder.sign_pdf(cert, key);
+    }
+
+    if let Some(r) = args.sign_reason {
+        sess_builder.signature_reason(r);
+    }
+
     // Input and path setup
 
-    let input_path = args.input;
+    // `input` is only missing when `--synctex-query` is given.
+    let input_path = args.input.unwrap();
+    let tex_input_name;
+
     if input_path == "-" {
         // Don't provide an input path to the ProcessingSession, so it will default to stdin.
-        sess_builder.tex_input_name("texput.tex");
+        tex_input_name = "texput.tex".to_owned();
+        sess_builder.tex_input_name(&tex_input_name);
         sess_builder.output_dir(Path::new(""));
         tt_note!(
             status,
@@ -116,10 +270,34 @@ fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBack
         );
     } else {
         let input_path = Path::new(&input_path);
-        sess_builder.primary_input_path(input_path);
 
         if let Some(fname) = input_path.file_name() {
-            sess_builder.tex_input_name(&fname.to_string_lossy());
+            let fname = fname.to_string_lossy();
+            let archive_stem_len = ARCHIVE_EXTENSIONS.iter().find_map(|ext| {
+                let n = fname.len().checked_sub(ext.len())?;
+                let suffix = fname.get(n..)?;
+
+                if suffix.eq_ignore_ascii_case(ext) {
+                    Some(n)
+                } else {
+                    None
+                }
+            });
+
+            if let Some(n) = archive_stem_len {
+                // Outputs are named after the archive, not its main file.
+                sess_builder.primary_input_archive(input_path);
+                tex_input_name = format!("{}.tex", &fname[..n]);
+
+                if let Some(ref main) = args.archive_main {
+                    sess_builder.archive_main_file(main);
+                }
+            } else {
+                sess_builder.primary_input_path(input_path);
+                tex_input_name = fname.into_owned();
+            }
+
+            sess_builder.tex_input_name(&tex_input_name);
         } else {
             return Err(errmsg!(
                 "can't figure out a basename for input path \"{}\"",
@@ -138,13 +316,33 @@ fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBack
     }
 
     if let Some(output_dir) = args.outdir {
-        if !output_dir.is_dir() {
+        if output_dir.as_path() == Path::new("-") {
+            if args.print_stdout {
+                return Err(errmsg!(
+                    "cannot print the engine's chatter when writing output to standard output"
+                ));
+            }
+
+            let mut main_output = PathBuf::from(&tex_input_name);
+            main_output.set_extension(match output_format {
+                OutputFormat::Aux => "aux",
+                OutputFormat::Html => "html",
+                OutputFormat::Epub => "epub",
+                OutputFormat::Xdv => "xdv",
+                OutputFormat::Pdf => "pdf",
+                OutputFormat::Svg => "svg",
+                OutputFormat::Txt => "txt",
+                OutputFormat::Format => "fmt",
+            });
+            sess_builder.output_sink(Box::new(StdoutOutputSink::new(main_output)));
+        } else if !output_dir.is_dir() {
             return Err(errmsg!(
                 "output directory \"{}\" does not exist",
                 output_dir.display()
             ));
+        } else {
+            sess_builder.output_dir(output_dir);
         }
-        sess_builder.output_dir(output_dir);
     }
 
     // Set up the rest of I/O.
@@ -188,9 +386,74 @@ fn inner(args: CliOptions, config: PersistentConfig, status: &mut dyn StatusBack
     result
 }
 
+fn synctex(args: SynctexOptions) -> Result<()> {
+    match args {
+        SynctexOptions::View {
+            output,
+            input,
+            line,
+        } => {
+            let boxes = SyncTex::for_output(&output)?.forward(&input, line);
+
+            if boxes.is_empty() {
+                return Err(errmsg!(
+                    "nothing in \"{}\" corresponds to line {} of \"{}\"",
+                    output.display(),
+                    line,
+                    input
+                ));
+            }
+
+            for b in boxes {
+                println!(
+                    "page:{} x:{:.2} y:{:.2} width:{:.2} height:{:.2} depth:{:.2}",
+                    b.page, b.x, b.y, b.width, b.height, b.depth
+                );
+            }
+        }
+
+        SynctexOptions::Edit { output, page, x, y } => {
+            let loc = match SyncTex::for_output(&output)?.inverse(page, x, y) {
+                Some(loc) => loc,
+                None => {
+                    return Err(errmsg!(
+                        "nothing on page {} of \"{}\" corresponds to an input line",
+                        page,
+                        output.display()
+                    ))
+                }
+            };
+
+            if let Some(column) = loc.column {
+                println!("{}:{}:{}", loc.file, loc.line, column);
+            } else {
+                println!("{}:{}", loc.file, loc.line);
+            }
+        }
+    }
+
+    Ok(())
+}
+
 fn main() {
     let args = CliOptions::from_args();
 
+    // A SyncTeX query does not process a document, so it needs none of the
+    // setup below. Its arguments are parsed on their own, as if they were
+    // those of a separate program.
+
+    if let Some(query) = args.synctex_query {
+        let query = iter::once(OsString::from("tectonic --synctex-query")).chain(query);
+        let mut status = PlainStatusBackend::new(ChatterLevel::Minimal);
+
+        if let Err(ref e) = synctex(SynctexOptions::from_iter(query)) {
+            tt_error!(status, ""; e);
+            process::exit(1)
+        }
+
+        return;
+    }
+
     // The Tectonic crate comes with a hidden internal "test mode" that forces
     // it to use a specified set of local files, rather than going to the
     // bundle -- this makes it so that we can run tests without having to go
@@ -226,7 +489,18 @@ fn main() {
     // something I'd be relatively OK with since it'd only affect the progam
     // UI, not the processing results).
 
-    let chatter_level = ChatterLevel::from_str(&args.chatter_level).unwrap();
+    // If the output is going to stdout, we mustn't mix any of our own chatter
+    // in with it.
+
+    let chatter_level = if args
+        .outdir
+        .as_ref()
+        .map_or(false, |p| p.as_path() == Path::new("-"))
+    {
+        ChatterLevel::Minimal
+    } else {
+        ChatterLevel::from_str(&args.chatter_level).unwrap()
+    };
     let use_cli_color = match &*args.cli_color {
         "always" => true,
         "auto" => atty::is(atty::Stream::Stdout),
@@ -29,7 +29,8 @@ use crate::status::StatusBackend;
 /// This is part of the "test mode" described in the `test_util` module. When
 /// test mode is activated in this module, the `default_bundle()` and
 /// `format_cache_path()` functions return results pointing to the test asset
-/// tree, rather than whatever the user has actually configured.
+/// tree, rather than whatever the user has actually configured, and
+/// `image_cache_path()` disables the image cache.
 static CONFIG_TEST_MODE_ACTIVATED: AtomicBool = AtomicBool::new(false);
 
 #[doc(hidden)]
@@ -170,6 +171,15 @@ impl PersistentConfig {
             Ok(app_dirs::user_cache_dir("formats")?)
         }
     }
+
+    pub fn image_cache_path(&self) -> Result<Option<PathBuf>> {
+        if CONFIG_TEST_MODE_ACTIVATED.load(Ordering::SeqCst) {
+            // Test runs should not depend on what earlier ones left behind.
+            Ok(None)
+        } else {
+            Ok(Some(app_dirs::user_cache_dir("images")?))
+        }
+    }
 }
 
 impl Default for PersistentConfig {
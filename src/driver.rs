@@ -14,20 +14,28 @@
 use byte_unit::Byte;
 use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
-use std::fs::File;
-use std::io::Write;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
 use std::str::FromStr;
 
 use crate::digest::DigestData;
-use crate::engines::IoEventBackend;
+use crate::engines::{IoEventBackend, PdfConformance, PdfSignatureConfig};
 use crate::errors::{ErrorKind, Result, ResultExt};
-use crate::io::{Bundle, InputOrigin, IoProvider, IoSetup, IoSetupBuilder, OpenResult};
+use crate::flatten::Flattener;
+use crate::io::archive::{ArchiveBuilder, ArchiveFormat};
+use crate::io::{
+    ArchiveIo, Bundle, InputOrigin, IoProvider, IoSetup, IoSetupBuilder, OpenResult,
+    ProviderPosition,
+};
 use crate::status::StatusBackend;
 use crate::unstable_opts::UnstableOptions;
 use crate::{ctry, errmsg, tt_error, tt_note, tt_warning};
-use crate::{BibtexEngine, Spx2HtmlEngine, TexEngine, TexResult, XdvipdfmxEngine};
+use crate::{
+    BibtexEngine, RasterEngine, Spx2EpubEngine, Spx2HtmlEngine, Spx2TxtEngine, SvgEngine,
+    TexEngine, TexResult, XdvipdfmxEngine,
+};
 use std::result::Result as StdResult;
 
 /// Different patterns with which files may have been accessed by the
@@ -206,10 +214,16 @@ pub enum OutputFormat {
     Aux,
     /// A '.html' file.
     Html,
+    /// An '.epub' book.
+    Epub,
     /// An extended DVI file.
     Xdv,
     /// A '.pdf' file.
     Pdf,
+    /// A '.svg' file for each page.
+    Svg,
+    /// A '.txt' file with the text of the document.
+    Txt,
     /// A '.fmt' file, for initializing the TeX engine.
     Format,
 }
@@ -221,14 +235,25 @@ impl FromStr for OutputFormat {
         match a_str {
             "aux" => Ok(OutputFormat::Aux),
             "html" => Ok(OutputFormat::Html),
+            "epub" => Ok(OutputFormat::Epub),
             "xdv" => Ok(OutputFormat::Xdv),
             "pdf" => Ok(OutputFormat::Pdf),
+            "svg" => Ok(OutputFormat::Svg),
+            "txt" => Ok(OutputFormat::Txt),
             "fmt" => Ok(OutputFormat::Format),
             _ => Err("unsupported or unknown format"),
         }
     }
 }
 
+impl OutputFormat {
+    /// Whether this format is made from the semantically paginated SPX
+    /// output of the engine, rather than from XDV.
+    fn is_semantic(self) -> bool {
+        self == OutputFormat::Html || self == OutputFormat::Epub || self == OutputFormat::Txt
+    }
+}
+
 impl Default for OutputFormat {
     fn default() -> OutputFormat {
         OutputFormat::Pdf
@@ -277,6 +302,9 @@ enum PrimaryInputMode {
 
     /// An in-memory buffer.
     Buffer(Vec<u8>),
+
+    /// The main file of a source archive on the filesystem.
+    Archive(PathBuf),
 }
 
 impl Default for PrimaryInputMode {
@@ -285,8 +313,212 @@ impl Default for PrimaryInputMode {
     }
 }
 
-/// Different places where the output files might land.
+/// The role that a file plays among the outputs of a processing session.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OutputKind {
+    /// A final product of the session, such as the PDF or SyncTeX file.
+    Primary,
+
+    /// A log file emitted by one of the engines (`.log` or `.blg`).
+    Log,
+
+    /// A file that was generated along the way, such as the `.aux` or `.bbl`
+    /// file. These are only handed to the sink if intermediates are kept.
+    Intermediate,
+}
+
+/// A destination for the files produced by a [`ProcessingSession`].
+///
+/// When the session has finished running, it hands each of the files that it
+/// has decided to keep to its sink, one at a time. The default sink,
+/// [`DirectoryOutputSink`], writes them into a directory on the filesystem,
+/// but library users can provide their own implementations to send the files
+/// elsewhere without touching the disk.
+pub trait OutputSink {
+    /// Accept an output file.
+    ///
+    /// The `name` is the name of the file as the engines know it, for instance
+    /// `"paper.pdf"`. The contents of the file should be read out of `data`.
+    fn write_output(
+        &mut self,
+        name: &OsStr,
+        kind: OutputKind,
+        data: &mut dyn Read,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()>;
+
+    /// Get the filesystem path at which the named output file will land.
+    ///
+    /// This is used for status messages and Makefile rules. Sinks that do not
+    /// write to the filesystem should return `None`, which is the default.
+    fn file_path(&self, _name: &OsStr) -> Option<PathBuf> {
+        None
+    }
+}
+
+impl<S: OutputSink + ?Sized> OutputSink for Box<S> {
+    fn write_output(
+        &mut self,
+        name: &OsStr,
+        kind: OutputKind,
+        data: &mut dyn Read,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        (**self).write_output(name, kind, data, status)
+    }
+
+    fn file_path(&self, name: &OsStr) -> Option<PathBuf> {
+        (**self).file_path(name)
+    }
+}
+
+/// An [`OutputSink`] that writes files into a directory on the filesystem.
 #[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirectoryOutputSink {
+    root: PathBuf,
+    atomic: bool,
+}
+
+impl DirectoryOutputSink {
+    /// Create a sink that writes files into the directory `root`.
+    pub fn new<P: AsRef<Path>>(root: P) -> DirectoryOutputSink {
+        DirectoryOutputSink {
+            root: root.as_ref().to_owned(),
+            atomic: false,
+        }
+    }
+
+    /// If set to `true`, each file is first written to a temporary file in the
+    /// output directory, and then renamed into place. This way, a failure
+    /// partway through writing a file will never leave a truncated version
+    /// of it behind.
+    pub fn atomic(&mut self, atomic: bool) -> &mut Self {
+        self.atomic = atomic;
+        self
+    }
+}
+
+impl OutputSink for DirectoryOutputSink {
+    fn write_output(
+        &mut self,
+        name: &OsStr,
+        _kind: OutputKind,
+        data: &mut dyn Read,
+        _status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        let real_path = self.root.join(name);
+
+        if self.atomic {
+            // We don't use the `tempfile` crate here because it creates files
+            // with restrictive permissions, which would then carry over to
+            // the final output.
+            let mut temp_name = OsString::from(".");
+            temp_name.push(real_path.file_name().unwrap_or(name));
+            temp_name.push(format!(".{}.tmp", std::process::id()));
+            let temp_path = real_path.with_file_name(temp_name);
+
+            let result = File::create(&temp_path)
+                .and_then(|mut f| io::copy(data, &mut f).and_then(|_| f.sync_all()))
+                .and_then(|_| fs::rename(&temp_path, &real_path));
+
+            if let Err(e) = result {
+                let _ = fs::remove_file(&temp_path);
+                return Err(e.into());
+            }
+        } else {
+            let mut f = File::create(&real_path)?;
+            io::copy(data, &mut f)?;
+        }
+
+        Ok(())
+    }
+
+    fn file_path(&self, name: &OsStr) -> Option<PathBuf> {
+        Some(self.root.join(name))
+    }
+}
+
+/// An [`OutputSink`] that sends a single, designated output file to this
+/// process’s standard output stream.
+///
+/// Any other files handed to this sink are discarded with a warning. The
+/// one exception is a primary output split into one file per page, as the
+/// SVG engine does for multi-page documents: there is no single stream to
+/// print, so such output is an error.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StdoutOutputSink {
+    name: OsString,
+}
+
+impl StdoutOutputSink {
+    /// Create a sink that will print the output file named `name`.
+    pub fn new<N: AsRef<OsStr>>(name: N) -> StdoutOutputSink {
+        StdoutOutputSink {
+            name: name.as_ref().to_owned(),
+        }
+    }
+
+    /// Whether `name` is one page of the designated file, that is, has the
+    /// form `<stem>-<number>.<ext>` for a designated `<stem>.<ext>`.
+    fn is_page_of_name(&self, name: &OsStr) -> bool {
+        let ours = Path::new(&self.name);
+        let theirs = Path::new(name);
+
+        if theirs.extension() != ours.extension() {
+            return false;
+        }
+
+        let (stem, page) = match (ours.file_stem(), theirs.file_stem()) {
+            (Some(s), Some(p)) => (s.to_string_lossy(), p.to_string_lossy()),
+            _ => return false,
+        };
+
+        if !page.starts_with(&*stem) {
+            return false;
+        }
+
+        let suffix = &page[stem.len()..];
+        suffix.len() > 1
+            && suffix.starts_with('-')
+            && suffix[1..].bytes().all(|b| b.is_ascii_digit())
+    }
+}
+
+impl OutputSink for StdoutOutputSink {
+    fn write_output(
+        &mut self,
+        name: &OsStr,
+        kind: OutputKind,
+        data: &mut dyn Read,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        if kind == OutputKind::Primary && self.is_page_of_name(name) {
+            return Err(errmsg!(
+                "`{}` is one page of a multi-page document, which cannot be written to \
+                 standard output; give an output directory instead",
+                name.to_string_lossy()
+            ));
+        }
+
+        if name != self.name {
+            tt_warning!(
+                status,
+                "not writing `{}`: only `{}` goes to standard output",
+                name.to_string_lossy(),
+                self.name.to_string_lossy()
+            );
+            return Ok(());
+        }
+
+        let stdout = io::stdout();
+        let mut lock = stdout.lock();
+        io::copy(data, &mut lock)?;
+        lock.flush()?;
+        Ok(())
+    }
+}
+
+/// Different places where the output files might land.
 enum OutputDestination {
     /// The "sensible" default. Files will land in the same directory as the
     /// input file, or the current working directory if the input is something
@@ -299,6 +531,9 @@ enum OutputDestination {
     /// Files will not be written to disk. The code running the engine should
     /// examine the memory layer of the I/O stack to obtain the output files.
     Nowhere,
+
+    /// Files will be handed off to a caller-specified sink.
+    Sink(Box<dyn OutputSink>),
 }
 
 impl Default for OutputDestination {
@@ -325,7 +560,24 @@ pub struct ProcessingSessionBuilder {
     keep_intermediates: bool,
     keep_logs: bool,
     synctex: bool,
+    math_actual_text: bool,
     unstables: UnstableOptions,
+    custom_providers: Vec<(ProviderPosition, Box<dyn IoProvider>)>,
+    virtual_files: Vec<(OsString, Vec<u8>)>,
+    atomic_writes: bool,
+    archive_main_file: Option<String>,
+    export_archive_path: Option<PathBuf>,
+    export_bundle_files: bool,
+    flattened_output_path: Option<PathBuf>,
+    flatten_keep_comments: bool,
+    pdf_conformance: PdfConformance,
+    output_intent_profile: Option<String>,
+    linearize_pdf: bool,
+    optimize_pdf: bool,
+    signing_paths: Option<(PathBuf, PathBuf)>,
+    signature_reason: Option<String>,
+    image_cache_path: Option<PathBuf>,
+    png_preview_dpi: Option<f64>,
 }
 
 impl ProcessingSessionBuilder {
@@ -346,6 +598,31 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Sets the primary input to be a source archive (a Zip or tar file,
+    /// possibly gzipped) containing a TeX document.
+    ///
+    /// The archive's contents will be made available to the engines without
+    /// being extracted to disk, and its main file will be detected
+    /// automatically unless specified with [`archive_main_file`]. Outputs
+    /// default to the directory containing the archive. If no
+    /// [`tex_input_name`] is specified, the name of the main file is used.
+    ///
+    /// [`archive_main_file`]: #method.archive_main_file
+    /// [`tex_input_name`]: #method.tex_input_name
+    pub fn primary_input_archive<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.primary_input = PrimaryInputMode::Archive(p.as_ref().to_owned());
+        self
+    }
+
+    /// Sets the path of the main file within a source archive, overriding the
+    /// automatic detection.
+    ///
+    /// This only has an effect if the primary input is an archive.
+    pub fn archive_main_file(&mut self, name: &str) -> &mut Self {
+        self.archive_main_file = Some(name.to_owned());
+        self
+    }
+
     /// Sets the name of the main input file.
     ///
     /// This value will be used to infer the names of the output files; for example, if
@@ -377,6 +654,26 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Hand the output files to a caller-specified sink rather than writing
+    /// them to disk.
+    ///
+    /// The sink receives every file that would otherwise have been written to
+    /// the output directory, tagged with an [`OutputKind`] describing its
+    /// role. Note that Makefile rules cannot be generated in this mode.
+    pub fn output_sink(&mut self, sink: Box<dyn OutputSink>) -> &mut Self {
+        self.output_dest = OutputDestination::Sink(sink);
+        self
+    }
+
+    /// If set to `true`, output files written to the output directory are
+    /// replaced atomically. See [`DirectoryOutputSink::atomic`].
+    ///
+    /// This has no effect if a custom sink is specified.
+    pub fn atomic_writes(&mut self, a: bool) -> &mut Self {
+        self.atomic_writes = a;
+        self
+    }
+
     /// The name of the `.fmt` file used to initialize the TeX engine.
     ///
     /// This file does not necessarily have to exist already; it will be created if it doesn't.
@@ -397,6 +694,15 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Sets the path to the image cache.
+    ///
+    /// Images converted for the PDF output are kept here, so that later runs
+    /// do not need to convert them again. By default, images are not cached.
+    pub fn image_cache_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.image_cache_path = Some(p.as_ref().to_owned());
+        self
+    }
+
     /// The type of output to create.
     pub fn output_format(&mut self, f: OutputFormat) -> &mut Self {
         self.output_format = f;
@@ -416,6 +722,100 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// If set, a self-contained source archive of the document will be written
+    /// out at the given path after a successful run.
+    ///
+    /// See [`ProcessingSession::export_archive`] for details. The archive
+    /// format is determined from the file extension, which must be one of
+    /// `.zip`, `.tar`, `.tar.gz`, or `.tgz`.
+    pub fn export_archive_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.export_archive_path = Some(p.as_ref().to_owned());
+        self
+    }
+
+    /// If set to `true`, the exported source archive will also include the
+    /// files from the bundle that the document used.
+    pub fn export_bundle_files(&mut self, b: bool) -> &mut Self {
+        self.export_bundle_files = b;
+        self
+    }
+
+    /// If set, a flattened version of the document, with all of its included
+    /// files inlined into one, will be written out at the given path after a
+    /// successful run.
+    ///
+    /// See [`ProcessingSession::flatten`] for details.
+    pub fn flattened_output_path<P: AsRef<Path>>(&mut self, p: P) -> &mut Self {
+        self.flattened_output_path = Some(p.as_ref().to_owned());
+        self
+    }
+
+    /// If set to `true`, the flattened document will keep the comments of the
+    /// original source files.
+    pub fn flatten_keep_comments(&mut self, k: bool) -> &mut Self {
+        self.flatten_keep_comments = k;
+        self
+    }
+
+    /// Sets the conformance level that the PDF output must meet, such as
+    /// PDF/A-2b. The run fails if the document cannot be made to conform.
+    pub fn pdf_conformance(&mut self, c: PdfConformance) -> &mut Self {
+        self.pdf_conformance = c;
+        self
+    }
+
+    /// Sets the name of the ICC profile to embed as the PDF's output intent
+    /// when a conformance level is set. PDF/A output falls back to
+    /// `sRGB.icc`; PDF/X output requires a profile to be given.
+    pub fn output_intent_profile<S: Into<String>>(&mut self, name: S) -> &mut Self {
+        self.output_intent_profile = Some(name.into());
+        self
+    }
+
+    /// If set to `true`, the PDF output is linearized ("fast web view"), so
+    /// that the first page can be shown before the whole file is loaded.
+    pub fn linearize_pdf(&mut self, l: bool) -> &mut Self {
+        self.linearize_pdf = l;
+        self
+    }
+
+    /// Also render each page of the document to a PNG image, at the given
+    /// resolution in pixels per inch. This only works for the output
+    /// formats that go through XDV.
+    pub fn png_preview(&mut self, dpi: f64) -> &mut Self {
+        self.png_preview_dpi = Some(dpi);
+        self
+    }
+
+    /// If set to `true`, the PDF output goes through a size optimization
+    /// pass that merges duplicate objects and drops unused resources.
+    pub fn optimize_pdf(&mut self, o: bool) -> &mut Self {
+        self.optimize_pdf = o;
+        self
+    }
+
+    /// Signs the PDF output with the PEM-encoded certificate and private key
+    /// found in the given files. The signature goes into the first field
+    /// reserved with the `pdf:signature` special, or into an invisible field
+    /// on the first page if the document has none.
+    pub fn sign_pdf<P: AsRef<Path>, Q: AsRef<Path>>(
+        &mut self,
+        certificate: P,
+        private_key: Q,
+    ) -> &mut Self {
+        self.signing_paths = Some((
+            certificate.as_ref().to_owned(),
+            private_key.as_ref().to_owned(),
+        ));
+        self
+    }
+
+    /// Sets the reason for signing that is recorded in the PDF signature.
+    pub fn signature_reason<S: Into<String>>(&mut self, reason: S) -> &mut Self {
+        self.signature_reason = Some(reason.into());
+        self
+    }
+
     /// If set, and if the pass is set to `PassSetting::Default`, the TeX engine will be re-run
     /// *exactly* this many times.
     ///
@@ -446,6 +846,27 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// Adds a custom I/O provider that the engines will consult for files.
+    ///
+    /// The `position` determines where the provider sits in the I/O stack,
+    /// and hence which other sources of files it takes precedence over. See
+    /// [`ProviderPosition`] for the details. This can be called multiple
+    /// times to add several providers.
+    pub fn io_provider(&mut self, position: ProviderPosition, p: Box<dyn IoProvider>) -> &mut Self {
+        self.custom_providers.push((position, p));
+        self
+    }
+
+    /// Adds an in-memory "virtual file" that the engines will be able to read.
+    ///
+    /// Virtual files shadow files of the same name on the filesystem and in
+    /// the bundle. This makes it possible to supply `\input` files and other
+    /// resources without writing them to disk first.
+    pub fn virtual_file<N: AsRef<OsStr>>(&mut self, name: N, data: Vec<u8>) -> &mut Self {
+        self.virtual_files.push((name.as_ref().to_owned(), data));
+        self
+    }
+
     /// If set to `true`, various intermediate files will be written out to the filesystem.
     pub fn keep_intermediates(&mut self, k: bool) -> &mut Self {
         self.keep_intermediates = k;
@@ -464,6 +885,14 @@ impl ProcessingSessionBuilder {
         self
     }
 
+    /// If set to `true`, each math formula in the PDF output is marked up
+    /// with its TeX source as `/ActualText`, for screen readers and
+    /// copy-paste.
+    pub fn math_actual_text(&mut self, m: bool) -> &mut Self {
+        self.math_actual_text = m;
+        self
+    }
+
     /// Loads unstable options into the processing session
     pub fn unstables(&mut self, opts: UnstableOptions) -> &mut Self {
         self.unstables = opts;
@@ -471,13 +900,19 @@ impl ProcessingSessionBuilder {
     }
 
     /// Creates a `ProcessingSession`.
-    pub fn create(self, status: &mut dyn StatusBackend) -> Result<ProcessingSession> {
+    pub fn create(mut self, status: &mut dyn StatusBackend) -> Result<ProcessingSession> {
         let mut io = IoSetupBuilder::default();
         io.bundle(self.bundle.expect("a bundle must be specified"))
             .use_genuine_stdout(self.print_stdout);
         for p in &self.hidden_input_paths {
             io.hide_path(p);
         }
+        for (position, p) in self.custom_providers {
+            io.custom_provider(position, p);
+        }
+        for (name, data) in self.virtual_files {
+            io.virtual_file(name, data);
+        }
 
         let (primary_input_path, default_output_path) = match self.primary_input {
             PrimaryInputMode::Path(p) => {
@@ -513,25 +948,91 @@ impl ProcessingSessionBuilder {
                 io.primary_input_buffer(buf);
                 (None, "".into())
             }
+
+            PrimaryInputMode::Archive(p) => {
+                let mut archive = ArchiveIo::open(&p)?;
+
+                if let Some(ref name) = self.archive_main_file {
+                    archive.set_main_file(name)?;
+                }
+
+                tt_note!(
+                    status,
+                    "using \"{}\" as the main file of the source archive",
+                    archive.main_file()
+                );
+
+                if self.tex_input_name.is_none() {
+                    let main = Path::new(archive.main_file());
+                    // The main file name must exist, so unwrap is OK here.
+                    self.tex_input_name = Some(main.file_name().unwrap().to_string_lossy().into());
+                }
+
+                // The archive's contents take precedence over anything lying
+                // around next to it on the filesystem, but not over files
+                // generated by the engines, such as a fresh `.bbl` file.
+                io.primary_input_buffer(archive.main_file_contents().to_owned());
+                io.custom_provider(ProviderPosition::BeforeFilesystem, archive);
+
+                let parent = match p.parent() {
+                    Some(parent) => parent.to_owned(),
+                    None => {
+                        return Err(errmsg!(
+                            "can't figure out a parent directory for archive path \"{}\"",
+                            p.to_string_lossy()
+                        ));
+                    }
+                };
+
+                io.filesystem_root(&parent);
+                (None, parent)
+            }
         };
 
         let output_path = match self.output_dest {
             OutputDestination::Default => Some(default_output_path),
-            OutputDestination::Path(p) => Some(p),
+            OutputDestination::Path(ref p) => Some(p.clone()),
+            OutputDestination::Nowhere | OutputDestination::Sink(_) => None,
+        };
+
+        let output_sink: Option<Box<dyn OutputSink>> = match self.output_dest {
+            OutputDestination::Sink(sink) => Some(sink),
             OutputDestination::Nowhere => None,
+            _ => {
+                // The check above ensures that this is never None.
+                let mut sink = DirectoryOutputSink::new(output_path.as_ref().unwrap());
+                sink.atomic(self.atomic_writes);
+                Some(Box::new(sink))
+            }
         };
 
         if let Some(ref p) = self.format_cache_path {
             io.format_cache_path(p);
         }
 
+        let pdf_signature = match self.signing_paths {
+            Some((ref cert_path, ref key_path)) => {
+                let certificate_pem = ctry!(fs::read(cert_path);
+                    "couldn't read the signing certificate \"{}\"", cert_path.display());
+                let private_key_pem = ctry!(fs::read(key_path);
+                    "couldn't read the signing key \"{}\"", key_path.display());
+                Some(PdfSignatureConfig {
+                    certificate_pem,
+                    private_key_pem,
+                    reason: self.signature_reason.clone(),
+                    ..PdfSignatureConfig::default()
+                })
+            }
+            None => None,
+        };
+
         let tex_input_name = self
             .tex_input_name
             .expect("tex_input_name must be specified");
         let mut aux_path = PathBuf::from(tex_input_name.clone());
         aux_path.set_extension("aux");
         let mut xdv_path = aux_path.clone();
-        xdv_path.set_extension(if self.output_format == OutputFormat::Html {
+        xdv_path.set_extension(if self.output_format.is_semantic() {
             "spx"
         } else {
             "xdv"
@@ -552,10 +1053,23 @@ impl ProcessingSessionBuilder {
             output_format: self.output_format,
             makefile_output_path: self.makefile_output_path,
             output_path,
+            output_sink,
+            export_archive_path: self.export_archive_path,
+            export_bundle_files: self.export_bundle_files,
+            flattened_output_path: self.flattened_output_path,
+            flatten_keep_comments: self.flatten_keep_comments,
+            pdf_conformance: self.pdf_conformance,
+            output_intent_profile: self.output_intent_profile,
+            linearize_pdf: self.linearize_pdf,
+            optimize_pdf: self.optimize_pdf,
+            pdf_signature,
+            image_cache_path: self.image_cache_path,
+            png_preview_dpi: self.png_preview_dpi,
             tex_rerun_specification: self.reruns,
             keep_intermediates: self.keep_intermediates,
             keep_logs: self.keep_logs,
             synctex_enabled: self.synctex,
+            math_actual_text: self.math_actual_text,
             unstables: self.unstables,
         })
     }
@@ -609,15 +1123,49 @@ pub struct ProcessingSession {
     /// to the path of `primary_input_path` or `.` if STDIN is used. If set to
     /// None, the output files will not be saved to disk — in which case, the
     /// caller should access the memory layer of the `io` field to gain access
-    /// to the output files.
+    /// to the output files, or have set up a custom sink.
     output_path: Option<PathBuf>,
 
+    /// This is where the output files are sent once processing has finished.
+    /// If None, the outputs are left in the memory layer of the `io` field.
+    output_sink: Option<Box<dyn OutputSink>>,
+
+    /// If set, a source archive of the document is exported here after a
+    /// successful run.
+    export_archive_path: Option<PathBuf>,
+    export_bundle_files: bool,
+
+    /// If set, a flattened version of the document is written here after a
+    /// successful run.
+    flattened_output_path: Option<PathBuf>,
+    flatten_keep_comments: bool,
+
+    /// The conformance level requested of the PDF output, and the ICC profile
+    /// to use for its output intent.
+    pdf_conformance: PdfConformance,
+    output_intent_profile: Option<String>,
+
+    /// Whether to write the PDF output in linearized form, and whether to
+    /// run the size optimization pass on it.
+    linearize_pdf: bool,
+    optimize_pdf: bool,
+
+    /// The certificate and key to sign the PDF output with, if any.
+    pdf_signature: Option<PdfSignatureConfig>,
+
+    /// Where converted images are cached across runs, if anywhere.
+    image_cache_path: Option<PathBuf>,
+
+    /// The resolution of the PNG page previews to make, if any.
+    png_preview_dpi: Option<f64>,
+
     pass: PassSetting,
     output_format: OutputFormat,
     tex_rerun_specification: Option<usize>,
     keep_intermediates: bool,
     keep_logs: bool,
     synctex_enabled: bool,
+    math_actual_text: bool,
 
     unstables: UnstableOptions,
 }
@@ -810,19 +1358,282 @@ impl ProcessingSession {
             ctry!(writeln!(mf_dest, ""); "couldn't write to Makefile-rules file");
         }
 
+        // Export the source archive, maybe.
+
+        if let Some(p) = self.export_archive_path.take() {
+            let include_bundle_files = self.export_bundle_files;
+            let result = self.export_archive(&p, include_bundle_files, status);
+            self.export_archive_path = Some(p);
+            result?;
+        }
+
+        // Flatten the document, maybe.
+
+        if let Some(p) = self.flattened_output_path.take() {
+            let keep_comments = self.flatten_keep_comments;
+            let result = self.flatten(keep_comments, status).and_then(|text| {
+                let mut f = ctry!(File::create(&p); "couldn't create \"{}\"", p.display());
+                ctry!(f.write_all(text.as_bytes()); "couldn't write \"{}\"", p.display());
+                status.note_highlighted(
+                    "Wrote flattened document ",
+                    &format!("`{}`", p.display()),
+                    "",
+                );
+                Ok(())
+            });
+            self.flattened_output_path = Some(p);
+            result?;
+        }
+
         // All done.
 
         Ok(())
     }
 
+    /// Write out a self-contained source archive of the document.
+    ///
+    /// This should be called after [`ProcessingSession::run`]. The archive
+    /// contains the main input file, every other file that the engines read
+    /// from outside of the bundle, and any `.bbl` files generated by BibTeX,
+    /// so that the document can be compiled elsewhere without running BibTeX.
+    /// If `include_bundle_files` is true, the support files that were pulled
+    /// from the bundle are included as well, with the exception of the
+    /// format file.
+    ///
+    /// Files that were accessed through absolute paths or paths outside of
+    /// the document's directory are left out with a warning, since they
+    /// can't be placed sensibly in the archive. The archive format is
+    /// determined from the extension of `dest`.
+    pub fn export_archive<P: AsRef<Path>>(
+        &mut self,
+        dest: P,
+        include_bundle_files: bool,
+        status: &mut dyn StatusBackend,
+    ) -> Result<()> {
+        let dest = dest.as_ref();
+        let format = match ArchiveFormat::from_file_name(&dest.to_string_lossy()) {
+            Some(f) => f,
+            None => {
+                return Err(errmsg!(
+                    "can't tell what kind of archive to write to \"{}\"; \
+                     use a name ending in .zip, .tar, .tar.gz, or .tgz",
+                    dest.display()
+                ));
+            }
+        };
+
+        let mut archive = ArchiveBuilder::new();
+
+        {
+            let mut stack = self.io.as_stack();
+            let mut data = Vec::new();
+            let mut ih = ctry!(stack.input_open_primary(status).must_exist(); "couldn't reopen the primary input");
+            ih.read_to_end(&mut data)?;
+            archive.add_file(self.primary_input_tex_path.as_str(), data);
+        }
+
+        let mut n_bundle_files = 0;
+
+        for (name, summ) in &self.events.0 {
+            let sname = match name.to_str() {
+                Some(s) => s,
+                None => {
+                    tt_warning!(
+                        status,
+                        "not exporting `{}`: its name is not Unicode",
+                        name.to_string_lossy()
+                    );
+                    continue;
+                }
+            };
+
+            if sname.is_empty() || sname.ends_with(".fmt") {
+                continue; // stdout or the format file
+            }
+
+            // A file that the run wrote is generated, even if the engine
+            // looked for it first: TeX reads the `.bbl` file before BibTeX
+            // writes it.
+            let generated = summ.access_pattern != AccessPattern::Read;
+
+            if generated && !sname.ends_with(".bbl") {
+                continue;
+            }
+
+            if summ.input_origin == InputOrigin::NotInput && !generated {
+                continue; // the engine looked for it, but it didn't exist
+            }
+
+            let path = Path::new(sname);
+
+            if path.is_absolute()
+                || path
+                    .components()
+                    .any(|c| c == std::path::Component::ParentDir)
+            {
+                tt_warning!(
+                    status,
+                    "not exporting `{}`: it is outside of the document directory",
+                    sname
+                );
+                continue;
+            }
+
+            if generated {
+                if let Some(data) = self.io.mem.files.borrow().get(name) {
+                    archive.add_file(sname, data.clone());
+                }
+                continue;
+            }
+
+            let opened = self
+                .io
+                .as_stack_without_bundle()
+                .input_open_name(name, status);
+
+            let mut ih = match opened {
+                OpenResult::Ok(ih) => ih,
+                OpenResult::Err(e) => return Err(e),
+                OpenResult::NotAvailable => {
+                    if !include_bundle_files {
+                        continue;
+                    }
+
+                    let bundle = match self.io.bundle {
+                        Some(ref mut b) => b,
+                        None => continue,
+                    };
+
+                    match bundle.input_open_name(name, status) {
+                        OpenResult::Ok(ih) => {
+                            n_bundle_files += 1;
+                            ih
+                        }
+                        OpenResult::Err(e) => return Err(e),
+                        OpenResult::NotAvailable => continue,
+                    }
+                }
+            };
+
+            let mut data = Vec::new();
+            ih.read_to_end(&mut data)?;
+            archive.add_file(sname, data);
+        }
+
+        let n_files = archive.file_names().count();
+        let mut f = ctry!(File::create(dest); "couldn't create \"{}\"", dest.display());
+        ctry!(archive.write(format, &mut f); "couldn't write source archive \"{}\"", dest.display());
+
+        status.note_highlighted(
+            "Exported ",
+            &format!("`{}`", dest.display()),
+            &format!(
+                " ({} files, of which {} from the bundle)",
+                n_files, n_bundle_files
+            ),
+        );
+
+        Ok(())
+    }
+
+    /// Flatten the document into a single TeX file.
+    ///
+    /// This should be called after [`ProcessingSession::run`]. Every `\input`
+    /// and `\include` in the main input file that the engine actually
+    /// followed is replaced by the contents of the included file, recursively,
+    /// and `\bibliography` is replaced by the contents of the `.bbl` file
+    /// generated by BibTeX. Files that came from the bundle are never inlined.
+    /// See the `flatten` module for the details and limitations.
+    pub fn flatten(
+        &mut self,
+        keep_comments: bool,
+        status: &mut dyn StatusBackend,
+    ) -> Result<String> {
+        let main = {
+            let mut stack = self.io.as_stack();
+            let mut data = Vec::new();
+            let mut ih = ctry!(stack.input_open_primary(status).must_exist(); "couldn't reopen the primary input");
+            ih.read_to_end(&mut data)?;
+            ctry!(String::from_utf8(data); "the primary input is not valid UTF-8")
+        };
+
+        let opened = self
+            .events
+            .0
+            .iter()
+            .filter(|(_, summ)| {
+                summ.access_pattern == AccessPattern::Read
+                    && summ.input_origin != InputOrigin::NotInput
+            })
+            .filter_map(|(name, _)| name.to_str().map(|s| s.to_owned()))
+            .collect::<Vec<_>>();
+
+        let mut bbl_name = PathBuf::from(&self.tex_aux_path);
+        bbl_name.set_extension("bbl");
+        let bbl = self
+            .io
+            .mem
+            .files
+            .borrow()
+            .get(bbl_name.as_os_str())
+            .map(|data| String::from_utf8_lossy(data).into_owned());
+
+        let io = &mut self.io;
+        let mut unreadable = Vec::new();
+
+        let text = {
+            let mut flattener = Flattener::new(opened, |name| {
+                let mut ih = match io
+                    .as_stack_without_bundle()
+                    .input_open_name(OsStr::new(name), status)
+                {
+                    OpenResult::Ok(ih) => ih,
+                    _ => return None,
+                };
+
+                let mut data = Vec::new();
+
+                match ih
+                    .read_to_end(&mut data)
+                    .ok()
+                    .and_then(|_| String::from_utf8(data).ok())
+                {
+                    Some(s) => Some(s),
+                    None => {
+                        unreadable.push(name.to_owned());
+                        None
+                    }
+                }
+            });
+
+            flattener.keep_comments(keep_comments);
+
+            if let Some(bbl) = bbl {
+                flattener.bbl(bbl);
+            }
+
+            flattener.flatten(&main)?
+        };
+
+        for name in unreadable {
+            tt_warning!(
+                status,
+                "not inlining `{}`: it could not be read as UTF-8 text",
+                name
+            );
+        }
+
+        Ok(text)
+    }
+
     fn write_files(
         &mut self,
         mut mf_dest_maybe: Option<&mut File>,
         status: &mut dyn StatusBackend,
         only_logs: bool,
     ) -> Result<u32> {
-        let root = match self.output_path {
-            Some(ref p) => p,
+        let sink = match self.output_sink {
+            Some(ref mut s) => s,
 
             None => {
                 // We were told not to write anything!
@@ -839,6 +1650,10 @@ impl ProcessingSession {
 
             let sname = name.to_string_lossy();
             let summ = self.events.0.get_mut(name).unwrap();
+            let is_intermediate = summ.access_pattern != AccessPattern::Written
+                || ALWAYS_INTERMEDIATE_EXTENSIONS
+                    .iter()
+                    .any(|ext| sname.ends_with(ext));
 
             if !only_logs && (self.output_format == OutputFormat::Aux) {
                 // In this mode we're only writing the .aux file. I initially
@@ -849,12 +1664,7 @@ impl ProcessingSession {
                 if !sname.ends_with(".aux") {
                     continue;
                 }
-            } else if !self.keep_intermediates
-                && (summ.access_pattern != AccessPattern::Written
-                    || ALWAYS_INTERMEDIATE_EXTENSIONS
-                        .iter()
-                        .any(|ext| sname.ends_with(ext)))
-            {
+            } else if !self.keep_intermediates && is_intermediate {
                 n_skipped_intermediates += 1;
                 continue;
             }
@@ -878,16 +1688,33 @@ impl ProcessingSession {
                 continue;
             }
 
-            let real_path = root.join(name);
+            let kind = if is_logfile {
+                OutputKind::Log
+            } else if is_intermediate && self.output_format != OutputFormat::Aux {
+                OutputKind::Intermediate
+            } else {
+                OutputKind::Primary
+            };
+
+            let real_path = sink.file_path(name);
+            let display_name = match real_path {
+                Some(ref p) => p.to_string_lossy(),
+                None => sname.clone(),
+            };
             let byte_len = Byte::from_bytes(contents.len() as u128);
             status.note_highlighted(
                 "Writing ",
-                &format!("`{}`", real_path.to_string_lossy()),
+                &format!("`{}`", display_name),
                 &format!(" ({})", byte_len.get_appropriate_unit(true).to_string()),
             );
 
-            let mut f = File::create(&real_path)?;
-            f.write_all(contents)?;
+            ctry!(sink.write_output(name, kind, &mut contents.as_slice(), status); "couldn't write output file `{}`", display_name);
+
+            let real_path = match real_path {
+                Some(p) => p,
+                None => continue,
+            };
+
             summ.got_written_to_disk = true;
 
             if let Some(ref mut mf_dest) = mf_dest_maybe {
@@ -977,12 +1804,34 @@ impl ProcessingSession {
             tt_warning!(status, "{}", warnings);
         }
 
-        // And finally, xdvipdfmx or spx2html. Maybe.
+        // Page previews are made from the XDV file, so they come before the
+        // final pass gets rid of it.
+
+        if let Some(dpi) = self.png_preview_dpi {
+            match self.output_format {
+                OutputFormat::Pdf | OutputFormat::Svg | OutputFormat::Xdv => {
+                    self.png_preview_pass(dpi, status)?;
+                }
+                _ => tt_warning!(
+                    status,
+                    "PNG page previews can only be made for PDF, SVG or XDV output"
+                ),
+            }
+        }
+
+        // And finally, xdvipdfmx, spx2html, spx2epub, spx2txt or the SVG
+        // renderer. Maybe.
 
         if let OutputFormat::Pdf = self.output_format {
             self.xdvipdfmx_pass(status)?;
         } else if let OutputFormat::Html = self.output_format {
             self.spx2html_pass(status)?;
+        } else if let OutputFormat::Epub = self.output_format {
+            self.spx2epub_pass(status)?;
+        } else if let OutputFormat::Txt = self.output_format {
+            self.spx2txt_pass(status)?;
+        } else if let OutputFormat::Svg = self.output_format {
+            self.svg_pass(status)?;
         }
 
         Ok(0)
@@ -1107,7 +1956,8 @@ impl ProcessingSession {
                 .halt_on_error_mode(true)
                 .initex_mode(self.output_format == OutputFormat::Format)
                 .synctex(self.synctex_enabled)
-                .semantic_pagination(self.output_format == OutputFormat::Html)
+                .semantic_pagination(self.output_format.is_semantic())
+                .math_actual_text(self.math_actual_text)
                 .process(
                     &mut stack,
                     &mut self.events,
@@ -1172,7 +2022,13 @@ impl ProcessingSession {
     fn xdvipdfmx_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
         {
             let mut stack = self.io.as_stack();
-            let mut engine = XdvipdfmxEngine::new();
+            let mut engine = XdvipdfmxEngine::new()
+                .with_conformance(self.pdf_conformance)
+                .with_output_intent_profile(self.output_intent_profile.clone())
+                .with_linearization(self.linearize_pdf)
+                .with_size_optimization(self.optimize_pdf)
+                .with_signature(self.pdf_signature.clone())
+                .with_image_cache(self.image_cache_path.clone());
             status.note_highlighted("Running ", "xdvipdfmx", " ...");
             engine.process(
                 &mut stack,
@@ -1205,6 +2061,71 @@ impl ProcessingSession {
         Ok(0)
     }
 
+    fn spx2epub_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
+        {
+            let mut stack = self.io.as_stack();
+            let mut engine = Spx2EpubEngine::new();
+            status.note_highlighted("Running ", "spx2epub", " ...");
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                status,
+                &self.tex_xdv_path.to_str().unwrap(),
+            )?;
+        }
+
+        self.io.mem.files.borrow_mut().remove(&self.tex_xdv_path);
+        Ok(0)
+    }
+
+    fn spx2txt_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
+        {
+            let mut stack = self.io.as_stack();
+            let mut engine = Spx2TxtEngine::new();
+            status.note_highlighted("Running ", "spx2txt", " ...");
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                status,
+                &self.tex_xdv_path.to_str().unwrap(),
+            )?;
+        }
+
+        self.io.mem.files.borrow_mut().remove(&self.tex_xdv_path);
+        Ok(0)
+    }
+
+    fn svg_pass(&mut self, status: &mut dyn StatusBackend) -> Result<i32> {
+        {
+            let mut stack = self.io.as_stack();
+            let mut engine = SvgEngine::new();
+            status.note_highlighted("Running ", "svg", " ...");
+            engine.process(
+                &mut stack,
+                &mut self.events,
+                status,
+                &self.tex_xdv_path.to_str().unwrap(),
+            )?;
+        }
+
+        self.io.mem.files.borrow_mut().remove(&self.tex_xdv_path);
+        Ok(0)
+    }
+
+    fn png_preview_pass(&mut self, dpi: f64, status: &mut dyn StatusBackend) -> Result<i32> {
+        let mut stack = self.io.as_stack();
+        let mut engine = RasterEngine::new().with_resolution(dpi);
+        status.note_highlighted("Rendering ", "page previews", " ...");
+        engine.process(
+            &mut stack,
+            &mut self.events,
+            status,
+            &self.tex_xdv_path.to_str().unwrap(),
+            &self.unstables,
+        )?;
+        Ok(0)
+    }
+
     /// Consume this session and return the current set of files in memory.
     ///
     /// This convenience function tries to help with the annoyances of getting
@@ -29,17 +29,28 @@ use crate::io::{InputFeatures, InputHandle, InputOrigin, IoProvider, OpenResult,
 use crate::status::StatusBackend;
 use crate::{tt_error, tt_warning};
 
+mod render;
+
 // Public sub-modules and reexports.
 
 pub mod bibtex;
+pub mod raster;
+pub mod spx2epub;
 pub mod spx2html;
+pub mod spx2txt;
+pub mod svg;
 pub mod tex;
 pub mod xdvipdfmx;
 
 pub use self::bibtex::BibtexEngine;
+pub use self::raster::RasterEngine;
+pub use self::spx2epub::Spx2EpubEngine;
 pub use self::spx2html::Spx2HtmlEngine;
+pub use self::spx2txt::Spx2TxtEngine;
+pub use self::svg::SvgEngine;
 pub use self::tex::TexEngine;
 pub use self::xdvipdfmx::XdvipdfmxEngine;
+pub use tectonic_engine::{PdfConformance, PdfSignatureConfig};
 
 #[cfg(unix)]
 fn osstr_from_cstr(s: &CStr) -> Cow<OsStr> {
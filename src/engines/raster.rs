@@ -0,0 +1,967 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Render XDV files to PNG images, one per page.
+//!
+//! This is meant for previews and thumbnails, not as a replacement for the
+//! PDF output. Glyphs of native fonts are rendered with FreeType, rules are
+//! filled, PNG and JPEG images are composited, and the `color`,
+//! `background` and page size specials are honored. Characters of TFM-based
+//! fonts are drawn from the outline fonts that the font map assigns to them,
+//! as xdvipdfmx does.
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::OsStr;
+use std::io::{Cursor, Error as IoError, Read, Write};
+use std::rc::Rc;
+use tectonic_engine::freetype::face::LoadFlag;
+use tectonic_engine::freetype::freetype_sys::{FT_Fixed, FT_Outline_Embolden, FT_Pos};
+use tectonic_engine::freetype::{self, Bitmap, Library, Matrix, RenderMode, Vector};
+use tectonic_xdv::{
+    FileType, FontDefinition, FontNum, NativeFontDefinition, XdvEvents, XdvPageReader,
+};
+
+use super::render::{self, ColorStack, ImageSpecial, Rgb, TfmFont, TfmFontResolver};
+use super::IoEventBackend;
+use crate::errors::{Error, Result};
+use crate::io::{IoProvider, IoStack, OpenResult};
+use crate::status::StatusBackend;
+use crate::unstable_opts::UnstableOptions;
+use crate::{ctry, errmsg, tt_warning};
+
+/// The distance of the origin of the DVI coordinates from the top and left
+/// edges of the page, in big points.
+const DVI_ORIGIN: f64 = 72.;
+
+pub struct RasterEngine {
+    dpi: f64,
+}
+
+impl RasterEngine {
+    pub fn new() -> RasterEngine {
+        RasterEngine { dpi: 96. }
+    }
+
+    /// Set the resolution of the images, in pixels per inch.
+    pub fn with_resolution(mut self, dpi: f64) -> Self {
+        self.dpi = dpi;
+        self
+    }
+
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        xdv: &str,
+        unstables: &UnstableOptions,
+    ) -> Result<()> {
+        if !self.dpi.is_finite() || self.dpi <= 0. {
+            return Err(errmsg!("invalid resolution for page images: {}", self.dpi));
+        }
+
+        let paper = unstables.paper_size.as_ref().map_or("letter", |s| &s[..]);
+        let paper_size = match parse_paper_size(paper) {
+            Some(size) => size,
+            None => {
+                tt_warning!(status, "unknown paper size \"{}\"; using letter", paper);
+                (612., 792.)
+            }
+        };
+
+        let mut input = io.input_open_name(OsStr::new(xdv), status).must_exist()?;
+        events.input_opened(input.name(), input.origin());
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let (name, digest_opt) = input.into_name_digest();
+        events.input_closed(name, digest_opt);
+
+        // FIXME? The engine should probably be responsible for choosing this.
+        let mut stem = xdv.to_owned();
+
+        if xdv.ends_with(".xdv") {
+            stem.truncate(stem.len() - 4);
+        }
+
+        let library = ctry!(Library::init(); "failed to initialize FreeType");
+        let mut resources = Resources::new(library, paper_size);
+        let mut reader = XdvPageReader::new(Cursor::new(data))?;
+        let n_pages = reader.n_pages();
+
+        for page in 0..n_pages {
+            let outname = if n_pages == 1 {
+                format!("{}.png", stem)
+            } else {
+                format!("{}-{}.png", stem, page + 1)
+            };
+
+            let state = State::new(&mut resources, io, events, status);
+            let state = reader.process_page(page, state)?;
+            state.finished(&outname, self.dpi)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for RasterEngine {
+    fn default() -> Self {
+        RasterEngine::new()
+    }
+}
+
+/// Parse a length, such as `210mm`, and return it in big points.
+fn parse_length(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let split = s
+        .find(|c: char| !(c.is_ascii_digit() || c == '.' || c == '-' || c == '+'))
+        .unwrap_or(s.len());
+    let value: f64 = s[..split].parse().ok()?;
+    let mut unit = s[split..].trim();
+
+    if unit.starts_with("true") {
+        unit = &unit[4..];
+    }
+
+    let pt = 72. / 72.27;
+    let dd = 1238. / 1157. * pt;
+
+    let factor = match unit {
+        "pt" => pt,
+        "bp" => 1.,
+        "in" => 72.,
+        "cm" => 72. / 2.54,
+        "mm" => 72. / 25.4,
+        "pc" => 12. * pt,
+        "dd" => dd,
+        "cc" => 12. * dd,
+        "sp" => pt / 65536.,
+        _ => return None,
+    };
+
+    Some(value * factor)
+}
+
+/// Parse a paper size as given to xdvipdfmx: either the name of a common
+/// size, or a width and a height separated by a comma.
+fn parse_paper_size(spec: &str) -> Option<(f64, f64)> {
+    let size = match spec {
+        "letter" => (612., 792.),
+        "legal" => (612., 1008.),
+        "ledger" => (1224., 792.),
+        "tabloid" => (792., 1224.),
+        "executive" => (522., 756.),
+        "a3" => (841.89, 1190.55),
+        "a4" => (595.276, 841.89),
+        "a5" => (419.528, 595.276),
+        "a6" => (297.638, 419.528),
+        "b5" => (498.898, 708.661),
+        _ => {
+            let mut dims = spec.splitn(2, ',');
+            (parse_length(dims.next()?)?, parse_length(dims.next()?)?)
+        }
+    };
+
+    Some(size)
+}
+
+/// A font whose glyphs can be drawn: either a native font, or a TFM-based
+/// font that the font map assigns an outline font to.
+struct Font {
+    face: Option<usize>,
+    size: f64,
+    color: Option<u32>,
+    extend: f64,
+    slant: f64,
+    embolden: f64,
+
+    /// For TFM-based fonts, the glyph of each character code.
+    chars: Option<Vec<u16>>,
+}
+
+/// A decoded image.
+struct Image {
+    /// The size of the image in pixels.
+    columns: usize,
+    rows: usize,
+
+    /// The natural size of the image in big points.
+    width: f64,
+    height: f64,
+
+    /// The pixels of the image, as RGBA.
+    pixels: Vec<u8>,
+}
+
+impl Image {
+    fn decode(data: &[u8]) -> Option<Image> {
+        let (mime, width, height) = render::raster_image_info(data)?;
+
+        let (columns, rows, pixels) = match mime {
+            "image/png" => {
+                let mut decoder = png::Decoder::new(data);
+                decoder.set_transformations(
+                    png::Transformations::EXPAND | png::Transformations::STRIP_16,
+                );
+                let (info, mut reader) = decoder.read_info().ok()?;
+                let mut buf = vec![0; info.buffer_size()];
+                reader.next_frame(&mut buf).ok()?;
+
+                let pixels: Vec<u8> = match reader.output_color_type().0 {
+                    png::ColorType::Grayscale => {
+                        buf.iter().flat_map(|&l| vec![l, l, l, 255]).collect()
+                    }
+                    png::ColorType::GrayscaleAlpha => buf
+                        .chunks(2)
+                        .flat_map(|p| vec![p[0], p[0], p[0], p[1]])
+                        .collect(),
+                    png::ColorType::RGB => buf
+                        .chunks(3)
+                        .flat_map(|p| vec![p[0], p[1], p[2], 255])
+                        .collect(),
+                    png::ColorType::RGBA => buf,
+                    png::ColorType::Indexed => return None,
+                };
+
+                (info.width as usize, info.height as usize, pixels)
+            }
+            "image/jpeg" => {
+                let mut decoder = jpeg_decoder::Decoder::new(data);
+                let buf = decoder.decode().ok()?;
+                let info = decoder.info()?;
+
+                let pixels = match info.pixel_format {
+                    jpeg_decoder::PixelFormat::L8 => {
+                        buf.iter().flat_map(|&l| vec![l, l, l, 255]).collect()
+                    }
+                    jpeg_decoder::PixelFormat::RGB24 => buf
+                        .chunks(3)
+                        .flat_map(|p| vec![p[0], p[1], p[2], 255])
+                        .collect(),
+                    jpeg_decoder::PixelFormat::CMYK32 => buf
+                        .chunks(4)
+                        .flat_map(|p| {
+                            let k = 255 - u32::from(p[3]);
+                            let c = |v: u8| ((255 - u32::from(v)) * k / 255) as u8;
+                            vec![c(p[0]), c(p[1]), c(p[2]), 255]
+                        })
+                        .collect(),
+                };
+
+                (usize::from(info.width), usize::from(info.height), pixels)
+            }
+            _ => return None,
+        };
+
+        if pixels.len() != columns * rows * 4 {
+            return None;
+        }
+
+        Some(Image {
+            columns,
+            rows,
+            width,
+            height,
+            pixels,
+        })
+    }
+}
+
+/// Something to draw on a page. Positions are in big points, relative to
+/// the origin of the DVI coordinates.
+enum Op {
+    Glyphs {
+        font: FontNum,
+        color: Rgb,
+        glyphs: Vec<(u16, f64, f64)>,
+    },
+    Rule {
+        x: f64,
+        y: f64,
+        width: f64,
+        height: f64,
+        color: Rgb,
+    },
+    Image {
+        path: String,
+        x: f64,
+        y: f64,
+        matrix: [f64; 6],
+    },
+}
+
+/// An RGB image that is drawn into.
+struct Canvas {
+    width: usize,
+    height: usize,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    fn new(width: usize, height: usize, background: Rgb) -> Canvas {
+        let mut pixels = Vec::with_capacity(width * height * 3);
+
+        for _ in 0..width * height {
+            pixels.extend_from_slice(&background);
+        }
+
+        Canvas {
+            width,
+            height,
+            pixels,
+        }
+    }
+
+    /// Blend a color into a pixel with the given opacity, from 0 to 255.
+    fn blend(&mut self, x: i64, y: i64, color: Rgb, alpha: u32) {
+        if alpha == 0 || x < 0 || y < 0 || x >= self.width as i64 || y >= self.height as i64 {
+            return;
+        }
+
+        let i = (y as usize * self.width + x as usize) * 3;
+
+        for (dst, &src) in self.pixels[i..i + 3].iter_mut().zip(&color) {
+            let mixed = u32::from(*dst) * (255 - alpha) + u32::from(src) * alpha;
+            *dst = ((mixed + 127) / 255) as u8;
+        }
+    }
+
+    /// Fill a rectangle, given in pixels. Rectangles are made at least one
+    /// pixel wide and high, so that thin rules do not disappear.
+    fn fill_rect(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, color: Rgb) {
+        let (x0, y0) = (x0.round() as i64, y0.round() as i64);
+        let x1 = (x1.round() as i64).max(x0 + 1);
+        let y1 = (y1.round() as i64).max(y0 + 1);
+
+        for y in y0.max(0)..y1.min(self.height as i64) {
+            for x in x0.max(0)..x1.min(self.width as i64) {
+                self.blend(x, y, color, 255);
+            }
+        }
+    }
+
+    /// Draw an anti-aliased glyph bitmap with its top left corner at the
+    /// given pixel.
+    fn draw_coverage(&mut self, left: i64, top: i64, bitmap: &Bitmap, color: Rgb, alpha: u32) {
+        // Blank glyphs such as spaces have no buffer at all.
+        if bitmap.width() <= 0 || bitmap.rows() <= 0 {
+            return;
+        }
+
+        let buffer = bitmap.buffer();
+        let pitch = bitmap.pitch() as usize;
+
+        for row in 0..bitmap.rows() as usize {
+            for column in 0..bitmap.width() as usize {
+                let coverage = u32::from(buffer[row * pitch + column]);
+                self.blend(
+                    left + column as i64,
+                    top + row as i64,
+                    color,
+                    coverage * alpha / 255,
+                );
+            }
+        }
+    }
+
+    /// Draw an image. The matrix maps the pixel coordinates of the image to
+    /// those of the canvas.
+    fn draw_image(&mut self, image: &Image, m: &[f64; 6]) {
+        let det = m[0] * m[3] - m[1] * m[2];
+
+        if det == 0. {
+            return;
+        }
+
+        // The inverse of the matrix, to find the pixel of the image that
+        // covers each pixel of the canvas.
+        let inv = [
+            m[3] / det,
+            -m[1] / det,
+            -m[2] / det,
+            m[0] / det,
+            (m[2] * m[5] - m[3] * m[4]) / det,
+            (m[1] * m[4] - m[0] * m[5]) / det,
+        ];
+
+        let (cols, rows) = (image.columns as f64, image.rows as f64);
+        let corners = [(0., 0.), (cols, 0.), (0., rows), (cols, rows)];
+        let xs = corners.iter().map(|&(u, v)| m[0] * u + m[2] * v + m[4]);
+        let ys = corners.iter().map(|&(u, v)| m[1] * u + m[3] * v + m[5]);
+        let x0 = xs.clone().fold(f64::INFINITY, f64::min).floor().max(0.) as i64;
+        let x1 = xs.fold(f64::NEG_INFINITY, f64::max).ceil() as i64;
+        let y0 = ys.clone().fold(f64::INFINITY, f64::min).floor().max(0.) as i64;
+        let y1 = ys.fold(f64::NEG_INFINITY, f64::max).ceil() as i64;
+
+        for y in y0..y1.min(self.height as i64) {
+            for x in x0..x1.min(self.width as i64) {
+                let (cx, cy) = (x as f64 + 0.5, y as f64 + 0.5);
+                let u = inv[0] * cx + inv[2] * cy + inv[4];
+                let v = inv[1] * cx + inv[3] * cy + inv[5];
+
+                if u < 0. || v < 0. || u >= cols || v >= rows {
+                    continue;
+                }
+
+                let i = (v as usize * image.columns + u as usize) * 4;
+                let p = &image.pixels[i..i + 4];
+                self.blend(x, y, [p[0], p[1], p[2]], u32::from(p[3]));
+            }
+        }
+    }
+
+    fn encode_png(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+
+        {
+            let mut encoder = png::Encoder::new(&mut data, self.width as u32, self.height as u32);
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut writer = encoder.write_header().map_err(IoError::from)?;
+            writer
+                .write_image_data(&self.pixels)
+                .map_err(IoError::from)?;
+        }
+
+        Ok(data)
+    }
+}
+
+/// State that is shared by all of the pages of a file.
+struct Resources {
+    library: Library,
+    faces: Vec<freetype::Face>,
+    face_ids: HashMap<(String, u32), Option<usize>>,
+    fonts: HashMap<FontNum, Font>,
+    tfm_fonts: HashMap<FontNum, Option<TfmFont>>,
+    tfm_resolver: TfmFontResolver,
+    images: HashMap<String, Option<Image>>,
+    colors: ColorStack,
+    default_page_size: (f64, f64),
+    page_size: (f64, f64),
+    warned_specials: HashSet<String>,
+}
+
+impl Resources {
+    fn new(library: Library, page_size: (f64, f64)) -> Resources {
+        Resources {
+            library,
+            faces: Vec::new(),
+            face_ids: HashMap::new(),
+            fonts: HashMap::new(),
+            tfm_fonts: HashMap::new(),
+            tfm_resolver: TfmFontResolver::default(),
+            images: HashMap::new(),
+            colors: ColorStack::default(),
+            default_page_size: page_size,
+            page_size,
+            warned_specials: HashSet::new(),
+        }
+    }
+
+    /// Draw a run of glyphs. The canvas has *k* pixels per big point.
+    fn draw_glyphs(
+        &mut self,
+        canvas: &mut Canvas,
+        k: f64,
+        font_num: FontNum,
+        color: Rgb,
+        glyphs: &[(u16, f64, f64)],
+    ) {
+        let font = match self.fonts.get(&font_num) {
+            Some(font) => font,
+            None => return,
+        };
+
+        let face = match font.face {
+            Some(id) => &mut self.faces[id],
+            None => return,
+        };
+
+        let (color, alpha) = match font.color {
+            Some(rgba) => (
+                [(rgba >> 24) as u8, (rgba >> 16) as u8, (rgba >> 8) as u8],
+                rgba & 0xFF,
+            ),
+            None => (color, 255),
+        };
+
+        if face
+            .set_char_size((font.size * k * 64.).round() as isize, 0, 72, 72)
+            .is_err()
+        {
+            return;
+        }
+
+        for &(gid, x, y) in glyphs {
+            // FreeType takes care of the fractional part of the position.
+            let (x, y) = ((x + DVI_ORIGIN) * k, (y + DVI_ORIGIN) * k);
+            let (left, top) = (x.floor(), y.floor());
+
+            let mut matrix = Matrix {
+                xx: (font.extend * 65536.).round() as FT_Fixed,
+                xy: (font.slant * 65536.).round() as FT_Fixed,
+                yx: 0,
+                yy: 0x10000,
+            };
+            let mut delta = Vector {
+                x: ((x - left) * 64.).round() as FT_Pos,
+                y: (-(y - top) * 64.).round() as FT_Pos,
+            };
+            face.set_transform(&mut matrix, &mut delta);
+
+            if face
+                .load_glyph(u32::from(gid), LoadFlag::NO_HINTING)
+                .is_err()
+            {
+                continue;
+            }
+
+            if font.embolden > 0. {
+                let strength = (font.embolden * k * 64.).round() as FT_Pos;
+
+                unsafe {
+                    FT_Outline_Embolden(&mut (*face.raw_mut().glyph).outline, strength);
+                }
+            }
+
+            let slot = face.glyph();
+
+            if slot.render_glyph(RenderMode::Normal).is_err() {
+                continue;
+            }
+
+            canvas.draw_coverage(
+                left as i64 + i64::from(slot.bitmap_left()),
+                top as i64 - i64::from(slot.bitmap_top()),
+                &slot.bitmap(),
+                color,
+                alpha,
+            );
+        }
+    }
+}
+
+struct State<'a, 'b: 'a> {
+    res: &'a mut Resources,
+    io: &'a mut IoStack<'b>,
+    events: &'a mut dyn IoEventBackend,
+    status: &'a mut dyn StatusBackend,
+    scale: f64,
+    background: Option<Rgb>,
+    ops: Vec<Op>,
+}
+
+impl<'a, 'b: 'a> State<'a, 'b> {
+    pub fn new(
+        res: &'a mut Resources,
+        io: &'a mut IoStack<'b>,
+        events: &'a mut dyn IoEventBackend,
+        status: &'a mut dyn StatusBackend,
+    ) -> Self {
+        Self {
+            res,
+            io,
+            events,
+            status,
+            scale: 0.,
+            background: None,
+            ops: Vec::new(),
+        }
+    }
+
+    /// Load a font file, caching the result.
+    fn load_face(&mut self, path: &str, index: u32) -> Result<Option<usize>> {
+        let key = (path.to_owned(), index);
+
+        if let Some(id) = self.res.face_ids.get(&key) {
+            return Ok(*id);
+        }
+
+        let mut id = None;
+
+        for name in &render::font_file_names(path) {
+            if let Some(data) = render::read_input(self.io, self.events, self.status, name)? {
+                match self
+                    .res
+                    .library
+                    .new_memory_face(Rc::new(data), index as isize)
+                {
+                    Ok(face) => {
+                        self.res.faces.push(face);
+                        id = Some(self.res.faces.len() - 1);
+                    }
+                    Err(e) => tt_warning!(self.status, "failed to load font \"{}\": {}", name, e),
+                }
+
+                break;
+            }
+        }
+
+        if id.is_none() {
+            tt_warning!(
+                self.status,
+                "font \"{}\" not available; its glyphs will not be drawn",
+                path
+            );
+        }
+
+        self.res.face_ids.insert(key, id);
+        Ok(id)
+    }
+
+    /// Convert a length in DVI units to big points.
+    fn bp(&self, dvi: i32) -> f64 {
+        f64::from(dvi) * self.scale
+    }
+
+    fn color(&self) -> Rgb {
+        self.res.colors.current().unwrap_or([0, 0, 0])
+    }
+
+    fn warn_special(&mut self, special: &str) {
+        if self.res.warned_specials.insert(special.to_owned()) {
+            tt_warning!(self.status, "ignoring unsupported special \"{}\"", special);
+        }
+    }
+
+    fn handle_color_special(&mut self, special: &str, args: &str) {
+        if !self.res.colors.apply_special(args) {
+            self.warn_special(special);
+        }
+    }
+
+    fn handle_pagesize_special(&mut self, special: &str, args: &str) {
+        let mut size = self.res.page_size;
+        let mut words = args.split_whitespace();
+
+        while let Some(word) = words.next() {
+            let value = match word {
+                "default" => {
+                    size = self.res.default_page_size;
+                    continue;
+                }
+                "width" | "height" => words.next().and_then(parse_length),
+                _ => None,
+            };
+
+            match (word, value) {
+                ("width", Some(w)) => size.0 = w,
+                ("height", Some(h)) => size.1 = h,
+                _ => {
+                    self.warn_special(special);
+                    return;
+                }
+            }
+        }
+
+        self.res.page_size = size;
+    }
+
+    fn handle_image_special(&mut self, x: i32, y: i32, args: &str) -> Result<()> {
+        let (matrix, path) = match ImageSpecial::parse(args) {
+            Some(image) => (image.matrix, image.path),
+            None => {
+                self.warn_special(&format!("pdf:image {}", args));
+                return Ok(());
+            }
+        };
+
+        if !self.res.images.contains_key(path) {
+            let image = match render::read_input(self.io, self.events, self.status, path)? {
+                Some(data) => {
+                    let image = Image::decode(&data);
+
+                    if image.is_none() {
+                        tt_warning!(
+                            self.status,
+                            "image \"{}\" is not a PNG or JPEG image that can be rendered",
+                            path
+                        );
+                    }
+
+                    image
+                }
+                None => {
+                    tt_warning!(self.status, "image \"{}\" not available", path);
+                    None
+                }
+            };
+
+            self.res.images.insert(path.to_owned(), image);
+        }
+
+        self.ops.push(Op::Image {
+            path: path.to_owned(),
+            x: self.bp(x),
+            y: self.bp(y),
+            matrix,
+        });
+        Ok(())
+    }
+
+    pub fn finished(self, outname: &str, dpi: f64) -> Result<()> {
+        let k = dpi / 72.;
+        let (width, height) = self.res.page_size;
+        let mut canvas = Canvas::new(
+            ((width * k).round() as usize).max(1),
+            ((height * k).round() as usize).max(1),
+            self.background.unwrap_or([255, 255, 255]),
+        );
+
+        for op in &self.ops {
+            match *op {
+                Op::Glyphs {
+                    font,
+                    color,
+                    ref glyphs,
+                } => self.res.draw_glyphs(&mut canvas, k, font, color, glyphs),
+                Op::Rule {
+                    x,
+                    y,
+                    width,
+                    height,
+                    color,
+                } => canvas.fill_rect(
+                    (x + DVI_ORIGIN) * k,
+                    (y - height + DVI_ORIGIN) * k,
+                    (x + width + DVI_ORIGIN) * k,
+                    (y + DVI_ORIGIN) * k,
+                    color,
+                ),
+                Op::Image {
+                    ref path,
+                    x,
+                    y,
+                    matrix,
+                } => {
+                    let image = match self.res.images.get(path) {
+                        Some(Some(image)) => image,
+                        _ => continue,
+                    };
+
+                    // The matrix applies to the image in PDF coordinates,
+                    // with the origin at its lower left corner and the y
+                    // axis pointing up. Combine it with the scaling from
+                    // pixels of the image to big points, and from there to
+                    // pixels of the canvas.
+                    let [a, b, c, d, e, f] = matrix;
+                    let sx = image.width / image.columns as f64;
+                    let sy = image.height / image.rows as f64;
+                    let m = [
+                        a * sx * k,
+                        -b * sx * k,
+                        -c * sy * k,
+                        d * sy * k,
+                        (x + c * image.height + e + DVI_ORIGIN) * k,
+                        (y - d * image.height - f + DVI_ORIGIN) * k,
+                    ];
+                    canvas.draw_image(image, &m);
+                }
+            }
+        }
+
+        let data = canvas.encode_png()?;
+
+        let mut output = match self.io.output_open_name(OsStr::new(outname)) {
+            OpenResult::Ok(h) => h,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no way to write output file \"{}\"", outname));
+            }
+            OpenResult::Err(e) => {
+                return Err(e);
+            }
+        };
+
+        self.events.output_opened(OsStr::new(outname));
+        output.write_all(&data)?;
+        let (name, digest) = output.into_name_digest();
+        self.events.output_closed(name, digest);
+        Ok(())
+    }
+}
+
+impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
+    type Error = Error;
+
+    fn handle_preamble(&mut self, filetype: FileType, mag: u32, _comment: &[u8]) -> Result<()> {
+        if filetype != FileType::Xdv {
+            return Err(errmsg!("file should be XDV format but got {}", filetype));
+        }
+
+        self.scale = render::dvi_unit_in_bp(mag);
+        Ok(())
+    }
+
+    fn handle_define_font(&mut self, font_num: FontNum, font: &FontDefinition) -> Result<()> {
+        if self.res.tfm_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let name = format!("{}.tfm", font.name);
+        let tfm = match render::read_input(self.io, self.events, self.status, &name)? {
+            Some(data) => TfmFont::parse(&data, font.scale_factor),
+            None => None,
+        };
+
+        if tfm.is_none() {
+            tt_warning!(self.status, "failed to load font metrics \"{}\"", name);
+        }
+
+        self.res.tfm_fonts.insert(font_num, tfm);
+
+        let (entry, encoding) =
+            match self
+                .res
+                .tfm_resolver
+                .resolve(self.io, self.events, self.status, &font.name)?
+            {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+
+        let face = self.load_face(&entry.font_file, 0)?;
+        let chars = face.map(|id| {
+            render::tfm_glyph_table(&mut self.res.faces[id], encoding.as_ref().map(|e| &e[..]))
+        });
+
+        self.res.fonts.insert(
+            font_num,
+            Font {
+                face,
+                size: self.bp(font.scale_factor as i32),
+                color: None,
+                extend: entry.extend,
+                slant: entry.slant,
+                embolden: 0.,
+                chars,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<()> {
+        if self.res.fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let face = self.load_face(&font.path, font.face_index)?;
+        let size = self.bp(font.size);
+
+        self.res.fonts.insert(
+            font_num,
+            Font {
+                face,
+                size,
+                color: font.color_rgba,
+                extend: f64::from(font.extend.unwrap_or(0x10000)) / 65536.,
+                slant: f64::from(font.slant.unwrap_or(0)) / 65536.,
+                embolden: f64::from(font.embolden.unwrap_or(0)) / 65536.,
+                chars: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn char_width(&mut self, font_num: FontNum, char_num: i32) -> i32 {
+        match self.res.tfm_fonts.get(&font_num) {
+            Some(Some(tfm)) => tfm.width(char_num),
+            _ => 0,
+        }
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let table = match self.res.fonts.get(&font_num) {
+            Some(Font {
+                chars: Some(table), ..
+            }) => table,
+            _ => return Ok(()),
+        };
+
+        let glyphs = chars
+            .iter()
+            .enumerate()
+            .filter_map(|(i, &c)| {
+                let gid = *table.get(c as usize).filter(|&&gid| gid != 0)?;
+                Some((gid, self.bp(x[i]), self.bp(y[i])))
+            })
+            .collect();
+
+        self.ops.push(Op::Glyphs {
+            font: font_num,
+            color: self.color(),
+            glyphs,
+        });
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        _width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let glyphs = glyphs
+            .iter()
+            .enumerate()
+            .map(|(i, &gid)| (gid, self.bp(x[i]), self.bp(y[i])))
+            .collect();
+
+        self.ops.push(Op::Glyphs {
+            font: font_num,
+            color: self.color(),
+            glyphs,
+        });
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<()> {
+        if height <= 0 || width <= 0 {
+            return Ok(());
+        }
+
+        self.ops.push(Op::Rule {
+            x: self.bp(x),
+            y: self.bp(y),
+            width: self.bp(width),
+            height: self.bp(height),
+            color: self.color(),
+        });
+        Ok(())
+    }
+
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<()> {
+        let special = String::from_utf8_lossy(contents);
+        let special = special.trim();
+        let mut words = special.splitn(2, &[' ', '='][..]);
+
+        match (words.next(), words.next()) {
+            (Some("color"), Some(args)) => self.handle_color_special(special, args),
+            (Some("background"), Some(args)) => match render::parse_color(args) {
+                Some(c) => self.background = Some(c),
+                None => self.warn_special(special),
+            },
+            (Some("papersize"), Some(args)) => match parse_paper_size(args) {
+                Some(size) => self.res.page_size = size,
+                None => self.warn_special(special),
+            },
+            (Some("pdf:pagesize"), Some(args)) => self.handle_pagesize_special(special, args),
+            (Some("pdf:image"), Some(args)) => self.handle_image_special(x, y, args)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,640 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Helpers for the engines that interpret the pages of XDV files themselves,
+//! instead of handing them over to xdvipdfmx.
+
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io::Read;
+use std::rc::Rc;
+use tectonic_engine::freetype;
+use tectonic_engine::freetype::freetype_sys::{
+    FT_Get_Name_Index, FT_Set_Charmap, FT_ENCODING_ADOBE_CUSTOM, FT_ENCODING_ADOBE_EXPERT,
+    FT_ENCODING_ADOBE_LATIN_1, FT_ENCODING_ADOBE_STANDARD,
+};
+
+use super::IoEventBackend;
+use crate::errors::Result;
+use crate::io::{IoProvider, IoStack, OpenResult};
+use crate::status::StatusBackend;
+use crate::tt_warning;
+
+/// The font map that xdvipdfmx consults to find the font files of TFM-based
+/// fonts.
+const FONT_MAP: &str = "pdftex.map";
+
+/// Get the size of a DVI unit in big points, given the magnification of the
+/// file. XDV units are scaled points.
+pub(crate) fn dvi_unit_in_bp(mag: u32) -> f64 {
+    f64::from(mag) / 1000. * 72. / 72.27 / 65536.
+}
+
+/// Read a whole input file, if it exists.
+pub(crate) fn read_input(
+    io: &mut IoStack,
+    events: &mut dyn IoEventBackend,
+    status: &mut dyn StatusBackend,
+    name: &str,
+) -> Result<Option<Vec<u8>>> {
+    let mut input = match io.input_open_name(OsStr::new(name), status) {
+        OpenResult::Ok(h) => h,
+        OpenResult::NotAvailable => return Ok(None),
+        OpenResult::Err(e) => return Err(e),
+    };
+
+    events.input_opened(input.name(), input.origin());
+    let mut data = Vec::new();
+    input.read_to_end(&mut data)?;
+    let (name, digest_opt) = input.into_name_digest();
+    events.input_closed(name, digest_opt);
+    Ok(Some(data))
+}
+
+/// Get the names under which the file of a native font may be found. XeTeX
+/// records the names of some fonts without their extensions.
+pub(crate) fn font_file_names(path: &str) -> Vec<String> {
+    let mut names = vec![path.to_owned()];
+
+    if !path.rsplit('/').next().unwrap_or(path).contains('.') {
+        for ext in &["otf", "ttf", "ttc", "OTF", "TTF", "TTC", "dfont"] {
+            names.push(format!("{}.{}", path, ext));
+        }
+    }
+
+    names
+}
+
+/// The widths of the characters of a TFM-based font, in DVI units.
+pub(crate) struct TfmFont {
+    first_char: i32,
+    widths: Vec<i32>,
+}
+
+impl TfmFont {
+    pub fn parse(data: &[u8], scale: u32) -> Option<TfmFont> {
+        let word = |i: usize| -> Option<usize> { be_u16(data, 2 * i).map(usize::from) };
+
+        let lh = word(1)?;
+        let bc = word(2)?;
+        let ec = word(3)?;
+        let nw = word(4)?;
+        let n_chars = if ec >= bc { ec + 1 - bc } else { 0 };
+        let char_info = 24 + 4 * lh;
+        let width_base = char_info + 4 * n_chars;
+
+        if data.len() < width_base + 4 * nw {
+            return None;
+        }
+
+        let widths = (0..n_chars)
+            .map(|i| {
+                let wi = usize::from(data[char_info + 4 * i]);
+
+                if wi >= nw {
+                    return 0;
+                }
+
+                let fix = be_u32(data, width_base + 4 * wi).unwrap() as i32;
+                ((i64::from(fix) * i64::from(scale)) >> 20) as i32
+            })
+            .collect();
+
+        Some(TfmFont {
+            first_char: bc as i32,
+            widths,
+        })
+    }
+
+    pub fn width(&self, c: i32) -> i32 {
+        if c < self.first_char {
+            return 0;
+        }
+
+        self.widths
+            .get((c - self.first_char) as usize)
+            .cloned()
+            .unwrap_or(0)
+    }
+}
+
+/// What the font map says about a TFM-based font.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct FontMapEntry {
+    /// The name of the outline font file, such as `cmr10.pfb`.
+    pub font_file: String,
+
+    /// The name of the encoding file, if the font is reencoded.
+    pub encoding: Option<String>,
+
+    pub extend: f64,
+    pub slant: f64,
+}
+
+/// A font map in the format of `pdftex.map`, which tells us how to draw the
+/// characters of TFM-based fonts, the same way that xdvipdfmx embeds them.
+#[derive(Debug, Default)]
+pub(crate) struct FontMap {
+    entries: HashMap<String, FontMapEntry>,
+}
+
+impl FontMap {
+    /// Read the font map from the bundle. If it is not available, a warning
+    /// is issued and the map is empty.
+    pub fn load(
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+    ) -> Result<FontMap> {
+        match read_input(io, events, status, FONT_MAP)? {
+            Some(data) => Ok(FontMap::parse(&String::from_utf8_lossy(&data))),
+            None => {
+                tt_warning!(
+                    status,
+                    "font map \"{}\" not available; characters of TFM-based fonts will not be drawn",
+                    FONT_MAP
+                );
+                Ok(FontMap::default())
+            }
+        }
+    }
+
+    /// Parse a font map. Each line names a TFM font, optionally followed by
+    /// the PostScript name of the font, a quoted string of PostScript
+    /// instructions, and the encoding and font files, which are introduced
+    /// by `<`. Entries that do not name a font file refer to fonts that are
+    /// not embedded, and are skipped.
+    pub fn parse(text: &str) -> FontMap {
+        let mut entries = HashMap::new();
+
+        for line in text.lines() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with(&['%', '#', '*', ';'][..]) {
+                continue;
+            }
+
+            let mut words = map_line_words(line).into_iter();
+
+            let tfm = match words.next() {
+                Some(w) => w,
+                None => continue,
+            };
+
+            let mut entry = FontMapEntry {
+                font_file: String::new(),
+                encoding: None,
+                extend: 1.,
+                slant: 0.,
+            };
+
+            while let Some(word) = words.next() {
+                if word.starts_with('"') {
+                    let mut value = None;
+
+                    for op in word.trim_matches('"').split_whitespace() {
+                        match op {
+                            "SlantFont" => entry.slant = value.unwrap_or(0.),
+                            "ExtendFont" => entry.extend = value.unwrap_or(1.),
+                            _ => value = op.parse().ok(),
+                        }
+                    }
+                } else if word.starts_with('<') {
+                    let mut file = word.trim_start_matches(&['<', '['][..]).to_owned();
+
+                    if file.is_empty() {
+                        file = match words.next() {
+                            Some(w) => w,
+                            None => break,
+                        };
+                    }
+
+                    if file.ends_with(".enc") {
+                        entry.encoding = Some(file);
+                    } else {
+                        entry.font_file = file;
+                    }
+                }
+            }
+
+            if !entry.font_file.is_empty() {
+                entries.entry(tfm).or_insert(entry);
+            }
+        }
+
+        FontMap { entries }
+    }
+
+    pub fn get(&self, tfm: &str) -> Option<&FontMapEntry> {
+        self.entries.get(tfm)
+    }
+}
+
+/// Looks up TFM-based fonts in the font map, which is only read once the
+/// first such font is used, and loads their encodings.
+#[derive(Default)]
+pub(crate) struct TfmFontResolver {
+    map: Option<FontMap>,
+    encodings: HashMap<String, Option<Rc<Vec<String>>>>,
+}
+
+impl TfmFontResolver {
+    /// Find the font map entry of the TFM font `tfm`, along with its
+    /// encoding if it is reencoded. If the font cannot be drawn, a warning
+    /// is issued and `None` is returned.
+    pub fn resolve(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        tfm: &str,
+    ) -> Result<Option<(FontMapEntry, Option<Rc<Vec<String>>>)>> {
+        if self.map.is_none() {
+            self.map = Some(FontMap::load(io, events, status)?);
+        }
+
+        let entry = match self.map.as_ref().unwrap().get(tfm) {
+            Some(e) => e.clone(),
+            None => {
+                tt_warning!(
+                    status,
+                    "font \"{}\" is not in the font map; its characters will not be drawn",
+                    tfm
+                );
+                return Ok(None);
+            }
+        };
+
+        let encoding = match entry.encoding {
+            Some(ref name) => {
+                if !self.encodings.contains_key(name) {
+                    let names = match read_input(io, events, status, name)? {
+                        Some(data) => parse_encoding(&String::from_utf8_lossy(&data)),
+                        None => None,
+                    };
+
+                    if names.is_none() {
+                        tt_warning!(status, "failed to load font encoding \"{}\"", name);
+                    }
+
+                    self.encodings.insert(name.clone(), names.map(Rc::new));
+                }
+
+                match self.encodings[name] {
+                    Some(ref names) => Some(names.clone()),
+                    None => return Ok(None),
+                }
+            }
+            None => None,
+        };
+
+        Ok(Some((entry, encoding)))
+    }
+}
+
+/// Split a line of a font map into words, keeping quoted strings together.
+fn map_line_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut rest = line.trim_start();
+
+    while !rest.is_empty() {
+        let end = if rest.starts_with('"') {
+            rest[1..].find('"').map_or(rest.len(), |i| i + 2)
+        } else {
+            rest.find(char::is_whitespace).unwrap_or(rest.len())
+        };
+
+        words.push(rest[..end].to_owned());
+        rest = rest[end..].trim_start();
+    }
+
+    words
+}
+
+/// Parse a PostScript encoding vector, as found in `.enc` files, returning
+/// the glyph names of the character codes.
+pub(crate) fn parse_encoding(text: &str) -> Option<Vec<String>> {
+    let text: String = text
+        .lines()
+        .map(|l| l.split('%').next().unwrap_or(""))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let start = text.find('[')? + 1;
+    let end = start + text[start..].find(']')?;
+
+    let names: Vec<String> = text[start..end]
+        .split(|c: char| c.is_whitespace() || c == '/')
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_owned())
+        .collect();
+
+    if names.is_empty() {
+        None
+    } else {
+        Some(names)
+    }
+}
+
+/// Find the glyphs of the character codes of a TFM-based font in its font
+/// file. If the font is reencoded, the glyphs are looked up by the names
+/// given in `encoding`; otherwise, the font’s built-in encoding is used. The
+/// result maps each code to a glyph index, 0 being the missing glyph.
+pub(crate) fn tfm_glyph_table(face: &mut freetype::Face, encoding: Option<&[String]>) -> Vec<u16> {
+    if let Some(names) = encoding {
+        return names
+            .iter()
+            .map(|name| {
+                let name = match CString::new(name.as_bytes()) {
+                    Ok(n) => n,
+                    Err(_) => return 0,
+                };
+
+                let gid = unsafe { FT_Get_Name_Index(face.raw_mut(), name.as_ptr() as *mut _) };
+                gid as u16
+            })
+            .collect();
+    }
+
+    // Type 1 fonts come with their built-in encoding as a charmap, next to
+    // the Unicode one that FreeType synthesizes. Other fonts keep their
+    // default charmap.
+    unsafe {
+        let raw = face.raw_mut();
+        let charmaps = if raw.num_charmaps > 0 {
+            std::slice::from_raw_parts(raw.charmaps, raw.num_charmaps as usize)
+        } else {
+            &[]
+        };
+
+        for &wanted in &[
+            FT_ENCODING_ADOBE_CUSTOM,
+            FT_ENCODING_ADOBE_STANDARD,
+            FT_ENCODING_ADOBE_EXPERT,
+            FT_ENCODING_ADOBE_LATIN_1,
+        ] {
+            if let Some(&cm) = charmaps.iter().find(|&&cm| (*cm).encoding == wanted) {
+                FT_Set_Charmap(raw, cm);
+                break;
+            }
+        }
+    }
+
+    (0..256).map(|c| face.get_char_index(c) as u16).collect()
+}
+
+/// An RGB color.
+pub(crate) type Rgb = [u8; 3];
+
+/// Parse the color specification of a `color` or `background` special.
+pub(crate) fn parse_color(spec: &str) -> Option<Rgb> {
+    let mut words = spec.split_whitespace().peekable();
+
+    let model = match words.peek() {
+        Some(&w) if w == "rgb" || w == "cmyk" || w == "gray" => {
+            words.next();
+            Some(w)
+        }
+        _ => None,
+    };
+
+    let values = words
+        .map(|w| w.parse::<f64>())
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .ok()?;
+
+    if values.iter().any(|v| !(0. ..=1.).contains(v)) {
+        return None;
+    }
+
+    let (r, g, b) = match (model, values.len()) {
+        (Some("rgb"), 3) | (None, 3) => (values[0], values[1], values[2]),
+        (Some("cmyk"), 4) | (None, 4) => {
+            let k = 1. - values[3];
+            (
+                (1. - values[0]) * k,
+                (1. - values[1]) * k,
+                (1. - values[2]) * k,
+            )
+        }
+        (Some("gray"), 1) | (None, 1) => (values[0], values[0], values[0]),
+        _ => return None,
+    };
+
+    let byte = |v: f64| (v * 255.).round() as u8;
+    Some([byte(r), byte(g), byte(b)])
+}
+
+/// The stack of colors manipulated by the `color` special.
+///
+/// Like xdvipdfmx, we keep it from one page to the next, so that colors
+/// may span page breaks.
+#[derive(Default)]
+pub(crate) struct ColorStack {
+    colors: Vec<Rgb>,
+}
+
+impl ColorStack {
+    /// Get the current color, if one has been set.
+    pub fn current(&self) -> Option<Rgb> {
+        self.colors.last().cloned()
+    }
+
+    /// Apply the arguments of a `color` special. Returns false if the color
+    /// could not be understood, in which case the current color is pushed
+    /// or kept, so that the stack stays balanced.
+    pub fn apply_special(&mut self, args: &str) -> bool {
+        let args = args.trim();
+
+        if args == "pop" {
+            self.colors.pop();
+            return true;
+        }
+
+        let mut words = args.splitn(2, ' ');
+        let (push, spec) = match (words.next(), words.next()) {
+            (Some("push"), Some(spec)) => (true, spec),
+            _ => (false, args),
+        };
+
+        let parsed = parse_color(spec);
+        let color = parsed.or_else(|| self.current()).unwrap_or([0, 0, 0]);
+
+        match self.colors.last_mut() {
+            Some(top) if !push => *top = color,
+            _ => self.colors.push(color),
+        }
+
+        parsed.is_some()
+    }
+}
+
+/// The arguments of a `pdf:image` special, as written by XeTeX.
+pub(crate) struct ImageSpecial<'a> {
+    /// The transformation of the image, in PDF coordinates. It applies to
+    /// the image at its natural size, with the origin at its lower left
+    /// corner, placed at the current point.
+    pub matrix: [f64; 6],
+
+    /// The name of the image file.
+    pub path: &'a str,
+}
+
+impl<'a> ImageSpecial<'a> {
+    pub fn parse(args: &'a str) -> Option<ImageSpecial<'a>> {
+        let open = args.find('(')?;
+        let close = args.rfind(')')?;
+
+        if close < open {
+            return None;
+        }
+
+        let mut matrix = [1., 0., 0., 1., 0., 0.];
+        let mut words = args[..open].split_whitespace();
+
+        while let Some(word) = words.next() {
+            match word {
+                "matrix" => {
+                    for v in matrix.iter_mut() {
+                        *v = words.next()?.parse().ok()?;
+                    }
+                }
+                "page" | "pagebox" => {
+                    words.next();
+                }
+                _ => {}
+            }
+        }
+
+        Some(ImageSpecial {
+            matrix,
+            path: &args[open + 1..close],
+        })
+    }
+}
+
+fn be_u16(data: &[u8], pos: usize) -> Option<u16> {
+    let b = data.get(pos..pos + 2)?;
+    Some(u16::from_be_bytes([b[0], b[1]]))
+}
+
+fn be_u32(data: &[u8], pos: usize) -> Option<u32> {
+    let b = data.get(pos..pos + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+/// Identify a PNG, JPEG or GIF image, returning its MIME type and its size
+/// in big points.
+pub(crate) fn raster_image_info(data: &[u8]) -> Option<(&'static str, f64, f64)> {
+    let (mime, (width, height)) = if data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        ("image/png", png_size(data)?)
+    } else if data.starts_with(b"\xff\xd8") {
+        ("image/jpeg", jpeg_size(data)?)
+    } else if data.starts_with(b"GIF8") {
+        ("image/gif", gif_size(data)?)
+    } else {
+        return None;
+    };
+
+    Some((mime, width, height))
+}
+
+/// Get the size of a PNG image in big points, honoring its resolution.
+fn png_size(data: &[u8]) -> Option<(f64, f64)> {
+    let mut size = None;
+    let mut density = (1., 1.);
+    let mut pos = 8;
+
+    while let Some(len) = be_u32(data, pos) {
+        let chunk = data.get(pos + 8..pos + 8 + len as usize)?;
+
+        match &data[pos + 4..pos + 8] {
+            b"IHDR" => size = Some((be_u32(chunk, 0)?, be_u32(chunk, 4)?)),
+            b"pHYs" if chunk.get(8) == Some(&1) => {
+                let xppm = be_u32(chunk, 0)?;
+                let yppm = be_u32(chunk, 4)?;
+
+                if xppm != 0 && yppm != 0 {
+                    density = (
+                        72. / 0.0254 / f64::from(xppm),
+                        72. / 0.0254 / f64::from(yppm),
+                    );
+                }
+            }
+            b"IDAT" | b"IEND" => break,
+            _ => {}
+        }
+
+        pos += 12 + len as usize;
+    }
+
+    let (width, height) = size?;
+    Some((f64::from(width) * density.0, f64::from(height) * density.1))
+}
+
+/// Get the size of a JPEG image in big points, honoring its resolution.
+fn jpeg_size(data: &[u8]) -> Option<(f64, f64)> {
+    let mut dpi = (72., 72.);
+    let mut pos = 2;
+
+    loop {
+        while data.get(pos + 1) == Some(&0xFF) {
+            pos += 1;
+        }
+
+        if data.get(pos) != Some(&0xFF) {
+            return None;
+        }
+
+        let marker = *data.get(pos + 1)?;
+
+        if marker == 0x01 || (0xD0..=0xD8).contains(&marker) {
+            pos += 2;
+            continue;
+        }
+
+        let len = usize::from(be_u16(data, pos + 2)?);
+
+        match marker {
+            0xE0 if data.get(pos + 4..pos + 9) == Some(b"JFIF\0") => {
+                let xd = f64::from(be_u16(data, pos + 12)?);
+                let yd = f64::from(be_u16(data, pos + 14)?);
+
+                if xd > 0. && yd > 0. {
+                    match data.get(pos + 11) {
+                        Some(1) => dpi = (xd, yd),
+                        Some(2) => dpi = (xd * 2.54, yd * 2.54),
+                        _ => {}
+                    }
+                }
+            }
+            0xC0..=0xCF if marker != 0xC4 && marker != 0xC8 && marker != 0xCC => {
+                let height = f64::from(be_u16(data, pos + 5)?);
+                let width = f64::from(be_u16(data, pos + 7)?);
+                return Some((width * 72. / dpi.0, height * 72. / dpi.1));
+            }
+            _ => {}
+        }
+
+        pos += 2 + len;
+    }
+}
+
+/// The size of an SVG pixel in big points.
+const PX_TO_BP: f64 = 72. / 96.;
+
+/// Get the size of an SVG image in big points, if it can be parsed.
+pub(crate) fn svg_image_size(data: &[u8]) -> Option<(f64, f64)> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default()).ok()?;
+    let size = tree.svg_node().size;
+    Some((size.width() * PX_TO_BP, size.height() * PX_TO_BP))
+}
+
+/// Get the size of a GIF image in big points.
+fn gif_size(data: &[u8]) -> Option<(f64, f64)> {
+    let b = data.get(6..10)?;
+    Some((
+        f64::from(u16::from_le_bytes([b[0], b[1]])),
+        f64::from(u16::from_le_bytes([b[2], b[3]])),
+    ))
+}
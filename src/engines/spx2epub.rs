@@ -0,0 +1,1146 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Convert Tectonic’s SPX format to an EPUB 3 book
+//!
+//! Each semantic page of the SPX file becomes an XHTML chapter, with the
+//! text put back together the same way as for the plain-text output. The
+//! native fonts that are used and the images placed with `pdf:image` are
+//! embedded in the book, and the navigation document is built from the
+//! `pdf:outline` bookmarks, as written by hyperref.
+
+use md5::{Digest, Md5};
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fmt::Write as FmtWrite;
+use std::io::{Cursor, Write};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tectonic_xdv::{FileType, FontDefinition, FontNum, NativeFontDefinition, XdvEvents, XdvParser};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use super::render::{self, ImageSpecial, TfmFont};
+use super::spx2txt::{self, GlyphText, Placement, TfmEncoding};
+use super::IoEventBackend;
+use crate::errors::{Error, Result};
+use crate::io::{IoProvider, IoStack, OpenResult};
+use crate::status::StatusBackend;
+use crate::{errmsg, tt_warning};
+
+#[derive(Default)]
+pub struct Spx2EpubEngine {}
+
+impl Spx2EpubEngine {
+    pub fn new() -> Spx2EpubEngine {
+        Default::default()
+    }
+
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<()> {
+        let mut input = io.input_open_name(OsStr::new(spx), status).must_exist()?;
+        events.input_opened(input.name(), input.origin());
+
+        // FIXME? The engine should probably be responsible for choosing this.
+        let stem = {
+            let mut s = spx.to_owned();
+
+            if spx.ends_with(".spx") {
+                s.truncate(s.len() - 4);
+            }
+
+            s
+        };
+
+        let book = {
+            let state = State::new(io, events, status);
+            let (state, _n_bytes) = XdvParser::process(&mut input, state)?;
+            state.finished()
+        };
+
+        let (name, digest_opt) = input.into_name_digest();
+        events.input_closed(name, digest_opt);
+
+        let data = book.to_epub(&stem)?;
+        let outname = format!("{}.epub", stem);
+
+        let mut output = match io.output_open_name(OsStr::new(&outname)) {
+            OpenResult::Ok(h) => h,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no way to write output file \"{}\"", outname));
+            }
+            OpenResult::Err(e) => {
+                return Err(e);
+            }
+        };
+
+        events.output_opened(OsStr::new(&outname));
+        output.write_all(&data)?;
+        let (name, digest) = output.into_name_digest();
+        events.output_closed(name, digest);
+        Ok(())
+    }
+}
+
+/// Escape text for use in XML content and attribute values.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            _ => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// Parse a PDF string object, literal or hexadecimal, at the start of
+/// *data*, and decode it as UTF-16 if it starts with a byte order mark or as
+/// Latin-1 otherwise.
+fn parse_pdf_string(data: &[u8]) -> Option<String> {
+    let mut bytes = Vec::new();
+
+    match data.first()? {
+        b'(' => {
+            let mut depth = 0;
+            let mut iter = data[1..].iter().cloned().peekable();
+
+            while let Some(b) = iter.next() {
+                match b {
+                    b'(' => {
+                        depth += 1;
+                        bytes.push(b);
+                    }
+                    b')' if depth == 0 => break,
+                    b')' => {
+                        depth -= 1;
+                        bytes.push(b);
+                    }
+                    b'\\' => match iter.next()? {
+                        b'n' => bytes.push(b'\n'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'b' => bytes.push(8),
+                        b'f' => bytes.push(12),
+                        b'\n' => {}
+                        d @ b'0'..=b'7' => {
+                            let mut value = u32::from(d - b'0');
+
+                            for _ in 0..2 {
+                                match iter.peek() {
+                                    Some(&d @ b'0'..=b'7') => {
+                                        value = value * 8 + u32::from(d - b'0');
+                                        iter.next();
+                                    }
+                                    _ => break,
+                                }
+                            }
+
+                            bytes.push(value as u8);
+                        }
+                        other => bytes.push(other),
+                    },
+                    _ => bytes.push(b),
+                }
+            }
+        }
+        b'<' => {
+            let end = data.iter().position(|&b| b == b'>')?;
+            let digits: Vec<u8> = data[1..end]
+                .iter()
+                .filter_map(|&b| (b as char).to_digit(16))
+                .map(|d| d as u8)
+                .collect();
+
+            for pair in digits.chunks(2) {
+                bytes.push(pair[0] << 4 | pair.get(1).cloned().unwrap_or(0));
+            }
+        }
+        _ => return None,
+    }
+
+    if bytes.starts_with(&[0xFE, 0xFF]) {
+        let units: Vec<u16> = bytes[2..]
+            .chunks(2)
+            .filter(|pair| pair.len() == 2)
+            .map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+            .collect();
+        Some(String::from_utf16_lossy(&units))
+    } else {
+        Some(bytes.iter().map(|&b| b as char).collect())
+    }
+}
+
+/// Find the string value of a key in a PDF dictionary.
+fn dict_string(dict: &[u8], key: &[u8]) -> Option<String> {
+    let start = dict.windows(key.len()).position(|w| w == key)? + key.len();
+    let value = &dict[start..];
+    let skip = value.iter().position(|b| !b.is_ascii_whitespace())?;
+    parse_pdf_string(&value[skip..])
+}
+
+/// Parse the arguments of a `pdf:outline` special, returning the level and
+/// the title of the bookmark.
+fn parse_outline(args: &[u8]) -> Option<(i32, String)> {
+    let title = dict_string(args, b"/Title")?;
+    let text = String::from_utf8_lossy(args);
+    let mut rest = text.trim_start();
+
+    // Skip the optional marker of a closed entry.
+    for marker in &["[-]", "-", "[+]", "+"] {
+        if rest.starts_with(marker) && !rest[marker.len()..].starts_with(char::is_numeric) {
+            rest = rest[marker.len()..].trim_start();
+            break;
+        }
+    }
+
+    let end = rest
+        .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+        .unwrap_or(rest.len());
+    let level = rest[..end].parse().ok()?;
+    Some((level, title))
+}
+
+/// The time the book was last modified: that given by `SOURCE_DATE_EPOCH`,
+/// if it is set, so that builds are reproducible, or else the current time.
+fn modification_time() -> SystemTime {
+    std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .and_then(|secs| UNIX_EPOCH.checked_add(Duration::from_secs(secs)))
+        .unwrap_or_else(SystemTime::now)
+}
+
+/// Format a time as required by `dcterms:modified`.
+fn format_timestamp(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Convert days since the epoch to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    )
+}
+
+/// A file embedded in the book.
+struct Resource {
+    href: String,
+    media_type: &'static str,
+    data: Vec<u8>,
+}
+
+/// A piece of a line or paragraph.
+enum Item {
+    Text(FontNum, String),
+    Space,
+    Image(usize, f64),
+    Anchor(String),
+}
+
+/// A line or paragraph being put together. Positions are in DVI units.
+#[derive(Default)]
+struct Block {
+    items: Vec<Item>,
+    y: i32,
+    end: i32,
+    size: i32,
+}
+
+impl Block {
+    /// Take the contents of this block, leaving it empty.
+    fn split_off(&mut self) -> Block {
+        let block = Block {
+            items: self.items.drain(..).collect(),
+            y: self.y,
+            end: self.end,
+            size: self.size,
+        };
+        *self = Block::default();
+        block
+    }
+
+    fn last_text_mut(&mut self) -> Option<&mut String> {
+        match self.items.last_mut() {
+            Some(Item::Text(_, text)) => Some(text),
+            _ => None,
+        }
+    }
+
+    fn ends_with_hyphen(&self) -> bool {
+        match self.items.last() {
+            Some(Item::Text(_, text)) => spx2txt::ends_with_hyphen(text),
+            _ => false,
+        }
+    }
+
+    fn starts_lowercase(&self) -> bool {
+        let first = self.items.iter().find_map(|item| match item {
+            Item::Text(_, text) => text.chars().next(),
+            _ => None,
+        });
+
+        match first {
+            Some(c) => c.is_lowercase(),
+            None => false,
+        }
+    }
+}
+
+struct NativeFont {
+    glyph_text: Rc<GlyphText>,
+    size: i32,
+    file: Option<usize>,
+}
+
+struct TextFont {
+    metrics: Option<TfmFont>,
+    size: i32,
+    encoding: TfmEncoding,
+}
+
+/// An entry of the table of contents.
+struct NavEntry {
+    level: i32,
+    title: String,
+    chapter: usize,
+    anchor: String,
+}
+
+/// Everything that goes into the book.
+#[derive(Default)]
+struct Book {
+    title: Option<String>,
+    author: Option<String>,
+    language: Option<String>,
+    chapters: Vec<String>,
+    fonts: Vec<Resource>,
+    images: Vec<Resource>,
+    stylesheet: String,
+    nav: Vec<NavEntry>,
+}
+
+impl Book {
+    fn chapter_href(index: usize) -> String {
+        format!("chapter-{}.xhtml", index + 1)
+    }
+
+    fn xhtml(&self, title: &str, body: &str) -> String {
+        let lang = escape(self.language.as_ref().map_or("und", |s| &s[..]));
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <!DOCTYPE html>\n\
+             <html xmlns=\"http://www.w3.org/1999/xhtml\" \
+             xmlns:epub=\"http://www.idpf.org/2007/ops\" xml:lang=\"{}\" lang=\"{}\">\n\
+             <head>\n\
+             <title>{}</title>\n\
+             <link rel=\"stylesheet\" type=\"text/css\" href=\"style.css\"/>\n\
+             </head>\n\
+             <body>\n\
+             {}\
+             </body>\n\
+             </html>\n",
+            lang,
+            lang,
+            escape(title),
+            body
+        )
+    }
+
+    /// Build the navigation document, nesting the entries by their levels.
+    fn nav_document(&self, title: &str) -> String {
+        let mut body = String::from("<nav epub:type=\"toc\" id=\"toc\">\n<h1>Contents</h1>\n");
+        let min_level = self.nav.iter().map(|e| e.level).min().unwrap_or(1);
+        let mut depth = 0;
+
+        let fallback: Vec<NavEntry>;
+        let entries = if self.nav.is_empty() {
+            fallback = (0..self.chapters.len())
+                .map(|i| NavEntry {
+                    level: min_level,
+                    title: format!("Page {}", i + 1),
+                    chapter: i,
+                    anchor: String::new(),
+                })
+                .collect();
+            &fallback
+        } else {
+            &self.nav
+        };
+
+        for entry in entries {
+            let level = (entry.level - min_level + 1).max(1).min(depth + 1);
+
+            if level > depth {
+                body.push_str("<ol>\n");
+                depth = level;
+            } else {
+                body.push_str("</li>\n");
+
+                while depth > level {
+                    body.push_str("</ol>\n</li>\n");
+                    depth -= 1;
+                }
+            }
+
+            let mut href = Book::chapter_href(entry.chapter);
+
+            if !entry.anchor.is_empty() {
+                href.push('#');
+                href.push_str(&entry.anchor);
+            }
+
+            write!(
+                body,
+                "<li><a href=\"{}\">{}</a>",
+                escape(&href),
+                escape(&entry.title)
+            )
+            .unwrap();
+        }
+
+        body.push_str("</li>\n");
+
+        while depth > 1 {
+            body.push_str("</ol>\n</li>\n");
+            depth -= 1;
+        }
+
+        body.push_str("</ol>\n</nav>\n");
+        self.xhtml(title, &body)
+    }
+
+    fn package_document(&self, title: &str) -> String {
+        let mut hash = Md5::default();
+
+        for chapter in &self.chapters {
+            hash.update(chapter.as_bytes());
+        }
+
+        // Make a name-based UUID from the contents.
+        let mut uuid = hash.finalize();
+        uuid[6] = (uuid[6] & 0x0F) | 0x30;
+        uuid[8] = (uuid[8] & 0x3F) | 0x80;
+        let hex: String = uuid.iter().map(|b| format!("{:02x}", b)).collect();
+
+        let mut metadata = String::new();
+        writeln!(
+            metadata,
+            "<dc:identifier id=\"uid\">urn:uuid:{}-{}-{}-{}-{}</dc:identifier>",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+        .unwrap();
+        writeln!(metadata, "<dc:title>{}</dc:title>", escape(title)).unwrap();
+
+        if let Some(ref author) = self.author {
+            writeln!(metadata, "<dc:creator>{}</dc:creator>", escape(author)).unwrap();
+        }
+
+        writeln!(
+            metadata,
+            "<dc:language>{}</dc:language>",
+            escape(self.language.as_ref().map_or("und", |s| &s[..]))
+        )
+        .unwrap();
+        writeln!(
+            metadata,
+            "<meta property=\"dcterms:modified\">{}</meta>",
+            format_timestamp(modification_time())
+        )
+        .unwrap();
+
+        let mut manifest = String::from(
+            "<item id=\"nav\" href=\"nav.xhtml\" media-type=\"application/xhtml+xml\" \
+             properties=\"nav\"/>\n\
+             <item id=\"css\" href=\"style.css\" media-type=\"text/css\"/>\n",
+        );
+        let mut spine = String::new();
+
+        for i in 0..self.chapters.len() {
+            writeln!(
+                manifest,
+                "<item id=\"chapter-{}\" href=\"{}\" media-type=\"application/xhtml+xml\"/>",
+                i + 1,
+                Book::chapter_href(i)
+            )
+            .unwrap();
+            writeln!(spine, "<itemref idref=\"chapter-{}\"/>", i + 1).unwrap();
+        }
+
+        for (i, font) in self.fonts.iter().enumerate() {
+            writeln!(
+                manifest,
+                "<item id=\"font-{}\" href=\"{}\" media-type=\"{}\"/>",
+                i + 1,
+                font.href,
+                font.media_type
+            )
+            .unwrap();
+        }
+
+        for (i, image) in self.images.iter().enumerate() {
+            writeln!(
+                manifest,
+                "<item id=\"image-{}\" href=\"{}\" media-type=\"{}\"/>",
+                i + 1,
+                image.href,
+                image.media_type
+            )
+            .unwrap();
+        }
+
+        format!(
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+             <package xmlns=\"http://www.idpf.org/2007/opf\" version=\"3.0\" \
+             unique-identifier=\"uid\">\n\
+             <metadata xmlns:dc=\"http://purl.org/dc/elements/1.1/\">\n\
+             {}\
+             </metadata>\n\
+             <manifest>\n\
+             {}\
+             </manifest>\n\
+             <spine>\n\
+             {}\
+             </spine>\n\
+             </package>\n",
+            metadata, manifest, spine
+        )
+    }
+
+    /// Package the book as an EPUB container.
+    fn to_epub(&self, stem: &str) -> Result<Vec<u8>> {
+        let title = self.title.as_ref().map_or(stem, |s| &s[..]);
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+
+        // The mimetype must come first and be stored uncompressed, so that
+        // the format can be recognized from the first bytes of the file.
+        zip.start_file(
+            "mimetype",
+            FileOptions::default().compression_method(CompressionMethod::Stored),
+        )?;
+        zip.write_all(b"application/epub+zip")?;
+
+        zip.start_file("META-INF/container.xml", FileOptions::default())?;
+        zip.write_all(
+            b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+              <container version=\"1.0\" xmlns=\"urn:oasis:names:tc:opendocument:xmlns:container\">\n\
+              <rootfiles>\n\
+              <rootfile full-path=\"OEBPS/content.opf\" \
+              media-type=\"application/oebps-package+xml\"/>\n\
+              </rootfiles>\n\
+              </container>\n",
+        )?;
+
+        zip.start_file("OEBPS/content.opf", FileOptions::default())?;
+        zip.write_all(self.package_document(title).as_bytes())?;
+
+        zip.start_file("OEBPS/nav.xhtml", FileOptions::default())?;
+        zip.write_all(self.nav_document(title).as_bytes())?;
+
+        zip.start_file("OEBPS/style.css", FileOptions::default())?;
+        zip.write_all(self.stylesheet.as_bytes())?;
+
+        for (i, chapter) in self.chapters.iter().enumerate() {
+            zip.start_file(
+                format!("OEBPS/{}", Book::chapter_href(i)),
+                FileOptions::default(),
+            )?;
+            zip.write_all(self.xhtml(title, chapter).as_bytes())?;
+        }
+
+        for resource in self.fonts.iter().chain(&self.images) {
+            zip.start_file(format!("OEBPS/{}", resource.href), FileOptions::default())?;
+            zip.write_all(&resource.data)?;
+        }
+
+        Ok(zip.finish()?.into_inner())
+    }
+}
+
+struct State<'a, 'b: 'a> {
+    io: &'a mut IoStack<'b>,
+    events: &'a mut dyn IoEventBackend,
+    status: &'a mut dyn StatusBackend,
+    scale: f64,
+    book: Book,
+    faces: HashMap<(String, u32), (Rc<GlyphText>, Option<usize>)>,
+    native_fonts: HashMap<FontNum, NativeFont>,
+    tfm_fonts: HashMap<FontNum, TextFont>,
+    used_fonts: BTreeMap<FontNum, (Option<usize>, i32)>,
+    images: HashMap<String, Option<usize>>,
+    line: Block,
+    paragraph: Block,
+    pending_anchors: Vec<String>,
+    body: String,
+}
+
+impl<'a, 'b: 'a> State<'a, 'b> {
+    pub fn new(
+        io: &'a mut IoStack<'b>,
+        events: &'a mut dyn IoEventBackend,
+        status: &'a mut dyn StatusBackend,
+    ) -> Self {
+        Self {
+            io,
+            events,
+            status,
+            scale: 0.,
+            book: Book::default(),
+            faces: HashMap::new(),
+            native_fonts: HashMap::new(),
+            tfm_fonts: HashMap::new(),
+            used_fonts: BTreeMap::new(),
+            images: HashMap::new(),
+            line: Block::default(),
+            paragraph: Block::default(),
+            pending_anchors: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    /// Load a font file, caching the result. OpenType and TrueType fonts
+    /// are embedded in the book; collections are not, since style sheets
+    /// cannot pick a face out of them.
+    fn load_face(&mut self, path: &str, index: u32) -> Result<(Rc<GlyphText>, Option<usize>)> {
+        let key = (path.to_owned(), index);
+
+        if let Some(face) = self.faces.get(&key) {
+            return Ok(face.clone());
+        }
+
+        let mut face = None;
+
+        for name in &render::font_file_names(path) {
+            if let Some(data) = render::read_input(self.io, self.events, self.status, name)? {
+                let glyph_text = Rc::new(spx2txt::glyph_text(&data, index));
+                let ext = match data.get(..4) {
+                    Some(b"OTTO") => Some(("otf", "font/otf")),
+                    Some(b"\0\x01\0\0") | Some(b"true") => Some(("ttf", "font/ttf")),
+                    _ => None,
+                };
+
+                let file = ext.map(|(ext, media_type)| {
+                    self.book.fonts.push(Resource {
+                        href: format!("fonts/font-{}.{}", self.book.fonts.len() + 1, ext),
+                        media_type,
+                        data,
+                    });
+                    self.book.fonts.len() - 1
+                });
+
+                if file.is_none() {
+                    tt_warning!(self.status, "font \"{}\" cannot be embedded", name);
+                }
+
+                face = Some((glyph_text, file));
+                break;
+            }
+        }
+
+        let face = face.unwrap_or_else(|| {
+            tt_warning!(
+                self.status,
+                "font \"{}\" not available; its text will be lost",
+                path
+            );
+            (Rc::new(GlyphText::new()), None)
+        });
+
+        self.faces.insert(key, face.clone());
+        Ok(face)
+    }
+
+    /// Add an item that is set at (*x*, *y*) and extends *width* units to
+    /// the right, in a font of the given size.
+    fn add(&mut self, item: Item, x: i32, y: i32, width: i32, size: i32) {
+        let line = &self.line;
+
+        match spx2txt::placement(line.y, line.end, line.size, x, y, size) {
+            _ if line.items.is_empty() => {
+                self.line.y = y;
+                self.line.size = size;
+            }
+            Placement::NewLine => {
+                self.end_line();
+                self.line.y = y;
+                self.line.size = size;
+            }
+            Placement::NewWord => match line.items.last() {
+                Some(Item::Space) => {}
+                _ => self.line.items.push(Item::Space),
+            },
+            Placement::SameWord => {}
+        }
+
+        for anchor in self.pending_anchors.drain(..) {
+            self.line.items.push(Item::Anchor(anchor));
+        }
+
+        match (item, self.line.items.last_mut()) {
+            (Item::Text(font, text), Some(Item::Text(last_font, last_text)))
+                if font == *last_font =>
+            {
+                last_text.push_str(&text);
+            }
+            (item, _) => self.line.items.push(item),
+        }
+
+        self.line.end = x + width;
+    }
+
+    fn add_text(&mut self, font_num: FontNum, text: &str, x: i32, y: i32, width: i32, size: i32) {
+        if text.is_empty() {
+            return;
+        }
+
+        self.add(Item::Text(font_num, text.to_owned()), x, y, width, size);
+    }
+
+    /// Finish the current line, joining it to the current paragraph if it
+    /// continues a hyphenated word.
+    fn end_line(&mut self) {
+        let mut line = self.line.split_off();
+
+        while let Some(Item::Space) = line.items.last() {
+            line.items.pop();
+        }
+
+        if line.items.is_empty() {
+            return;
+        }
+
+        let continues = self.paragraph.ends_with_hyphen()
+            && line.starts_lowercase()
+            && line.y > self.paragraph.y
+            && line.y - self.paragraph.y <= 2 * line.size.max(self.paragraph.size);
+
+        if continues {
+            if let Some(text) = self.paragraph.last_text_mut() {
+                text.pop();
+            }
+        } else {
+            self.end_paragraph();
+        }
+
+        self.paragraph.items.append(&mut line.items);
+        self.paragraph.y = line.y;
+        self.paragraph.size = line.size;
+    }
+
+    fn end_paragraph(&mut self) {
+        let paragraph = self.paragraph.split_off();
+
+        if paragraph.items.is_empty() {
+            return;
+        }
+
+        let mut span: Option<FontNum> = None;
+        self.body.push_str("<p>");
+
+        for item in &paragraph.items {
+            if let Item::Text(font, _) = item {
+                if span != Some(*font) {
+                    if span.is_some() {
+                        self.body.push_str("</span>");
+                    }
+
+                    write!(self.body, "<span class=\"f{}\">", font).unwrap();
+                    span = Some(*font);
+                }
+            }
+
+            match item {
+                Item::Text(_, text) => self.body.push_str(&escape(text)),
+                Item::Space => self.body.push(' '),
+                Item::Image(index, width) => write!(
+                    self.body,
+                    "<img src=\"{}\" alt=\"\" style=\"width: {:.2}em\"/>",
+                    self.book.images[*index].href, width
+                )
+                .unwrap(),
+                Item::Anchor(id) => write!(self.body, "<a id=\"{}\"></a>", id).unwrap(),
+            }
+        }
+
+        if span.is_some() {
+            self.body.push_str("</span>");
+        }
+
+        self.body.push_str("</p>\n");
+    }
+
+    fn handle_image_special(&mut self, x: i32, y: i32, args: &str) -> Result<()> {
+        let (matrix, path) = match ImageSpecial::parse(args) {
+            Some(image) => (image.matrix, image.path),
+            None => {
+                tt_warning!(
+                    self.status,
+                    "ignoring unsupported special \"pdf:image {}\"",
+                    args
+                );
+                return Ok(());
+            }
+        };
+
+        let index = match self.images.get(path) {
+            Some(index) => *index,
+            None => {
+                let index = self.load_image(path)?;
+                self.images.insert(path.to_owned(), index);
+                index
+            }
+        };
+
+        let index = match index {
+            Some(i) => i,
+            None => return Ok(()),
+        };
+
+        // Work out the displayed width of the image, in big points.
+        let data = &self.book.images[index].data;
+        let natural_width = render::raster_image_info(data)
+            .map(|(_, width, _)| width)
+            .or_else(|| render::svg_image_size(data).map(|(width, _)| width))
+            .unwrap_or(0.);
+        let width = natural_width * matrix[0].hypot(matrix[1]);
+        let dvi_width = (width / self.scale) as i32;
+        let size = self.line.size;
+
+        // Sizes are given relative to ten-point text.
+        self.add(
+            Item::Image(index, width * 72.27 / 72. / 10.),
+            x,
+            y,
+            dvi_width,
+            size,
+        );
+        Ok(())
+    }
+
+    fn load_image(&mut self, path: &str) -> Result<Option<usize>> {
+        let data = match render::read_input(self.io, self.events, self.status, path)? {
+            Some(data) => data,
+            None => {
+                tt_warning!(self.status, "image \"{}\" not available", path);
+                return Ok(None);
+            }
+        };
+
+        let (ext, media_type) = match render::raster_image_info(&data) {
+            Some(("image/png", _, _)) => ("png", "image/png"),
+            Some(("image/jpeg", _, _)) => ("jpg", "image/jpeg"),
+            Some(("image/gif", _, _)) => ("gif", "image/gif"),
+            _ if render::svg_image_size(&data).is_some() => ("svg", "image/svg+xml"),
+            _ => {
+                tt_warning!(
+                    self.status,
+                    "image \"{}\" is not in a format that can be used in EPUB",
+                    path
+                );
+                return Ok(None);
+            }
+        };
+
+        self.book.images.push(Resource {
+            href: format!("images/image-{}.{}", self.book.images.len() + 1, ext),
+            media_type,
+            data,
+        });
+        Ok(Some(self.book.images.len() - 1))
+    }
+
+    fn handle_outline_special(&mut self, args: &[u8]) {
+        let (level, title) = match parse_outline(args) {
+            Some(outline) => outline,
+            None => return,
+        };
+
+        let anchor = format!("nav-{}", self.book.nav.len() + 1);
+        self.book.nav.push(NavEntry {
+            level,
+            title,
+            chapter: self.book.chapters.len(),
+            anchor: anchor.clone(),
+        });
+        self.pending_anchors.push(anchor);
+    }
+
+    fn stylesheet(&self) -> String {
+        let mut css = String::from("p { margin: 0 0 0.5em 0; }\nimg { max-width: 100%; }\n");
+
+        for (i, font) in self.book.fonts.iter().enumerate() {
+            writeln!(
+                css,
+                "@font-face {{ font-family: \"font-{}\"; src: url(\"{}\"); }}",
+                i + 1,
+                font.href
+            )
+            .unwrap();
+        }
+
+        for (font_num, (file, size)) in &self.used_fonts {
+            write!(css, ".f{} {{ ", font_num).unwrap();
+
+            if let Some(file) = file {
+                write!(css, "font-family: \"font-{}\"; ", file + 1).unwrap();
+            }
+
+            // Sizes are given relative to ten-point text.
+            writeln!(css, "font-size: {:.2}em; }}", f64::from(*size) / 655_360.).unwrap();
+        }
+
+        css
+    }
+
+    pub fn finished(mut self) -> Book {
+        self.handle_end_page().unwrap();
+
+        if self.book.chapters.is_empty() {
+            self.book.chapters.push(String::new());
+        }
+
+        self.book.stylesheet = self.stylesheet();
+        self.book
+    }
+}
+
+impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
+    type Error = Error;
+
+    fn handle_preamble(&mut self, filetype: FileType, mag: u32, _comment: &[u8]) -> Result<()> {
+        if filetype != FileType::Spx {
+            return Err(errmsg!("file should be SPX format but got {}", filetype));
+        }
+
+        self.scale = render::dvi_unit_in_bp(mag);
+        Ok(())
+    }
+
+    fn handle_end_page(&mut self) -> Result<()> {
+        if !self.pending_anchors.is_empty() {
+            self.end_line();
+            self.end_paragraph();
+            let anchors: Vec<Item> = self.pending_anchors.drain(..).map(Item::Anchor).collect();
+            self.paragraph.items.extend(anchors);
+        }
+
+        self.end_line();
+        self.end_paragraph();
+
+        if !self.body.is_empty() {
+            let body = self.body.split_off(0);
+            self.book.chapters.push(body);
+        }
+
+        Ok(())
+    }
+
+    fn handle_define_font(&mut self, font_num: FontNum, font: &FontDefinition) -> Result<()> {
+        if self.tfm_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let name = format!("{}.tfm", font.name);
+        let metrics = match render::read_input(self.io, self.events, self.status, &name)? {
+            Some(data) => TfmFont::parse(&data, font.scale_factor),
+            None => None,
+        };
+
+        if metrics.is_none() {
+            tt_warning!(self.status, "failed to load font metrics \"{}\"", name);
+        }
+
+        self.tfm_fonts.insert(
+            font_num,
+            TextFont {
+                metrics,
+                size: font.scale_factor as i32,
+                encoding: TfmEncoding::from_font_name(&font.name),
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<()> {
+        if self.native_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let (glyph_text, file) = self.load_face(&font.path, font.face_index)?;
+        self.native_fonts.insert(
+            font_num,
+            NativeFont {
+                glyph_text,
+                size: font.size,
+                file,
+            },
+        );
+        Ok(())
+    }
+
+    fn char_width(&mut self, font_num: FontNum, char_num: i32) -> i32 {
+        match self.tfm_fonts.get(&font_num) {
+            Some(TextFont {
+                metrics: Some(tfm), ..
+            }) => tfm.width(char_num),
+            _ => 0,
+        }
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let (size, encoding) = match self.tfm_fonts.get(&font_num) {
+            Some(font) => (font.size, font.encoding),
+            None => return Ok(()),
+        };
+        self.used_fonts.insert(font_num, (None, size));
+
+        for (i, &c) in chars.iter().enumerate() {
+            if let Some(text) = spx2txt::tfm_char_text(encoding, c) {
+                let width = self.char_width(font_num, c);
+                self.add_text(font_num, text, x[i], y[i], width, size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let (glyph_text, size) = match self.native_fonts.get(&font_num) {
+            Some(font) => {
+                self.used_fonts.insert(font_num, (font.file, font.size));
+                (font.glyph_text.clone(), font.size)
+            }
+            None => return Ok(()),
+        };
+
+        let end = x.first().map_or(0, |x0| x0 + width);
+
+        for (i, gid) in glyphs.iter().enumerate() {
+            if let Some(text) = glyph_text.get(gid) {
+                let next = x.get(i + 1).cloned().unwrap_or(end);
+                self.add_text(font_num, text, x[i], y[i], next - x[i], size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: FontNum,
+        text: &[u16],
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        // When the engine tells us the text of the glyphs, trust it.
+        let text = String::from_utf16_lossy(text);
+
+        match (self.native_fonts.get(&font_num), x.first(), y.first()) {
+            (Some(font), Some(&x0), Some(&y0)) if !text.is_empty() => {
+                let size = font.size;
+                self.used_fonts.insert(font_num, (font.file, size));
+                self.add_text(font_num, &text, x0, y0, width, size);
+                Ok(())
+            }
+            _ => self.handle_glyph_run(font_num, width, glyphs, x, y),
+        }
+    }
+
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<()> {
+        let split = contents
+            .iter()
+            .position(|b| b.is_ascii_whitespace() || *b == b'<' || *b == b'[')
+            .unwrap_or(contents.len());
+        let args = &contents[split..];
+
+        match &contents[..split] {
+            b"pdf:image" => self.handle_image_special(x, y, &String::from_utf8_lossy(args))?,
+            b"pdf:outline" | b"pdf:out" => self.handle_outline_special(args),
+            b"pdf:docinfo" => {
+                if let Some(title) = dict_string(args, b"/Title") {
+                    self.book.title = Some(title);
+                }
+
+                if let Some(author) = dict_string(args, b"/Author") {
+                    self.book.author = Some(author);
+                }
+            }
+            b"pdf:put" => {
+                if let Some(lang) = dict_string(args, b"/Lang") {
+                    self.book.language = Some(lang);
+                }
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        let at = |secs| format_timestamp(UNIX_EPOCH + Duration::from_secs(secs));
+
+        assert_eq!(at(0), "1970-01-01T00:00:00Z");
+        assert_eq!(at(1_456_304_492), "2016-02-24T09:01:32Z");
+        assert_eq!(at(951_825_600), "2000-02-29T12:00:00Z");
+    }
+}
@@ -7,7 +7,7 @@
 
 use std::ffi::OsStr;
 use std::io::Write;
-use tectonic_xdv::{FileType, XdvEvents, XdvParser};
+use tectonic_xdv::{FileType, FontNum, XdvEvents, XdvParser};
 
 use super::IoEventBackend;
 use crate::errors::{Error, Result};
@@ -66,6 +66,7 @@ struct State<'a, 'b: 'a> {
     cur_output: Option<OutputHandle>,
     warned_lost_chars: bool,
     buf: Vec<u8>,
+    math_depth: usize,
 }
 
 impl<'a, 'b: 'a> State<'a, 'b> {
@@ -83,10 +84,16 @@ impl<'a, 'b: 'a> State<'a, 'b> {
             cur_output: None,
             warned_lost_chars: false,
             buf: Vec::new(),
+            math_depth: 0,
         }
     }
 }
 
+/// The engine brackets each formula with these specials, the first of
+/// which carries its MathML transcription.
+const MATH_BEGIN_SPECIAL: &[u8] = b"tectonic:math ";
+const MATH_END_SPECIAL: &[u8] = b"tectonic:endmath";
+
 #[inline(always)]
 fn as_printable_ascii(c: i32) -> Option<u8> {
     if c > 0x20 && c < 0x7F {
@@ -128,7 +135,36 @@ impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
         Ok(())
     }
 
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<()> {
+    fn handle_special(&mut self, contents: &[u8]) -> Result<()> {
+        if contents.starts_with(MATH_BEGIN_SPECIAL) {
+            // Formulas can nest via boxes; only the outermost one is
+            // written, and its characters are replaced by the markup.
+            self.math_depth += 1;
+
+            if self.math_depth == 1 {
+                if let Some(ref mut dest) = self.cur_output {
+                    dest.write_all(&contents[MATH_BEGIN_SPECIAL.len()..])?;
+                    dest.write_all(b"\n")?;
+                }
+            }
+        } else if contents == MATH_END_SPECIAL {
+            self.math_depth = self.math_depth.saturating_sub(1);
+        }
+
+        Ok(())
+    }
+
+    fn handle_char_run(
+        &mut self,
+        _font_num: FontNum,
+        chars: &[i32],
+        _x: &[i32],
+        _y: &[i32],
+    ) -> Result<()> {
+        if self.math_depth > 0 {
+            return Ok(());
+        }
+
         let dest = match self.cur_output {
             Some(ref mut h) => h,
             None => {
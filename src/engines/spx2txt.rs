@@ -0,0 +1,889 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Convert Tectonic’s SPX format to plain text
+//!
+//! In semantic pagination mode, the engine sets each paragraph as a single
+//! line at its natural width, so the text of a paragraph can be read off the
+//! glyphs on one baseline. Words are separated where the gaps between glyphs
+//! are wide enough to be interword glue. Glyphs of native fonts are mapped
+//! back to Unicode with the cmaps of the fonts, falling back on their names
+//! for ligatures and other unencoded glyphs; characters of TFM-based fonts
+//! are read through the encoding that the name of the font implies.
+//! Paragraphs are separated by blank lines, and pages by form feeds.
+
+use std::collections::HashMap;
+use std::ffi::{CStr, OsStr};
+use std::io::Write;
+use std::rc::Rc;
+use tectonic_engine::freetype::freetype_sys::{
+    FT_Face, FT_Get_First_Char, FT_Get_Glyph_Name, FT_Get_Next_Char, FT_Set_Charmap,
+    FT_ENCODING_UNICODE,
+};
+use tectonic_engine::freetype::Library;
+use tectonic_xdv::{FileType, FontDefinition, FontNum, NativeFontDefinition, XdvEvents, XdvParser};
+
+use super::render::{self, TfmFont};
+use super::IoEventBackend;
+use crate::errors::{Error, Result};
+use crate::io::{IoProvider, IoStack, OpenResult};
+use crate::status::StatusBackend;
+use crate::{errmsg, tt_warning};
+
+#[derive(Default)]
+pub struct Spx2TxtEngine {}
+
+impl Spx2TxtEngine {
+    pub fn new() -> Spx2TxtEngine {
+        Default::default()
+    }
+
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        spx: &str,
+    ) -> Result<()> {
+        let mut input = io.input_open_name(OsStr::new(spx), status).must_exist()?;
+        events.input_opened(input.name(), input.origin());
+
+        // FIXME? The engine should probably be responsible for choosing this.
+        let outname = {
+            let mut s = spx.to_owned();
+
+            if spx.ends_with(".spx") {
+                s.truncate(s.len() - 4);
+            }
+
+            s.push_str(".txt");
+            s
+        };
+
+        let text = {
+            let state = State::new(io, events, status);
+            let (state, _n_bytes) = XdvParser::process(&mut input, state)?;
+            state.finished()
+        };
+
+        let (name, digest_opt) = input.into_name_digest();
+        events.input_closed(name, digest_opt);
+
+        let mut output = match io.output_open_name(OsStr::new(&outname)) {
+            OpenResult::Ok(h) => h,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no way to write output file \"{}\"", outname));
+            }
+            OpenResult::Err(e) => {
+                return Err(e);
+            }
+        };
+
+        events.output_opened(OsStr::new(&outname));
+        output.write_all(text.as_bytes())?;
+        let (name, digest) = output.into_name_digest();
+        events.output_closed(name, digest);
+        Ok(())
+    }
+}
+
+/// The text of the glyphs of a font.
+pub(crate) type GlyphText = HashMap<u16, String>;
+
+/// Spell out the ligatures of the Alphabetic Presentation Forms block, which
+/// are of no use to someone searching the text.
+fn expand_ligature(c: char) -> String {
+    match c {
+        '\u{FB00}' => "ff".to_owned(),
+        '\u{FB01}' => "fi".to_owned(),
+        '\u{FB02}' => "fl".to_owned(),
+        '\u{FB03}' => "ffi".to_owned(),
+        '\u{FB04}' => "ffl".to_owned(),
+        '\u{FB05}' | '\u{FB06}' => "st".to_owned(),
+        _ => c.to_string(),
+    }
+}
+
+/// Work out the text of a glyph from its name, following the conventions of
+/// the Adobe Glyph List: `f_f_i` is a ligature of the glyphs named `f`, `f`
+/// and `i`, `a.sc` is a variant of `a`, and `uni00E9` and `u1F600` give
+/// code points directly.
+fn text_from_glyph_name(name: &str, by_name: &HashMap<String, String>) -> Option<String> {
+    let base = name.split('.').next()?;
+    let mut text = String::new();
+
+    for part in base.split('_') {
+        if let Some(t) = by_name.get(part) {
+            text.push_str(t);
+        } else if part.len() >= 7 && part.len() % 4 == 3 && part.starts_with("uni") {
+            for i in (3..part.len()).step_by(4) {
+                let c = u32::from_str_radix(&part[i..i + 4], 16).ok()?;
+                text.push_str(&expand_ligature(std::char::from_u32(c)?));
+            }
+        } else if part.len() >= 5 && part.len() <= 7 && part.starts_with('u') {
+            let c = u32::from_str_radix(&part[1..], 16).ok()?;
+            text.push_str(&expand_ligature(std::char::from_u32(c)?));
+        } else {
+            return None;
+        }
+    }
+
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+/// Build the map from the glyphs of a font to their text.
+pub(crate) fn glyph_text(data: &[u8], index: u32) -> GlyphText {
+    let mut map = GlyphText::new();
+
+    let library = match Library::init() {
+        Ok(l) => l,
+        Err(_) => return map,
+    };
+
+    let mut face = match library.new_memory_face(data.to_vec(), index as isize) {
+        Ok(f) => f,
+        Err(_) => return map,
+    };
+
+    let raw: FT_Face = face.raw_mut();
+    let n_glyphs = unsafe { (*raw).num_glyphs };
+
+    // Read the Unicode charmap, if the font has one.
+    unsafe {
+        let charmaps = if (*raw).num_charmaps > 0 {
+            std::slice::from_raw_parts((*raw).charmaps, (*raw).num_charmaps as usize)
+        } else {
+            &[]
+        };
+
+        if let Some(&cm) = charmaps
+            .iter()
+            .find(|&&cm| (*cm).encoding == FT_ENCODING_UNICODE)
+        {
+            FT_Set_Charmap(raw, cm);
+            let mut gid = 0;
+            let mut c = FT_Get_First_Char(raw, &mut gid);
+
+            while gid != 0 {
+                if let Some(ch) = std::char::from_u32(c as u32) {
+                    map.entry(gid as u16).or_insert_with(|| expand_ligature(ch));
+                }
+
+                c = FT_Get_Next_Char(raw, c, &mut gid);
+            }
+        }
+    }
+
+    let glyph_name = |gid: u16| -> Option<String> {
+        let mut buf = [0u8; 64];
+        let error = unsafe {
+            FT_Get_Glyph_Name(
+                raw,
+                u32::from(gid),
+                buf.as_mut_ptr() as *mut _,
+                buf.len() as u32,
+            )
+        };
+
+        if error != 0 || buf[0] == 0 {
+            return None;
+        }
+
+        let name = CStr::from_bytes_with_nul(&buf[..=buf.iter().position(|&b| b == 0)?]).ok()?;
+        name.to_str().ok().map(|n| n.to_owned())
+    };
+
+    let by_name: HashMap<String, String> = map
+        .iter()
+        .filter_map(|(&gid, text)| glyph_name(gid).map(|name| (name, text.clone())))
+        .collect();
+
+    for gid in 0..n_glyphs.min(0x1_0000) {
+        let gid = gid as u16;
+
+        if map.contains_key(&gid) {
+            continue;
+        }
+
+        if let Some(text) = glyph_name(gid).and_then(|name| text_from_glyph_name(&name, &by_name)) {
+            map.insert(gid, text);
+        }
+    }
+
+    map
+}
+
+/// The encodings of TFM-based fonts that we know how to read text from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TfmEncoding {
+    /// The original TeX text encoding of the Computer Modern fonts.
+    Ot1,
+    /// The variant of OT1 used by the typewriter fonts.
+    Ot1Typewriter,
+    /// The math italic encoding of `cmmi` and friends.
+    Oml,
+    /// The Cork encoding of the EC, cm-super and Latin Modern fonts.
+    T1,
+    /// Symbol and other fonts whose characters we don't try to read.
+    Unknown,
+}
+
+impl TfmEncoding {
+    /// Guess the encoding of a font from the name of its TFM file. TFM files
+    /// don't reliably say how they are encoded, but the names of the common
+    /// fonts follow well-known patterns.
+    pub fn from_font_name(name: &str) -> TfmEncoding {
+        let name = name.to_ascii_lowercase();
+        let base = name.trim_start_matches("rm-");
+        let starts_with_any = |prefixes: &[&str]| prefixes.iter().any(|p| base.starts_with(p));
+
+        if name.starts_with("ec")
+            || name.starts_with("sf")
+            || name.starts_with("t1-")
+            || name.contains("-t1")
+            || name.ends_with("8t")
+        {
+            TfmEncoding::T1
+        } else if starts_with_any(&["cmtt", "cmsltt", "cmitt", "cmtcsc", "lmtt", "lmtcsc"]) {
+            TfmEncoding::Ot1Typewriter
+        } else if starts_with_any(&["cmmi", "lmmi"]) {
+            TfmEncoding::Oml
+        } else if starts_with_any(&[
+            "cmsy", "cmbsy", "cmex", "lmsy", "lmbsy", "lmex", "msam", "msbm", "lasy", "wasy",
+            "stmary", "esint", "eusm", "eusb", "euex",
+        ]) {
+            TfmEncoding::Unknown
+        } else {
+            TfmEncoding::Ot1
+        }
+    }
+}
+
+/// The text of the characters of OT1 fonts. Accents, which TeX sets as
+/// separate characters, and the bar of `Ł` and `ł` have no text of their own.
+const OT1: [&str; 128] = [
+    "Γ", "Δ", "Θ", "Λ", "Ξ", "Π", "Σ", "Υ", "Φ", "Ψ", "Ω", "ff", "fi", "fl", "ffi", "ffl", //
+    "ı", "ȷ", "", "", "", "", "", "", "", "ß", "æ", "œ", "ø", "Æ", "Œ", "Ø", //
+    "", "!", "”", "#", "$", "%", "&", "’", "(", ")", "*", "+", ",", "-", ".", "/", //
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", ":", ";", "¡", "=", "¿", "?", //
+    "@", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", //
+    "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "[", "“", "]", "", "", //
+    "‘", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", //
+    "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "–", "—", "", "", "", //
+];
+
+/// The text of the characters of OT1 typewriter fonts, which have no
+/// ligatures and keep the ASCII characters in their usual places.
+const OT1_TYPEWRITER: [&str; 128] = [
+    "Γ", "Δ", "Θ", "Λ", "Ξ", "Π", "Σ", "Υ", "Φ", "Ψ", "Ω", "↑", "↓", "'", "¡", "¿", //
+    "ı", "ȷ", "", "", "", "", "", "", "", "ß", "æ", "œ", "ø", "Æ", "Œ", "Ø", //
+    "␣", "!", "\"", "#", "$", "%", "&", "’", "(", ")", "*", "+", ",", "-", ".", "/", //
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", ":", ";", "<", "=", ">", "?", //
+    "@", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", //
+    "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "[", "\\", "]", "^", "_", //
+    "‘", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", //
+    "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "{", "|", "}", "~", "", //
+];
+
+/// The text of the characters of OML fonts: Greek, Latin letters and
+/// digits, and a few punctuation marks. The other symbols are left out.
+const OML: [&str; 128] = [
+    "Γ", "Δ", "Θ", "Λ", "Ξ", "Π", "Σ", "Υ", "Φ", "Ψ", "Ω", "α", "β", "γ", "δ", "ϵ", //
+    "ζ", "η", "θ", "ι", "κ", "λ", "μ", "ν", "ξ", "π", "ρ", "σ", "τ", "υ", "ϕ", "χ", //
+    "ψ", "ω", "ε", "ϑ", "ϖ", "ϱ", "ς", "φ", "", "", "", "", "", "", "", "", //
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", ".", ",", "<", "/", ">", "", //
+    "∂", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", //
+    "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "", "", "", "", "", //
+    "ℓ", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", //
+    "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "ı", "ȷ", "℘", "", "", //
+];
+
+/// The text of the characters of T1 fonts. As with OT1, the accents have
+/// no text, nor do the compound word mark and the zero for ‰.
+const T1: [&str; 256] = [
+    "", "", "", "", "", "", "", "", "", "", "", "", "", "‚", "‹", "›", //
+    "“", "”", "„", "«", "»", "–", "—", "", "", "ı", "ȷ", "ff", "fi", "fl", "ffi", "ffl", //
+    "␣", "!", "\"", "#", "$", "%", "&", "’", "(", ")", "*", "+", ",", "-", ".", "/", //
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", ":", ";", "<", "=", ">", "?", //
+    "@", "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", //
+    "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z", "[", "\\", "]", "^", "_", //
+    "‘", "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m", "n", "o", //
+    "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z", "{", "|", "}", "~", "-", //
+    "Ă", "Ą", "Ć", "Č", "Ď", "Ě", "Ę", "Ğ", "Ĺ", "Ľ", "Ł", "Ń", "Ň", "Ŋ", "Ő", "Ŕ", //
+    "Ř", "Ś", "Š", "Ş", "Ť", "Ţ", "Ű", "Ů", "Ÿ", "Ź", "Ž", "Ż", "Ĳ", "İ", "đ", "§", //
+    "ă", "ą", "ć", "č", "ď", "ě", "ę", "ğ", "ĺ", "ľ", "ł", "ń", "ň", "ŋ", "ő", "ŕ", //
+    "ř", "ś", "š", "ş", "ť", "ţ", "ű", "ů", "ÿ", "ź", "ž", "ż", "ĳ", "¡", "¿", "£", //
+    "À", "Á", "Â", "Ã", "Ä", "Å", "Æ", "Ç", "È", "É", "Ê", "Ë", "Ì", "Í", "Î", "Ï", //
+    "Ð", "Ñ", "Ò", "Ó", "Ô", "Õ", "Ö", "Œ", "Ø", "Ù", "Ú", "Û", "Ü", "Ý", "Þ", "SS", //
+    "à", "á", "â", "ã", "ä", "å", "æ", "ç", "è", "é", "ê", "ë", "ì", "í", "î", "ï", //
+    "ð", "ñ", "ò", "ó", "ô", "õ", "ö", "œ", "ø", "ù", "ú", "û", "ü", "ý", "þ", "ß", //
+];
+
+/// Get the text of a character of a TFM-based font in the given encoding, if
+/// it has any.
+pub(crate) fn tfm_char_text(encoding: TfmEncoding, c: i32) -> Option<&'static str> {
+    let table: &[&'static str] = match encoding {
+        TfmEncoding::Ot1 => &OT1,
+        TfmEncoding::Ot1Typewriter => &OT1_TYPEWRITER,
+        TfmEncoding::Oml => &OML,
+        TfmEncoding::T1 => &T1,
+        TfmEncoding::Unknown => return None,
+    };
+
+    if c < 0 {
+        return None;
+    }
+
+    match table.get(c as usize) {
+        Some(&text) if !text.is_empty() => Some(text),
+        _ => None,
+    }
+}
+
+pub(crate) fn ends_with_hyphen(text: &str) -> bool {
+    let mut chars = text.chars().rev();
+
+    match (chars.next(), chars.next()) {
+        (Some(h), Some(c)) => (h == '-' || h == '\u{2010}' || h == '\u{AD}') && c.is_alphabetic(),
+        _ => false,
+    }
+}
+
+/// Where a piece of text goes relative to the line that is being put
+/// together.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Placement {
+    SameWord,
+    NewWord,
+    NewLine,
+}
+
+/// Work out the placement of a piece of text that is set at (*x*, *y*) in a
+/// font of the given size, following text on the baseline *line_y* that ends
+/// at *line_end*. Positions and sizes are in DVI units.
+pub(crate) fn placement(
+    line_y: i32,
+    line_end: i32,
+    line_size: i32,
+    x: i32,
+    y: i32,
+    size: i32,
+) -> Placement {
+    let size = size.max(line_size);
+    let dy = (y - line_y).abs();
+
+    // Going back to the left on another baseline starts a new line; merely
+    // shifting the baseline is how sub- and superscripts and accents are
+    // made.
+    if (x < line_end - size / 2 && dy > size) || dy > 3 * size {
+        Placement::NewLine
+    } else if x - line_end > size / 5 {
+        Placement::NewWord
+    } else {
+        Placement::SameWord
+    }
+}
+
+struct NativeFont {
+    glyph_text: Rc<GlyphText>,
+    size: i32,
+}
+
+struct TextFont {
+    metrics: Option<TfmFont>,
+    size: i32,
+    encoding: TfmEncoding,
+}
+
+/// A line of text being put together. Positions are in DVI units.
+#[derive(Default)]
+struct Line {
+    text: String,
+    y: i32,
+    end: i32,
+    size: i32,
+}
+
+impl Line {
+    /// Take the contents of this line, leaving it empty.
+    fn split_off(&mut self) -> Line {
+        let line = Line {
+            text: self.text.split_off(0),
+            y: self.y,
+            end: self.end,
+            size: self.size,
+        };
+        *self = Line::default();
+        line
+    }
+}
+
+struct State<'a, 'b: 'a> {
+    io: &'a mut IoStack<'b>,
+    events: &'a mut dyn IoEventBackend,
+    status: &'a mut dyn StatusBackend,
+    faces: HashMap<(String, u32), Rc<GlyphText>>,
+    native_fonts: HashMap<FontNum, NativeFont>,
+    tfm_fonts: HashMap<FontNum, TextFont>,
+    line: Line,
+    paragraph: Line,
+    page_is_empty: bool,
+    n_pages: usize,
+    text: String,
+}
+
+impl<'a, 'b: 'a> State<'a, 'b> {
+    pub fn new(
+        io: &'a mut IoStack<'b>,
+        events: &'a mut dyn IoEventBackend,
+        status: &'a mut dyn StatusBackend,
+    ) -> Self {
+        Self {
+            io,
+            events,
+            status,
+            faces: HashMap::new(),
+            native_fonts: HashMap::new(),
+            tfm_fonts: HashMap::new(),
+            line: Line::default(),
+            paragraph: Line::default(),
+            page_is_empty: true,
+            n_pages: 0,
+            text: String::new(),
+        }
+    }
+
+    /// Load the glyph map of a font file, caching the result.
+    fn load_glyph_text(&mut self, path: &str, index: u32) -> Result<Rc<GlyphText>> {
+        let key = (path.to_owned(), index);
+
+        if let Some(map) = self.faces.get(&key) {
+            return Ok(map.clone());
+        }
+
+        let mut map = None;
+
+        for name in &render::font_file_names(path) {
+            if let Some(data) = render::read_input(self.io, self.events, self.status, name)? {
+                map = Some(glyph_text(&data, index));
+                break;
+            }
+        }
+
+        let map = Rc::new(map.unwrap_or_else(|| {
+            tt_warning!(
+                self.status,
+                "font \"{}\" not available; its text will be lost",
+                path
+            );
+            GlyphText::new()
+        }));
+
+        self.faces.insert(key, map.clone());
+        Ok(map)
+    }
+
+    /// Add a piece of text that is set at (*x*, *y*) and extends *width*
+    /// units to the right, in a font of the given size.
+    fn add(&mut self, text: &str, x: i32, y: i32, width: i32, size: i32) {
+        if text.is_empty() {
+            return;
+        }
+
+        let line = &self.line;
+
+        match placement(line.y, line.end, line.size, x, y, size) {
+            _ if line.text.is_empty() => {
+                self.line.y = y;
+                self.line.size = size;
+            }
+            Placement::NewLine => {
+                self.end_line();
+                self.line.y = y;
+                self.line.size = size;
+            }
+            Placement::NewWord if !line.text.ends_with(' ') => self.line.text.push(' '),
+            _ => {}
+        }
+
+        self.line.text.push_str(text);
+        self.line.end = x + width;
+    }
+
+    /// Finish the current line, joining it to the current paragraph if it
+    /// continues a hyphenated word.
+    fn end_line(&mut self) {
+        let line = self.line.split_off();
+        let text = line.text.trim();
+
+        if text.is_empty() {
+            return;
+        }
+
+        let continues = ends_with_hyphen(&self.paragraph.text)
+            && text.starts_with(char::is_lowercase)
+            && line.y > self.paragraph.y
+            && line.y - self.paragraph.y <= 2 * line.size.max(self.paragraph.size);
+
+        if continues {
+            self.paragraph.text.pop();
+        } else {
+            self.end_paragraph();
+        }
+
+        self.paragraph.text.push_str(text);
+        self.paragraph.y = line.y;
+        self.paragraph.size = line.size;
+    }
+
+    fn end_paragraph(&mut self) {
+        let paragraph = self.paragraph.split_off();
+
+        if paragraph.text.is_empty() {
+            return;
+        }
+
+        if !self.page_is_empty {
+            self.text.push('\n');
+        }
+
+        self.text.push_str(&paragraph.text);
+        self.text.push('\n');
+        self.page_is_empty = false;
+    }
+
+    pub fn finished(mut self) -> String {
+        self.end_line();
+        self.end_paragraph();
+        self.text
+    }
+}
+
+impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
+    type Error = Error;
+
+    fn handle_header(&mut self, filetype: FileType, _comment: &[u8]) -> Result<()> {
+        if filetype != FileType::Spx {
+            return Err(errmsg!("file should be SPX format but got {}", filetype));
+        }
+
+        Ok(())
+    }
+
+    fn handle_begin_page(&mut self, _counters: &[i32], _previous_bop: i32) -> Result<()> {
+        if self.n_pages > 0 {
+            self.text.push_str("\u{c}\n");
+        }
+
+        self.n_pages += 1;
+        self.page_is_empty = true;
+        Ok(())
+    }
+
+    fn handle_end_page(&mut self) -> Result<()> {
+        self.end_line();
+        self.end_paragraph();
+        Ok(())
+    }
+
+    fn handle_define_font(&mut self, font_num: FontNum, font: &FontDefinition) -> Result<()> {
+        if self.tfm_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let name = format!("{}.tfm", font.name);
+        let metrics = match render::read_input(self.io, self.events, self.status, &name)? {
+            Some(data) => TfmFont::parse(&data, font.scale_factor),
+            None => None,
+        };
+
+        if metrics.is_none() {
+            tt_warning!(self.status, "failed to load font metrics \"{}\"", name);
+        }
+
+        self.tfm_fonts.insert(
+            font_num,
+            TextFont {
+                metrics,
+                size: font.scale_factor as i32,
+                encoding: TfmEncoding::from_font_name(&font.name),
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<()> {
+        if self.native_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let glyph_text = self.load_glyph_text(&font.path, font.face_index)?;
+        self.native_fonts.insert(
+            font_num,
+            NativeFont {
+                glyph_text,
+                size: font.size,
+            },
+        );
+        Ok(())
+    }
+
+    fn char_width(&mut self, font_num: FontNum, char_num: i32) -> i32 {
+        match self.tfm_fonts.get(&font_num) {
+            Some(TextFont {
+                metrics: Some(tfm), ..
+            }) => tfm.width(char_num),
+            _ => 0,
+        }
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let (size, encoding) = match self.tfm_fonts.get(&font_num) {
+            Some(font) => (font.size, font.encoding),
+            None => return Ok(()),
+        };
+
+        for (i, &c) in chars.iter().enumerate() {
+            if let Some(text) = tfm_char_text(encoding, c) {
+                let width = self.char_width(font_num, c);
+                self.add(text, x[i], y[i], width, size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let (glyph_text, size) = match self.native_fonts.get(&font_num) {
+            Some(font) => (font.glyph_text.clone(), font.size),
+            None => return Ok(()),
+        };
+
+        let end = x.first().map_or(0, |x0| x0 + width);
+
+        for (i, gid) in glyphs.iter().enumerate() {
+            if let Some(text) = glyph_text.get(gid) {
+                let next = x.get(i + 1).cloned().unwrap_or(end);
+                self.add(text, x[i], y[i], next - x[i], size);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: FontNum,
+        text: &[u16],
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        // When the engine tells us the text of the glyphs, trust it.
+        let text = String::from_utf16_lossy(text);
+
+        match (self.native_fonts.get(&font_num), x.first(), y.first()) {
+            (Some(font), Some(&x0), Some(&y0)) if !text.is_empty() => {
+                let size = font.size;
+                self.add(&text, x0, y0, width, size);
+                Ok(())
+            }
+            _ => self.handle_glyph_run(font_num, width, glyphs, x, y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(encoding: TfmEncoding, chars: &[i32]) -> String {
+        chars
+            .iter()
+            .filter_map(|&c| tfm_char_text(encoding, c))
+            .collect()
+    }
+
+    #[test]
+    fn encoding_from_font_name() {
+        assert_eq!(TfmEncoding::from_font_name("cmr10"), TfmEncoding::Ot1);
+        assert_eq!(TfmEncoding::from_font_name("cmbx12"), TfmEncoding::Ot1);
+        assert_eq!(
+            TfmEncoding::from_font_name("cmtt10"),
+            TfmEncoding::Ot1Typewriter
+        );
+        assert_eq!(
+            TfmEncoding::from_font_name("rm-lmtt10"),
+            TfmEncoding::Ot1Typewriter
+        );
+        assert_eq!(TfmEncoding::from_font_name("cmmi10"), TfmEncoding::Oml);
+        assert_eq!(TfmEncoding::from_font_name("ecrm1000"), TfmEncoding::T1);
+        assert_eq!(TfmEncoding::from_font_name("ec-lmr10"), TfmEncoding::T1);
+        assert_eq!(TfmEncoding::from_font_name("sfrm1000"), TfmEncoding::T1);
+        assert_eq!(TfmEncoding::from_font_name("ptmr8t"), TfmEncoding::T1);
+        assert_eq!(TfmEncoding::from_font_name("cmsy10"), TfmEncoding::Unknown);
+        assert_eq!(TfmEncoding::from_font_name("cmex10"), TfmEncoding::Unknown);
+    }
+
+    #[test]
+    fn ot1_table() {
+        // ``Quoted'' -- text---with ligatures, as TeX sets it in cmr10.
+        let chars = [
+            0x5C, 0x51, 0x75, 0x6F, 0x74, 0x65, 0x64, 0x22, 0x7B, 0x0C, 0x6E, 0x64, 0x7C, 0x0E,
+        ];
+        assert_eq!(text(TfmEncoding::Ot1, &chars), "“Quoted”–find—ffi");
+        assert_eq!(text(TfmEncoding::Ot1, &[0x3C, 0x3E, 0x27, 0x60]), "¡¿’‘");
+        assert_eq!(text(TfmEncoding::Ot1, &[0x19, 0x1B, 0x1F]), "ßœØ");
+
+        // Accents and the bar of Ł have no text of their own.
+        assert_eq!(text(TfmEncoding::Ot1, &[0x13, 0x65, 0x20, 0x4C]), "eL");
+        assert_eq!(tfm_char_text(TfmEncoding::Ot1, 0x80), None);
+        assert_eq!(tfm_char_text(TfmEncoding::Ot1, -1), None);
+    }
+
+    #[test]
+    fn other_tables() {
+        assert_eq!(
+            text(TfmEncoding::Ot1Typewriter, &[0x22, 0x3C, 0x5C, 0x7B, 0x7C]),
+            "\"<\\{|"
+        );
+        assert_eq!(text(TfmEncoding::Oml, &[0x0B, 0x78, 0x3D, 0x40]), "αx/∂");
+        assert_eq!(
+            text(TfmEncoding::T1, &[0x10, 0xE9, 0x15, 0xDF, 0x11, 0x1C]),
+            "“é–SS”fi"
+        );
+        assert_eq!(text(TfmEncoding::T1, &[0xAA, 0x9E, 0x00, 0x17]), "łđ");
+        assert_eq!(tfm_char_text(TfmEncoding::Unknown, 0x41), None);
+    }
+
+    #[test]
+    fn glyph_names() {
+        let mut by_name = HashMap::new();
+        by_name.insert("f".to_owned(), "f".to_owned());
+        by_name.insert("i".to_owned(), "i".to_owned());
+        by_name.insert("a".to_owned(), "a".to_owned());
+
+        assert_eq!(
+            text_from_glyph_name("f_f_i", &by_name),
+            Some("ffi".to_owned())
+        );
+        assert_eq!(text_from_glyph_name("a.sc", &by_name), Some("a".to_owned()));
+        assert_eq!(
+            text_from_glyph_name("uni00E9", &by_name),
+            Some("é".to_owned())
+        );
+        assert_eq!(
+            text_from_glyph_name("uni00660069", &by_name),
+            Some("fi".to_owned())
+        );
+        assert_eq!(
+            text_from_glyph_name("uniFB01", &by_name),
+            Some("fi".to_owned())
+        );
+        assert_eq!(
+            text_from_glyph_name("u1F600", &by_name),
+            Some("😀".to_owned())
+        );
+        assert_eq!(
+            text_from_glyph_name("f_uni0131", &by_name),
+            Some("fı".to_owned())
+        );
+        assert_eq!(text_from_glyph_name("glyph123", &by_name), None);
+        assert_eq!(text_from_glyph_name("uniZZZZ", &by_name), None);
+        assert_eq!(text_from_glyph_name(".notdef", &by_name), None);
+    }
+
+    #[test]
+    fn font_glyph_text() {
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/assets/lmroman12-regular.otf"
+        );
+        let map = glyph_text(&std::fs::read(path).unwrap(), 0);
+        let has = |text: &str| map.values().any(|t| t == text);
+
+        assert!(has("A"));
+        assert!(has("é"));
+        assert!(has("fi"));
+        assert!(has("ffi"));
+        assert!(!map.contains_key(&0));
+    }
+
+    #[test]
+    fn hyphens() {
+        assert!(ends_with_hyphen("hy-"));
+        assert!(ends_with_hyphen("hy\u{2010}"));
+        assert!(ends_with_hyphen("hy\u{AD}"));
+        assert!(!ends_with_hyphen("-"));
+        assert!(!ends_with_hyphen("1-"));
+        assert!(!ends_with_hyphen("word"));
+        assert!(!ends_with_hyphen(""));
+    }
+
+    #[test]
+    fn placements() {
+        let size = 655_360;
+
+        // Continuing right after the end of the text, or just past it.
+        assert_eq!(placement(0, 1000, size, 1000, 0, size), Placement::SameWord);
+        assert_eq!(
+            placement(0, 1000, size, 1000 + size / 10, 0, size),
+            Placement::SameWord
+        );
+
+        // An interword space.
+        assert_eq!(
+            placement(0, 1000, size, 1000 + size / 3, 0, size),
+            Placement::NewWord
+        );
+
+        // Sub- and superscripts stay on the line.
+        assert_eq!(
+            placement(0, 1000, size, 1000, size / 2, size * 7 / 10),
+            Placement::SameWord
+        );
+        assert_eq!(
+            placement(0, 1000, size, 1000, -size / 2, size * 7 / 10),
+            Placement::SameWord
+        );
+
+        // Going back to the left on the next baseline starts a new line, as
+        // does jumping far away.
+        assert_eq!(
+            placement(0, 10 * size, size, 0, 2 * size, size),
+            Placement::NewLine
+        );
+        assert_eq!(
+            placement(0, 1000, size, 1000, 4 * size, size),
+            Placement::NewLine
+        );
+
+        // An accent set back over the previous character on a raised baseline.
+        assert_eq!(
+            placement(0, 10 * size, size, 9 * size, -size / 3, size),
+            Placement::SameWord
+        );
+    }
+}
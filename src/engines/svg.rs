@@ -0,0 +1,786 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Convert XDV files to SVG, one file per page.
+//!
+//! Glyphs of native (OpenType and TrueType) fonts are drawn from their
+//! outlines, which are defined once per page and then referenced for every
+//! use. Rules become rectangles, the `color` and `background` specials are
+//! honored, and raster and SVG images are embedded directly into the output.
+//! Characters of TFM-based fonts are drawn from the outline fonts that the
+//! font map assigns to them, as xdvipdfmx does. All outlines are read with
+//! the FreeType library that the engine uses.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::ffi::OsStr;
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{Cursor, Read, Write};
+use tectonic_engine::freetype::face::LoadFlag;
+use tectonic_engine::freetype::outline::Curve;
+use tectonic_engine::freetype::{self, Library};
+use tectonic_xdv::XdvPageReader;
+use tectonic_xdv::{FileType, FontDefinition, FontNum, NativeFontDefinition, XdvEvents};
+
+use super::render::{self, ColorStack, ImageSpecial, Rgb, TfmFont, TfmFontResolver};
+use super::IoEventBackend;
+use crate::errors::{Error, Result};
+use crate::io::{IoProvider, IoStack, OpenResult};
+use crate::status::StatusBackend;
+use crate::{ctry, errmsg, tt_warning};
+
+#[derive(Default)]
+pub struct SvgEngine {}
+
+impl SvgEngine {
+    pub fn new() -> SvgEngine {
+        Default::default()
+    }
+
+    pub fn process(
+        &mut self,
+        io: &mut IoStack,
+        events: &mut dyn IoEventBackend,
+        status: &mut dyn StatusBackend,
+        xdv: &str,
+    ) -> Result<()> {
+        let mut input = io.input_open_name(OsStr::new(xdv), status).must_exist()?;
+        events.input_opened(input.name(), input.origin());
+        let mut data = Vec::new();
+        input.read_to_end(&mut data)?;
+        let (name, digest_opt) = input.into_name_digest();
+        events.input_closed(name, digest_opt);
+
+        // FIXME? The engine should probably be responsible for choosing this.
+        let mut stem = xdv.to_owned();
+
+        if xdv.ends_with(".xdv") {
+            stem.truncate(stem.len() - 4);
+        }
+
+        let library = ctry!(Library::init(); "failed to initialize FreeType");
+        let mut reader = XdvPageReader::new(Cursor::new(data))?;
+        let n_pages = reader.n_pages();
+        let mut resources = Resources::new(library);
+
+        for page in 0..n_pages {
+            let outname = if n_pages == 1 {
+                format!("{}.svg", stem)
+            } else {
+                format!("{}-{}.svg", stem, page + 1)
+            };
+
+            let state = State::new(&mut resources, io, events, status);
+            let state = reader.process_page(page, state)?;
+            state.finished(&outname)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A number formatted compactly, with at most three decimal places.
+struct Num(f64);
+
+impl fmt::Display for Num {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = format!("{:.3}", self.0);
+        let s = s.trim_end_matches('0').trim_end_matches('.');
+
+        if s == "-0" {
+            f.write_str("0")
+        } else {
+            f.write_str(s)
+        }
+    }
+}
+
+/// A rectangle, as (x_min, y_min, x_max, y_max).
+type BBox = (f64, f64, f64, f64);
+
+/// The outline of a glyph, in font units.
+struct Glyph {
+    path: String,
+    bbox: BBox,
+}
+
+/// The path data of an outline, in SVG syntax.
+struct PathBuilder(String);
+
+impl PathBuilder {
+    fn move_to(&mut self, (x, y): (f64, f64)) {
+        write!(self.0, "M{} {}", Num(x), Num(y)).unwrap();
+    }
+
+    fn line_to(&mut self, (x, y): (f64, f64)) {
+        write!(self.0, "L{} {}", Num(x), Num(y)).unwrap();
+    }
+
+    fn quad_to(&mut self, (x1, y1): (f64, f64), (x, y): (f64, f64)) {
+        write!(self.0, "Q{} {} {} {}", Num(x1), Num(y1), Num(x), Num(y)).unwrap();
+    }
+
+    fn curve_to(&mut self, (x1, y1): (f64, f64), (x2, y2): (f64, f64), (x, y): (f64, f64)) {
+        write!(
+            self.0,
+            "C{} {} {} {} {} {}",
+            Num(x1),
+            Num(y1),
+            Num(x2),
+            Num(y2),
+            Num(x),
+            Num(y)
+        )
+        .unwrap();
+    }
+
+    fn close(&mut self) {
+        self.0.push('Z');
+    }
+}
+
+/// A font file, with the outlines of the glyphs used so far.
+struct Face {
+    face: freetype::Face,
+    units_per_em: f64,
+    glyphs: HashMap<u16, Option<Glyph>>,
+}
+
+impl Face {
+    fn new(library: &Library, data: Vec<u8>, index: u32) -> Option<Face> {
+        let face = library.new_memory_face(data, index as isize).ok()?;
+
+        if !face.is_scalable() {
+            return None;
+        }
+
+        Some(Face {
+            units_per_em: f64::from(face.em_size()),
+            face,
+            glyphs: HashMap::new(),
+        })
+    }
+
+    fn glyph(&mut self, gid: u16) -> Option<&Glyph> {
+        let face = &self.face;
+
+        self.glyphs
+            .entry(gid)
+            .or_insert_with(|| outline_glyph(face, gid))
+            .as_ref()
+    }
+}
+
+/// Get the outline of a glyph, in font units.
+fn outline_glyph(face: &freetype::Face, gid: u16) -> Option<Glyph> {
+    face.load_glyph(u32::from(gid), LoadFlag::NO_SCALE).ok()?;
+    let outline = face.glyph().outline()?;
+    let points = outline.points();
+
+    if points.is_empty() {
+        return None;
+    }
+
+    let mut builder = PathBuilder(String::new());
+    let p = |v: &freetype::Vector| (v.x as f64, v.y as f64);
+
+    for contour in outline.contours_iter() {
+        builder.move_to(p(contour.start()));
+
+        for curve in contour {
+            match curve {
+                Curve::Line(a) => builder.line_to(p(&a)),
+                Curve::Bezier2(a, b) => builder.quad_to(p(&a), p(&b)),
+                Curve::Bezier3(a, b, c) => builder.curve_to(p(&a), p(&b), p(&c)),
+            }
+        }
+
+        builder.close();
+    }
+
+    // The control box, which contains the glyph.
+    let xs = points.iter().map(|v| v.x as f64);
+    let ys = points.iter().map(|v| v.y as f64);
+
+    Some(Glyph {
+        path: builder.0,
+        bbox: (
+            xs.clone().fold(f64::INFINITY, f64::min),
+            ys.clone().fold(f64::INFINITY, f64::min),
+            xs.fold(f64::NEG_INFINITY, f64::max),
+            ys.fold(f64::NEG_INFINITY, f64::max),
+        ),
+    })
+}
+
+/// A font whose glyphs can be drawn: either a native font, or a TFM-based
+/// font that the font map assigns an outline font to.
+struct Font {
+    face: Option<usize>,
+    size: i32,
+    color: Option<u32>,
+    extend: f64,
+    slant: f64,
+    embolden: f64,
+
+    /// For TFM-based fonts, the glyph of each character code.
+    chars: Option<Vec<u16>>,
+}
+
+/// An image, ready to be embedded.
+struct Image {
+    width: f64,
+    height: f64,
+    href: String,
+}
+
+impl Image {
+    fn new(data: &[u8]) -> Option<Image> {
+        let (mime, width, height) = match render::raster_image_info(data) {
+            Some(info) => info,
+            None => {
+                let (width, height) = render::svg_image_size(data)?;
+                ("image/svg+xml", width, height)
+            }
+        };
+
+        Some(Image {
+            width,
+            height,
+            href: format!("data:{};base64,{}", mime, base64::encode(data)),
+        })
+    }
+}
+
+fn hex(color: Rgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", color[0], color[1], color[2])
+}
+
+/// State that is shared by all of the pages of a file.
+struct Resources {
+    library: Library,
+    faces: Vec<Face>,
+    face_ids: HashMap<(String, u32), Option<usize>>,
+    fonts: HashMap<FontNum, Font>,
+    tfm_fonts: HashMap<FontNum, Option<TfmFont>>,
+    tfm_resolver: TfmFontResolver,
+    images: HashMap<String, Option<Image>>,
+    colors: ColorStack,
+    warned_specials: HashSet<String>,
+}
+
+impl Resources {
+    fn new(library: Library) -> Resources {
+        Resources {
+            library,
+            faces: Vec::new(),
+            face_ids: HashMap::new(),
+            fonts: HashMap::new(),
+            tfm_fonts: HashMap::new(),
+            tfm_resolver: TfmFontResolver::default(),
+            images: HashMap::new(),
+            colors: ColorStack::default(),
+            warned_specials: HashSet::new(),
+        }
+    }
+}
+
+struct State<'a, 'b: 'a> {
+    res: &'a mut Resources,
+    io: &'a mut IoStack<'b>,
+    events: &'a mut dyn IoEventBackend,
+    status: &'a mut dyn StatusBackend,
+    scale: f64,
+    background: Option<Rgb>,
+    glyphs: BTreeSet<(usize, u16)>,
+    bbox: Option<BBox>,
+    body: String,
+}
+
+impl<'a, 'b: 'a> State<'a, 'b> {
+    pub fn new(
+        res: &'a mut Resources,
+        io: &'a mut IoStack<'b>,
+        events: &'a mut dyn IoEventBackend,
+        status: &'a mut dyn StatusBackend,
+    ) -> Self {
+        Self {
+            res,
+            io,
+            events,
+            status,
+            scale: 0.,
+            background: None,
+            glyphs: BTreeSet::new(),
+            bbox: None,
+            body: String::new(),
+        }
+    }
+
+    /// Load a font file, caching the result.
+    fn load_face(&mut self, path: &str, index: u32) -> Result<Option<usize>> {
+        let key = (path.to_owned(), index);
+
+        if let Some(id) = self.res.face_ids.get(&key) {
+            return Ok(*id);
+        }
+
+        let mut id = None;
+
+        for name in &render::font_file_names(path) {
+            if let Some(data) = render::read_input(self.io, self.events, self.status, name)? {
+                match Face::new(&self.res.library, data, index) {
+                    Some(face) => {
+                        self.res.faces.push(face);
+                        id = Some(self.res.faces.len() - 1);
+                    }
+                    None => tt_warning!(self.status, "failed to load font \"{}\"", name),
+                }
+
+                break;
+            }
+        }
+
+        if id.is_none() {
+            tt_warning!(
+                self.status,
+                "font \"{}\" not available; its glyphs will not be drawn",
+                path
+            );
+        }
+
+        self.res.face_ids.insert(key, id);
+        Ok(id)
+    }
+
+    /// Convert a length in DVI units to big points.
+    fn bp(&self, dvi: i32) -> f64 {
+        f64::from(dvi) * self.scale
+    }
+
+    fn extend_bbox(&mut self, x: f64, y: f64) {
+        self.bbox = Some(match self.bbox {
+            Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+            None => (x, y, x, y),
+        });
+    }
+
+    /// Extend the bounding box of the page by a rectangle that is
+    /// transformed by the matrix *m*.
+    fn extend_bbox_transformed(&mut self, m: &[f64; 6], rect: BBox) {
+        for &(x, y) in &[
+            (rect.0, rect.1),
+            (rect.0, rect.3),
+            (rect.2, rect.1),
+            (rect.2, rect.3),
+        ] {
+            self.extend_bbox(m[0] * x + m[2] * y + m[4], m[1] * x + m[3] * y + m[5]);
+        }
+    }
+
+    fn fill(&self) -> String {
+        match self.res.colors.current() {
+            Some(c) => format!(" fill=\"{}\"", hex(c)),
+            None => String::new(),
+        }
+    }
+
+    fn warn_special(&mut self, special: &str) {
+        if self.res.warned_specials.insert(special.to_owned()) {
+            tt_warning!(self.status, "ignoring unsupported special \"{}\"", special);
+        }
+    }
+
+    fn handle_color_special(&mut self, special: &str, args: &str) {
+        if !self.res.colors.apply_special(args) {
+            self.warn_special(special);
+        }
+    }
+
+    fn handle_image_special(&mut self, x: i32, y: i32, args: &str) -> Result<()> {
+        let (matrix, path) = match ImageSpecial::parse(args) {
+            Some(image) => (image.matrix, image.path),
+            None => {
+                self.warn_special(&format!("pdf:image {}", args));
+                return Ok(());
+            }
+        };
+
+        if !self.res.images.contains_key(path) {
+            let image = match render::read_input(self.io, self.events, self.status, path)? {
+                Some(data) => {
+                    let image = Image::new(&data);
+
+                    if image.is_none() {
+                        tt_warning!(
+                            self.status,
+                            "image \"{}\" is not in a format that can be embedded in SVG",
+                            path
+                        );
+                    }
+
+                    image
+                }
+                None => {
+                    tt_warning!(self.status, "image \"{}\" not available", path);
+                    None
+                }
+            };
+
+            self.res.images.insert(path.to_owned(), image);
+        }
+
+        let (width, height, href) = match self.res.images[path] {
+            Some(ref image) => (image.width, image.height, image.href.clone()),
+            None => return Ok(()),
+        };
+
+        // The matrix applies to the image in PDF coordinates, with the
+        // origin at its lower left corner and the y axis pointing up.
+        let [a, b, c, d, e, f] = matrix;
+        let m = [
+            a,
+            -b,
+            -c,
+            d,
+            self.bp(x) + c * height + e,
+            self.bp(y) - d * height - f,
+        ];
+
+        self.extend_bbox_transformed(&m, (0., 0., width, height));
+        writeln!(
+            self.body,
+            "<image width=\"{}\" height=\"{}\" preserveAspectRatio=\"none\" \
+             transform=\"matrix({} {} {} {} {} {})\" xlink:href=\"{}\"/>",
+            Num(width),
+            Num(height),
+            Num(m[0]),
+            Num(m[1]),
+            Num(m[2]),
+            Num(m[3]),
+            Num(m[4]),
+            Num(m[5]),
+            href
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    pub fn finished(self, outname: &str) -> Result<()> {
+        let (x0, y0, x1, y1) = self.bbox.unwrap_or((0., 0., 0., 0.));
+        let (width, height) = (x1 - x0, y1 - y0);
+        let mut svg = String::new();
+
+        writeln!(svg, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>").unwrap();
+        writeln!(
+            svg,
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" \
+             xmlns:xlink=\"http://www.w3.org/1999/xlink\" version=\"1.1\" \
+             width=\"{}pt\" height=\"{}pt\" viewBox=\"{} {} {} {}\">",
+            Num(width),
+            Num(height),
+            Num(x0),
+            Num(y0),
+            Num(width),
+            Num(height)
+        )
+        .unwrap();
+
+        if !self.glyphs.is_empty() {
+            svg.push_str("<defs>\n");
+
+            for &(face, gid) in &self.glyphs {
+                if let Some(Some(glyph)) = self.res.faces[face].glyphs.get(&gid) {
+                    writeln!(svg, "<path id=\"g{}-{}\" d=\"{}\"/>", face, gid, glyph.path).unwrap();
+                }
+            }
+
+            svg.push_str("</defs>\n");
+        }
+
+        if let Some(color) = self.background {
+            writeln!(
+                svg,
+                "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"{}\"/>",
+                Num(x0),
+                Num(y0),
+                Num(width),
+                Num(height),
+                hex(color)
+            )
+            .unwrap();
+        }
+
+        svg.push_str(&self.body);
+        svg.push_str("</svg>\n");
+
+        let mut output = match self.io.output_open_name(OsStr::new(outname)) {
+            OpenResult::Ok(h) => h,
+            OpenResult::NotAvailable => {
+                return Err(errmsg!("no way to write output file \"{}\"", outname));
+            }
+            OpenResult::Err(e) => {
+                return Err(e);
+            }
+        };
+
+        self.events.output_opened(OsStr::new(outname));
+        output.write_all(svg.as_bytes())?;
+        let (name, digest) = output.into_name_digest();
+        self.events.output_closed(name, digest);
+        Ok(())
+    }
+}
+
+impl<'a, 'b: 'a> XdvEvents for State<'a, 'b> {
+    type Error = Error;
+
+    fn handle_preamble(&mut self, filetype: FileType, mag: u32, _comment: &[u8]) -> Result<()> {
+        if filetype != FileType::Xdv {
+            return Err(errmsg!("file should be XDV format but got {}", filetype));
+        }
+
+        self.scale = render::dvi_unit_in_bp(mag);
+        Ok(())
+    }
+
+    fn handle_define_font(&mut self, font_num: FontNum, font: &FontDefinition) -> Result<()> {
+        if self.res.tfm_fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let name = format!("{}.tfm", font.name);
+        let tfm = match render::read_input(self.io, self.events, self.status, &name)? {
+            Some(data) => TfmFont::parse(&data, font.scale_factor),
+            None => None,
+        };
+
+        if tfm.is_none() {
+            tt_warning!(self.status, "failed to load font metrics \"{}\"", name);
+        }
+
+        self.res.tfm_fonts.insert(font_num, tfm);
+
+        let (entry, encoding) =
+            match self
+                .res
+                .tfm_resolver
+                .resolve(self.io, self.events, self.status, &font.name)?
+            {
+                Some(r) => r,
+                None => return Ok(()),
+            };
+
+        let face = self.load_face(&entry.font_file, 0)?;
+        let faces = &mut self.res.faces;
+        let chars = face.map(|id| {
+            render::tfm_glyph_table(&mut faces[id].face, encoding.as_ref().map(|e| &e[..]))
+        });
+
+        self.res.fonts.insert(
+            font_num,
+            Font {
+                face,
+                size: font.scale_factor as i32,
+                color: None,
+                extend: entry.extend,
+                slant: entry.slant,
+                embolden: 0.,
+                chars,
+            },
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<()> {
+        if self.res.fonts.contains_key(&font_num) {
+            return Ok(());
+        }
+
+        let face = self.load_face(&font.path, font.face_index)?;
+
+        self.res.fonts.insert(
+            font_num,
+            Font {
+                face,
+                size: font.size,
+                color: font.color_rgba,
+                extend: f64::from(font.extend.unwrap_or(0x10000)) / 65536.,
+                slant: f64::from(font.slant.unwrap_or(0)) / 65536.,
+                embolden: f64::from(font.embolden.unwrap_or(0)) / 65536.,
+                chars: None,
+            },
+        );
+        Ok(())
+    }
+
+    fn char_width(&mut self, font_num: FontNum, char_num: i32) -> i32 {
+        match self.res.tfm_fonts.get(&font_num) {
+            Some(Some(tfm)) => tfm.width(char_num),
+            _ => 0,
+        }
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let table = match self.res.fonts.get(&font_num) {
+            Some(Font {
+                chars: Some(table), ..
+            }) => table,
+            _ => return Ok(()),
+        };
+
+        let mut glyphs = Vec::with_capacity(chars.len());
+        let mut gx = Vec::with_capacity(chars.len());
+        let mut gy = Vec::with_capacity(chars.len());
+
+        for (i, &c) in chars.iter().enumerate() {
+            if let Some(&gid) = table.get(c as usize).filter(|&&gid| gid != 0) {
+                glyphs.push(gid);
+                gx.push(x[i]);
+                gy.push(y[i]);
+            }
+        }
+
+        self.handle_glyph_run(font_num, 0, &glyphs, &gx, &gy)
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        _width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<()> {
+        let (face_id, size, extend, slant, embolden, color) = match self.res.fonts.get(&font_num) {
+            Some(Font {
+                face: Some(face_id),
+                size,
+                extend,
+                slant,
+                embolden,
+                color,
+                ..
+            }) => (*face_id, *size, *extend, *slant, *embolden, *color),
+            _ => return Ok(()),
+        };
+
+        let s = self.bp(size) / self.res.faces[face_id].units_per_em;
+        let mut uses = String::new();
+
+        for (i, &gid) in glyphs.iter().enumerate() {
+            let bbox = match self.res.faces[face_id].glyph(gid) {
+                Some(glyph) => glyph.bbox,
+                None => continue,
+            };
+
+            let m = [s * extend, 0., s * slant, -s, self.bp(x[i]), self.bp(y[i])];
+
+            self.extend_bbox_transformed(&m, bbox);
+            self.glyphs.insert((face_id, gid));
+            writeln!(
+                uses,
+                "<use xlink:href=\"#g{}-{}\" transform=\"matrix({} {} {} {} {} {})\"/>",
+                face_id,
+                gid,
+                Num(m[0]),
+                Num(m[1]),
+                Num(m[2]),
+                Num(m[3]),
+                Num(m[4]),
+                Num(m[5])
+            )
+            .unwrap();
+        }
+
+        if uses.is_empty() {
+            return Ok(());
+        }
+
+        let (color, mut attrs) = match color {
+            Some(rgba) if rgba & 0xFF == 0xFF => (format!("#{:06x}", rgba >> 8), String::new()),
+            Some(rgba) => (
+                format!("#{:06x}", rgba >> 8),
+                format!(" fill-opacity=\"{}\"", Num(f64::from(rgba & 0xFF) / 255.)),
+            ),
+            None => (
+                hex(self.res.colors.current().unwrap_or([0, 0, 0])),
+                String::new(),
+            ),
+        };
+
+        if color != "#000000" {
+            attrs = format!(" fill=\"{}\"{}", color, attrs);
+        }
+
+        if embolden > 0. {
+            // The stroke is applied to the glyphs in font units.
+            write!(
+                attrs,
+                " stroke=\"{}\" stroke-width=\"{}\"",
+                color,
+                Num(embolden / s)
+            )
+            .unwrap();
+        }
+
+        if attrs.is_empty() {
+            self.body.push_str(&uses);
+        } else {
+            writeln!(self.body, "<g{}>", attrs).unwrap();
+            self.body.push_str(&uses);
+            self.body.push_str("</g>\n");
+        }
+
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<()> {
+        if height <= 0 || width <= 0 {
+            return Ok(());
+        }
+
+        let (x, y) = (self.bp(x), self.bp(y));
+        let (w, h) = (self.bp(width), self.bp(height));
+        self.extend_bbox(x, y - h);
+        self.extend_bbox(x + w, y);
+        writeln!(
+            self.body,
+            "<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\"{}/>",
+            Num(x),
+            Num(y - h),
+            Num(w),
+            Num(h),
+            self.fill()
+        )
+        .unwrap();
+        Ok(())
+    }
+
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<()> {
+        let special = String::from_utf8_lossy(contents);
+        let special = special.trim();
+        let mut words = special.splitn(2, ' ');
+
+        match (words.next(), words.next()) {
+            (Some("color"), Some(args)) => self.handle_color_special(special, args),
+            (Some("background"), Some(args)) => match render::parse_color(args) {
+                Some(c) => self.background = Some(c),
+                None => self.warn_special(special),
+            },
+            (Some("pdf:image"), Some(args)) => self.handle_image_special(x, y, args)?,
+            _ => {}
+        }
+
+        Ok(())
+    }
+}
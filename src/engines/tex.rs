@@ -34,6 +34,7 @@ pub struct TexEngine {
     initex_mode: bool,
     synctex_enabled: bool,
     semantic_pagination_enabled: bool,
+    math_actual_text_enabled: bool,
 }
 
 impl Default for TexEngine {
@@ -43,6 +44,7 @@ impl Default for TexEngine {
             initex_mode: false,
             synctex_enabled: false,
             semantic_pagination_enabled: false,
+            math_actual_text_enabled: false,
         }
     }
 }
@@ -86,6 +88,17 @@ impl TexEngine {
         self
     }
 
+    /// Configure the engine to record the source of each math formula.
+    ///
+    /// The source, as the tokens that were read from the input files, is
+    /// attached to the typeset formula with `pdf:bactualtext` and
+    /// `pdf:eactualtext` specials, so that the PDF output carries it as
+    /// `/ActualText` for screen readers and copy-paste.
+    pub fn math_actual_text(&mut self, enabled: bool) -> &mut Self {
+        self.math_actual_text_enabled = enabled;
+        self
+    }
+
     // This function can't be generic across the IoProvider trait, for now,
     // since the global pointer that stashes the ExecutionState must have a
     // complete type.
@@ -130,6 +143,10 @@ impl TexEngine {
         unsafe {
             super::tt_xetex_set_int_variable("semantic_pagination_enabled", v);
         }
+        let v = if self.math_actual_text_enabled { 1 } else { 0 };
+        unsafe {
+            super::tt_xetex_set_int_variable("math_actual_text_enabled", v);
+        }
 
         unsafe {
             match super::tex_simple_main(&*bridge, format_file_name, input_file_name) {
@@ -2,7 +2,11 @@
 // Copyright 2017 the Tectonic Project
 // Licensed under the MIT License.
 
-use super::{ExecutionState, IoEventBackend, TectonicBridgeApi};
+use std::path::PathBuf;
+
+use super::{
+    ExecutionState, IoEventBackend, PdfConformance, PdfSignatureConfig, TectonicBridgeApi,
+};
 use crate::errors::{ErrorKind, Result};
 use crate::io::IoStack;
 use crate::status::StatusBackend;
@@ -11,6 +15,12 @@ use crate::unstable_opts::UnstableOptions;
 pub struct XdvipdfmxEngine {
     enable_compression: bool,
     deterministic_tags: bool,
+    conformance: PdfConformance,
+    output_intent_profile: Option<String>,
+    linearize: bool,
+    optimize: bool,
+    signature: Option<PdfSignatureConfig>,
+    image_cache_path: Option<PathBuf>,
 }
 
 impl XdvipdfmxEngine {
@@ -18,6 +28,12 @@ impl XdvipdfmxEngine {
         XdvipdfmxEngine {
             enable_compression: true,
             deterministic_tags: false,
+            conformance: PdfConformance::None,
+            output_intent_profile: None,
+            linearize: false,
+            optimize: false,
+            signature: None,
+            image_cache_path: None,
         }
     }
 
@@ -31,6 +47,50 @@ impl XdvipdfmxEngine {
         self
     }
 
+    /// Target a PDF conformance level such as PDF/A-2b. Anything in the
+    /// document that the standard forbids causes processing to fail.
+    pub fn with_conformance(mut self, conformance: PdfConformance) -> Self {
+        self.conformance = conformance;
+        self
+    }
+
+    /// Set the name of the ICC profile embedded in the document's
+    /// OutputIntent when a conformance level is active.
+    pub fn with_output_intent_profile<S: Into<String>>(mut self, profile: Option<S>) -> Self {
+        self.output_intent_profile = profile.map(|s| s.into());
+        self
+    }
+
+    /// Write a linearized ("fast web view") PDF, laid out so that viewers
+    /// can display the first page before the whole file has arrived.
+    pub fn with_linearization(mut self, linearize: bool) -> Self {
+        self.linearize = linearize;
+        self
+    }
+
+    /// Shrink the PDF output by merging identical objects, such as the fonts
+    /// that every imported PDF figure brings along, and dropping unused
+    /// resources.
+    pub fn with_size_optimization(mut self, optimize: bool) -> Self {
+        self.optimize = optimize;
+        self
+    }
+
+    /// Sign the PDF output with the given certificate and key. The signature
+    /// goes into the first signature field of the document, or into an
+    /// invisible one if there is none.
+    pub fn with_signature(mut self, signature: Option<PdfSignatureConfig>) -> Self {
+        self.signature = signature;
+        self
+    }
+
+    /// Keep converted images in the given directory, so that later runs can
+    /// reuse them instead of decoding and compressing them again.
+    pub fn with_image_cache(mut self, path: Option<PathBuf>) -> Self {
+        self.image_cache_path = path;
+        self
+    }
+
     pub fn process(
         &mut self,
         io: &mut IoStack,
@@ -47,6 +107,12 @@ impl XdvipdfmxEngine {
         // We default to "letter" paper size by default
         let config = super::XdvipdfmxConfig {
             paperspec: paperspec_str.map_or("letter".into(), |s| s.into()),
+            conformance: self.conformance,
+            output_intent_profile: self.output_intent_profile.clone(),
+            linearize: self.linearize,
+            optimize: self.optimize,
+            signature: self.signature.clone(),
+            image_cache_path: self.image_cache_path.clone(),
         };
 
         let /*mut*/ state = ExecutionState::new(io, events, status);
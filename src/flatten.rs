@@ -0,0 +1,463 @@
+// src/flatten.rs -- merging a multi-file document into a single TeX file
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Flatten a multi-file LaTeX document into a single file.
+//!
+//! Many journals require submissions to consist of a single `.tex` file. The
+//! [`Flattener`] takes the main input of a document and inlines the files
+//! that it pulls in with `\input` and `\include`, and substitutes the contents
+//! of the BibTeX-generated `.bbl` file for `\bibliography`.
+//!
+//! Rather than trying to emulate TeX’s file lookup, the flattener is told
+//! which files the engine actually opened during a processing run, and only
+//! inlines those. This means that conditional inclusions that were not taken
+//! are left alone. The scan of the source text is lexical: it understands
+//! control sequences and comments, but not catcode changes, so an `\input`
+//! inside a verbatim environment will be expanded if the named file happened
+//! to be opened elsewhere.
+
+use std::collections::HashSet;
+use std::path::Path;
+
+use crate::errmsg;
+use crate::errors::Result;
+
+/// The maximum depth of nested inclusions, to protect against cycles.
+const MAX_DEPTH: usize = 64;
+
+type FileReader<'a> = Box<dyn FnMut(&str) -> Option<String> + 'a>;
+
+/// A helper that inlines included files into a document’s main input.
+pub struct Flattener<'a> {
+    opened: HashSet<String>,
+    read_file: FileReader<'a>,
+    bbl: Option<String>,
+    keep_comments: bool,
+}
+
+impl<'a> Flattener<'a> {
+    /// Create a new flattener.
+    ///
+    /// `opened` lists the names of the files that the engine opened, as it
+    /// resolved them (that is, including any `.tex` extension that it added).
+    /// `read_file` is used to get the contents of those files; if it returns
+    /// `None` for a file, the corresponding inclusion command is left in
+    /// place.
+    pub fn new<I, F>(opened: I, read_file: F) -> Flattener<'a>
+    where
+        I: IntoIterator<Item = String>,
+        F: FnMut(&str) -> Option<String> + 'a,
+    {
+        Flattener {
+            opened: opened.into_iter().collect(),
+            read_file: Box::new(read_file),
+            bbl: None,
+            keep_comments: false,
+        }
+    }
+
+    /// Set the contents of the `.bbl` file to substitute for
+    /// `\bibliography`.
+    pub fn bbl(&mut self, bbl: String) -> &mut Self {
+        self.bbl = Some(bbl);
+        self
+    }
+
+    /// If set to `true`, comments are preserved, and markers are added to
+    /// show where each inlined file begins and ends. Otherwise, comments are
+    /// removed (in a way that preserves their effect on spacing).
+    pub fn keep_comments(&mut self, k: bool) -> &mut Self {
+        self.keep_comments = k;
+        self
+    }
+
+    /// Flatten the document whose main file has the given contents.
+    pub fn flatten(&mut self, main: &str) -> Result<String> {
+        let mut out = String::with_capacity(main.len());
+        self.flatten_text(main, &mut out, 0, false)?;
+        Ok(out)
+    }
+
+    /// Figure out which opened file an `\input` argument refers to, following
+    /// the same extension rules as the engine's I/O layer: if the name has no
+    /// extension, `.tex` may be added.
+    fn resolve(&self, name: &str) -> Option<String> {
+        let name = name.trim();
+
+        if self.opened.contains(name) {
+            return Some(name.to_owned());
+        }
+
+        if Path::new(name).extension().is_none() {
+            let with_ext = format!("{}.tex", name);
+
+            if self.opened.contains(&with_ext) {
+                return Some(with_ext);
+            }
+        }
+
+        None
+    }
+
+    fn inline_file(&mut self, name: &str, out: &mut String, depth: usize) -> Result<bool> {
+        let resolved = match self.resolve(name) {
+            Some(r) => r,
+            None => return Ok(false),
+        };
+
+        let text = match (self.read_file)(&resolved) {
+            Some(t) => t,
+            None => return Ok(false),
+        };
+
+        if self.keep_comments {
+            out.push_str(&format!("%%% begin inlined file {}\n", resolved));
+        }
+
+        self.flatten_text(&text, out, depth + 1, true)?;
+
+        if !out.ends_with('\n') {
+            out.push('\n');
+        }
+
+        if self.keep_comments {
+            out.push_str(&format!("%%% end inlined file {}\n", resolved));
+        }
+
+        Ok(true)
+    }
+
+    fn flatten_text(
+        &mut self,
+        text: &str,
+        out: &mut String,
+        depth: usize,
+        is_included: bool,
+    ) -> Result<()> {
+        if depth > MAX_DEPTH {
+            return Err(errmsg!(
+                "files are nested more than {} deep while flattening; is there a cycle?",
+                MAX_DEPTH
+            ));
+        }
+
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        let mut line_start = 0;
+        let mut stop_at_eol = false;
+
+        while i < bytes.len() {
+            match bytes[i] {
+                b'\n' => {
+                    out.push('\n');
+                    i += 1;
+                    line_start = i;
+
+                    if stop_at_eol {
+                        return Ok(());
+                    }
+                }
+
+                b'%' => {
+                    let eol = text[i..].find('\n').map(|n| i + n).unwrap_or(bytes.len());
+
+                    if self.keep_comments {
+                        out.push_str(&text[i..eol]);
+                    } else if text[line_start..i].trim().is_empty() {
+                        // A comment-only line: drop the whole thing,
+                        // including its newline.
+                        out.truncate(out.trim_end_matches(&[' ', '\t'][..]).len());
+                        i = (eol + 1).min(bytes.len());
+                        line_start = i;
+                        continue;
+                    } else {
+                        // The comment suppresses the end-of-line space, so
+                        // we need to keep the percent sign.
+                        out.push('%');
+                    }
+
+                    i = eol;
+                }
+
+                b'\\' => {
+                    let (cs, after) = control_sequence(text, i);
+
+                    if is_included && cs == "endinput" {
+                        // TeX finishes the current line and then stops.
+                        if rest_of_line_is_blank(text, after).is_some() {
+                            return Ok(());
+                        }
+
+                        stop_at_eol = true;
+                        i = after;
+                        continue;
+                    }
+
+                    i = self.handle_control_sequence(text, cs, i, after, out, depth)?;
+                }
+
+                _ => {
+                    let next = next_char_boundary(text, i);
+                    out.push_str(&text[i..next]);
+                    i = next;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Process a control sequence starting at `start` and ending at `after`,
+    /// returning the position at which scanning should resume.
+    fn handle_control_sequence(
+        &mut self,
+        text: &str,
+        cs: &str,
+        start: usize,
+        after: usize,
+        out: &mut String,
+        depth: usize,
+    ) -> Result<usize> {
+        match cs {
+            "input" => {
+                // Both the LaTeX `\input{name}` and the primitive `\input
+                // name` syntaxes are allowed.
+                let arg_start = skip_spaces(text, after);
+                let (name, end, resume) = if text[arg_start..].starts_with('{') {
+                    let (name, end) = match braced_argument(text, arg_start) {
+                        Some(r) => r,
+                        None => return Ok(self.copy(text, start, after, out)),
+                    };
+
+                    // The inlined text ends with a newline, so a newline
+                    // right after the closing brace would turn into a blank
+                    // line, and hence a paragraph break. Only the rest of
+                    // this line is dropped.
+                    let resume = rest_of_line_is_blank(text, end).unwrap_or(end);
+                    (name, end, resume)
+                } else {
+                    let end = text[arg_start..]
+                        .find(|c: char| c.is_whitespace() || c == '}' || c == '\\' || c == '%')
+                        .map(|n| arg_start + n)
+                        .unwrap_or_else(|| text.len());
+
+                    if end == arg_start {
+                        return Ok(self.copy(text, start, after, out));
+                    }
+
+                    // The primitive syntax eats the space that terminates the
+                    // file name.
+                    let eaten = if text[end..].starts_with(&[' ', '\n'][..]) {
+                        end + 1
+                    } else {
+                        end
+                    };
+                    (&text[arg_start..end], eaten, eaten)
+                };
+
+                if self.inline_file(name, out, depth)? {
+                    Ok(resume)
+                } else {
+                    Ok(self.copy(text, start, end, out))
+                }
+            }
+
+            "include" => {
+                let arg_start = skip_spaces(text, after);
+
+                let (name, end) = match braced_argument(text, arg_start) {
+                    Some(r) => r,
+                    None => return Ok(self.copy(text, start, after, out)),
+                };
+
+                let mut inlined = String::new();
+
+                if self.inline_file(&format!("{}.tex", name.trim()), &mut inlined, depth)? {
+                    out.push_str("\\clearpage\n");
+                    out.push_str(&inlined);
+                    out.push_str("\\clearpage");
+                    Ok(end)
+                } else {
+                    Ok(self.copy(text, start, end, out))
+                }
+            }
+
+            "bibliography" => {
+                let arg_start = skip_spaces(text, after);
+
+                let end = match braced_argument(text, arg_start) {
+                    Some((_, end)) => end,
+                    None => return Ok(self.copy(text, start, after, out)),
+                };
+
+                match self.bbl {
+                    Some(ref bbl) => {
+                        if self.keep_comments {
+                            out.push_str("%%% begin inlined bibliography\n");
+                        }
+
+                        out.push_str(bbl);
+
+                        if !bbl.ends_with('\n') {
+                            out.push('\n');
+                        }
+
+                        if self.keep_comments {
+                            out.push_str("%%% end inlined bibliography\n");
+                        }
+
+                        Ok(end)
+                    }
+
+                    None => Ok(self.copy(text, start, end, out)),
+                }
+            }
+
+            _ => Ok(self.copy(text, start, after, out)),
+        }
+    }
+
+    fn copy(&self, text: &str, start: usize, end: usize, out: &mut String) -> usize {
+        out.push_str(&text[start..end]);
+        end
+    }
+}
+
+/// Parse the control sequence whose backslash is at `start`, returning its
+/// name and the position just after it.
+fn control_sequence(text: &str, start: usize) -> (&str, usize) {
+    let name_start = start + 1;
+
+    if name_start >= text.len() {
+        return ("", text.len());
+    }
+
+    let letters = text[name_start..]
+        .find(|c: char| !c.is_ascii_alphabetic())
+        .unwrap_or(text.len() - name_start);
+
+    if letters > 0 {
+        (
+            &text[name_start..name_start + letters],
+            name_start + letters,
+        )
+    } else {
+        let end = next_char_boundary(text, name_start);
+        (&text[name_start..end], end)
+    }
+}
+
+fn next_char_boundary(text: &str, i: usize) -> usize {
+    let mut next = i + 1;
+
+    while !text.is_char_boundary(next) {
+        next += 1;
+    }
+
+    next
+}
+
+fn skip_spaces(text: &str, i: usize) -> usize {
+    text[i..]
+        .find(|c: char| c != ' ' && c != '\t')
+        .map(|n| i + n)
+        .unwrap_or_else(|| text.len())
+}
+
+/// If the text from `i` to the end of its line is only whitespace, return the
+/// position of the start of the next line.
+fn rest_of_line_is_blank(text: &str, i: usize) -> Option<usize> {
+    let eol = text[i..]
+        .find('\n')
+        .map(|n| i + n)
+        .unwrap_or_else(|| text.len());
+
+    if text[i..eol].trim().is_empty() {
+        Some((eol + 1).min(text.len()))
+    } else {
+        None
+    }
+}
+
+/// Parse a `{...}` group starting at `start`, returning its contents and the
+/// position just after the closing brace.
+fn braced_argument(text: &str, start: usize) -> Option<(&str, usize)> {
+    if !text[start..].starts_with('{') {
+        return None;
+    }
+
+    let mut level = 0;
+
+    for (n, c) in text[start..].char_indices() {
+        match c {
+            '{' => level += 1,
+            '}' => {
+                level -= 1;
+
+                if level == 0 {
+                    return Some((&text[start + 1..start + n], start + n + 1));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn flatten(files: &[(&str, &str)], opened: &[&str], main: &str, keep: bool) -> String {
+        let files: HashMap<String, String> = files
+            .iter()
+            .map(|(k, v)| ((*k).to_owned(), (*v).to_owned()))
+            .collect();
+        let mut f = Flattener::new(opened.iter().map(|s| (*s).to_owned()), |name| {
+            files.get(name).cloned()
+        });
+        f.keep_comments(keep).bbl("BBL\n".to_owned());
+        f.flatten(main).unwrap()
+    }
+
+    #[test]
+    fn inputs_and_includes() {
+        let out = flatten(
+            &[
+                ("intro.tex", "Intro.\n"),
+                ("sec/a.tex", "A \\input b.tex\n"),
+                ("b.tex", "B\n\\endinput\nignored\n"),
+            ],
+            &["intro.tex", "sec/a.tex", "b.tex"],
+            "\\include{intro}\n\\input{sec/a}\n\\input{missing}\n\\bibliography{refs}\n",
+            false,
+        );
+        assert_eq!(
+            out,
+            "\\clearpage\nIntro.\n\\clearpage\nA B\n\\input{missing}\nBBL\n\n"
+        );
+    }
+
+    #[test]
+    fn primitive_input_keeps_paragraphs() {
+        // The primitive syntax eats the newline that ends the file name, so
+        // the blank line after it is still a paragraph break.
+        let out = flatten(
+            &[("b.tex", "B\n")],
+            &["b.tex"],
+            "A\n\\input b.tex\n\nC\n",
+            false,
+        );
+        assert_eq!(out, "A\nB\n\nC\n");
+    }
+
+    #[test]
+    fn comments() {
+        let main = "a% trailing\n% whole line\nb\\% c\n";
+        assert_eq!(flatten(&[], &[], main, false), "a%\nb\\% c\n");
+        assert_eq!(flatten(&[], &[], main, true), main);
+    }
+}
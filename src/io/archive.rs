@@ -0,0 +1,605 @@
+// src/io/archive.rs -- I/O on the files in a document source archive
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Read-only access to a document packaged up as a source archive.
+//!
+//! Preprint servers and journals commonly accept submissions as a `.tar.gz`
+//! or `.zip` file containing the main `.tex` file along with its figures,
+//! style files, and so on. The [`ArchiveIo`] provider makes the contents of
+//! such an archive available to the engines without extracting anything to
+//! disk, and can figure out which file is the main one. The
+//! [`ArchiveBuilder`] goes the other way, creating such archives.
+
+use flate2::read::GzDecoder;
+use flate2::{Compression, GzBuilder};
+use std::collections::{BTreeMap, HashMap};
+use std::convert::TryFrom;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::Path;
+use std::str;
+use zip::write::FileOptions;
+use zip::{ZipArchive, ZipWriter};
+
+use super::{try_normalize_tex_path, InputHandle, InputOrigin, IoProvider, OpenResult};
+use crate::errors::Result;
+use crate::status::StatusBackend;
+use crate::{ctry, errmsg};
+
+const TAR_BLOCK_SIZE: usize = 512;
+
+/// An `ArchiveIo` is an I/O provider that serves up files from a Zip or
+/// (possibly gzipped) tar archive.
+///
+/// The whole archive is decompressed into memory when it is opened. All of
+/// the paths within the archive are interpreted relative to the directory
+/// containing the main file, falling back to the archive root, so that
+/// `\input` directives behave as they would if the archive were unpacked and
+/// compiled in place.
+pub struct ArchiveIo {
+    files: HashMap<String, Vec<u8>>,
+    main_file: String,
+    prefix: String,
+}
+
+impl ArchiveIo {
+    /// Open an archive file on the filesystem.
+    ///
+    /// The archive format is determined by sniffing the file contents, not
+    /// from its name.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<ArchiveIo> {
+        let mut data = Vec::new();
+        ctry!(File::open(path.as_ref()).and_then(|mut f| f.read_to_end(&mut data));
+              "failed to read source archive \"{}\"", path.as_ref().display());
+        Self::from_bytes(data)
+    }
+
+    /// Load an archive from an in-memory buffer.
+    ///
+    /// Zip files, plain tar files, and gzipped tar files are supported. A
+    /// gzipped file that is not a tar file is treated as a single `.tex`
+    /// file, since that is how arXiv stores single-file submissions.
+    pub fn from_bytes(data: Vec<u8>) -> Result<ArchiveIo> {
+        let files = if data.starts_with(b"PK\x03\x04") {
+            read_zip(data)?
+        } else if data.starts_with(&[0x1f, 0x8b]) {
+            let mut inner = Vec::new();
+            ctry!(GzDecoder::new(&data[..]).read_to_end(&mut inner); "failed to decompress source archive");
+
+            if is_tar(&inner) {
+                read_tar(&inner)?
+            } else {
+                let mut files = HashMap::new();
+                files.insert("main.tex".to_owned(), inner);
+                files
+            }
+        } else if is_tar(&data) {
+            read_tar(&data)?
+        } else {
+            return Err(errmsg!(
+                "unrecognized source archive format (expected a Zip or tar file)"
+            ));
+        };
+
+        let main_file = find_main_file(&files)?;
+        let prefix = match main_file.rfind('/') {
+            Some(i) => main_file[..=i].to_owned(),
+            None => String::new(),
+        };
+
+        Ok(ArchiveIo {
+            files,
+            main_file,
+            prefix,
+        })
+    }
+
+    /// Override the automatically-detected main file.
+    ///
+    /// Returns an error if no file of that name exists in the archive.
+    pub fn set_main_file(&mut self, name: &str) -> Result<()> {
+        let name = try_normalize_tex_path(name).unwrap_or_else(|| name.to_owned());
+
+        if !self.files.contains_key(&name) {
+            return Err(errmsg!("no file \"{}\" in the source archive", name));
+        }
+
+        self.prefix = match name.rfind('/') {
+            Some(i) => name[..=i].to_owned(),
+            None => String::new(),
+        };
+        self.main_file = name;
+        Ok(())
+    }
+
+    /// Get the path of the main `.tex` file within the archive.
+    pub fn main_file(&self) -> &str {
+        &self.main_file
+    }
+
+    /// Get the contents of the main `.tex` file.
+    pub fn main_file_contents(&self) -> &[u8] {
+        &self.files[&self.main_file]
+    }
+
+    /// Iterate over the names of all of the files in the archive.
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|k| k.as_str())
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Vec<u8>> {
+        let name = try_normalize_tex_path(name)?;
+
+        if !self.prefix.is_empty() {
+            let prefixed = try_normalize_tex_path(&format!("{}{}", self.prefix, name))?;
+
+            if let Some(data) = self.files.get(&prefixed) {
+                return Some(data);
+            }
+        }
+
+        self.files.get(&name)
+    }
+}
+
+impl IoProvider for ArchiveIo {
+    fn input_open_name(
+        &mut self,
+        name: &OsStr,
+        _status: &mut dyn StatusBackend,
+    ) -> OpenResult<InputHandle> {
+        let data = match name.to_str().and_then(|n| self.lookup(n)) {
+            Some(d) => d.clone(),
+            None => return OpenResult::NotAvailable,
+        };
+
+        OpenResult::Ok(InputHandle::new_read_only(
+            name,
+            Cursor::new(data),
+            InputOrigin::Other,
+        ))
+    }
+
+    fn input_open_primary(&mut self, _status: &mut dyn StatusBackend) -> OpenResult<InputHandle> {
+        OpenResult::Ok(InputHandle::new_read_only(
+            OsStr::new(""),
+            Cursor::new(self.files[&self.main_file].clone()),
+            InputOrigin::Other,
+        ))
+    }
+}
+
+/// The formats that [`ArchiveBuilder`] can write.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchiveFormat {
+    /// An uncompressed tar file.
+    Tar,
+
+    /// A gzipped tar file.
+    TarGz,
+
+    /// A Zip file.
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Guess the archive format from a file name.
+    pub fn from_file_name(name: &str) -> Option<ArchiveFormat> {
+        let name = name.to_lowercase();
+
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// A helper for assembling a source archive out of in-memory files.
+///
+/// Files are written out in sorted order with fixed timestamps and
+/// permissions, so that the same inputs always yield the same archive.
+#[derive(Clone, Debug, Default)]
+pub struct ArchiveBuilder {
+    files: BTreeMap<String, Vec<u8>>,
+}
+
+impl ArchiveBuilder {
+    pub fn new() -> ArchiveBuilder {
+        Default::default()
+    }
+
+    /// Add a file to the archive, replacing any previous file with the same
+    /// name.
+    pub fn add_file<N: Into<String>>(&mut self, name: N, data: Vec<u8>) -> &mut Self {
+        self.files.insert(name.into(), data);
+        self
+    }
+
+    /// Iterate over the names of the files that have been added.
+    pub fn file_names(&self) -> impl Iterator<Item = &str> {
+        self.files.keys().map(|k| k.as_str())
+    }
+
+    /// Write the archive to a stream in the specified format.
+    ///
+    /// Zip files can't be written in a single pass, so the stream must be
+    /// seekable.
+    pub fn write<W: Write + Seek>(&self, format: ArchiveFormat, dest: W) -> Result<()> {
+        match format {
+            ArchiveFormat::Tar => self.write_tar(dest),
+
+            ArchiveFormat::TarGz => {
+                let mut gz = GzBuilder::new().write(dest, Compression::default());
+                self.write_tar(&mut gz)?;
+                gz.finish()?;
+                Ok(())
+            }
+
+            ArchiveFormat::Zip => self.write_zip(dest),
+        }
+    }
+
+    fn write_zip<W: Write + Seek>(&self, dest: W) -> Result<()> {
+        let mut zip = ZipWriter::new(dest);
+
+        for (name, data) in &self.files {
+            zip.start_file(name.as_str(), FileOptions::default())?;
+            zip.write_all(data)?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+
+    /// Write a POSIX ustar archive, using GNU-style long name records for
+    /// names that don't fit in the header.
+    fn write_tar<W: Write>(&self, mut dest: W) -> Result<()> {
+        for (name, data) in &self.files {
+            if name.len() > 99 {
+                let mut long_name = name.as_bytes().to_owned();
+                long_name.push(0);
+                write_tar_entry(&mut dest, "././@LongLink", b'L', &long_name)?;
+            }
+
+            write_tar_entry(&mut dest, name, b'0', data)?;
+        }
+
+        dest.write_all(&[0u8; 2 * TAR_BLOCK_SIZE])?;
+        Ok(())
+    }
+}
+
+fn write_tar_entry<W: Write>(dest: &mut W, name: &str, typeflag: u8, data: &[u8]) -> Result<()> {
+    let mut header = [0u8; TAR_BLOCK_SIZE];
+    let name = name.as_bytes();
+    let n = name.len().min(99);
+    header[..n].copy_from_slice(&name[..n]);
+    header[100..108].copy_from_slice(b"0000644\0"); // mode
+    header[108..116].copy_from_slice(b"0000000\0"); // uid
+    header[116..124].copy_from_slice(b"0000000\0"); // gid
+    header[124..136].copy_from_slice(format!("{:011o}\0", data.len()).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+    header[156] = typeflag;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // The checksum is computed with the checksum field filled with spaces.
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|b| u32::from(*b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+    dest.write_all(&header)?;
+    dest.write_all(data)?;
+
+    let padding = (TAR_BLOCK_SIZE - data.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+    dest.write_all(&[0u8; TAR_BLOCK_SIZE][..padding])?;
+    Ok(())
+}
+
+fn read_zip(data: Vec<u8>) -> Result<HashMap<String, Vec<u8>>> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+    let mut files = HashMap::new();
+
+    for i in 0..zip.len() {
+        let mut item = zip.by_index(i)?;
+
+        if item.name().ends_with('/') {
+            continue; // directory
+        }
+
+        let name = match try_normalize_tex_path(item.name()) {
+            Some(n) => n,
+            None => continue,
+        };
+
+        // The size in the archive can't be trusted, so don't preallocate it.
+        let mut buf = Vec::new();
+        item.read_to_end(&mut buf)?;
+        files.insert(name, buf);
+    }
+
+    Ok(files)
+}
+
+fn is_tar(data: &[u8]) -> bool {
+    data.len() >= TAR_BLOCK_SIZE && &data[257..262] == b"ustar"
+}
+
+/// Get a NUL-terminated string out of a tar header field.
+fn tar_str(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Parse a numeric tar header field. These are usually octal text, but GNU
+/// tar uses a big-endian binary encoding for large values, flagged by
+/// setting the high bit of the first byte.
+fn tar_number(field: &[u8]) -> Result<u64> {
+    if !field.is_empty() && field[0] & 0x80 != 0 {
+        let mut n = u64::from(field[0] & 0x7f);
+
+        for b in &field[1..] {
+            n = (n << 8) | u64::from(*b);
+        }
+
+        return Ok(n);
+    }
+
+    let text = str::from_utf8(field)?.trim_matches(|c| c == '\0' || c == ' ');
+
+    if text.is_empty() {
+        return Ok(0);
+    }
+
+    Ok(ctry!(u64::from_str_radix(text, 8); "malformed number in tar header"))
+}
+
+/// Find the "path" record in a pax extended header, if there is one.
+fn pax_path(data: &[u8]) -> Option<String> {
+    let text = String::from_utf8_lossy(data);
+
+    // Records have the form "<length> <key>=<value>\n". Paths can contain
+    // newlines in principle, but we don't worry about that.
+    for record in text.split('\n') {
+        let kv = match record.find(' ') {
+            Some(i) => &record[i + 1..],
+            None => continue,
+        };
+
+        if kv.starts_with("path=") {
+            return Some(kv[5..].to_owned());
+        }
+    }
+
+    None
+}
+
+fn read_tar(data: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut files = HashMap::new();
+    let mut offset = 0;
+    let mut long_name = None;
+
+    while offset + TAR_BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + TAR_BLOCK_SIZE];
+
+        if header.iter().all(|b| *b == 0) {
+            break; // end-of-archive marker
+        }
+
+        let size = tar_number(&header[124..136])?;
+        let start = offset + TAR_BLOCK_SIZE;
+        let end = match usize::try_from(size)
+            .ok()
+            .and_then(|s| start.checked_add(s))
+        {
+            Some(end) if end <= data.len() => end,
+            _ => return Err(errmsg!("tar archive is truncated")),
+        };
+        let size = end - start;
+
+        let contents = &data[start..end];
+        offset = start + (size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE * TAR_BLOCK_SIZE;
+
+        match header[156] {
+            b'L' => {
+                // GNU long name for the next entry.
+                long_name = Some(tar_str(contents));
+                continue;
+            }
+
+            b'x' => {
+                // pax extended header for the next entry.
+                long_name = pax_path(contents);
+                continue;
+            }
+
+            0 | b'0' | b'7' => {}
+
+            _ => {
+                // Directories, links, global pax headers, etc.
+                long_name = None;
+                continue;
+            }
+        }
+
+        let name = match long_name.take() {
+            Some(n) => n,
+            None => {
+                let base = tar_str(&header[0..100]);
+                let prefix = tar_str(&header[345..500]);
+
+                // Only POSIX ustar headers have a prefix field; GNU headers
+                // use that space for other things.
+                if &header[257..263] == b"ustar\0" && !prefix.is_empty() {
+                    format!("{}/{}", prefix, base)
+                } else {
+                    base
+                }
+            }
+        };
+
+        if let Some(name) = try_normalize_tex_path(&name) {
+            files.insert(name, contents.to_owned());
+        }
+    }
+
+    Ok(files)
+}
+
+/// Check whether a line of TeX source contains `\documentclass` (or the
+/// LaTeX 2.09 `\documentstyle`) outside of a comment.
+fn declares_document_class(text: &str) -> bool {
+    text.lines().any(|line| {
+        let code = match line.find('%') {
+            Some(i) => &line[..i],
+            None => line,
+        };
+
+        code.contains("\\documentclass") || code.contains("\\documentstyle")
+    })
+}
+
+/// Figure out the main file of an archive.
+///
+/// We first look for an arXiv-style `00README.XXX` file with a line of the
+/// form `<filename> toplevelfile`. Failing that, we look at the `.tex` files
+/// that declare a document class, preferring the ones closest to the archive
+/// root. Ties are broken alphabetically so that the choice is deterministic.
+fn find_main_file(files: &HashMap<String, Vec<u8>>) -> Result<String> {
+    if let Some(readme) = files.get("00README.XXX") {
+        for line in String::from_utf8_lossy(readme).lines() {
+            let mut pieces = line.split_whitespace();
+
+            if let (Some(name), Some("toplevelfile")) = (pieces.next(), pieces.next()) {
+                if let Some(name) = try_normalize_tex_path(name) {
+                    if files.contains_key(&name) {
+                        return Ok(name);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut tex_files: Vec<&String> = files.keys().filter(|n| n.ends_with(".tex")).collect();
+
+    if tex_files.len() == 1 {
+        return Ok(tex_files[0].clone());
+    }
+
+    tex_files.retain(|n| declares_document_class(&String::from_utf8_lossy(&files[*n])));
+    tex_files.sort_by_key(|n| (n.matches('/').count(), (*n).clone()));
+
+    match tex_files.first() {
+        Some(n) => Ok((*n).clone()),
+        None => Err(errmsg!(
+            "couldn't identify the main file of the source archive: no .tex file contains \\documentclass"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::status::NoopStatusBackend;
+
+    fn tar_entry(archive: &mut Vec<u8>, name: &str, contents: &[u8]) {
+        let mut header = [0u8; TAR_BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{:011o}\0", contents.len());
+        header[124..136].copy_from_slice(size.as_bytes());
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        archive.extend_from_slice(&header);
+        archive.extend_from_slice(contents);
+        let padding = (TAR_BLOCK_SIZE - contents.len() % TAR_BLOCK_SIZE) % TAR_BLOCK_SIZE;
+        archive.extend(std::iter::repeat(0).take(padding));
+    }
+
+    #[test]
+    fn tar_main_file_detection() {
+        let mut archive = Vec::new();
+        tar_entry(
+            &mut archive,
+            "./paper/macros.tex",
+            b"% \\documentclass{article}\n\\def\\x{y}\n",
+        );
+        tar_entry(
+            &mut archive,
+            "./paper/ms.tex",
+            b"\\documentclass{article}\n\\input{macros}\n",
+        );
+        tar_entry(&mut archive, "./paper/fig/plot.pdf", b"%PDF-1.5\n");
+        archive.extend_from_slice(&[0u8; 2 * TAR_BLOCK_SIZE]);
+
+        let mut aio = ArchiveIo::from_bytes(archive).unwrap();
+        assert_eq!(aio.main_file(), "paper/ms.tex");
+
+        let mut sb = NoopStatusBackend::new();
+        assert!(!aio
+            .input_open_name(OsStr::new("macros.tex"), &mut sb)
+            .is_not_available());
+        assert!(!aio
+            .input_open_name(OsStr::new("fig/plot.pdf"), &mut sb)
+            .is_not_available());
+        assert!(aio
+            .input_open_name(OsStr::new("ms.bbl"), &mut sb)
+            .is_not_available());
+    }
+
+    #[test]
+    fn tar_bad_size() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "paper.tex", b"\\documentclass{article}\n");
+        archive[124..136].copy_from_slice(&[0xFF; 12]);
+        archive.extend_from_slice(&[0u8; 2 * TAR_BLOCK_SIZE]);
+        assert!(ArchiveIo::from_bytes(archive.clone()).is_err());
+
+        archive[124..136].copy_from_slice(b"77777777777\0");
+        assert!(ArchiveIo::from_bytes(archive).is_err());
+    }
+
+    #[test]
+    fn builder_round_trip() {
+        let long_name = format!("{}/figure.pdf", "x".repeat(120));
+        let mut builder = ArchiveBuilder::new();
+        builder
+            .add_file("paper.tex", b"\\documentclass{article}\n".to_vec())
+            .add_file("paper.bbl", b"\\begin{thebibliography}{1}\n".to_vec())
+            .add_file(long_name.as_str(), b"%PDF-1.5\n".to_vec());
+
+        for format in &[ArchiveFormat::Tar, ArchiveFormat::TarGz, ArchiveFormat::Zip] {
+            let mut buf = Cursor::new(Vec::new());
+            builder.write(*format, &mut buf).unwrap();
+
+            let mut aio = ArchiveIo::from_bytes(buf.into_inner()).unwrap();
+            assert_eq!(aio.main_file(), "paper.tex");
+
+            let mut sb = NoopStatusBackend::new();
+            let mut h = aio
+                .input_open_name(OsStr::new(&long_name), &mut sb)
+                .unwrap();
+            let mut s = String::new();
+            h.read_to_string(&mut s).unwrap();
+            assert_eq!(s, "%PDF-1.5\n");
+        }
+    }
+
+    #[test]
+    fn readme_hint() {
+        let mut archive = Vec::new();
+        tar_entry(&mut archive, "a.tex", b"\\documentclass{article}\n");
+        tar_entry(&mut archive, "b.tex", b"\\documentclass{article}\n");
+        tar_entry(&mut archive, "00README.XXX", b"b.tex toplevelfile\n");
+        archive.extend_from_slice(&[0u8; 2 * TAR_BLOCK_SIZE]);
+
+        let aio = ArchiveIo::from_bytes(archive).unwrap();
+        assert_eq!(aio.main_file(), "b.tex");
+    }
+}
@@ -17,6 +17,7 @@ use crate::digest::{self, Digest, DigestData};
 use crate::errors::{Error, ErrorKind, Result};
 use crate::status::StatusBackend;
 
+pub mod archive;
 pub mod cached_itarbundle;
 pub mod dirbundle;
 pub mod filesystem;
@@ -468,9 +469,10 @@ impl InputFeatures for Cursor<Vec<u8>> {
 
 // Reexports
 
+pub use self::archive::ArchiveIo;
 pub use self::filesystem::{FilesystemIo, FilesystemPrimaryInputIo};
 pub use self::memory::MemoryIo;
-pub use self::setup::{IoSetup, IoSetupBuilder};
+pub use self::setup::{IoSetup, IoSetupBuilder, ProviderPosition};
 pub use self::stack::IoStack;
 pub use self::stdstreams::GenuineStdoutIo;
 
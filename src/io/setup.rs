@@ -1,4 +1,5 @@
 use std::collections::HashSet;
+use std::ffi::OsStr;
 use std::path::{Path, PathBuf};
 
 use crate::ctry;
@@ -6,7 +7,8 @@ use crate::errors::Result;
 use crate::io::format_cache::FormatCache;
 use crate::io::stdstreams::BufferedPrimaryIo;
 use crate::io::{
-    Bundle, FilesystemIo, FilesystemPrimaryInputIo, GenuineStdoutIo, IoProvider, IoStack, MemoryIo,
+    normalize_tex_path, Bundle, FilesystemIo, FilesystemPrimaryInputIo, GenuineStdoutIo,
+    IoProvider, IoStack, MemoryIo,
 };
 use crate::status::StatusBackend;
 
@@ -19,39 +21,83 @@ use crate::status::StatusBackend;
 /// `IoStack` when needed.
 ///
 /// The `IoStack` produced by an `IoSetup` follows a particular structure: memory I/O backed by
-/// filesystem I/O, backed by an optional `Bundle`.  This way, any newly created files will be
-/// created in memory, and you can examine them by poking at the `mem` field.
+/// any "virtual files" provided by the caller, then filesystem I/O, backed by an optional
+/// `Bundle`.  This way, any newly created files will be created in memory, and you can examine
+/// them by poking at the `mem` field. Custom I/O providers may be slotted into this structure at
+/// the points described by [`ProviderPosition`].
 
 pub struct IoSetup {
     primary_input: Box<dyn IoProvider>,
     pub bundle: Option<Box<dyn Bundle>>,
     pub mem: MemoryIo,
+    virtual_files: MemoryIo,
     filesystem: FilesystemIo,
     pub format_cache: Option<FormatCache>,
     genuine_stdout: Option<GenuineStdoutIo>,
     format_primary: Option<BufferedPrimaryIo>,
+    custom_providers: Vec<(ProviderPosition, Box<dyn IoProvider>)>,
 }
 
 impl IoSetup {
     pub fn as_stack(&mut self) -> IoStack {
+        self.build_stack(true)
+    }
+
+    /// Creates an `IoStack` that only includes the document's own files.
+    ///
+    /// This is the same as [`IoSetup::as_stack`], except that the bundle and
+    /// the format cache are left out. It can be used to figure out whether a
+    /// file that the engines read came from the user or from the support
+    /// files.
+    pub fn as_stack_without_bundle(&mut self) -> IoStack {
+        self.build_stack(false)
+    }
+
+    fn build_stack(&mut self, include_bundle: bool) -> IoStack {
         let mut providers: Vec<&mut dyn IoProvider> = Vec::new();
 
+        // Sort the custom providers into their slots up front, so that we
+        // only need to borrow the vector once.
+        let mut first = Vec::new();
+        let mut before_filesystem = Vec::new();
+        let mut before_bundle = Vec::new();
+        let mut last = Vec::new();
+
+        for (position, p) in &mut self.custom_providers {
+            let p: &mut dyn IoProvider = &mut **p;
+
+            match position {
+                ProviderPosition::First => first.push(p),
+                ProviderPosition::BeforeFilesystem => before_filesystem.push(p),
+                ProviderPosition::BeforeBundle => before_bundle.push(p),
+                ProviderPosition::Last => last.push(p),
+            }
+        }
+
         if let Some(ref mut p) = self.genuine_stdout {
             providers.push(p);
         }
 
+        providers.extend(first);
         providers.push(&mut *self.primary_input);
         providers.push(&mut self.mem);
+        providers.push(&mut self.virtual_files);
+        providers.extend(before_filesystem);
         providers.push(&mut self.filesystem);
+        providers.extend(before_bundle);
 
-        if let Some(ref mut b) = self.bundle {
-            providers.push(b.as_ioprovider_mut());
-        }
+        if include_bundle {
+            if let Some(ref mut b) = self.bundle {
+                providers.push(b.as_ioprovider_mut());
+            }
 
-        if let Some(ref mut c) = self.format_cache {
-            providers.push(&mut *c);
+            if let Some(ref mut c) = self.format_cache {
+                providers.push(&mut *c);
+            }
         }
 
+        providers.extend(last);
+
         IoStack::new(providers)
     }
 
@@ -61,8 +107,8 @@ impl IoSetup {
     ///
     /// - the primary input is not used here; instead, this method provides a "dummy" primary input
     ///   file containing only "\input format-file-name.tex"
-    /// - the filesystem is not included, and so only files that are present in the bundle can have
-    ///   an effect on the format file.
+    /// - the filesystem, virtual files, and custom providers are not included, and so only files
+    ///   that are present in the bundle can have an effect on the format file.
     ///
     /// You can use the resulting `IoStack` to run the TeX engine with `initex_mode` set to `true`;
     /// then the resulting format file(s) can be read from the memory I/O layer (i.e. `self.mem`).
@@ -93,6 +139,27 @@ impl IoSetup {
     }
 }
 
+/// Where a custom I/O provider is placed in the [`IoStack`] created by [`IoSetup::as_stack`].
+///
+/// Providers earlier in the stack take precedence over later ones. Multiple custom providers
+/// given the same position are consulted in the order in which they were added.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ProviderPosition {
+    /// Ahead of everything except the genuine stdout provider, including the primary input and
+    /// the memory layer. A provider here will capture any output files that it agrees to open,
+    /// so they will not appear in the memory layer.
+    First,
+
+    /// After the memory layer and virtual files, but ahead of the filesystem.
+    BeforeFilesystem,
+
+    /// After the filesystem, but ahead of the bundle.
+    BeforeBundle,
+
+    /// After the bundle and the format cache, as a provider of last resort.
+    Last,
+}
+
 /// Where does the "primary input" stream come from?
 enum PrimaryInputMode {
     /// The caller never specified; we'll panic.
@@ -117,6 +184,8 @@ pub struct IoSetupBuilder {
     bundle: Option<Box<dyn Bundle>>,
     use_genuine_stdout: bool,
     hidden_input_paths: HashSet<PathBuf>,
+    virtual_files: MemoryIo,
+    custom_providers: Vec<(ProviderPosition, Box<dyn IoProvider>)>,
 }
 
 impl Default for IoSetupBuilder {
@@ -128,6 +197,8 @@ impl Default for IoSetupBuilder {
             bundle: None,
             use_genuine_stdout: false,
             hidden_input_paths: HashSet::new(),
+            virtual_files: MemoryIo::new(false),
+            custom_providers: Vec::new(),
         }
     }
 }
@@ -191,6 +262,29 @@ impl IoSetupBuilder {
         self
     }
 
+    /// Adds a custom I/O provider at the specified position in the stack.
+    pub fn custom_provider<T: 'static + IoProvider>(
+        &mut self,
+        position: ProviderPosition,
+        provider: T,
+    ) -> &mut Self {
+        self.custom_providers.push((position, Box::new(provider)));
+        self
+    }
+
+    /// Adds an in-memory "virtual file" that the engines can read as if it
+    /// were on the filesystem.
+    ///
+    /// Virtual files are read-only: they take precedence over the filesystem,
+    /// but anything the engines write under the same name goes to the main
+    /// memory layer as usual. Adding a file with the same name twice replaces
+    /// the earlier contents.
+    pub fn virtual_file<N: AsRef<OsStr>>(&mut self, name: N, data: Vec<u8>) -> &mut Self {
+        self.virtual_files
+            .create_entry(&normalize_tex_path(name.as_ref()), data);
+        self
+    }
+
     /// Determines whether to use stdout.
     ///
     /// If this is set to false, I/O that was destined to stdout is instead redirected to the
@@ -251,6 +345,64 @@ impl IoSetupBuilder {
                 None
             },
             format_primary: None,
+            virtual_files: self.virtual_files,
+            custom_providers: self.custom_providers,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::io::OpenResult;
+    use crate::status::NoopStatusBackend;
+    use std::io::Read;
+
+    fn read_via_stack(setup: &mut IoSetup, name: &str) -> Option<String> {
+        let mut sb = NoopStatusBackend::new();
+        let mut stack = setup.as_stack();
+
+        match stack.input_open_name(OsStr::new(name), &mut sb) {
+            OpenResult::Ok(mut h) => {
+                let mut s = String::new();
+                h.read_to_string(&mut s).unwrap();
+                Some(s)
+            }
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn virtual_files_and_custom_providers() {
+        let mut sb = NoopStatusBackend::new();
+
+        let mut first = MemoryIo::new(false);
+        first.create_entry(OsStr::new("shadowed.tex"), b"first".to_vec());
+
+        let mut last = MemoryIo::new(false);
+        last.create_entry(OsStr::new("fallback.tex"), b"last".to_vec());
+
+        let mut builder = IoSetupBuilder::default();
+        builder
+            .primary_input_buffer(b"".to_vec())
+            .virtual_file("./sub/../virtual.tex", b"virtual".to_vec())
+            .virtual_file("shadowed.tex", b"virtual".to_vec())
+            .custom_provider(ProviderPosition::First, first)
+            .custom_provider(ProviderPosition::Last, last);
+        let mut setup = builder.create(&mut sb).unwrap();
+
+        assert_eq!(
+            read_via_stack(&mut setup, "virtual.tex"),
+            Some("virtual".to_owned())
+        );
+        assert_eq!(
+            read_via_stack(&mut setup, "shadowed.tex"),
+            Some("first".to_owned())
+        );
+        assert_eq!(
+            read_via_stack(&mut setup, "fallback.tex"),
+            Some("last".to_owned())
+        );
+        assert_eq!(read_via_stack(&mut setup, "nonexistent.tex"), None);
+    }
+}
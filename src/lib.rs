@@ -54,8 +54,10 @@ pub mod digest;
 pub mod driver;
 pub mod engines;
 pub mod errors;
+pub mod flatten;
 pub mod io;
 pub mod status;
+pub mod synctex;
 pub mod unstable_opts;
 
 // Note: this module is intentionally *not* gated by #[cfg(test)] -- see its
@@ -64,7 +66,11 @@ pub mod unstable_opts;
 pub mod test_util;
 
 pub use crate::engines::bibtex::BibtexEngine;
+pub use crate::engines::raster::RasterEngine;
+pub use crate::engines::spx2epub::Spx2EpubEngine;
 pub use crate::engines::spx2html::Spx2HtmlEngine;
+pub use crate::engines::spx2txt::Spx2TxtEngine;
+pub use crate::engines::svg::SvgEngine;
 pub use crate::engines::tex::{TexEngine, TexResult};
 pub use crate::engines::xdvipdfmx::XdvipdfmxEngine;
 pub use crate::errors::{Error, ErrorKind, Result};
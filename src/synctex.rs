@@ -0,0 +1,765 @@
+// src/synctex.rs -- reading SyncTeX data
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Query the SyncTeX data written by the `--synctex` option.
+//!
+//! A SyncTeX file records, for every page of the output, the boxes, glue,
+//! kerns, rules and math nodes that were shipped out, each tagged with the
+//! input file and line that produced it. The [`SyncTex`] type parses such a
+//! file, compressed or not, and answers the two questions that editors and
+//! viewers ask of it:
+//!
+//! - a *forward* (“view”) query maps a line of an input file to the places on
+//!   the output pages that it produced;
+//! - an *inverse* (“edit”) query maps a point on an output page to the input
+//!   line that produced whatever is there.
+//!
+//! All positions on the page are expressed in PDF big points, measured from
+//! the top left corner of the page, with *y* growing downwards. The scale and
+//! offsets given in the file’s preamble and post scriptum are applied, so
+//! that the numbers can be used directly against the PDF output.
+
+use flate2::read::GzDecoder;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use crate::errors::{Error, Result};
+use crate::{ctry, errmsg};
+
+/// The number of scaled points in a PDF big point: 65536 × 72.27 / 72.
+const SP_PER_BP: f64 = 65781.76;
+
+/// The file names of the SyncTeX data that may accompany an output, in order
+/// of preference.
+const EXTENSIONS: &[&str] = &["synctex.gz", "synctex"];
+
+/// A rectangle on an output page, as returned by a forward query.
+///
+/// Like a TeX box, the rectangle has a reference point on its baseline: it
+/// extends `width` to the right of `x`, `height` above `y` and `depth` below
+/// it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageBox {
+    /// The number of the page, starting at 1.
+    pub page: u32,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+    pub depth: f64,
+}
+
+/// A position in an input file, as returned by an inverse query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceLocation {
+    /// The name of the input file, as the engine recorded it.
+    pub file: String,
+    pub line: i32,
+    /// The column within the line, if the file records one.
+    pub column: Option<i32>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    VBox,
+    HBox,
+    VoidVBox,
+    VoidHBox,
+    Current,
+    Kern,
+    Glue,
+    Math,
+    Rule,
+}
+
+impl Kind {
+    fn from_byte(b: u8) -> Option<Kind> {
+        Some(match b {
+            b'[' => Kind::VBox,
+            b'(' => Kind::HBox,
+            b'v' => Kind::VoidVBox,
+            b'h' => Kind::VoidHBox,
+            b'x' => Kind::Current,
+            b'k' => Kind::Kern,
+            b'g' => Kind::Glue,
+            b'$' => Kind::Math,
+            b'r' => Kind::Rule,
+            _ => return None,
+        })
+    }
+
+    fn is_box(self) -> bool {
+        self.is_hbox() || self == Kind::VBox || self == Kind::VoidVBox || self == Kind::Rule
+    }
+
+    fn is_hbox(self) -> bool {
+        self == Kind::HBox || self == Kind::VoidHBox
+    }
+}
+
+/// A node of a page, in the units of the file.
+#[derive(Clone, Debug)]
+struct Record {
+    kind: Kind,
+    tag: i32,
+    line: i32,
+    column: Option<i32>,
+    h: i64,
+    v: i64,
+    width: i64,
+    height: i64,
+    depth: i64,
+    /// The index of the enclosing box within the page.
+    parent: Option<usize>,
+}
+
+impl Record {
+    /// The horizontal and vertical extents of the node.
+    fn extents(&self) -> ((i64, i64), (i64, i64)) {
+        let (x0, x1) = if self.width < 0 {
+            (self.h + self.width, self.h)
+        } else {
+            (self.h, self.h + self.width)
+        };
+        ((x0, x1), (self.v - self.height, self.v + self.depth))
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Page {
+    number: u32,
+    records: Vec<Record>,
+}
+
+/// The contents of a SyncTeX file.
+#[derive(Clone, Debug)]
+pub struct SyncTex {
+    inputs: HashMap<i32, String>,
+    pages: Vec<Page>,
+    /// The size of a unit of the file, in big points.
+    unit: f64,
+    x_offset: f64,
+    y_offset: f64,
+}
+
+/// Where we are in the file.
+#[derive(Clone, Copy, PartialEq)]
+enum Section {
+    Preamble,
+    Content,
+    Postamble,
+    PostScriptum,
+}
+
+impl SyncTex {
+    /// Read SyncTeX data from a file, which may be compressed with gzip.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<SyncTex> {
+        let path = path.as_ref();
+        let file = ctry!(File::open(path); "couldn't open SyncTeX file \"{}\"", path.display());
+        Ok(ctry!(SyncTex::from_reader(file); "couldn't read SyncTeX file \"{}\"", path.display()))
+    }
+
+    /// Find and read the SyncTeX data that accompanies the output file at
+    /// `path`, for instance `doc.synctex.gz` next to `doc.pdf`. If `path`
+    /// names a SyncTeX file itself, it is read directly.
+    pub fn for_output<P: AsRef<Path>>(path: P) -> Result<SyncTex> {
+        let path = path.as_ref();
+        let name = path.to_string_lossy();
+
+        if EXTENSIONS
+            .iter()
+            .any(|ext| name.ends_with(&format!(".{}", ext)))
+        {
+            return SyncTex::from_path(path);
+        }
+
+        for ext in EXTENSIONS {
+            let candidate = path.with_extension(ext);
+            if candidate.is_file() {
+                return SyncTex::from_path(candidate);
+            }
+        }
+
+        Err(errmsg!(
+            "no SyncTeX data found for \"{}\"; was it generated with --synctex?",
+            path.display()
+        ))
+    }
+
+    /// Read SyncTeX data from a stream, which may be compressed with gzip.
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<SyncTex> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+
+        if data.starts_with(&[0x1f, 0x8b]) {
+            let mut plain = Vec::new();
+            GzDecoder::new(&data[..]).read_to_end(&mut plain)?;
+            data = plain;
+        }
+
+        SyncTex::parse(&String::from_utf8_lossy(&data))
+    }
+
+    /// Parse uncompressed SyncTeX data.
+    pub fn parse(text: &str) -> Result<SyncTex> {
+        let mut inputs = HashMap::new();
+        let mut pages = Vec::new();
+        let mut section = Section::Preamble;
+        let mut seen_version = false;
+
+        let mut magnification = 1000.;
+        let mut unit = 1.;
+        let mut pre_x_offset = 0.;
+        let mut pre_y_offset = 0.;
+        let mut post_magnification = 1.;
+        let mut post_x_offset = None;
+        let mut post_y_offset = None;
+
+        let mut page: Option<Page> = None;
+        let mut open_boxes = Vec::new();
+        let mut form_depth = 0;
+
+        for (n, line) in text.lines().enumerate() {
+            let n = n + 1;
+            let malformed =
+                || -> Error { errmsg!("malformed SyncTeX data on line {}: \"{}\"", n, line) };
+
+            if line.is_empty() {
+                continue;
+            }
+
+            match section {
+                Section::Preamble => {
+                    if line == "Content:" {
+                        section = Section::Content;
+                        continue;
+                    }
+
+                    let (key, value) = split_field(line).ok_or_else(malformed)?;
+
+                    match key {
+                        "SyncTeX Version" => {
+                            if value.trim() != "1" {
+                                return Err(errmsg!(
+                                    "unsupported SyncTeX version \"{}\"",
+                                    value.trim()
+                                ));
+                            }
+                            seen_version = true;
+                        }
+                        "Input" => {
+                            let (tag, name) = split_field(value).ok_or_else(malformed)?;
+                            let tag = tag.parse().map_err(|_| malformed())?;
+                            inputs.insert(tag, name.to_owned());
+                        }
+                        "Magnification" => {
+                            magnification = parse_number(value).ok_or_else(malformed)?
+                        }
+                        "Unit" => unit = parse_number(value).ok_or_else(malformed)?,
+                        "X Offset" => pre_x_offset = parse_number(value).ok_or_else(malformed)?,
+                        "Y Offset" => pre_y_offset = parse_number(value).ok_or_else(malformed)?,
+                        _ => {}
+                    }
+                }
+
+                Section::Content => {
+                    let bytes = line.as_bytes();
+                    let rest = line.get(1..).unwrap_or("");
+
+                    match bytes[0] {
+                        // Inputs may also be declared as the content goes.
+                        b'I' if line.starts_with("Input:") => {
+                            let (tag, name) = split_field(&line[6..]).ok_or_else(malformed)?;
+                            let tag = tag.parse().map_err(|_| malformed())?;
+                            inputs.insert(tag, name.to_owned());
+                        }
+                        b'P' if line == "Postamble:" => {
+                            section = Section::Postamble;
+                        }
+                        b'!' => {}
+                        b'{' => {
+                            let number = rest.trim().parse().map_err(|_| malformed())?;
+                            page = Some(Page {
+                                number,
+                                records: Vec::new(),
+                            });
+                            open_boxes.clear();
+                        }
+                        b'}' => {
+                            if let Some(p) = page.take() {
+                                pages.push(p);
+                            }
+                        }
+                        // Forms are not referenced by the pages we produce, so
+                        // their contents are skipped.
+                        b'<' => form_depth += 1,
+                        b'>' => form_depth -= 1,
+                        b']' | b')' => {
+                            if form_depth == 0 {
+                                open_boxes.pop();
+                            }
+                        }
+                        b => {
+                            let kind = match Kind::from_byte(b) {
+                                Some(k) => k,
+                                // Ignore records that we do not understand,
+                                // such as form references.
+                                None => continue,
+                            };
+
+                            if form_depth > 0 {
+                                continue;
+                            }
+
+                            let p = page.as_mut().ok_or_else(malformed)?;
+                            let mut record = parse_record(kind, rest).ok_or_else(malformed)?;
+                            record.parent = open_boxes.last().cloned();
+                            p.records.push(record);
+
+                            if kind == Kind::VBox || kind == Kind::HBox {
+                                open_boxes.push(p.records.len() - 1);
+                            }
+                        }
+                    }
+                }
+
+                Section::Postamble => {
+                    if line == "Post scriptum:" {
+                        section = Section::PostScriptum;
+                    }
+                }
+
+                Section::PostScriptum => {
+                    let (key, value) = split_field(line).ok_or_else(malformed)?;
+
+                    match key {
+                        "Magnification" => {
+                            post_magnification = parse_number(value).ok_or_else(malformed)?
+                        }
+                        "X Offset" => {
+                            post_x_offset = Some(parse_dimension(value).ok_or_else(malformed)?)
+                        }
+                        "Y Offset" => {
+                            post_y_offset = Some(parse_dimension(value).ok_or_else(malformed)?)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if !seen_version {
+            return Err(errmsg!("not a SyncTeX file: no version line"));
+        }
+
+        if let Some(p) = page.take() {
+            pages.push(p);
+        }
+
+        let pre_unit = unit * magnification / 1000. / SP_PER_BP;
+        let unit = pre_unit * post_magnification;
+
+        Ok(SyncTex {
+            inputs,
+            pages,
+            unit,
+            x_offset: post_x_offset.unwrap_or(pre_x_offset * pre_unit),
+            y_offset: post_y_offset.unwrap_or(pre_y_offset * pre_unit),
+        })
+    }
+
+    /// The names of the input files that the data refers to.
+    pub fn inputs(&self) -> impl Iterator<Item = &str> {
+        self.inputs.values().map(|s| s.as_ref())
+    }
+
+    /// The numbers of the pages that the data describes.
+    pub fn pages(&self) -> impl Iterator<Item = u32> + '_ {
+        self.pages.iter().map(|p| p.number)
+    }
+
+    /// Find the places on the output pages that were produced by line `line`
+    /// of the input file `file`.
+    ///
+    /// The file is matched against the recorded input names first exactly,
+    /// then ignoring a leading `./`, and finally by its last path component.
+    /// If the line itself produced nothing — it may be blank, or a comment —
+    /// the nearest following line that did is used instead, then the nearest
+    /// preceding one. For each matching node, the result is the smallest
+    /// horizontal box that encloses it. Vertical boxes are not matched, since
+    /// the lines they are tagged with are usually those of their contents.
+    pub fn forward(&self, file: &str, line: i32) -> Vec<PageBox> {
+        let tags = self.find_input(file);
+        if tags.is_empty() {
+            return Vec::new();
+        }
+
+        let mut lines = Vec::new();
+        for p in &self.pages {
+            for r in &p.records {
+                if r.kind != Kind::VBox && tags.contains(&r.tag) {
+                    lines.push(r.line);
+                }
+            }
+        }
+
+        let target = match lines
+            .iter()
+            .filter(|l| **l >= line)
+            .min()
+            .or_else(|| lines.iter().max())
+        {
+            Some(l) => *l,
+            None => return Vec::new(),
+        };
+
+        let mut results = Vec::new();
+
+        for p in &self.pages {
+            let mut seen = Vec::new();
+
+            for (i, r) in p.records.iter().enumerate() {
+                if r.kind == Kind::VBox || r.line != target || !tags.contains(&r.tag) {
+                    continue;
+                }
+
+                let mut j = i;
+                while !p.records[j].kind.is_hbox() {
+                    match p.records[j].parent {
+                        Some(k) => j = k,
+                        None => {
+                            j = i;
+                            break;
+                        }
+                    }
+                }
+
+                if seen.contains(&j) {
+                    continue;
+                }
+                seen.push(j);
+
+                let b = &p.records[j];
+                results.push(PageBox {
+                    page: p.number,
+                    x: self.x(b.h),
+                    y: self.y(b.v),
+                    width: b.width as f64 * self.unit,
+                    height: b.height as f64 * self.unit,
+                    depth: b.depth as f64 * self.unit,
+                });
+            }
+        }
+
+        results
+    }
+
+    /// Find the input line that produced what is at the point (`x`, `y`) of
+    /// page `page`.
+    ///
+    /// The deepest horizontal box containing the point is looked up, and the
+    /// node within it that is horizontally closest to the point is reported.
+    /// If no box contains the point, the closest node on the page is used.
+    pub fn inverse(&self, page: u32, x: f64, y: f64) -> Option<SourceLocation> {
+        let p = self.pages.iter().find(|p| p.number == page)?;
+
+        // Back to the units of the file.
+        let h = ((x - self.x_offset) / self.unit).round() as i64;
+        let v = ((y - self.y_offset) / self.unit).round() as i64;
+
+        let known = |r: &Record| r.line > 0 && self.inputs.contains_key(&r.tag);
+
+        let container = p
+            .records
+            .iter()
+            .enumerate()
+            .filter(|(_, r)| r.kind.is_hbox() && known(r) && contains(r, h, v))
+            .min_by_key(|(_, r)| {
+                let ((x0, x1), (y0, y1)) = r.extents();
+                (x1 - x0) * (y1 - y0)
+            })
+            .map(|(i, _)| i);
+
+        let best = if let Some(c) = container {
+            p.records
+                .iter()
+                .filter(|r| r.parent == Some(c) && known(r))
+                .min_by_key(|r| {
+                    let ((x0, x1), _) = r.extents();
+                    distance(h, x0, x1)
+                })
+                .unwrap_or(&p.records[c])
+        } else {
+            p.records.iter().filter(|r| known(r)).min_by_key(|r| {
+                let ((x0, x1), (y0, y1)) = r.extents();
+                let dx = distance(h, x0, x1) as f64;
+                let dy = distance(v, y0, y1) as f64;
+                (dx * dx + dy * dy) as u64
+            })?
+        };
+
+        Some(SourceLocation {
+            file: self.inputs[&best.tag].clone(),
+            line: best.line,
+            column: best.column,
+        })
+    }
+
+    fn find_input(&self, file: &str) -> Vec<i32> {
+        fn strip(s: &str) -> &str {
+            s.trim_start_matches("./")
+        }
+
+        fn basename(s: &str) -> &str {
+            s.rsplit(&['/', '\\'][..]).next().unwrap_or(s)
+        }
+
+        let exact: Vec<i32> = self
+            .inputs
+            .iter()
+            .filter(|(_, name)| strip(name) == strip(file))
+            .map(|(tag, _)| *tag)
+            .collect();
+
+        if !exact.is_empty() {
+            return exact;
+        }
+
+        self.inputs
+            .iter()
+            .filter(|(_, name)| basename(name) == basename(file))
+            .map(|(tag, _)| *tag)
+            .collect()
+    }
+
+    fn x(&self, h: i64) -> f64 {
+        h as f64 * self.unit + self.x_offset
+    }
+
+    fn y(&self, v: i64) -> f64 {
+        v as f64 * self.unit + self.y_offset
+    }
+}
+
+fn contains(r: &Record, h: i64, v: i64) -> bool {
+    let ((x0, x1), (y0, y1)) = r.extents();
+    x0 <= h && h <= x1 && y0 <= v && v <= y1
+}
+
+/// The distance from `a` to the interval [`lo`, `hi`].
+fn distance(a: i64, lo: i64, hi: i64) -> i64 {
+    if a < lo {
+        lo - a
+    } else if a > hi {
+        a - hi
+    } else {
+        0
+    }
+}
+
+/// Split `key:value` at the first colon.
+fn split_field(s: &str) -> Option<(&str, &str)> {
+    let i = s.find(':')?;
+    Some((&s[..i], &s[i + 1..]))
+}
+
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse().ok()
+}
+
+/// Parse a TeX dimension such as `-1.5in`, returning it in big points. A bare
+/// number is taken to be in big points already.
+fn parse_dimension(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let split = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let value: f64 = s[..split].trim().parse().ok()?;
+
+    let factor = match &s[split..] {
+        "" | "bp" => 1.,
+        "pt" => 72. / 72.27,
+        "in" => 72.,
+        "cm" => 72. / 2.54,
+        "mm" => 72. / 25.4,
+        "pc" => 12. * 72. / 72.27,
+        "dd" => 1238. / 1157. * 72. / 72.27,
+        "cc" => 12. * 1238. / 1157. * 72. / 72.27,
+        "sp" => 1. / SP_PER_BP,
+        _ => return None,
+    };
+
+    Some(value * factor)
+}
+
+/// Parse the body of a node record: `tag,line[,column]:h,v[:W[,H,D]]`.
+fn parse_record(kind: Kind, s: &str) -> Option<Record> {
+    let mut parts = s.split(':');
+    let mut link = parts.next()?.split(',');
+    let mut point = parts.next()?.split(',');
+    let size: Vec<i64> = match parts.next() {
+        Some(s) => s
+            .split(',')
+            .map(|v| v.trim().parse().ok())
+            .collect::<Option<_>>()?,
+        None => Vec::new(),
+    };
+
+    let tag = link.next()?.trim().parse().ok()?;
+    let line = link.next()?.trim().parse().ok()?;
+    let column = match link.next() {
+        Some(c) => Some(c.trim().parse().ok()?),
+        None => None,
+    };
+    let h = point.next()?.trim().parse().ok()?;
+    let v = point.next()?.trim().parse().ok()?;
+
+    let (width, height, depth) = match (kind.is_box(), size.len()) {
+        (true, 3) => (size[0], size[1], size[2]),
+        (false, 1) if kind == Kind::Kern => (size[0], 0, 0),
+        (false, 0) => (0, 0, 0),
+        _ => return None,
+    };
+
+    Some(Record {
+        kind,
+        tag,
+        line,
+        column,
+        h,
+        v,
+        width,
+        height,
+        depth,
+        parent: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const SAMPLE: &str = "SyncTeX Version:1
+Input:1:./main.tex
+Input:2:./chapter.tex
+Output:pdf
+Magnification:1000
+Unit:1
+X Offset:0
+Y Offset:0
+Content:
+!150
+{1
+[1,5:4736287,4736287:26673152,43725786,0
+(1,7:4736287,5394432:26673152,455111,127431
+x1,7:4736287,5394432
+k1,7:6000000,5394432:100000
+g1,7:7000000,5394432
+)
+(2,3:4736287,6000000:26673152,455111,127431
+$2,3:8000000,6000000
+r2,4:9000000,6000000:65536,65536,0
+)
+]
+}1
+!80
+{2
+[1,9:4736287,4736287:26673152,43725786,0
+(1,9,4:4736287,5394432:1000000,455111,127431
+)
+]
+}2
+Postamble:
+Count:14
+Post scriptum:
+";
+
+    fn close(a: f64, b: f64) -> bool {
+        (a - b).abs() < 1e-4
+    }
+
+    #[test]
+    fn preamble() {
+        let s = SyncTex::parse(SAMPLE).unwrap();
+        let mut inputs: Vec<_> = s.inputs().collect();
+        inputs.sort();
+        assert_eq!(inputs, vec!["./chapter.tex", "./main.tex"]);
+        assert_eq!(s.pages().collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn forward() {
+        let s = SyncTex::parse(SAMPLE).unwrap();
+
+        let r = s.forward("main.tex", 7);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].page, 1);
+        assert!(close(r[0].x, 72.));
+        assert!(close(r[0].y, 5394432. / SP_PER_BP));
+        assert!(close(r[0].width, 26673152. / SP_PER_BP));
+
+        // Lines that produce nothing resolve to the next one that does.
+        let r = s.forward("/some/dir/main.tex", 8);
+        assert_eq!(r.len(), 1);
+        assert_eq!(r[0].page, 2);
+
+        // Nodes resolve to their enclosing hbox.
+        let r = s.forward("chapter.tex", 4);
+        assert_eq!(r.len(), 1);
+        assert!(close(r[0].y, 6000000. / SP_PER_BP));
+
+        assert!(s.forward("missing.tex", 1).is_empty());
+    }
+
+    #[test]
+    fn inverse() {
+        let s = SyncTex::parse(SAMPLE).unwrap();
+        let bp = |sp: f64| sp / SP_PER_BP;
+
+        let loc = s.inverse(1, bp(9000000.), bp(6000000.)).unwrap();
+        assert_eq!(loc.file, "./chapter.tex");
+        assert_eq!(loc.line, 4);
+
+        let loc = s.inverse(1, bp(6100000.), bp(5300000.)).unwrap();
+        assert_eq!(loc.file, "./main.tex");
+        assert_eq!(loc.line, 7);
+
+        let loc = s.inverse(2, bp(5000000.), bp(5394432.)).unwrap();
+        assert_eq!(loc.line, 9);
+        assert_eq!(loc.column, Some(4));
+
+        assert!(s.inverse(3, 0., 0.).is_none());
+    }
+
+    #[test]
+    fn post_scriptum() {
+        let text = format!("{}Magnification:2\nX Offset:1in\nY Offset:-2pt\n", SAMPLE);
+        let s = SyncTex::parse(&text).unwrap();
+        let r = s.forward("main.tex", 7);
+        assert!(close(r[0].x, 72. + 2. * 72.));
+        assert!(close(r[0].y, -2. * 72. / 72.27 + 2. * bp(5394432.)));
+
+        fn bp(sp: f64) -> f64 {
+            sp / SP_PER_BP
+        }
+    }
+
+    #[test]
+    fn gzipped() {
+        let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+        enc.write_all(SAMPLE.as_bytes()).unwrap();
+        let data = enc.finish().unwrap();
+        let s = SyncTex::from_reader(&data[..]).unwrap();
+        assert_eq!(s.forward("main.tex", 9)[0].page, 2);
+    }
+
+    #[test]
+    fn malformed() {
+        assert!(SyncTex::parse("Content:\n").is_err());
+        assert!(SyncTex::parse("SyncTeX Version:1\nContent:\n{1\n(1,2:3\n").is_err());
+    }
+}
@@ -183,6 +183,116 @@ fn bad_outfmt_1() {
     error_or_panic(output);
 }
 
+#[test]
+fn export_archive() {
+    if env::var("RUNNING_COVERAGE").is_ok() {
+        return;
+    }
+
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[
+        "subdirectory/relative_include.tex",
+        "subdirectory/content/1.tex",
+    ]);
+
+    let output = run_tectonic(
+        tempdir.path(),
+        &[
+            &fmt_arg,
+            "subdirectory/relative_include.tex",
+            "--export-archive=export.tar",
+        ],
+    );
+    success_or_panic(output);
+    check_file(&tempdir, "export.tar");
+
+    let mut archive = Vec::new();
+    File::open(tempdir.path().join("export.tar"))
+        .unwrap()
+        .read_to_end(&mut archive)
+        .unwrap();
+    let aio = tectonic::io::ArchiveIo::from_bytes(archive).unwrap();
+    let mut names: Vec<_> = aio.file_names().collect();
+    names.sort();
+    assert_eq!(names, vec!["content/1.tex", "relative_include.tex"]);
+}
+
+#[test]
+fn export_archive_bibliography() {
+    if env::var("RUNNING_COVERAGE").is_ok() {
+        return;
+    }
+
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&["bibliography.tex", "bibliography.bib"]);
+    fs::copy(
+        TEST_ROOT.join("bibtex/plain.bst"),
+        tempdir.path().join("plain.bst"),
+    )
+    .unwrap();
+
+    let output = run_tectonic(
+        tempdir.path(),
+        &[&fmt_arg, "bibliography.tex", "--export-archive=export.zip"],
+    );
+    success_or_panic(output);
+    check_file(&tempdir, "export.zip");
+
+    // The .bbl file that BibTeX generated goes along, so that the archive
+    // can be processed without running BibTeX again.
+    let mut archive = Vec::new();
+    File::open(tempdir.path().join("export.zip"))
+        .unwrap()
+        .read_to_end(&mut archive)
+        .unwrap();
+    let aio = tectonic::io::ArchiveIo::from_bytes(archive).unwrap();
+    let mut names: Vec<_> = aio.file_names().collect();
+    names.sort();
+    assert_eq!(
+        names,
+        vec![
+            "bibliography.bbl",
+            "bibliography.bib",
+            "bibliography.tex",
+            "plain.bst"
+        ]
+    );
+}
+
+#[test]
+fn flatten() {
+    if env::var("RUNNING_COVERAGE").is_ok() {
+        return;
+    }
+
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[
+        "subdirectory/relative_include.tex",
+        "subdirectory/content/1.tex",
+    ]);
+
+    let output = run_tectonic(
+        tempdir.path(),
+        &[
+            &fmt_arg,
+            "subdirectory/relative_include.tex",
+            "--flatten=flat.tex",
+        ],
+    );
+    success_or_panic(output);
+    check_file(&tempdir, "flat.tex");
+
+    let mut flat = String::new();
+    File::open(tempdir.path().join("flat.tex"))
+        .unwrap()
+        .read_to_string(&mut flat)
+        .unwrap();
+    assert_eq!(
+        flat,
+        "This is the main document.\n\nThis is content/1.tex\n\n\\bye\n\n\\bye\n"
+    );
+}
+
 #[test]
 fn help_flag() {
     if env::var("RUNNING_COVERAGE").is_ok() {
@@ -307,6 +417,25 @@ fn test_outdir_is_file() {
     success_or_panic(output);
 }
 
+#[test]
+fn test_outdir_stdout() {
+    if env::var("RUNNING_COVERAGE").is_ok() {
+        return;
+    }
+
+    let fmt_arg = get_plain_format_arg();
+    let tempdir = setup_and_copy_files(&[]);
+    let output = run_tectonic_with_stdin(
+        tempdir.path(),
+        &[&fmt_arg, "-", "--outdir=-"],
+        "Standard input content.\\bye",
+    );
+
+    assert!(output.stdout.starts_with(b"%PDF-"));
+    assert!(!tempdir.path().join("texput.pdf").exists());
+    success_or_panic(output);
+}
+
 #[test]
 fn test_keep_logs_on_error() {
     if env::var("RUNNING_COVERAGE").is_ok() {
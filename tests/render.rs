@@ -0,0 +1,96 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Render the XDV files of the TeX output tests to page images.
+
+use std::collections::HashSet;
+use std::default::Default;
+use std::ffi::OsStr;
+
+use tectonic::engines::{NoopIoEventBackend, RasterEngine};
+use tectonic::io::{FilesystemIo, IoProvider, IoStack, MemoryIo};
+use tectonic::status::NoopStatusBackend;
+
+#[path = "util/mod.rs"]
+mod util;
+use crate::util::test_path;
+
+/// Render `<stem>.xdv` and return the size and the pixels of the page, as
+/// RGB.
+fn render(stem: &str, dpi: f64) -> (u32, u32, Vec<u8>) {
+    util::set_test_root();
+
+    let xdvname = format!("{}.xdv", stem);
+    let pngname = format!("{}.png", stem);
+
+    let mut mem = MemoryIo::new(false);
+    let mut outputs = FilesystemIo::new(&test_path(&["tex-outputs"]), false, false, HashSet::new());
+    let mut assets = FilesystemIo::new(&test_path(&["assets"]), false, false, HashSet::new());
+
+    {
+        let io_list: Vec<&mut dyn IoProvider> = vec![&mut mem, &mut outputs, &mut assets];
+        let mut io = IoStack::new(io_list);
+        let mut events = NoopIoEventBackend::new();
+        let mut status = NoopStatusBackend::new();
+
+        RasterEngine::new()
+            .with_resolution(dpi)
+            .process(
+                &mut io,
+                &mut events,
+                &mut status,
+                &xdvname,
+                &Default::default(),
+            )
+            .unwrap();
+    }
+
+    let files = mem.files.borrow();
+    let data = files
+        .get(OsStr::new(&pngname))
+        .unwrap_or_else(|| panic!("{} was not written", pngname));
+
+    let decoder = png::Decoder::new(&data[..]);
+    let (info, mut reader) = decoder.read_info().unwrap();
+    assert_eq!(info.color_type, png::ColorType::RGB);
+    let mut pixels = vec![0; info.buffer_size()];
+    reader.next_frame(&mut pixels).unwrap();
+    (info.width, info.height, pixels)
+}
+
+/// Count the pixels that are noticeably darker than the white background.
+fn n_inked(pixels: &[u8]) -> usize {
+    pixels
+        .chunks(3)
+        .filter(|p| (p[0] as u32 + p[1] as u32 + p[2] as u32) < 3 * 192)
+        .count()
+}
+
+#[test]
+fn otf_basic() {
+    let (width, height, pixels) = render("otf_basic", 96.);
+
+    // The default paper is letter, 8.5 by 11 inches.
+    assert_eq!((width, height), (816, 1056));
+    assert!(n_inked(&pixels) > 0, "no glyphs were drawn");
+}
+
+#[test]
+fn tex_logo() {
+    let (width, height, low) = render("tex_logo", 72.);
+    assert_eq!((width, height), (612, 792));
+
+    let (width, height, high) = render("tex_logo", 144.);
+    assert_eq!((width, height), (1224, 1584));
+
+    // The same glyphs cover about four times as many pixels at twice the
+    // resolution.
+    let (low, high) = (n_inked(&low), n_inked(&high));
+    assert!(low > 0, "no glyphs were drawn");
+    assert!(
+        high > 3 * low && high < 5 * low,
+        "{} vs. {} inked pixels",
+        low,
+        high
+    );
+}
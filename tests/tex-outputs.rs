@@ -1,9 +1,13 @@
 // Copyright 2016-2018 the Tectonic Project
 // Licensed under the MIT License.
 
+use std::cell::RefCell;
 use std::collections::HashSet;
 use std::env;
+use std::ffi::OsStr;
+use std::fs;
 use std::path::Path;
+use std::rc::Rc;
 
 use tectonic::engines::tex::TexResult;
 use tectonic::engines::NoopIoEventBackend;
@@ -12,17 +16,33 @@ use tectonic::io::testing::SingleInputFileIo;
 use tectonic::io::{FilesystemIo, FilesystemPrimaryInputIo, IoProvider, IoStack, MemoryIo};
 use tectonic::status::NoopStatusBackend;
 use tectonic::unstable_opts::UnstableOptions;
-use tectonic::{TexEngine, XdvipdfmxEngine};
+use tectonic::{Spx2HtmlEngine, TexEngine, XdvipdfmxEngine};
 
 #[path = "util/mod.rs"]
 mod util;
 use crate::util::{ensure_plain_format, test_path, ExpectedInfo};
 
+/// Looks into the PDF output of a test.
+type PdfCheck = Box<dyn Fn(&[u8])>;
+
+/// Looks into the HTML output of a test.
+type HtmlCheck = Box<dyn Fn(&[u8])>;
+
+/// Sets up the TeX engine of a test.
+type TexEngineSetup = Box<dyn Fn(&mut TexEngine)>;
+
+/// Sets up the xdvipdfmx engine of a test.
+type PdfEngineSetup = Box<dyn Fn(XdvipdfmxEngine) -> XdvipdfmxEngine>;
+
 struct TestCase {
     stem: String,
     expected_result: Result<TexResult>,
     check_synctex: bool,
     check_pdf: bool,
+    pdf_check: Option<PdfCheck>,
+    html_check: Option<HtmlCheck>,
+    tex_engine: TexEngineSetup,
+    pdf_engine: PdfEngineSetup,
     extra_io: Vec<Box<dyn IoProvider>>,
     unstables: UnstableOptions,
 }
@@ -34,6 +54,10 @@ impl TestCase {
             expected_result: Ok(TexResult::Spotless),
             check_synctex: false,
             check_pdf: false,
+            pdf_check: None,
+            html_check: None,
+            tex_engine: Box::new(|_| {}),
+            pdf_engine: Box::new(|engine| engine),
             extra_io: Vec::new(),
             unstables: UnstableOptions::default(),
         }
@@ -49,6 +73,37 @@ impl TestCase {
         self
     }
 
+    /// Hand the PDF output to `check` instead of comparing it with an
+    /// expected file. Such tests are about one feature of the PDF output, so
+    /// the log and XDV files aren't compared either.
+    fn check_pdf_with<F: Fn(&[u8]) + 'static>(&mut self, check: F) -> &mut Self {
+        self.pdf_check = Some(Box::new(check));
+        self
+    }
+
+    /// Run the TeX engine with semantic pagination and hand the HTML that
+    /// spx2html makes of its output to `check`. As with `check_pdf_with`,
+    /// the log and SPX files aren't compared.
+    fn check_html_with<F: Fn(&[u8]) + 'static>(&mut self, check: F) -> &mut Self {
+        self.html_check = Some(Box::new(check));
+        self
+    }
+
+    /// Set up the TeX engine beyond the defaults of the test suite.
+    fn with_tex_engine<F: Fn(&mut TexEngine) + 'static>(&mut self, setup: F) -> &mut Self {
+        self.tex_engine = Box::new(setup);
+        self
+    }
+
+    /// Set up the xdvipdfmx engine beyond the defaults of the test suite.
+    fn with_pdf_engine<F>(&mut self, setup: F) -> &mut Self
+    where
+        F: Fn(XdvipdfmxEngine) -> XdvipdfmxEngine + 'static,
+    {
+        self.pdf_engine = Box::new(setup);
+        self
+    }
+
     fn with_fs(&mut self, path: &Path) -> &mut Self {
         self.extra_io.push(Box::new(FilesystemIo::new(
             path,
@@ -76,7 +131,10 @@ impl TestCase {
     fn go(&mut self) {
         util::set_test_root();
 
-        let expect_xdv = self.expected_result.is_ok();
+        let own_check = self.pdf_check.is_some() || self.html_check.is_some();
+        let expect_xdv = self.expected_result.is_ok() && !own_check;
+        let run_pdf = self.check_pdf || self.pdf_check.is_some();
+        let run_html = self.html_check.is_some();
 
         let mut p = test_path(&[]);
 
@@ -98,6 +156,12 @@ impl TestCase {
         p.set_extension("pdf");
         let pdfname = p.file_name().unwrap().to_str().unwrap().to_owned();
 
+        p.set_extension("spx");
+        let spxname = p.file_name().unwrap().to_str().unwrap().to_owned();
+
+        p.set_extension("html");
+        let htmlname = p.file_name().unwrap().to_str().unwrap().to_owned();
+
         // MemoryIo layer that will accept the outputs.
         let mut mem = MemoryIo::new(true);
 
@@ -106,7 +170,11 @@ impl TestCase {
         // add this layer.
         let mut assets = FilesystemIo::new(&test_path(&["assets"]), false, false, HashSet::new());
 
-        let expected_log = ExpectedInfo::read_with_extension(&mut p, "log");
+        let expected_log = if !own_check {
+            Some(ExpectedInfo::read_with_extension(&mut p, "log"))
+        } else {
+            None
+        };
 
         // Run the engine(s)!
         let res = {
@@ -120,7 +188,10 @@ impl TestCase {
             let mut events = NoopIoEventBackend::new();
             let mut status = NoopStatusBackend::new();
 
-            let tex_res = TexEngine::new().process(
+            let mut engine = TexEngine::new();
+            engine.semantic_pagination(run_html);
+            (self.tex_engine)(&mut engine);
+            let tex_res = engine.process(
                 &mut io,
                 &mut events,
                 &mut status,
@@ -129,14 +200,15 @@ impl TestCase {
                 &self.unstables,
             );
 
-            if self.check_pdf && tex_res.definitely_same(&Ok(TexResult::Spotless)) {
+            if run_pdf && tex_res.definitely_same(&Ok(TexResult::Spotless)) {
                 // While the xdv and log output is deterministic without setting
                 // SOURCE_DATE_EPOCH, xdvipdfmx uses the current date in various places.
                 env::set_var("SOURCE_DATE_EPOCH", "1456304492"); // TODO: default to deterministic behaviour
 
-                XdvipdfmxEngine::new()
+                let engine = XdvipdfmxEngine::new()
                     .with_compression(false)
-                    .with_deterministic_tags(true)
+                    .with_deterministic_tags(true);
+                (self.pdf_engine)(engine)
                     .process(
                         &mut io,
                         &mut events,
@@ -148,6 +220,12 @@ impl TestCase {
                     .unwrap();
             }
 
+            if run_html && tex_res.definitely_same(&Ok(TexResult::Spotless)) {
+                Spx2HtmlEngine::new()
+                    .process(&mut io, &mut events, &mut status, &spxname)
+                    .unwrap();
+            }
+
             tex_res
         };
 
@@ -162,7 +240,9 @@ impl TestCase {
 
         let files = mem.files.borrow();
 
-        expected_log.test_from_collection(&files);
+        if let Some(expected_log) = expected_log {
+            expected_log.test_from_collection(&files);
+        }
 
         if expect_xdv {
             ExpectedInfo::read_with_extension(&mut p, "xdv").test_from_collection(&files);
@@ -175,9 +255,70 @@ impl TestCase {
         if self.check_pdf {
             ExpectedInfo::read_with_extension(&mut p, "pdf").test_from_collection(&files);
         }
+
+        if let Some(ref check) = self.pdf_check {
+            let pdf = files
+                .get(OsStr::new(&pdfname))
+                .unwrap_or_else(|| panic!("{} was not written", pdfname));
+            check(pdf);
+        }
+
+        if let Some(ref check) = self.html_check {
+            let html = files
+                .get(OsStr::new(&htmlname))
+                .unwrap_or_else(|| panic!("{} was not written", htmlname));
+            check(html);
+        }
+    }
+}
+
+/// The position of the first occurrence of `needle` in `data`.
+fn find(data: &[u8], needle: &[u8]) -> Option<usize> {
+    data.windows(needle.len()).position(|w| w == needle)
+}
+
+/// The unsigned integers that follow the first occurrence of `key` in `data`,
+/// up to the first thing that is not one.
+fn numbers_after(data: &[u8], key: &[u8]) -> Vec<usize> {
+    let start = find(data, key).unwrap_or_else(|| {
+        panic!("no {} in the PDF output", String::from_utf8_lossy(key));
+    });
+    let mut rest = &data[start + key.len()..];
+    let mut numbers = Vec::new();
+
+    loop {
+        let n_white = rest.iter().take_while(|c| c.is_ascii_whitespace()).count();
+        let n_digits = rest[n_white..]
+            .iter()
+            .take_while(|c| c.is_ascii_digit())
+            .count();
+        if n_digits == 0 {
+            return numbers;
+        }
+        let digits = std::str::from_utf8(&rest[n_white..n_white + n_digits]).unwrap();
+        numbers.push(digits.parse().unwrap());
+        rest = &rest[n_white + n_digits..];
     }
 }
 
+/// The contents of indirect object `label` of a PDF file written without
+/// object streams.
+fn pdf_object(pdf: &[u8], label: usize) -> &[u8] {
+    let header = format!("\n{} 0 obj", label);
+    let start = find(pdf, header.as_bytes())
+        .unwrap_or_else(|| panic!("no object {} in the PDF output", label))
+        + header.len();
+    let len = find(&pdf[start..], b"endobj").unwrap();
+    &pdf[start..start + len]
+}
+
+/// Whether an indirect object starts at `offset`.
+fn is_object_start(pdf: &[u8], offset: usize) -> bool {
+    let rest = &pdf[offset..];
+    let n_digits = rest.iter().take_while(|c| c.is_ascii_digit()).count();
+    n_digits > 0 && rest[n_digits..].starts_with(b" 0 obj")
+}
+
 // Keep these alphabetized.
 #[test]
 fn md5_of_hello() {
@@ -228,6 +369,161 @@ fn unicode_file_name() {
         .go()
 }
 
+#[test]
+fn figure_twice() {
+    TestCase::new("figure_twice")
+        .with_pdf_engine(|engine| engine.with_size_optimization(true))
+        .check_pdf_with(|pdf| {
+            // The figure's content is written once, and both names on the
+            // page refer to it.
+            let figure = b"0 0 1 rg 0 0 20 10 re f";
+            let first = find(pdf, figure).unwrap();
+            assert!(find(&pdf[first + 1..], figure).is_none());
+
+            let xobjects = &pdf[find(pdf, b"/XObject<<").unwrap()..];
+            let fm0 = numbers_after(xobjects, b"/Fm0 ");
+            assert_eq!(fm0.len(), 2);
+            assert_eq!(numbers_after(xobjects, b"/Fm1 "), fm0);
+        })
+        .go()
+}
+
+#[test]
+fn image_formats() {
+    TestCase::new("image_formats")
+        .check_pdf_with(|pdf| {
+            // All of the images hold the same eight colors: the GIF as
+            // indices into its palette, the first TIFF page and the WebP as
+            // plain RGB samples. The second TIFF page is 2x3 gray.
+            let colors = b"\xff\x00\x00\x00\xff\x00\x00\x00\xff\xff\xff\xff\
+                           \x00\x00\x00\x12\x34\x56\x9a\xbc\xde\x80\x80\x80";
+            let palette = b"/Indexed/DeviceRGB 7<ff000000ff000000ffffffff0000001234569abcde808080>";
+            assert!(find(pdf, palette).is_some());
+            assert!(find(pdf, b"stream\n\x00\x01\x02\x03\x04\x05\x06\x07\n").is_some());
+
+            let rgb = [&b"stream\n"[..], &colors[..], b"\n"].concat();
+            let first = find(pdf, &rgb).unwrap();
+            assert!(find(&pdf[first + 1..], &rgb).is_some());
+
+            let gray = find(pdf, b"stream\n\x11\x22\x33\x44\x55\x66\n").unwrap();
+            let dict = &pdf[..gray];
+            let dict = &dict[dict.iter().rposition(|&b| b == b'<').unwrap()..];
+            assert_eq!(numbers_after(dict, b"/Width"), vec![2]);
+            assert_eq!(numbers_after(dict, b"/Height"), vec![3]);
+            assert!(find(dict, b"/DeviceGray").is_some());
+        })
+        .go()
+}
+
+#[test]
+fn image_cache() {
+    // The first run converts the four images and saves them in the cache,
+    // the second one takes them from there and writes the same PDF.
+    let cache = tempfile::tempdir().unwrap();
+    let first_pdf = Rc::new(RefCell::new(None));
+
+    for _ in 0..2 {
+        let dir = cache.path().to_owned();
+        let first_pdf = first_pdf.clone();
+        TestCase::new("image_formats")
+            .with_pdf_engine(move |engine| engine.with_image_cache(Some(dir.clone())))
+            .check_pdf_with(move |pdf| {
+                let mut first_pdf = first_pdf.borrow_mut();
+                match *first_pdf {
+                    None => *first_pdf = Some(pdf.to_vec()),
+                    Some(ref first) => assert!(pdf == &first[..], "the PDF output changed"),
+                }
+            })
+            .go();
+        assert_eq!(fs::read_dir(cache.path()).unwrap().count(), 4);
+    }
+}
+
+#[test]
+fn mathml() {
+    TestCase::new("mathml")
+        .check_html_with(|html| {
+            let lines: Vec<&[u8]> = html.split(|&c| c == b'\n').collect();
+            let (math, text): (Vec<&[u8]>, Vec<&[u8]>) = lines
+                .into_iter()
+                .partition(|line| line.starts_with(b"<math "));
+
+            // The characters of the formulas are left out of the text.
+            assert_eq!(text.concat(), b"Beforeandafter.");
+
+            assert_eq!(math.len(), 2);
+            let inline = math[0];
+            assert!(inline.starts_with(b"<math xmlns=\"http://www.w3.org/1998/Math/MathML\">"));
+            assert!(inline.ends_with(b"</math>"));
+            for markup in [
+                &b"<msup><mi>x</mi><mn>2</mn></msup><mo>+</mo>"[..],
+                b"<mfrac><mrow><mi>a</mi></mrow><mrow><mi>b</mi></mrow></mfrac>",
+                b"<mo>=</mo><msqrt><mi>y</mi></msqrt>",
+            ]
+            .iter()
+            {
+                assert!(find(inline, markup).is_some());
+            }
+
+            let display = math[1];
+            assert!(find(display, b" display=\"block\">").is_some());
+            assert!(find(
+                display,
+                b"<mo fence=\"true\" stretchy=\"true\">(</mo><mfrac>"
+            )
+            .is_some());
+            assert!(find(
+                display,
+                b"</mfrac><mo fence=\"true\" stretchy=\"true\">)</mo>"
+            )
+            .is_some());
+        })
+        .go()
+}
+
+#[test]
+fn actual_text() {
+    TestCase::new("actual_text")
+        .with_tex_engine(|engine| {
+            engine.math_actual_text(true);
+        })
+        .check_pdf_with(|pdf| {
+            // Each formula is a marked-content span whose ActualText is its
+            // source, without the math shifts around it.
+            let inline = b"/Span<</ActualText(f\\(x\\)=x^2)>>BDC";
+            let display = b"/Span<</ActualText(a\\\\over b)>>BDC";
+            let inline = find(pdf, inline).unwrap();
+            let display = find(pdf, display).unwrap();
+            assert!(inline < display);
+            assert!(find(&pdf[inline..display], b" EMC").is_some());
+            assert!(find(&pdf[display..], b" EMC").is_some());
+            assert_eq!(pdf.windows(5).filter(|w| *w == b"/Span").count(), 2);
+        })
+        .go()
+}
+
+#[test]
+fn svg_image() {
+    TestCase::new("svg_image")
+        .check_pdf_with(|pdf| {
+            // The image is 40x20 px, so 30x15 bp, and its user space is
+            // scaled down and flipped to fit the viewBox.
+            let form = find(pdf, b"/Subtype/Form").unwrap();
+            assert_eq!(numbers_after(&pdf[form..], b"/BBox["), vec![0, 0, 30, 15]);
+            assert!(find(pdf, b".375 0 0 -.375 0 15 cm").is_some());
+
+            // The red square, the gradient and the blue outline.
+            assert!(find(pdf, b"1 0 0 rg\n0 0 m\n40 0 l\n40 20 l\n0 20 l\nh\nf\n").is_some());
+            assert!(find(pdf, b"/Pattern cs /Sh1 scn").is_some());
+            let shading = &pdf[find(pdf, b"/Sh1<<").unwrap()..];
+            assert!(find(shading, b"/ShadingType 2").is_some());
+            assert!(find(shading, b"/C0[1 1 1]/C1[0 1 0]").is_some());
+            assert!(find(pdf, b"0 0 1 RG\n4 w\n").is_some());
+            assert!(find(pdf, b"\nS\n").is_some());
+        })
+        .go()
+}
+
 #[test]
 fn file_encoding() {
     // Need to do this here since we call test_path unusually early.
@@ -280,3 +576,116 @@ fn a4paper() {
         .check_pdf(true)
         .go()
 }
+
+#[test]
+fn linearized() {
+    TestCase::new("linearized")
+        .with_pdf_engine(|engine| engine.with_linearization(true))
+        .check_pdf_with(|pdf| {
+            // The linearization dictionary is the first object in the file.
+            let start = find(pdf, b" 0 obj").unwrap();
+            assert!(pdf[start..].starts_with(b" 0 obj\n<</Linearized 1/L"));
+            let end = start + find(&pdf[start..], b">>").unwrap();
+            let dict = &pdf[start..end];
+
+            assert_eq!(numbers_after(dict, b"/L "), vec![pdf.len()]);
+            assert_eq!(numbers_after(dict, b"/N "), vec![2]);
+
+            // The hint stream is followed by the objects of the first page.
+            let hints = numbers_after(dict, b"/H [");
+            assert_eq!(hints.len(), 2);
+            assert!(is_object_start(pdf, hints[0]));
+            assert!(find(&pdf[hints[0]..], b"stream").unwrap() < hints[1]);
+            assert!(is_object_start(pdf, hints[0] + hints[1]));
+
+            let first_page = numbers_after(dict, b"/O ");
+            let page = pdf_object(pdf, first_page[0]);
+            assert!(find(page, b"/Type/Page").is_some());
+            assert!(find(page, b"/Type/Pages").is_none());
+
+            // The first page ends where the second page's objects start.
+            let end_of_first = numbers_after(dict, b"/E ")[0];
+            assert!(end_of_first > hints[0] + hints[1]);
+            assert!(is_object_start(pdf, end_of_first));
+
+            // The main cross-reference table starts right after /T.
+            let main_xref = numbers_after(dict, b"/T ")[0];
+            assert!(pdf[main_xref].is_ascii_whitespace());
+            assert!(pdf[main_xref + 1..].starts_with(b"0000000000 65535 f"));
+        })
+        .go()
+}
+
+#[test]
+fn structure_tree() {
+    TestCase::new("structure_tree")
+        .check_pdf_with(|pdf| {
+            assert!(find(pdf, b"/StructTreeRoot ").is_some());
+            assert!(find(pdf, b"/MarkInfo<</Marked true>>").is_some());
+            assert!(find(pdf, b"/Type/StructTreeRoot/K[").is_some());
+            assert!(find(pdf, b"/Type/StructElem/S/Document/").is_some());
+            assert!(find(pdf, b"/Type/StructElem/S/P/").is_some());
+
+            // The paragraph's content is tagged with its role and an MCID,
+            // and the page refers to the parent tree.
+            assert!(find(pdf, b"/P <</MCID ").is_some());
+            assert!(find(pdf, b"/Artifact BMC").is_some());
+            assert_eq!(numbers_after(pdf, b"/StructParents "), vec![0]);
+            assert!(find(pdf, b"/Nums[0[").is_some());
+        })
+        .go()
+}
+
+#[test]
+fn layers() {
+    TestCase::new("layers")
+        .check_pdf_with(|pdf| {
+            assert!(find(pdf, b"/Type/OCG/Name(Notes)").is_some());
+            assert!(find(pdf, b"/Type/OCG/Name(Grid)").is_some());
+
+            // The content of each layer is marked up with its properties,
+            // which point to the optional content groups in declaration
+            // order.
+            assert!(find(pdf, b"/OC /OC1 BDC").is_some());
+            assert!(find(pdf, b"/OC /OC2 BDC").is_some());
+            let properties = &pdf[find(pdf, b"/Properties<<").unwrap()..];
+            let notes = numbers_after(properties, b"/OC1 ");
+            let grid = numbers_after(properties, b"/OC2 ");
+            assert_eq!(notes.len(), 2);
+            assert_eq!(grid.len(), 2);
+            assert_ne!(notes, grid);
+
+            // Both are listed, and only the notes are hidden.
+            assert_eq!(numbers_after(pdf, b"/OCProperties<</OCGs["), notes);
+            assert_eq!(numbers_after(pdf, b"/OFF["), notes);
+            assert!(find(pdf, b"/BaseState/ON").is_some());
+        })
+        .go()
+}
+
+#[test]
+fn acroform() {
+    TestCase::new("acroform")
+        .check_pdf_with(|pdf| {
+            // The fields are widget annotations of the page.
+            assert!(find(pdf, b"/Annots ").is_some());
+            assert!(find(pdf, b"/Subtype/Widget").is_some());
+
+            // "address.city" and "address.zip" become the kids of a field
+            // "address", which is listed in the AcroForm along with "agree".
+            let fields = numbers_after(pdf, b"/AcroForm<</Fields[");
+            assert_eq!(fields.len(), 2);
+            assert!(find(pdf, b"/T(address)/Kids[").is_some());
+            for name in [&b"/T(city)"[..], b"/T(zip)"].iter() {
+                let field = &pdf[find(pdf, name).unwrap()..];
+                assert_eq!(numbers_after(field, b"/Parent "), fields);
+            }
+            let agree = &pdf[find(pdf, b"/T(agree)").unwrap()..];
+            let end = find(agree, b">>").unwrap();
+            assert!(find(&agree[..end], b"/Parent").is_none());
+
+            assert!(find(pdf, b"/NeedAppearances true").is_some());
+            assert!(find(pdf, b"/DR<</Font<<").is_some());
+        })
+        .go()
+}
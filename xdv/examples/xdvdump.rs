@@ -9,7 +9,9 @@ use std::fs::File;
 use std::io;
 use std::process;
 use std::str;
-use tectonic_xdv::{FileType, XdvError};
+use tectonic_xdv::{
+    FileType, FontDefinition, FontNum, NativeFontDefinition, XdvError, XdvPageReader,
+};
 
 /// We'd like to use String as our error type, but we also would like to
 /// use the `XdvParser::process()` function, which when imposes the requirement
@@ -101,14 +103,78 @@ impl tectonic_xdv::XdvEvents for Stats {
         Ok(())
     }
 
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        println!("end page");
+        Ok(())
+    }
+
+    fn handle_define_font(
+        &mut self,
+        font_num: FontNum,
+        font: &FontDefinition,
+    ) -> Result<(), Self::Error> {
+        println!(
+            "define font {}: {}{} at {}",
+            font_num, font.area, font.name, font.scale_factor
+        );
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
+        println!(
+            "define native font {}: {}[{}] at {}",
+            font_num, font.path, font.face_index, font.size
+        );
+        Ok(())
+    }
+
+    fn handle_select_font(&mut self, font_num: FontNum) -> Result<(), Self::Error> {
+        println!("select font {}", font_num);
+        Ok(())
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
         let all_ascii_printable = chars.iter().all(|c| *c > 0x20 && *c < 0x7F);
         println!(
-            "chars: {:?} all_ascii_printable={:?}",
-            chars, all_ascii_printable
+            "chars (font {}) at ({}, {}): {:?} all_ascii_printable={:?}",
+            font_num, x[0], y[0], chars, all_ascii_printable
         );
         Ok(())
     }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        if glyphs.is_empty() {
+            return Ok(());
+        }
+
+        println!(
+            "glyphs (font {}) at ({}, {}), width {}: {:?}",
+            font_num, x[0], y[0], width, glyphs
+        );
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        println!("rule at ({}, {}): {} x {}", x, y, width, height);
+        Ok(())
+    }
 }
 
 fn main() {
@@ -121,6 +187,14 @@ fn main() {
                 .required(true)
                 .index(1),
         )
+        .arg(
+            Arg::with_name("page")
+                .long("page")
+                .short("p")
+                .value_name("N")
+                .help("Only dump the Nth page, counting from one")
+                .takes_value(true),
+        )
         .get_matches();
 
     let path = matches.value_of_os("PATH").unwrap();
@@ -137,6 +211,48 @@ fn main() {
         }
     };
 
+    if let Some(page) = matches.value_of("page") {
+        let page = match page.parse::<usize>() {
+            Ok(n) if n > 0 => n - 1,
+            _ => {
+                eprintln!("error: invalid page number \"{}\"", page);
+                process::exit(1);
+            }
+        };
+
+        let mut reader = match XdvPageReader::new(file) {
+            Ok(r) => r,
+            Err(e) => {
+                eprintln!(
+                    "error: failed to index \"{}\": {}",
+                    path.to_string_lossy(),
+                    e
+                );
+                process::exit(1);
+            }
+        };
+
+        if page >= reader.n_pages() {
+            eprintln!(
+                "error: \"{}\" only has {} pages",
+                path.to_string_lossy(),
+                reader.n_pages()
+            );
+            process::exit(1);
+        }
+
+        if let Err(e) = reader.process_page(page, Stats::new()) {
+            eprintln!(
+                "error: failed to parse \"{}\": {}",
+                path.to_string_lossy(),
+                e
+            );
+            process::exit(1);
+        }
+
+        return;
+    }
+
     let (_stats, n_bytes) = match tectonic_xdv::XdvParser::process(file, Stats::new()) {
         Ok(x) => x,
         Err(e) => {
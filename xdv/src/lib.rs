@@ -3,7 +3,8 @@
 
 #![deny(missing_docs)]
 
-//! A decoder for the XDV and SPX file formats used by Tectonic and XeTeX.
+//! A decoder and encoder for the XDV and SPX file formats used by Tectonic
+//! and XeTeX.
 //!
 //! Both of these file formats are derived from the venerable “device
 //! independent” (DVI) format used by TeX. The XDV format (name presumably
@@ -12,6 +13,10 @@
 //! (“semantically-paginated XDV”) is essentially the same as XDV, but
 //! expresses output that is not paginated for print — this is what Tectonic
 //! uses to produce its HTML output.
+//!
+//! Files are decoded with an `XdvParser`, which reports their contents to an
+//! implementation of `XdvEvents`, and written with an `XdvWriter`. An
+//! `XdvPageReader` decodes individual pages of files that can be seeked.
 
 use byteorder::{BigEndian, ByteOrder};
 use std::error;
@@ -20,6 +25,12 @@ use std::io::{Error as IoError, Read};
 use std::marker::PhantomData;
 use std::mem;
 
+mod seek;
+mod writer;
+
+pub use crate::seek::XdvPageReader;
+pub use crate::writer::XdvWriter;
+
 /// Errors that can occur when parsing XDV/SPX files.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum XdvError {
@@ -110,6 +121,37 @@ pub trait XdvEvents {
         Ok(())
     }
 
+    /// Handle the XDV preamble, including the magnification factor. The
+    /// default implementation forwards to `handle_header`.
+    #[allow(unused)]
+    fn handle_preamble(
+        &mut self,
+        filetype: FileType,
+        mag: u32,
+        comment: &[u8],
+    ) -> Result<(), Self::Error> {
+        self.handle_header(filetype, comment)
+    }
+
+    /// Handle the postamble. The font definitions that follow it are reported
+    /// through the usual events.
+    #[allow(unused)]
+    fn handle_postamble(&mut self, postamble: &Postamble) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a single command, exactly as it appears in the file.
+    ///
+    /// This is called for every command inside of pages and for every font
+    /// definition, in addition to the higher-level events, and is meant for
+    /// tools that transform XDV files with an `XdvWriter`. Note that runs of
+    /// characters are reported through `handle_char_run` only after the
+    /// commands that make them up.
+    #[allow(unused)]
+    fn handle_command(&mut self, command: &Command) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
     /// Begin a new page.
     #[allow(unused)]
     fn handle_begin_page(
@@ -126,11 +168,286 @@ pub trait XdvEvents {
         Ok(())
     }
 
-    /// Handle a sequence of characters without intervening commands
+    /// Handle a `\special` that occurs at the position (*x*, *y*). The
+    /// default implementation forwards to `handle_special`.
+    #[allow(unused)]
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<(), Self::Error> {
+        self.handle_special(contents)
+    }
+
+    /// End the current page.
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the definition of a TFM-based font. Fonts are defined before
+    /// they are first used and once more in the postamble.
+    #[allow(unused)]
+    fn handle_define_font(
+        &mut self,
+        font_num: FontNum,
+        font: &FontDefinition,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle the definition of a native font, as used by XeTeX for OpenType
+    /// and TrueType fonts. Fonts are defined before they are first used and
+    /// once more in the postamble.
+    #[allow(unused)]
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a change of the current font.
+    #[allow(unused)]
+    fn handle_select_font(&mut self, font_num: FontNum) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Return the width of a character of a TFM-based font, in DVI units.
+    ///
+    /// The parser uses this to keep track of the horizontal position as
+    /// characters are set, since the font metrics are not part of the file.
+    /// The default implementation returns zero.
+    #[allow(unused)]
+    fn char_width(&mut self, font_num: FontNum, char_num: i32) -> i32 {
+        0
+    }
+
+    /// Handle a sequence of characters of a TFM-based font without
+    /// intervening commands. The characters are placed at the positions
+    /// given by *x* and *y*, which have one entry per character.
     #[allow(unused)]
-    fn handle_char_run(&mut self, chars: &[i32]) -> Result<(), Self::Error> {
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
         Ok(())
     }
+
+    /// Handle a run of glyphs of a native font. The glyphs are placed at the
+    /// positions given by *x* and *y*, which have one entry per glyph.
+    /// Afterwards, the horizontal position advances by *width*.
+    #[allow(unused)]
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Handle a run of glyphs of a native font that comes with the text it
+    /// represents, as UTF-16. The default implementation ignores the text
+    /// and forwards the glyphs to `handle_glyph_run`.
+    #[allow(unused)]
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: FontNum,
+        text: &[u16],
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.handle_glyph_run(font_num, width, glyphs, x, y)
+    }
+
+    /// Handle a rule: a filled rectangle whose lower left corner is at
+    /// (*x*, *y*). Rules with a height or width that is not positive are
+    /// not visible.
+    #[allow(unused)]
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+/// The number by which a font is referred to in an XDV/SPX file.
+pub type FontNum = i32;
+
+/// A TFM-based font.
+///
+/// All positions and sizes in XDV/SPX files are in DVI units, which are
+/// TeX’s scaled points.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FontDefinition {
+    /// The checksum of the font’s TFM file.
+    pub checksum: u32,
+
+    /// The size at which the font is used.
+    pub scale_factor: u32,
+
+    /// The design size of the font.
+    pub design_size: u32,
+
+    /// The directory of the font, which is usually empty.
+    pub area: String,
+
+    /// The name of the font.
+    pub name: String,
+}
+
+/// A low-level XDV command, as found inside of pages.
+///
+/// Movements are given relative to the current position. The variants that
+/// make use of the *w*, *x*, *y* and *z* registers are kept distinct so that
+/// files can be rewritten without changes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command<'a> {
+    /// Set a character of the current font and move right by its width.
+    SetChar(i32),
+
+    /// Set a character of the current font without moving.
+    PutChar(i32),
+
+    /// Draw a rule and move right by its width. The operands are the height
+    /// and width.
+    SetRule(i32, i32),
+
+    /// Draw a rule without moving. The operands are the height and width.
+    PutRule(i32, i32),
+
+    /// Do nothing.
+    Noop,
+
+    /// Save the current position and registers.
+    Push,
+
+    /// Restore the most recently saved position and registers.
+    Pop,
+
+    /// Move right.
+    Right(i32),
+
+    /// Move right by the value of *w*.
+    W0,
+
+    /// Set *w* and move right by it.
+    W(i32),
+
+    /// Move right by the value of *x*.
+    X0,
+
+    /// Set *x* and move right by it.
+    X(i32),
+
+    /// Move down.
+    Down(i32),
+
+    /// Move down by the value of *y*.
+    Y0,
+
+    /// Set *y* and move down by it.
+    Y(i32),
+
+    /// Move down by the value of *z*.
+    Z0,
+
+    /// Set *z* and move down by it.
+    Z(i32),
+
+    /// Select a font.
+    SelectFont(FontNum),
+
+    /// A `\special`.
+    Special(&'a [u8]),
+
+    /// Define a TFM-based font.
+    DefineFont(FontNum, &'a FontDefinition),
+
+    /// Define a native font.
+    DefineNativeFont(FontNum, &'a NativeFontDefinition),
+
+    /// Set glyphs of the current native font and move right by *width*.
+    Glyphs {
+        /// The total advance of the glyphs.
+        width: i32,
+
+        /// The glyph IDs.
+        glyphs: &'a [u16],
+
+        /// The horizontal offsets of the glyphs from the current position.
+        dx: &'a [i32],
+
+        /// The vertical offsets of the glyphs from the current position.
+        dy: &'a [i32],
+    },
+
+    /// Like `Glyphs`, along with the text, as UTF-16, that they represent.
+    TextAndGlyphs {
+        /// The text of the glyphs.
+        text: &'a [u16],
+
+        /// The total advance of the glyphs.
+        width: i32,
+
+        /// The glyph IDs.
+        glyphs: &'a [u16],
+
+        /// The horizontal offsets of the glyphs from the current position.
+        dx: &'a [i32],
+
+        /// The vertical offsets of the glyphs from the current position.
+        dy: &'a [i32],
+    },
+}
+
+/// The information found in the postamble of an XDV file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Postamble {
+    /// The magnification factor, times 1000.
+    pub mag: u32,
+
+    /// The height plus depth of the tallest page.
+    pub max_v: u32,
+
+    /// The width of the widest page.
+    pub max_h: u32,
+
+    /// The maximum depth of the stack of saved positions.
+    pub max_stack_depth: u16,
+
+    /// The number of pages, modulo 65536.
+    pub n_pages: u16,
+}
+
+/// A native font, that is, an OpenType or TrueType font loaded by XeTeX.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct NativeFontDefinition {
+    /// The path of the font file.
+    pub path: String,
+
+    /// The index of the face within the font file.
+    pub face_index: u32,
+
+    /// The size at which the font is used.
+    pub size: i32,
+
+    /// Whether the font is used for vertical text.
+    pub vertical: bool,
+
+    /// The color of the text as 0xRRGGBBAA, if one is given.
+    pub color_rgba: Option<u32>,
+
+    /// The horizontal stretch factor, as a 16.16 fixed-point number.
+    pub extend: Option<i32>,
+
+    /// The slant, as a 16.16 fixed-point number.
+    pub slant: Option<i32>,
+
+    /// The emboldening strength, as a 16.16 fixed-point number.
+    pub embolden: Option<i32>,
 }
 
 /// State for parsing an XDV file.
@@ -140,9 +457,11 @@ pub struct XdvParser<T: XdvEvents> {
     filetype: FileType,
     state: ParserState,
     stack: Vec<State>,
-    cur_font_num: Option<i32>,
+    cur_font_num: Option<FontNum>,
     offset: u64,
     cur_char_run: Vec<i32>,
+    cur_char_x: Vec<i32>,
+    cur_char_y: Vec<i32>,
 }
 
 /// Which type of file is being parsed.
@@ -201,6 +520,8 @@ impl<T: XdvEvents> XdvParser<T> {
             cur_font_num: None,
             offset: 0,
             cur_char_run: Vec::new(),
+            cur_char_x: Vec::new(),
+            cur_char_y: Vec::new(),
         }
     }
 
@@ -215,12 +536,23 @@ impl<T: XdvEvents> XdvParser<T> {
     /// The initial buffer size is hardcoded to 4096 bytes. It doubles in size
     /// every time the parser is unable to make any progress at all in the
     /// current chunk, which should be a rare circumstance.
-    pub fn process<R: Read>(mut stream: R, events: T) -> Result<(T, u64), T::Error>
+    pub fn process<R: Read>(stream: R, events: T) -> Result<(T, u64), T::Error>
     where
         T::Error: From<IoError>,
     {
-        const BUF_SIZE: usize = 4096;
         let mut parser = Self::new(events);
+        parser.feed(stream)?;
+        let n_bytes = parser.current_offset();
+        Ok((parser.finish()?, n_bytes))
+    }
+
+    /// Parse everything that can be read from *stream*, returning the number
+    /// of bytes at its end that did not make up a complete command.
+    fn feed<R: Read>(&mut self, mut stream: R) -> Result<usize, T::Error>
+    where
+        T::Error: From<IoError>,
+    {
+        const BUF_SIZE: usize = 4096;
         let mut buf = Vec::with_capacity(BUF_SIZE);
         unsafe {
             buf.set_len(BUF_SIZE);
@@ -230,7 +562,7 @@ impl<T: XdvEvents> XdvParser<T> {
         loop {
             let n_read = stream.read(&mut buf[n_saved_bytes..])?;
             let n_in_buffer = n_saved_bytes + n_read;
-            let n_consumed = parser.parse(&buf[..n_in_buffer])?;
+            let n_consumed = self.parse(&buf[..n_in_buffer])?;
             n_saved_bytes = n_in_buffer - n_consumed;
 
             if n_consumed != 0 && n_saved_bytes != 0 {
@@ -263,8 +595,7 @@ impl<T: XdvEvents> XdvParser<T> {
             }
         }
 
-        let n_bytes = parser.current_offset();
-        Ok((parser.finish()?, n_bytes))
+        Ok(n_saved_bytes)
     }
 
     /// Parse the next chunk of XDV data.
@@ -284,23 +615,23 @@ impl<T: XdvEvents> XdvParser<T> {
             }
 
             let opcode = cursor.get_u8().unwrap();
-            let mut char_run_ended = true; // most commands end runs of characters
+
+            // Most commands end runs of characters, and the run must be
+            // reported before whatever the command itself reports.
+            if !self.cur_char_run.is_empty() && ends_char_run(opcode) {
+                self.flush_char_run()?;
+            }
 
             let rv = match opcode {
                 // This is the least ugly way I've found to map the u8 to the
                 // symbolic enum values.
-                oc if oc == Opcode::Noop as u8 => {
-                    char_run_ended = false;
-                    Ok(())
-                }
+                oc if oc == Opcode::Noop as u8 => self.do_noop(oc, &mut cursor),
 
                 oc if oc >= Opcode::DefineFont1 as u8 && oc <= Opcode::DefineFont4 as u8 => {
-                    char_run_ended = false;
                     self.do_define_font(oc, &mut cursor)
                 }
 
                 oc if oc == Opcode::DefineNativeFont as u8 => {
-                    char_run_ended = false;
                     self.do_define_native_font(oc, &mut cursor)
                 }
 
@@ -310,10 +641,7 @@ impl<T: XdvEvents> XdvParser<T> {
 
                 oc if oc == Opcode::EndOfPage as u8 => self.do_end_of_page(oc, &mut cursor),
 
-                oc if oc == Opcode::PushStack as u8 => {
-                    char_run_ended = false;
-                    self.do_push_stack(oc, &mut cursor)
-                }
+                oc if oc == Opcode::PushStack as u8 => self.do_push_stack(oc, &mut cursor),
 
                 oc if oc == Opcode::PopStack as u8 => self.do_pop_stack(oc, &mut cursor),
 
@@ -360,15 +688,21 @@ impl<T: XdvEvents> XdvParser<T> {
                 oc if oc >= Opcode::SetCharNumber0 as u8
                     && oc <= Opcode::SetCharNumber127 as u8 =>
                 {
-                    char_run_ended = false;
                     self.do_set_char_number(oc, &mut cursor)
                 }
 
                 oc if oc >= Opcode::SetChar1 as u8 && oc <= Opcode::SetChar4 as u8 => {
-                    char_run_ended = false;
                     self.do_set_char(oc, &mut cursor)
                 }
 
+                oc if oc >= Opcode::PutChar1 as u8 && oc <= Opcode::PutChar4 as u8 => {
+                    self.do_put_char(oc, &mut cursor)
+                }
+
+                oc if oc == Opcode::SetRule as u8 || oc == Opcode::PutRule as u8 => {
+                    self.do_rule(oc, &mut cursor)
+                }
+
                 oc if oc == Opcode::SetGlyphs as u8 => self.do_set_glyphs(oc, &mut cursor),
 
                 oc if oc == Opcode::SetTextAndGlyphs as u8 => {
@@ -403,17 +737,36 @@ impl<T: XdvEvents> XdvParser<T> {
                     return Err(e);
                 }
             }
-
-            if char_run_ended && !self.cur_char_run.is_empty() {
-                self.events.handle_char_run(&self.cur_char_run)?;
-                self.cur_char_run.clear();
-            }
         }
 
         self.offset += cursor.checkpoint as u64;
         Ok(cursor.checkpoint)
     }
 
+    fn flush_char_run(&mut self) -> Result<(), T::Error> {
+        // Characters can only be set once a font has been selected.
+        let font_num = self.cur_font_num.unwrap();
+        self.events.handle_char_run(
+            font_num,
+            &self.cur_char_run,
+            &self.cur_char_x,
+            &self.cur_char_y,
+        )?;
+        self.cur_char_run.clear();
+        self.cur_char_x.clear();
+        self.cur_char_y.clear();
+        Ok(())
+    }
+
+    fn command(&mut self, command: &Command) -> InternalResult<(), T::Error> {
+        self.events.handle_command(command)?;
+        Ok(())
+    }
+
+    fn do_noop(&mut self, _opcode: u8, _cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
+        self.command(&Command::Noop)
+    }
+
     fn do_preamble(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
         if self.state != ParserState::Preamble {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
@@ -429,10 +782,10 @@ impl<T: XdvEvents> XdvParser<T> {
 
         cursor.assert_u32(25_400_000)?; // dimensions unit numerator
         cursor.assert_u32(473_628_672)?; // dimensions unit denominator
-        cursor.get_u32()?; // 'mag' factor
+        let mag = cursor.get_u32()?;
         let n_comment = cursor.get_u8()?;
         self.events
-            .handle_header(self.filetype, cursor.get_slice(n_comment as usize)?)?;
+            .handle_preamble(self.filetype, mag, cursor.get_slice(n_comment as usize)?)?;
 
         self.state = ParserState::BetweenPages;
         Ok(())
@@ -447,20 +800,23 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
-        let _checksum = cursor.get_u32()?;
-        let _scale_factor = cursor.get_u32()?;
-        let _design_size = cursor.get_u32()?;
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::DefineFont1 as u8)?;
+        let checksum = cursor.get_u32()?;
+        let scale_factor = cursor.get_u32()?;
+        let design_size = cursor.get_u32()?;
         let area_len = cursor.get_u8()?;
         let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _area_str = from_utf8(cursor.get_slice(area_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
+        let area = cursor.get_string(area_len as usize)?;
+        let name = cursor.get_string(name_len as usize)?;
+        let font = FontDefinition {
+            checksum,
+            scale_factor,
+            design_size,
+            area,
+            name,
+        };
+        self.command(&Command::DefineFont(font_num, &font))?;
+        self.events.handle_define_font(font_num, &font)?;
         Ok(())
     }
 
@@ -473,41 +829,49 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _font_num = cursor.get_i32()?;
-        let _size = cursor.get_i32()?; // fixed-point
+        let font_num = cursor.get_i32()?;
+        let size = cursor.get_i32()?;
         let flags = cursor.get_u16()?;
-        let name_len = cursor.get_u8()?;
-        // XXX TEMP
-        use std::str::from_utf8;
-        let _name_str = from_utf8(cursor.get_slice(name_len as usize)?)
-            .unwrap()
-            .to_owned();
-        let _face_index = cursor.get_u32()?;
-
-        let _color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
+        let path_len = cursor.get_u8()?;
+        let path = cursor.get_string(path_len as usize)?;
+        let face_index = cursor.get_u32()?;
+
+        let color_rgba = if flags & NativeFontFlags::Colored as u16 != 0 {
             Some(cursor.get_u32()?)
         } else {
             None
         };
 
-        let _extend = if flags & NativeFontFlags::Extend as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let extend = if flags & NativeFontFlags::Extend as u16 != 0 {
+            Some(cursor.get_i32()?)
         } else {
             None
         };
 
-        let _slant = if flags & NativeFontFlags::Slant as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let slant = if flags & NativeFontFlags::Slant as u16 != 0 {
+            Some(cursor.get_i32()?)
         } else {
             None
         };
 
-        let _embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
-            Some(cursor.get_u32()?) // fixed-point
+        let embolden = if flags & NativeFontFlags::Embolden as u16 != 0 {
+            Some(cursor.get_i32()?)
         } else {
             None
         };
 
+        let font = NativeFontDefinition {
+            path,
+            face_index,
+            size,
+            vertical: flags & NativeFontFlags::Vertical as u16 != 0,
+            color_rgba,
+            extend,
+            slant,
+            embolden,
+        };
+        self.command(&Command::DefineNativeFont(font_num, &font))?;
+        self.events.handle_define_native_font(font_num, &font)?;
         Ok(())
     }
 
@@ -549,6 +913,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::Malformed(cursor.global_offset()).into_internal());
         }
 
+        self.events.handle_end_page()?;
         self.state = ParserState::BetweenPages;
         Ok(())
     }
@@ -562,6 +927,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::Push)?;
         let dup = self.stack.last().unwrap().clone();
         self.stack.push(dup);
         Ok(())
@@ -576,6 +942,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::Malformed(cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::Pop)?;
         self.stack.pop();
         Ok(())
     }
@@ -586,6 +953,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::Right1 as u8)?;
+        self.command(&Command::Right(n))?;
         self.stack.last_mut().unwrap().h += n;
         Ok(())
     }
@@ -599,6 +967,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::W0)?;
         let state = self.stack.last_mut().unwrap();
         state.h += state.w;
         Ok(())
@@ -610,6 +979,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::SetW1 as u8)?;
+        self.command(&Command::W(n))?;
         let state = self.stack.last_mut().unwrap();
         state.w = n;
         state.h += n;
@@ -625,6 +995,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::X0)?;
         let state = self.stack.last_mut().unwrap();
         state.h += state.x;
         Ok(())
@@ -636,6 +1007,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::SetX1 as u8)?;
+        self.command(&Command::X(n))?;
         let state = self.stack.last_mut().unwrap();
         state.x = n;
         state.h += n;
@@ -648,6 +1020,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::Down1 as u8)?;
+        self.command(&Command::Down(n))?;
         self.stack.last_mut().unwrap().v += n;
         Ok(())
     }
@@ -657,6 +1030,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::Y0)?;
         let state = self.stack.last_mut().unwrap();
         state.v += state.y;
         Ok(())
@@ -668,6 +1042,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::SetY1 as u8)?;
+        self.command(&Command::Y(n))?;
         let state = self.stack.last_mut().unwrap();
         state.y = n;
         state.v += n;
@@ -679,6 +1054,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        self.command(&Command::Z0)?;
         let state = self.stack.last_mut().unwrap();
         state.v += state.z;
         Ok(())
@@ -690,6 +1066,7 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_i32_smneg(opcode - Opcode::SetZ1 as u8)?;
+        self.command(&Command::Z(n))?;
         let state = self.stack.last_mut().unwrap();
         state.z = n;
         state.v += n;
@@ -706,8 +1083,7 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(i32::from(opcode - Opcode::SetFontNumber0 as u8));
-        Ok(())
+        self.select_font(i32::from(opcode - Opcode::SetFontNumber0 as u8))
     }
 
     /// This variant takes an argument that encodes the font number.
@@ -716,7 +1092,14 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        self.cur_font_num = Some(cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?);
+        let font_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetFont1 as u8)?;
+        self.select_font(font_num)
+    }
+
+    fn select_font(&mut self, font_num: FontNum) -> InternalResult<(), T::Error> {
+        self.command(&Command::SelectFont(font_num))?;
+        self.cur_font_num = Some(font_num);
+        self.events.handle_select_font(font_num)?;
         Ok(())
     }
 
@@ -730,9 +1113,9 @@ impl<T: XdvEvents> XdvParser<T> {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let char_num = opcode - Opcode::SetCharNumber0 as u8;
-        self.cur_char_run.push(i32::from(char_num));
-        Ok(())
+        let char_num = i32::from(opcode - Opcode::SetCharNumber0 as u8);
+        self.command(&Command::SetChar(char_num))?;
+        self.add_char(char_num, true, cursor)
     }
 
     /// This variant takes an argument that encodes the character number.
@@ -742,31 +1125,92 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let char_num = cursor.get_compact_i32_smpos(opcode - Opcode::SetChar1 as u8)?;
-        self.cur_char_run.push(char_num as i32);
-        Ok(())
+        self.command(&Command::SetChar(char_num))?;
+        self.add_char(char_num, true, cursor)
     }
 
-    fn do_set_glyphs(
+    /// Like `set_char`, but without moving the current position.
+    fn do_put_char(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
+        if self.state != ParserState::InPage {
+            return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
+        }
+
+        let char_num = cursor.get_compact_i32_smpos(opcode - Opcode::PutChar1 as u8)?;
+        self.command(&Command::PutChar(char_num))?;
+        self.add_char(char_num, false, cursor)
+    }
+
+    fn add_char(
         &mut self,
-        opcode: u8,
+        char_num: i32,
+        advance: bool,
         cursor: &mut Cursor<T>,
     ) -> InternalResult<(), T::Error> {
+        let font_num = match self.cur_font_num {
+            Some(n) => n,
+            None => return Err(XdvError::Malformed(cursor.global_offset()).into_internal()),
+        };
+
+        let state = self.stack.last_mut().unwrap();
+        self.cur_char_run.push(char_num);
+        self.cur_char_x.push(state.h);
+        self.cur_char_y.push(state.v);
+
+        if advance {
+            state.h += self.events.char_width(font_num, char_num);
+        }
+
+        Ok(())
+    }
+
+    fn do_rule(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
         if self.state != ParserState::InPage {
             return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
-        let _width = cursor.get_i32()?;
-        let n_glyphs = cursor.get_u16()?;
+        let height = cursor.get_i32()?;
+        let width = cursor.get_i32()?;
+        let is_set = opcode == Opcode::SetRule as u8;
 
-        for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
+        self.command(&if is_set {
+            Command::SetRule(height, width)
+        } else {
+            Command::PutRule(height, width)
+        })?;
+
+        let state = self.stack.last_mut().unwrap();
+        let (h, v) = (state.h, state.v);
+
+        if is_set {
+            state.h += width;
         }
 
-        for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
+        self.events.handle_rule(h, v, height, width)?;
+        Ok(())
+    }
+
+    fn do_set_glyphs(
+        &mut self,
+        opcode: u8,
+        cursor: &mut Cursor<T>,
+    ) -> InternalResult<(), T::Error> {
+        if self.state != ParserState::InPage {
+            return Err(XdvError::IllegalOpcode(opcode, cursor.global_offset()).into_internal());
         }
 
+        let width = cursor.get_i32()?;
+        let (glyphs, dx, dy) = self.get_glyphs(cursor)?;
+        let font_num = self.native_font_num(cursor)?;
+        self.command(&Command::Glyphs {
+            width,
+            glyphs: &glyphs,
+            dx: &dx,
+            dy: &dy,
+        })?;
+        let (x, y) = self.absolute_positions(&dx, &dy);
+        self.events
+            .handle_glyph_run(font_num, width, &glyphs, &x, &y)?;
+        self.stack.last_mut().unwrap().h += width;
         Ok(())
     }
 
@@ -786,19 +1230,60 @@ impl<T: XdvEvents> XdvParser<T> {
             chars.push(cursor.get_u16()?);
         }
 
-        let _width = cursor.get_i32()?;
-        let n_glyphs = cursor.get_u16()?;
+        let width = cursor.get_i32()?;
+        let (glyphs, dx, dy) = self.get_glyphs(cursor)?;
+        let font_num = self.native_font_num(cursor)?;
+        self.command(&Command::TextAndGlyphs {
+            text: &chars,
+            width,
+            glyphs: &glyphs,
+            dx: &dx,
+            dy: &dy,
+        })?;
+        let (x, y) = self.absolute_positions(&dx, &dy);
+        self.events
+            .handle_text_and_glyphs(font_num, &chars, width, &glyphs, &x, &y)?;
+        self.stack.last_mut().unwrap().h += width;
+        Ok(())
+    }
+
+    /// Read the glyphs of a glyph run, returning their IDs and their offsets
+    /// from the current position.
+    #[allow(clippy::type_complexity)]
+    fn get_glyphs(
+        &self,
+        cursor: &mut Cursor<T>,
+    ) -> InternalResult<(Vec<u16>, Vec<i32>, Vec<i32>), T::Error> {
+        let n_glyphs = cursor.get_u16()? as usize;
+        let mut dx = Vec::with_capacity(n_glyphs);
+        let mut dy = Vec::with_capacity(n_glyphs);
 
         for _ in 0..n_glyphs {
-            let _x = cursor.get_u32()?;
-            let _y = cursor.get_u32()?;
+            dx.push(cursor.get_i32()?);
+            dy.push(cursor.get_i32()?);
         }
 
+        let mut glyphs = Vec::with_capacity(n_glyphs);
+
         for _ in 0..n_glyphs {
-            let _glyph_id = cursor.get_i16()?;
+            glyphs.push(cursor.get_u16()?);
         }
 
-        Ok(())
+        Ok((glyphs, dx, dy))
+    }
+
+    fn absolute_positions(&self, dx: &[i32], dy: &[i32]) -> (Vec<i32>, Vec<i32>) {
+        let state = self.stack.last().unwrap();
+        let x = dx.iter().map(|dx| state.h + dx).collect();
+        let y = dy.iter().map(|dy| state.v + dy).collect();
+        (x, y)
+    }
+
+    fn native_font_num(&self, cursor: &mut Cursor<T>) -> InternalResult<FontNum, T::Error> {
+        match self.cur_font_num {
+            Some(n) => Ok(n),
+            None => Err(XdvError::Malformed(cursor.global_offset()).into_internal()),
+        }
     }
 
     fn do_special(&mut self, opcode: u8, cursor: &mut Cursor<T>) -> InternalResult<(), T::Error> {
@@ -807,7 +1292,10 @@ impl<T: XdvEvents> XdvParser<T> {
         }
 
         let n = cursor.get_compact_u32(opcode - Opcode::Special1 as u8)?;
-        self.events.handle_special(cursor.get_slice(n as usize)?)?;
+        let contents = cursor.get_slice(n as usize)?;
+        let state = self.stack.last().unwrap();
+        self.events.handle_command(&Command::Special(contents))?;
+        self.events.handle_special_at(state.h, state.v, contents)?;
 
         Ok(())
     }
@@ -820,11 +1308,18 @@ impl<T: XdvEvents> XdvParser<T> {
         cursor.get_u32()?; // last_bop
         cursor.assert_u32(25_400_000)?; // dimensions unit numerator
         cursor.assert_u32(473_628_672)?; // dimensions unit denominator
-        cursor.get_u32()?; // 'mag' factor
-        cursor.get_u32()?; // largest height+depth of tallest page
-        cursor.get_u32()?; // largest width of widest page
-        cursor.get_u16()?; // maximum stack depth
-        cursor.get_u16()?; // number of pages
+        let mag = cursor.get_u32()?;
+        let max_v = cursor.get_u32()?; // largest height+depth of tallest page
+        let max_h = cursor.get_u32()?; // largest width of widest page
+        let max_stack_depth = cursor.get_u16()?;
+        let n_pages = cursor.get_u16()?;
+        self.events.handle_postamble(&Postamble {
+            mag,
+            max_v,
+            max_h,
+            max_stack_depth,
+            n_pages,
+        })?;
 
         self.state = ParserState::PostambleFontDefinitions;
         Ok(())
@@ -1075,6 +1570,15 @@ impl<'a, T: XdvEvents> Cursor<'a, T> {
         }
     }
 
+    pub fn get_string(&mut self, n: usize) -> InternalResult<String, T::Error> {
+        let offset = self.global_offset();
+
+        match String::from_utf8(self.get_slice(n)?.to_vec()) {
+            Ok(s) => Ok(s),
+            Err(_) => Err(XdvError::Malformed(offset).into_internal()),
+        }
+    }
+
     pub fn get_slice(&mut self, n: usize) -> InternalResult<&[u8], T::Error> {
         if self.buf.len() < n {
             return Err(InternalError::NeedMoreData);
@@ -1095,6 +1599,10 @@ enum Opcode {
     SetCharNumber127 = 127,
     SetChar1 = 128,
     SetChar4 = 131,
+    SetRule = 132,
+    PutChar1 = 133,
+    PutChar4 = 136,
+    PutRule = 137,
     Noop = 138,
     BeginningOfPage = 139,
     EndOfPage = 140,
@@ -1132,6 +1640,17 @@ enum Opcode {
     SetTextAndGlyphs = 254, // "SET_TEXT_AND_GLYPHS", "XDV_TEXT_AND_GLYPHS"
 }
 
+/// Whether a command ends a run of characters. Only commands that set more
+/// characters, and those that neither move nor draw, keep runs going.
+fn ends_char_run(opcode: u8) -> bool {
+    !(opcode <= Opcode::SetChar4 as u8
+        || (opcode >= Opcode::PutChar1 as u8 && opcode <= Opcode::PutChar4 as u8)
+        || opcode == Opcode::Noop as u8
+        || opcode == Opcode::PushStack as u8
+        || (opcode >= Opcode::DefineFont1 as u8 && opcode <= Opcode::DefineFont4 as u8)
+        || opcode == Opcode::DefineNativeFont as u8)
+}
+
 /// Identifier bytes used by the XDV writing code.
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u8)]
@@ -1143,7 +1662,6 @@ enum IdByte {
 /// Flags for XeTeX native fonts
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 #[repr(u16)]
-#[allow(unused)]
 enum NativeFontFlags {
     Vertical = 0x0100,
     Colored = 0x0200,
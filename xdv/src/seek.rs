@@ -0,0 +1,218 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Random access to the pages of XDV/SPX files.
+
+use byteorder::{BigEndian, ByteOrder};
+use std::io::{Error as IoError, ErrorKind, Read, Seek, SeekFrom};
+
+use super::{FileType, IdByte, Opcode, ParserState, XdvError, XdvEvents, XdvParser};
+
+/// The size of the postamble up to its font definitions.
+const POSTAMBLE_HEADER_SIZE: u64 = 29;
+
+/// The size of a beginning-of-page command.
+const BOP_SIZE: usize = 45;
+
+/// A reader that gives random access to the pages of an XDV/SPX file.
+///
+/// When created, the reader finds the postamble at the end of the file and
+/// follows the back-pointers of the pages to build an index of them. Pages
+/// can then be processed individually, in any order, without reading the
+/// rest of the file.
+#[derive(Debug)]
+pub struct XdvPageReader<R: Read + Seek> {
+    stream: R,
+    filetype: FileType,
+    preamble_size: u64,
+    postamble: u64,
+    post_postamble: u64,
+    pages: Vec<(u64, [i32; 10])>,
+}
+
+fn malformed(offset: u64) -> IoError {
+    IoError::new(ErrorKind::InvalidData, XdvError::Malformed(offset))
+}
+
+fn read_at<R: Read + Seek>(stream: &mut R, offset: u64, buf: &mut [u8]) -> Result<(), IoError> {
+    stream.seek(SeekFrom::Start(offset))?;
+
+    stream.read_exact(buf).map_err(|e| {
+        if e.kind() == ErrorKind::UnexpectedEof {
+            malformed(offset)
+        } else {
+            e
+        }
+    })
+}
+
+impl<R: Read + Seek> XdvPageReader<R> {
+    /// Index the pages of an XDV/SPX file.
+    ///
+    /// Files that do not have the expected structure are reported as errors
+    /// of the kind `InvalidData`, wrapping an `XdvError`.
+    pub fn new(mut stream: R) -> Result<Self, IoError> {
+        let len = stream.seek(SeekFrom::End(0))?;
+
+        // The file ends with the pointer to the postamble, the identification
+        // byte, and at least four bytes of 223.
+        let tail_len = len.min(256);
+        let mut tail = vec![0; tail_len as usize];
+        read_at(&mut stream, len - tail_len, &mut tail)?;
+
+        let n_padding = tail.iter().rev().take_while(|b| **b == 0xDF).count();
+
+        if n_padding < 4 || n_padding + 6 > tail.len() {
+            return Err(malformed(len));
+        }
+
+        let id_pos = tail.len() - n_padding - 1;
+        let filetype = match tail[id_pos] {
+            b if b == IdByte::Xdv as u8 => FileType::Xdv,
+            b if b == IdByte::Spx as u8 => FileType::Spx,
+            _ => return Err(malformed(len - tail_len + id_pos as u64)),
+        };
+
+        let post_postamble = len - tail_len + id_pos as u64 - 5;
+
+        if tail[id_pos - 5] != Opcode::DoublePostamble as u8 {
+            return Err(malformed(post_postamble));
+        }
+
+        let postamble = BigEndian::read_u32(&tail[id_pos - 4..id_pos]) as u64;
+
+        let mut preamble = [0u8; 15];
+        read_at(&mut stream, 0, &mut preamble)?;
+
+        if preamble[0] != Opcode::Preamble as u8 || preamble[1] != tail[id_pos] {
+            return Err(malformed(0));
+        }
+
+        let preamble_size = 15 + u64::from(preamble[14]);
+
+        if postamble < preamble_size || postamble + POSTAMBLE_HEADER_SIZE > post_postamble {
+            return Err(malformed(post_postamble));
+        }
+
+        let mut header = [0u8; POSTAMBLE_HEADER_SIZE as usize];
+        read_at(&mut stream, postamble, &mut header)?;
+
+        if header[0] != Opcode::Postamble as u8 {
+            return Err(malformed(postamble));
+        }
+
+        // Follow the chain of pages backwards from the last one. Pointers
+        // must decrease, so that broken files cannot make us loop.
+        let mut pages = Vec::new();
+        let mut bop = BigEndian::read_i32(&header[1..5]);
+        let mut limit = postamble;
+
+        while bop != -1 {
+            let offset = bop as u64;
+
+            if bop < 0 || offset < preamble_size || offset + BOP_SIZE as u64 > limit {
+                return Err(malformed(limit));
+            }
+
+            let mut buf = [0u8; BOP_SIZE];
+            read_at(&mut stream, offset, &mut buf)?;
+
+            if buf[0] != Opcode::BeginningOfPage as u8 {
+                return Err(malformed(offset));
+            }
+
+            let mut counters = [0i32; 10];
+            BigEndian::read_i32_into(&buf[1..41], &mut counters);
+            pages.push((offset, counters));
+            bop = BigEndian::read_i32(&buf[41..45]);
+            limit = offset;
+        }
+
+        pages.reverse();
+
+        Ok(XdvPageReader {
+            stream,
+            filetype,
+            preamble_size,
+            postamble,
+            post_postamble,
+            pages,
+        })
+    }
+
+    /// Get the type of the file.
+    pub fn filetype(&self) -> FileType {
+        self.filetype
+    }
+
+    /// Get the number of pages in the file.
+    pub fn n_pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Get the values of TeX’s `\count0` to `\count9` for a page, as given
+    /// when the page was shipped out.
+    ///
+    /// Panics if *page* is not less than the number of pages.
+    pub fn page_counters(&self, page: usize) -> &[i32] {
+        &self.pages[page].1
+    }
+
+    /// Process a single page, counting from zero.
+    ///
+    /// The events for the preamble are emitted first, followed by those for
+    /// all of the font definitions of the postamble, so that every font is
+    /// defined before the page is processed. The postamble itself is not
+    /// reported.
+    ///
+    /// Panics if *page* is not less than the number of pages.
+    pub fn process_page<T: XdvEvents>(&mut self, page: usize, events: T) -> Result<T, T::Error>
+    where
+        T::Error: From<IoError>,
+    {
+        let start = self.pages[page].0;
+        let end = match self.pages.get(page + 1) {
+            Some((offset, _)) => *offset,
+            None => self.postamble,
+        };
+
+        let mut parser = XdvParser::new(events);
+        self.feed(&mut parser, 0, self.preamble_size)?;
+        self.feed(
+            &mut parser,
+            self.postamble + POSTAMBLE_HEADER_SIZE,
+            self.post_postamble,
+        )?;
+        self.feed(&mut parser, start, end)?;
+
+        if parser.state != ParserState::BetweenPages {
+            return Err(XdvError::Malformed(end).into());
+        }
+
+        Ok(parser.events)
+    }
+
+    fn feed<T: XdvEvents>(
+        &mut self,
+        parser: &mut XdvParser<T>,
+        start: u64,
+        end: u64,
+    ) -> Result<(), T::Error>
+    where
+        T::Error: From<IoError>,
+    {
+        self.stream.seek(SeekFrom::Start(start))?;
+        parser.offset = start;
+
+        if parser.feed((&mut self.stream).take(end - start))? != 0 || parser.offset != end {
+            return Err(XdvError::UnexpectedEndOfStream.into());
+        }
+
+        Ok(())
+    }
+
+    /// Consume this object, returning the underlying stream.
+    pub fn into_inner(self) -> R {
+        self.stream
+    }
+}
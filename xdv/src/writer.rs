@@ -0,0 +1,504 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Writing XDV/SPX files.
+
+use byteorder::{BigEndian, WriteBytesExt};
+use std::collections::BTreeMap;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write};
+
+use super::{
+    Command, FileType, FontDefinition, FontNum, IdByte, NativeFontDefinition, NativeFontFlags,
+    Opcode, Postamble,
+};
+
+/// A font known to the writer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Font {
+    Tfm(FontDefinition),
+    Native(NativeFontDefinition),
+}
+
+/// A writer that emits XDV/SPX files.
+///
+/// The writer takes care of the bookkeeping of the format: the back-pointers
+/// of the pages, the postamble and its maxima, and the font definitions.
+/// Fonts are registered with `Command::DefineFont` and
+/// `Command::DefineNativeFont`, and their definitions are written out just
+/// before they are first selected, and again in the postamble, as TeX does.
+/// Numbers are encoded in the same way as TeX encodes them, so that a file
+/// written by XeTeX and passed through an `XdvParser` into an `XdvWriter`
+/// comes out unchanged.
+#[derive(Debug)]
+pub struct XdvWriter<W: Write> {
+    dest: W,
+    filetype: FileType,
+    mag: u32,
+    offset: u64,
+    buf: Vec<u8>,
+    in_page: bool,
+    stack_depth: u16,
+    max_stack_depth: u16,
+    n_pages: u32,
+    last_bop: i32,
+    max_v: u32,
+    max_h: u32,
+    fonts: BTreeMap<FontNum, (Font, bool)>,
+}
+
+fn invalid<T>(message: &str) -> IoResult<T> {
+    Err(IoError::new(ErrorKind::InvalidInput, message))
+}
+
+/// The size marker of a signed number, following the rules that TeX uses
+/// for movements.
+fn signed_size(n: i32) -> u8 {
+    let a = i64::from(n).abs();
+
+    if a >= 0x80_0000 {
+        3
+    } else if a >= 0x8000 {
+        2
+    } else if a >= 0x80 {
+        1
+    } else {
+        0
+    }
+}
+
+/// The size marker of a number that is stored unsigned unless it needs all
+/// four bytes.
+fn unsigned_size(n: i32) -> u8 {
+    if !(0..0x100_0000).contains(&n) {
+        3
+    } else if n >= 0x1_0000 {
+        2
+    } else if n >= 0x100 {
+        1
+    } else {
+        0
+    }
+}
+
+fn put_sized(buf: &mut Vec<u8>, size: u8, n: i32) {
+    let bytes = n.to_be_bytes();
+    buf.extend_from_slice(&bytes[3 - size as usize..]);
+}
+
+impl<W: Write> XdvWriter<W> {
+    /// Create a new writer and write the preamble of the file. The
+    /// magnification factor is given times 1000, so that 1000 means no
+    /// magnification.
+    pub fn new(dest: W, filetype: FileType, mag: u32, comment: &[u8]) -> IoResult<Self> {
+        if comment.len() > 255 {
+            return invalid("XDV comment is longer than 255 bytes");
+        }
+
+        let mut writer = XdvWriter {
+            dest,
+            filetype,
+            mag,
+            offset: 0,
+            buf: Vec::new(),
+            in_page: false,
+            stack_depth: 0,
+            max_stack_depth: 0,
+            n_pages: 0,
+            last_bop: -1,
+            max_v: 0,
+            max_h: 0,
+            fonts: BTreeMap::new(),
+        };
+
+        writer.buf.push(Opcode::Preamble as u8);
+        let id = writer.id_byte();
+        writer.buf.push(id);
+        writer.put_header_units();
+        writer.buf.push(comment.len() as u8);
+        writer.buf.extend_from_slice(comment);
+        writer.flush_buf()?;
+        Ok(writer)
+    }
+
+    fn id_byte(&self) -> u8 {
+        match self.filetype {
+            FileType::Xdv => IdByte::Xdv as u8,
+            FileType::Spx => IdByte::Spx as u8,
+        }
+    }
+
+    fn put_header_units(&mut self) {
+        self.buf.write_u32::<BigEndian>(25_400_000).unwrap(); // dimensions unit numerator
+        self.buf.write_u32::<BigEndian>(473_628_672).unwrap(); // dimensions unit denominator
+        self.buf.write_u32::<BigEndian>(self.mag).unwrap();
+    }
+
+    fn flush_buf(&mut self) -> IoResult<()> {
+        self.dest.write_all(&self.buf)?;
+        self.offset += self.buf.len() as u64;
+        self.buf.clear();
+        Ok(())
+    }
+
+    /// Record the size of a page, so that the postamble can state the size
+    /// of the tallest and widest pages. The height includes the depth.
+    pub fn record_page_size(&mut self, height: u32, width: u32) {
+        self.max_v = self.max_v.max(height);
+        self.max_h = self.max_h.max(width);
+    }
+
+    /// Take into account the maxima stated in the postamble of a file that is
+    /// being transformed. TeX may state a larger stack depth than its pages
+    /// need, so this is needed to write such files back unchanged.
+    pub fn record_postamble(&mut self, postamble: &Postamble) {
+        self.record_page_size(postamble.max_v, postamble.max_h);
+        self.max_stack_depth = self.max_stack_depth.max(postamble.max_stack_depth);
+    }
+
+    /// Begin a new page, with the given values of TeX’s `\count0` to
+    /// `\count9`.
+    pub fn begin_page(&mut self, counters: &[i32]) -> IoResult<()> {
+        if self.in_page {
+            return invalid("cannot begin an XDV page inside of another");
+        }
+
+        if counters.len() != 10 {
+            return invalid("XDV pages must have exactly ten counters");
+        }
+
+        let bop = self.offset as i32;
+        self.buf.push(Opcode::BeginningOfPage as u8);
+
+        for c in counters {
+            self.buf.write_i32::<BigEndian>(*c).unwrap();
+        }
+
+        self.buf.write_i32::<BigEndian>(self.last_bop).unwrap();
+        self.flush_buf()?;
+
+        self.last_bop = bop;
+        self.n_pages += 1;
+        self.in_page = true;
+        Ok(())
+    }
+
+    /// End the current page.
+    pub fn end_page(&mut self) -> IoResult<()> {
+        if !self.in_page {
+            return invalid("cannot end an XDV page outside of one");
+        }
+
+        if self.stack_depth != 0 {
+            return invalid("XDV page ended with unbalanced pushes");
+        }
+
+        self.buf.push(Opcode::EndOfPage as u8);
+        self.flush_buf()?;
+        self.in_page = false;
+        Ok(())
+    }
+
+    /// Write a command. Apart from font definitions and no-ops, commands may
+    /// only be written inside of pages.
+    pub fn command(&mut self, command: &Command) -> IoResult<()> {
+        match *command {
+            Command::DefineFont(font_num, font) => {
+                return self.define_font(font_num, Font::Tfm(font.clone()));
+            }
+            Command::DefineNativeFont(font_num, font) => {
+                return self.define_font(font_num, Font::Native(font.clone()));
+            }
+            Command::Noop => {
+                self.buf.push(Opcode::Noop as u8);
+                return self.flush_buf();
+            }
+            _ => {}
+        }
+
+        if !self.in_page {
+            return invalid("XDV commands must be inside of pages");
+        }
+
+        match *command {
+            Command::SetChar(c) => {
+                if (0..=Opcode::SetCharNumber127 as i32).contains(&c) {
+                    self.buf.push(c as u8);
+                } else {
+                    self.put_unsigned(Opcode::SetChar1, c);
+                }
+            }
+            Command::PutChar(c) => self.put_unsigned(Opcode::PutChar1, c),
+            Command::SetRule(height, width) => self.put_rule(Opcode::SetRule, height, width),
+            Command::PutRule(height, width) => self.put_rule(Opcode::PutRule, height, width),
+            Command::Push => {
+                self.stack_depth += 1;
+                self.max_stack_depth = self.max_stack_depth.max(self.stack_depth);
+                self.buf.push(Opcode::PushStack as u8);
+            }
+            Command::Pop => {
+                if self.stack_depth == 0 {
+                    return invalid("XDV pop without matching push");
+                }
+
+                self.stack_depth -= 1;
+                self.buf.push(Opcode::PopStack as u8);
+            }
+            Command::Right(n) => self.put_signed(Opcode::Right1, n),
+            Command::W0 => self.buf.push(Opcode::RightByW as u8),
+            Command::W(n) => self.put_signed(Opcode::SetW1, n),
+            Command::X0 => self.buf.push(Opcode::RightByX as u8),
+            Command::X(n) => self.put_signed(Opcode::SetX1, n),
+            Command::Down(n) => self.put_signed(Opcode::Down1, n),
+            Command::Y0 => self.buf.push(Opcode::DownByY as u8),
+            Command::Y(n) => self.put_signed(Opcode::SetY1, n),
+            Command::Z0 => self.buf.push(Opcode::DownByZ as u8),
+            Command::Z(n) => self.put_signed(Opcode::SetZ1, n),
+            Command::SelectFont(font_num) => self.select_font(font_num)?,
+            Command::Special(contents) => {
+                // TeX only uses the shortest and the longest form.
+                if contents.len() < 0x100 {
+                    self.buf.push(Opcode::Special1 as u8);
+                    self.buf.push(contents.len() as u8);
+                } else {
+                    self.buf.push(Opcode::Special4 as u8);
+                    self.buf
+                        .write_u32::<BigEndian>(contents.len() as u32)
+                        .unwrap();
+                }
+
+                self.buf.extend_from_slice(contents);
+            }
+            Command::Glyphs {
+                width,
+                glyphs,
+                dx,
+                dy,
+            } => {
+                self.buf.push(Opcode::SetGlyphs as u8);
+                self.put_glyphs(width, glyphs, dx, dy)?;
+            }
+            Command::TextAndGlyphs {
+                text,
+                width,
+                glyphs,
+                dx,
+                dy,
+            } => {
+                if text.len() > 0xFFFF {
+                    return invalid("too much text in XDV glyph run");
+                }
+
+                self.buf.push(Opcode::SetTextAndGlyphs as u8);
+                self.buf.write_u16::<BigEndian>(text.len() as u16).unwrap();
+
+                for c in text {
+                    self.buf.write_u16::<BigEndian>(*c).unwrap();
+                }
+
+                self.put_glyphs(width, glyphs, dx, dy)?;
+            }
+            Command::DefineFont(..) | Command::DefineNativeFont(..) | Command::Noop => {
+                unreachable!()
+            }
+        }
+
+        self.flush_buf()
+    }
+
+    fn put_signed(&mut self, op1: Opcode, n: i32) {
+        let size = signed_size(n);
+        self.buf.push(op1 as u8 + size);
+        put_sized(&mut self.buf, size, n);
+    }
+
+    fn put_unsigned(&mut self, op1: Opcode, n: i32) {
+        let size = unsigned_size(n);
+        self.buf.push(op1 as u8 + size);
+        put_sized(&mut self.buf, size, n);
+    }
+
+    fn put_rule(&mut self, opcode: Opcode, height: i32, width: i32) {
+        self.buf.push(opcode as u8);
+        self.buf.write_i32::<BigEndian>(height).unwrap();
+        self.buf.write_i32::<BigEndian>(width).unwrap();
+    }
+
+    fn put_glyphs(&mut self, width: i32, glyphs: &[u16], dx: &[i32], dy: &[i32]) -> IoResult<()> {
+        if glyphs.len() > 0xFFFF {
+            return invalid("too many glyphs in XDV glyph run");
+        }
+
+        if dx.len() != glyphs.len() || dy.len() != glyphs.len() {
+            return invalid("XDV glyph run has mismatched offsets");
+        }
+
+        self.buf.write_i32::<BigEndian>(width).unwrap();
+        self.buf
+            .write_u16::<BigEndian>(glyphs.len() as u16)
+            .unwrap();
+
+        for (x, y) in dx.iter().zip(dy) {
+            self.buf.write_i32::<BigEndian>(*x).unwrap();
+            self.buf.write_i32::<BigEndian>(*y).unwrap();
+        }
+
+        for g in glyphs {
+            self.buf.write_u16::<BigEndian>(*g).unwrap();
+        }
+
+        Ok(())
+    }
+
+    fn define_font(&mut self, font_num: FontNum, font: Font) -> IoResult<()> {
+        match font {
+            Font::Tfm(ref font) if font.area.len() > 255 => {
+                return invalid("XDV font area is longer than 255 bytes");
+            }
+            Font::Tfm(ref font) if font.name.len() > 255 => {
+                return invalid("XDV font name is longer than 255 bytes");
+            }
+            Font::Native(ref font) if font.path.len() > 255 => {
+                return invalid("XDV native font path is longer than 255 bytes");
+            }
+            _ => {}
+        }
+
+        match self.fonts.get(&font_num) {
+            Some((known, _)) if *known == font => Ok(()),
+            Some(_) => invalid("conflicting definitions of an XDV font"),
+            None => {
+                self.fonts.insert(font_num, (font, false));
+                Ok(())
+            }
+        }
+    }
+
+    fn select_font(&mut self, font_num: FontNum) -> IoResult<()> {
+        let font = match self.fonts.get_mut(&font_num) {
+            Some((font, written)) => {
+                if *written {
+                    None
+                } else {
+                    *written = true;
+                    Some(font.clone())
+                }
+            }
+            None => return invalid("XDV font selected before being defined"),
+        };
+
+        if let Some(font) = font {
+            self.put_font_definition(font_num, &font);
+        }
+
+        if (0..=63).contains(&font_num) {
+            self.buf.push(Opcode::SetFontNumber0 as u8 + font_num as u8);
+        } else {
+            self.put_unsigned(Opcode::SetFont1, font_num);
+        }
+
+        Ok(())
+    }
+
+    /// Write a font definition. The lengths of the names have been checked
+    /// by `define_font`, so they fit in a byte.
+    fn put_font_definition(&mut self, font_num: FontNum, font: &Font) {
+        match font {
+            Font::Tfm(font) => {
+                self.put_unsigned(Opcode::DefineFont1, font_num);
+                self.buf.write_u32::<BigEndian>(font.checksum).unwrap();
+                self.buf.write_u32::<BigEndian>(font.scale_factor).unwrap();
+                self.buf.write_u32::<BigEndian>(font.design_size).unwrap();
+                self.buf.push(font.area.len() as u8);
+                self.buf.push(font.name.len() as u8);
+                self.buf.extend_from_slice(font.area.as_bytes());
+                self.buf.extend_from_slice(font.name.as_bytes());
+            }
+
+            Font::Native(font) => {
+                let mut flags = 0;
+                let mut options = Vec::new();
+
+                if font.vertical {
+                    flags |= NativeFontFlags::Vertical as u16;
+                }
+
+                if let Some(rgba) = font.color_rgba {
+                    flags |= NativeFontFlags::Colored as u16;
+                    options.push(rgba as i32);
+                }
+
+                if let Some(extend) = font.extend {
+                    flags |= NativeFontFlags::Extend as u16;
+                    options.push(extend);
+                }
+
+                if let Some(slant) = font.slant {
+                    flags |= NativeFontFlags::Slant as u16;
+                    options.push(slant);
+                }
+
+                if let Some(embolden) = font.embolden {
+                    flags |= NativeFontFlags::Embolden as u16;
+                    options.push(embolden);
+                }
+
+                self.buf.push(Opcode::DefineNativeFont as u8);
+                self.buf.write_i32::<BigEndian>(font_num).unwrap();
+                self.buf.write_i32::<BigEndian>(font.size).unwrap();
+                self.buf.write_u16::<BigEndian>(flags).unwrap();
+                self.buf.push(font.path.len() as u8);
+                self.buf.extend_from_slice(font.path.as_bytes());
+                self.buf.write_u32::<BigEndian>(font.face_index).unwrap();
+
+                for option in options {
+                    self.buf.write_i32::<BigEndian>(option).unwrap();
+                }
+            }
+        }
+    }
+
+    /// Write the postamble, finishing the file, and return the underlying
+    /// writer.
+    pub fn finish(mut self) -> IoResult<W> {
+        if self.in_page {
+            return invalid("XDV file ended inside of a page");
+        }
+
+        let post = self.offset as i32;
+        self.buf.push(Opcode::Postamble as u8);
+        self.buf.write_i32::<BigEndian>(self.last_bop).unwrap();
+        self.put_header_units();
+        self.buf.write_u32::<BigEndian>(self.max_v).unwrap();
+        self.buf.write_u32::<BigEndian>(self.max_h).unwrap();
+        self.buf
+            .write_u16::<BigEndian>(self.max_stack_depth)
+            .unwrap();
+        self.buf
+            .write_u16::<BigEndian>(self.n_pages as u16)
+            .unwrap();
+
+        // TeX lists the fonts from the last to the first.
+        let fonts: Vec<_> = self
+            .fonts
+            .iter()
+            .rev()
+            .filter(|(_, (_, written))| *written)
+            .map(|(num, (font, _))| (*num, font.clone()))
+            .collect();
+
+        for (font_num, font) in &fonts {
+            self.put_font_definition(*font_num, font);
+        }
+
+        self.buf.push(Opcode::DoublePostamble as u8);
+        self.buf.write_i32::<BigEndian>(post).unwrap();
+        let id = self.id_byte();
+        self.buf.push(id);
+
+        // At least four bytes of 223, up to a multiple of four.
+        let n = 4 + (4 - (self.offset as usize + self.buf.len()) % 4) % 4;
+        self.buf.resize(self.buf.len() + n, 0xDF);
+        self.flush_buf()?;
+        Ok(self.dest)
+    }
+}
@@ -0,0 +1,261 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Check the positions that the XDV parser reports for the contents of a
+//! page, which it works out from the movement commands.
+
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::io::Error as IoError;
+
+use tectonic_xdv::{
+    Command, FileType, FontDefinition, FontNum, NativeFontDefinition, XdvError, XdvEvents,
+    XdvParser, XdvWriter,
+};
+
+#[derive(Debug)]
+enum Error {
+    Io(IoError),
+    Xdv(XdvError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Xdv(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<XdvError> for Error {
+    fn from(e: XdvError) -> Self {
+        Error::Xdv(e)
+    }
+}
+
+/// Record the high-level events, leaving out the individual commands.
+#[derive(Debug, Default)]
+struct Recorder {
+    events: Vec<String>,
+}
+
+impl XdvEvents for Recorder {
+    type Error = Error;
+
+    fn handle_begin_page(&mut self, counters: &[i32], _previous: i32) -> Result<(), Self::Error> {
+        self.events.push(format!("bop {}", counters[0]));
+        Ok(())
+    }
+
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        self.events.push("eop".to_owned());
+        Ok(())
+    }
+
+    fn handle_define_font(
+        &mut self,
+        font_num: FontNum,
+        font: &FontDefinition,
+    ) -> Result<(), Self::Error> {
+        self.events.push(format!(
+            "define {} {} {:x} {}",
+            font_num, font.name, font.checksum, font.scale_factor
+        ));
+        Ok(())
+    }
+
+    fn handle_define_native_font(
+        &mut self,
+        font_num: FontNum,
+        font: &NativeFontDefinition,
+    ) -> Result<(), Self::Error> {
+        self.events.push(format!(
+            "define native {} {} {} {} {:?} {:?} {:?} {:?}",
+            font_num,
+            font.path,
+            font.face_index,
+            font.size,
+            font.color_rgba,
+            font.extend,
+            font.slant,
+            font.embolden
+        ));
+        Ok(())
+    }
+
+    fn handle_select_font(&mut self, font_num: FontNum) -> Result<(), Self::Error> {
+        self.events.push(format!("font {}", font_num));
+        Ok(())
+    }
+
+    fn char_width(&mut self, _font_num: FontNum, char_num: i32) -> i32 {
+        1000 * char_num
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.events
+            .push(format!("chars {} {:?} {:?} {:?}", font_num, chars, x, y));
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.events.push(format!(
+            "glyphs {} {} {:?} {:?} {:?}",
+            font_num, width, glyphs, x, y
+        ));
+        Ok(())
+    }
+
+    fn handle_text_and_glyphs(
+        &mut self,
+        font_num: FontNum,
+        text: &[u16],
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.events.push(format!(
+            "text {} {} {} {:?} {:?} {:?}",
+            font_num,
+            String::from_utf16_lossy(text),
+            width,
+            glyphs,
+            x,
+            y
+        ));
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        self.events
+            .push(format!("rule {} {} {} {}", x, y, height, width));
+        Ok(())
+    }
+
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<(), Self::Error> {
+        self.events.push(format!(
+            "special {} {} {}",
+            x,
+            y,
+            String::from_utf8_lossy(contents)
+        ));
+        Ok(())
+    }
+}
+
+#[test]
+fn positions() {
+    let tfm = FontDefinition {
+        checksum: 0x1234_5678,
+        scale_factor: 0x000A_0000,
+        design_size: 0x000A_0000,
+        area: String::new(),
+        name: "cmr10".to_owned(),
+    };
+    let native = NativeFontDefinition {
+        path: "texgyrepagella-regular.otf".to_owned(),
+        face_index: 1,
+        size: 0x000C_0000,
+        vertical: false,
+        color_rgba: Some(0xff00_00ff),
+        extend: Some(0x0001_8000),
+        slant: Some(-0x4000),
+        embolden: None,
+    };
+
+    let mut writer = XdvWriter::new(Vec::new(), FileType::Xdv, 1000, b"positions").unwrap();
+    let mut counters = [0; 10];
+    counters[0] = 1;
+    writer.begin_page(&counters).unwrap();
+    let commands = [
+        Command::DefineFont(1, &tfm),
+        Command::DefineNativeFont(2, &native),
+        Command::SelectFont(1),
+        Command::Down(1000),
+        // Characters advance by their width, except for a put.
+        Command::SetChar(10),
+        Command::SetChar(20),
+        Command::PutChar(5),
+        Command::Push,
+        Command::W(500),
+        Command::W0,
+        Command::X(-100),
+        Command::X0,
+        Command::Y(20),
+        Command::Y0,
+        Command::Z(-40),
+        Command::Z0,
+        Command::SetChar(1),
+        Command::SetRule(10, 200),
+        Command::Special(b"inside"),
+        Command::Pop,
+        Command::PutRule(7, 70),
+        Command::SelectFont(2),
+        Command::Glyphs {
+            width: 900,
+            glyphs: &[3, 1, 4],
+            dx: &[0, 300, 600],
+            dy: &[0, 0, -50],
+        },
+        Command::TextAndGlyphs {
+            text: &[0x48, 0x69],
+            width: 400,
+            glyphs: &[7, 8],
+            dx: &[0, 200],
+            dy: &[0, 0],
+        },
+        Command::Special(b"after"),
+    ];
+    for command in commands.iter() {
+        writer.command(command).unwrap();
+    }
+    writer.end_page().unwrap();
+    writer.record_page_size(792 * 65536, 612 * 65536);
+    let data = writer.finish().unwrap();
+
+    let (recorder, _) = XdvParser::process(&data[..], Recorder::default()).unwrap();
+    let tfm_event = "define 1 cmr10 12345678 655360";
+    let native_event = "define native 2 texgyrepagella-regular.otf 1 786432 \
+                        Some(4278190335) Some(98304) Some(-16384) None";
+    // The writer puts each definition right before the font's first use,
+    // and lists the fonts in the postamble from the last to the first.
+    let expected = [
+        "bop 1",
+        tfm_event,
+        "font 1",
+        "chars 1 [10, 20, 5] [0, 10000, 30000] [1000, 1000, 1000]",
+        "chars 1 [1] [30800] [960]",
+        "rule 31800 960 10 200",
+        "special 32000 960 inside",
+        "rule 30000 1000 7 70",
+        native_event,
+        "font 2",
+        "glyphs 2 900 [3, 1, 4] [30000, 30300, 30600] [1000, 1000, 950]",
+        "text 2 Hi 400 [7, 8] [30900, 31100] [1000, 1000]",
+        "special 31300 1000 after",
+        "eop",
+        native_event,
+        tfm_event,
+    ];
+    assert_eq!(recorder.events, expected);
+}
@@ -0,0 +1,160 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Check that the XDV files produced by the test suite of the main crate
+//! come out of an `XdvParser` and an `XdvWriter` unchanged.
+
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::fs;
+use std::io::Error as IoError;
+use std::path::PathBuf;
+
+use tectonic_xdv::{
+    Command, FileType, FontDefinition, NativeFontDefinition, Postamble, XdvError, XdvEvents,
+    XdvParser, XdvWriter,
+};
+
+#[derive(Debug)]
+enum Error {
+    Io(IoError),
+    Xdv(XdvError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Xdv(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<XdvError> for Error {
+    fn from(e: XdvError) -> Self {
+        Error::Xdv(e)
+    }
+}
+
+/// Pass everything that is parsed on to a writer.
+#[derive(Debug, Default)]
+struct Rewriter {
+    writer: Option<XdvWriter<Vec<u8>>>,
+    output: Option<Vec<u8>>,
+}
+
+impl Rewriter {
+    fn writer(&mut self) -> &mut XdvWriter<Vec<u8>> {
+        self.writer.as_mut().expect("XDV event before the preamble")
+    }
+}
+
+impl XdvEvents for Rewriter {
+    type Error = Error;
+
+    fn handle_preamble(
+        &mut self,
+        filetype: FileType,
+        mag: u32,
+        comment: &[u8],
+    ) -> Result<(), Self::Error> {
+        self.writer = Some(XdvWriter::new(Vec::new(), filetype, mag, comment)?);
+        Ok(())
+    }
+
+    fn handle_begin_page(&mut self, counters: &[i32], _previous: i32) -> Result<(), Self::Error> {
+        Ok(self.writer().begin_page(counters)?)
+    }
+
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        Ok(self.writer().end_page()?)
+    }
+
+    fn handle_command(&mut self, command: &Command) -> Result<(), Self::Error> {
+        // The font definitions of the postamble are written by the writer
+        // itself.
+        match self.writer {
+            Some(ref mut writer) => Ok(writer.command(command)?),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_postamble(&mut self, postamble: &Postamble) -> Result<(), Self::Error> {
+        let mut writer = self
+            .writer
+            .take()
+            .expect("XDV postamble before the preamble");
+        writer.record_postamble(postamble);
+        self.output = Some(writer.finish()?);
+        Ok(())
+    }
+}
+
+fn tex_outputs() -> PathBuf {
+    let mut p = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    p.push("..");
+    p.push("tests");
+    p.push("tex-outputs");
+    p
+}
+
+#[test]
+fn roundtrip_tex_outputs() {
+    let mut n = 0;
+
+    for entry in fs::read_dir(tex_outputs()).unwrap() {
+        let path = entry.unwrap().path();
+
+        if path.extension().map_or(false, |e| e == "xdv") {
+            let original = fs::read(&path).unwrap();
+            let (rewriter, _) = XdvParser::process(&original[..], Rewriter::default())
+                .unwrap_or_else(|e| panic!("failed to rewrite {}: {}", path.display(), e));
+            let output = rewriter.output.expect("XDV file without a postamble");
+            assert!(output == original, "{} changed", path.display());
+            n += 1;
+        }
+    }
+
+    assert!(n > 0, "no XDV files found in {}", tex_outputs().display());
+}
+
+#[test]
+fn long_font_names() {
+    let mut writer = XdvWriter::new(Vec::new(), FileType::Xdv, 1000, b"").unwrap();
+    let long = "x".repeat(256);
+
+    let tfm = FontDefinition {
+        checksum: 0,
+        scale_factor: 0x000A_0000,
+        design_size: 0x000A_0000,
+        area: String::new(),
+        name: long.clone(),
+    };
+    assert!(writer.command(&Command::DefineFont(1, &tfm)).is_err());
+
+    let tfm = FontDefinition {
+        area: long.clone(),
+        name: "cmr10".to_owned(),
+        ..tfm
+    };
+    assert!(writer.command(&Command::DefineFont(2, &tfm)).is_err());
+
+    let native = NativeFontDefinition {
+        path: long,
+        face_index: 0,
+        size: 0x000A_0000,
+        vertical: false,
+        color_rgba: None,
+        extend: None,
+        slant: None,
+        embolden: None,
+    };
+    assert!(writer
+        .command(&Command::DefineNativeFont(3, &native))
+        .is_err());
+}
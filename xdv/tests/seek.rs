@@ -0,0 +1,220 @@
+// Copyright 2020 the Tectonic Project
+// Licensed under the MIT License.
+
+//! Check that an `XdvPageReader` reports the same events for a page as a
+//! parse of the whole file.
+
+use std::fmt::{Display, Error as FmtError, Formatter};
+use std::io::{Cursor, Error as IoError};
+
+use tectonic_xdv::{
+    Command, FileType, FontDefinition, FontNum, NativeFontDefinition, XdvError, XdvEvents,
+    XdvPageReader, XdvParser, XdvWriter,
+};
+
+#[derive(Debug)]
+enum Error {
+    Io(IoError),
+    Xdv(XdvError),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> Result<(), FmtError> {
+        match self {
+            Error::Io(e) => write!(f, "{}", e),
+            Error::Xdv(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<IoError> for Error {
+    fn from(e: IoError) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<XdvError> for Error {
+    fn from(e: XdvError) -> Self {
+        Error::Xdv(e)
+    }
+}
+
+/// Record the events inside of pages, one list per page.
+#[derive(Debug, Default)]
+struct Recorder {
+    pages: Vec<Vec<String>>,
+    in_page: bool,
+}
+
+impl Recorder {
+    fn record(&mut self, event: String) {
+        if self.in_page {
+            self.pages.last_mut().unwrap().push(event);
+        }
+    }
+}
+
+impl XdvEvents for Recorder {
+    type Error = Error;
+
+    fn handle_begin_page(&mut self, counters: &[i32], previous: i32) -> Result<(), Self::Error> {
+        self.pages
+            .push(vec![format!("bop {:?} {}", counters, previous)]);
+        self.in_page = true;
+        Ok(())
+    }
+
+    fn handle_end_page(&mut self) -> Result<(), Self::Error> {
+        self.record("eop".to_owned());
+        self.in_page = false;
+        Ok(())
+    }
+
+    fn handle_command(&mut self, command: &Command) -> Result<(), Self::Error> {
+        self.record(format!("{:?}", command));
+        Ok(())
+    }
+
+    fn handle_special_at(&mut self, x: i32, y: i32, contents: &[u8]) -> Result<(), Self::Error> {
+        self.record(format!("special {} {} {:?}", x, y, contents));
+        Ok(())
+    }
+
+    fn handle_select_font(&mut self, font_num: FontNum) -> Result<(), Self::Error> {
+        self.record(format!("font {}", font_num));
+        Ok(())
+    }
+
+    fn char_width(&mut self, _font_num: FontNum, char_num: i32) -> i32 {
+        1000 * char_num
+    }
+
+    fn handle_char_run(
+        &mut self,
+        font_num: FontNum,
+        chars: &[i32],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.record(format!("chars {} {:?} {:?} {:?}", font_num, chars, x, y));
+        Ok(())
+    }
+
+    fn handle_glyph_run(
+        &mut self,
+        font_num: FontNum,
+        width: i32,
+        glyphs: &[u16],
+        x: &[i32],
+        y: &[i32],
+    ) -> Result<(), Self::Error> {
+        self.record(format!(
+            "glyphs {} {} {:?} {:?} {:?}",
+            font_num, width, glyphs, x, y
+        ));
+        Ok(())
+    }
+
+    fn handle_rule(&mut self, x: i32, y: i32, height: i32, width: i32) -> Result<(), Self::Error> {
+        self.record(format!("rule {} {} {} {}", x, y, height, width));
+        Ok(())
+    }
+}
+
+/// Write a file of `n_pages` pages. The fonts are defined on the first page
+/// only, so that the later pages depend on the definitions in the postamble.
+fn multi_page_file(n_pages: i32) -> Vec<u8> {
+    let mut writer = XdvWriter::new(Vec::new(), FileType::Xdv, 1000, b"seek test").unwrap();
+    let tfm = FontDefinition {
+        checksum: 0x1234_5678,
+        scale_factor: 0x000A_0000,
+        design_size: 0x000A_0000,
+        area: String::new(),
+        name: "cmr10".to_owned(),
+    };
+    let native = NativeFontDefinition {
+        path: "texgyrepagella-regular.otf".to_owned(),
+        face_index: 0,
+        size: 0x000C_0000,
+        vertical: false,
+        color_rgba: None,
+        extend: None,
+        slant: None,
+        embolden: None,
+    };
+
+    for page in 0..n_pages {
+        let mut counters = [0; 10];
+        counters[0] = page + 1;
+        counters[1] = -page;
+        writer.begin_page(&counters).unwrap();
+
+        let label = format!("page {}", page);
+        let commands = [
+            Command::Push,
+            Command::Down(100 * (page + 1)),
+            Command::Right(50),
+            Command::Special(label.as_bytes()),
+            Command::Pop,
+        ];
+        for command in commands.iter() {
+            writer.command(command).unwrap();
+        }
+        if page == 0 {
+            writer.command(&Command::DefineFont(1, &tfm)).unwrap();
+            writer
+                .command(&Command::DefineNativeFont(2, &native))
+                .unwrap();
+        }
+
+        writer.command(&Command::SelectFont(1)).unwrap();
+        for c in 1..=page + 2 {
+            writer.command(&Command::SetChar(c)).unwrap();
+        }
+        writer.command(&Command::W(7)).unwrap();
+        writer.command(&Command::W0).unwrap();
+        writer.command(&Command::PutRule(10, 20 + page)).unwrap();
+        writer.command(&Command::Y(-30)).unwrap();
+
+        writer.command(&Command::SelectFont(2)).unwrap();
+        let glyphs = [3, 1, 4];
+        let dx = [0, 300, 600 + page];
+        let dy = [0, 0, -page];
+        writer
+            .command(&Command::Glyphs {
+                width: 900,
+                glyphs: &glyphs,
+                dx: &dx,
+                dy: &dy,
+            })
+            .unwrap();
+        writer.command(&Command::SetRule(5, 5)).unwrap();
+        writer.end_page().unwrap();
+    }
+
+    writer.record_page_size(792 * 65536, 612 * 65536);
+    writer.finish().unwrap()
+}
+
+#[test]
+fn pages_match_sequential_parse() {
+    let data = multi_page_file(4);
+    let (sequential, _) = XdvParser::process(&data[..], Recorder::default()).unwrap();
+    assert_eq!(sequential.pages.len(), 4);
+
+    let mut reader = XdvPageReader::new(Cursor::new(&data[..])).unwrap();
+    assert_eq!(reader.filetype(), FileType::Xdv);
+    assert_eq!(reader.n_pages(), 4);
+
+    // Out of order, and with a page visited twice.
+    for &page in [2, 0, 3, 1, 2].iter() {
+        let mut counters = [0; 10];
+        counters[0] = page as i32 + 1;
+        counters[1] = -(page as i32);
+        assert_eq!(reader.page_counters(page), &counters[..]);
+
+        let events = reader.process_page(page, Recorder::default()).unwrap();
+        assert_eq!(events.pages.len(), 1);
+        assert_eq!(events.pages[0], sequential.pages[page], "page {}", page);
+    }
+}